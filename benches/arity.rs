@@ -0,0 +1,52 @@
+use criterion::{criterion_group, criterion_main, measurement::Measurement, BatchSize, BenchmarkGroup, BenchmarkId, Criterion, Throughput};
+use rand::thread_rng;
+use merkle_race::merkle_crhf::{new_merkle_crhf_const_arity, new_merkle_crhf_from_leaves, Blake3HashFunc};
+use merkle_race::random_updates;
+
+const NUM_LEAVES: usize = 1 << 20;
+const NUM_UPDATES: usize = 1000;
+
+// Compares updating a tree whose arity is only known at run time with updating one whose arity is fixed
+// at compile time. Blake3 is used since it is the fastest hash, so the index arithmetic is a bigger
+// part of the update time.
+//
+// NOTE(Perf): With 1,000 updates in a tree of 2^20 leaves, the compile-time arity was ~4% faster at arity
+// 16, but made no difference at arity 256, where hashing all 256 children of every parent dominates.
+pub fn arity_benchmark<M: Measurement, const ARITY: usize>(
+    c: &mut BenchmarkGroup<M>,
+) {
+    let mut merkle = new_merkle_crhf_from_leaves::<Blake3HashFunc>(ARITY, NUM_LEAVES);
+    let mut fixed = new_merkle_crhf_const_arity::<Blake3HashFunc, ARITY>(NUM_LEAVES);
+
+    c.throughput(Throughput::Elements(NUM_UPDATES as u64));
+    c.bench_function(BenchmarkId::new("dyn", ARITY), |b| {
+        b.iter_batched(
+            || random_updates(&mut thread_rng(), NUM_LEAVES, NUM_UPDATES),
+            |updates| merkle.update_leaves(updates),
+            BatchSize::SmallInput,
+        )
+    });
+    c.bench_function(BenchmarkId::new("const", ARITY), |b| {
+        b.iter_batched(
+            || random_updates(&mut thread_rng(), NUM_LEAVES, NUM_UPDATES),
+            |updates| fixed.update_leaves(updates),
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+pub fn bench_group(c: &mut Criterion) {
+    let mut group = c.benchmark_group("arity");
+
+    arity_benchmark::<_, 16>(&mut group);
+    arity_benchmark::<_, 256>(&mut group);
+
+    group.finish();
+}
+
+criterion_group!(
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = bench_group);
+
+criterion_main!(benches);
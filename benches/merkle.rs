@@ -1,68 +1,81 @@
-// use criterion::{
-//     criterion_group, criterion_main, measurement::Measurement, BenchmarkGroup, BenchmarkId,
-//     Criterion, Throughput,
-// };
-// use itertools::Itertools;
-// use merkle_race::max_leaves;
-// use merkle_race::merkle_crhf::{new_merkle_crhf_from_height, TinySha3HashFunc};
-// use more_asserts::assert_le;
-// use rand::{distributions::Alphanumeric, Rng};
-// use std::iter::zip;
+use criterion::{criterion_group, criterion_main, measurement::Measurement, BatchSize, BenchmarkGroup, BenchmarkId, Criterion, Throughput};
+use rand::thread_rng;
+use rust_incrhash::compressed_ristretto::CompRistBlakeIncHash;
+use rust_incrhash::ristretto::RistBlakeIncHash;
+use merkle_race::hashing_traits::ParallelTreeHasherFunc;
+use merkle_race::merkle_abstract::AbstractMerkle;
+use merkle_race::merkle_crhf::{new_merkle_crhf_from_leaves, Sha3HashFunc};
+use merkle_race::merkle_pp::new_merklepp_from_leaves;
+use merkle_race::verkle::{deterministic_bases, new_verkle_from_leaves};
+use merkle_race::workload::{LeafDistribution, Workload};
+use merkle_race::{random_workload_updates, TEST_LEAF_LENGTH};
+
+const NUM_LEAVES: usize = 1 << 20;
+const ARITIES: [usize; 3] = [2, 16, 256];
+const NUM_UPDATES: [usize; 2] = [100, 1000];
+
+// Times a batch of updates to a (warm) tree exactly as main.rs does, i.e., by preprocessing the leaves
+// and then propagating them up the tree, for every arity and # of updates. The same tree is updated in
+// every iteration, like in main.rs's later rounds.
 //
-// const LEAF_LENGTH: usize = 32 + 64;
-//
-// pub fn merkle_sha3_benchmark<M: Measurement>(
-//     c: &mut BenchmarkGroup<M>,
-//     arity: usize,
-//     height: usize,
-//     num_updates: usize,
-// ) {
-//     let max_leaves = max_leaves(arity, height);
-//
-//     let mut merkle = new_merkle_crhf_from_height::<TinySha3HashFunc>(arity, height);
-//
-//     let name_prefix = format!("arity-{}/height-{}", arity, height);
-//
-//     c.throughput(Throughput::Elements(num_updates as u64));
-//     c.bench_with_input(
-//         //BenchmarkId::new("arity-".to_string() + arity.to_string().as_str() + "/height-" + height.to_string().as_str(),num_updates),
-//         BenchmarkId::new(name_prefix, num_updates),
-//         &num_updates,
-//         |b, &num_updates| {
-//             let updates: Vec<(usize, String)> = zip(
-//                 (0..num_updates)
-//                     .map(|_| rand::thread_rng().gen_range(0..max_leaves))
-//                     .sorted()
-//                     .collect::<Vec<usize>>(),
-//                 (0..num_updates)
-//                     .map(|_| {
-//                         rand::thread_rng()
-//                             .sample_iter(&Alphanumeric)
-//                             .take(LEAF_LENGTH)
-//                             .map(char::from)
-//                             .collect()
-//                     })
-//                     .collect::<Vec<String>>(),
-//             )
-//             .collect::<Vec<(usize, String)>>();
-//             assert_le!(num_updates, merkle.num_leaves());
-//
-//             b.iter(|| merkle.update_leaves(&updates));
-//         },
-//     );
-// }
-//
-// pub fn merkle_sha3_group(c: &mut Criterion) {
-//     let mut group = c.benchmark_group("merkle_sha3");
-//
-//     //merkle_sha3_benchmark(&mut group, 2, 10, 100);
-//     merkle_sha3_benchmark(&mut group, 2, 28, 100000);
-//
-//     group.finish();
-// }
-//
-// criterion_group!(
-//     name = benches;
-//     config = Criterion::default().sample_size(10);
-//     targets = merkle_sha3_group);
-// criterion_main!(benches);
+// NOTE: Like main.rs, hashes in parallel when built with the 'parallel' feature.
+pub fn race_benchmark<M: Measurement, HashType, Hasher>(
+    c: &mut BenchmarkGroup<M>,
+    arity: usize,
+    mut merkle: AbstractMerkle<String, HashType, Hasher>,
+) where
+    HashType: Default + Clone + Send + Sync,
+    Hasher: ParallelTreeHasherFunc<String, HashType>,
+{
+    let workload = Workload::new(NUM_LEAVES, LeafDistribution::Uniform);
+
+    for num_updates in NUM_UPDATES {
+        c.throughput(Throughput::Elements(num_updates as u64));
+        c.bench_function(BenchmarkId::new(format!("arity-{}", arity), num_updates), |b| {
+            b.iter_batched(
+                || random_workload_updates(&mut thread_rng(), &workload, num_updates, TEST_LEAF_LENGTH),
+                |updates| {
+                    #[cfg(feature = "parallel")]
+                    {
+                        let (queue, _) = merkle.preprocess_leaves_parallel(updates);
+                        merkle.update_preprocessed_leaves_parallel(queue);
+                    }
+                    #[cfg(not(feature = "parallel"))]
+                    {
+                        let (queue, _) = merkle.preprocess_leaves(updates);
+                        merkle.update_preprocessed_leaves(queue);
+                    }
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+}
+
+pub fn race_group(c: &mut Criterion) {
+    let mut group = c.benchmark_group("merkle_sha3");
+    for arity in ARITIES {
+        race_benchmark(&mut group, arity, new_merkle_crhf_from_leaves::<Sha3HashFunc>(arity, NUM_LEAVES));
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("merkle++");
+    for arity in ARITIES {
+        let merklepp = new_merklepp_from_leaves::<CompRistBlakeIncHash, RistBlakeIncHash>(arity, NUM_LEAVES);
+        race_benchmark(&mut group, arity, merklepp);
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("verkle");
+    for arity in ARITIES {
+        race_benchmark(&mut group, arity, new_verkle_from_leaves(arity, NUM_LEAVES, deterministic_bases(arity)));
+    }
+    group.finish();
+}
+
+criterion_group!(
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = race_group);
+
+criterion_main!(benches);
@@ -1,11 +1,13 @@
 use criterion::{criterion_group, criterion_main, measurement::Measurement, BenchmarkGroup, Criterion, Throughput};
 use rand::{Rng, thread_rng};
 use curve25519_dalek::constants::{RISTRETTO_BASEPOINT_TABLE};
+use curve25519_dalek::ristretto::RistrettoPoint;
 use curve25519_dalek::scalar::Scalar;
 use rust_incrhash::compressed_ristretto::CompRistBlakeIncHash;
 use rust_incrhash::ristretto::RistBlakeIncHash;
 use merkle_race::merkle_pp::{hash_child, MerkleppHashValue};
-use merkle_race::verkle::hash_to_scalar;
+use merkle_race::random_updates;
+use merkle_race::verkle::{deterministic_bases, hash_to_scalar, new_verkle_from_leaves};
 
 pub fn hash_to_scalar_benchmark<M: Measurement>(
     c: &mut BenchmarkGroup<M>,
@@ -40,11 +42,78 @@ pub fn incrhash_benchmark<M: Measurement>(
     });
 }
 
+// Compares (de)compressing the updated parents on a level one at a time, as VerkleHasher does, with
+// compressing them in one batch, which shares a single field inversion across all of them.
+//
+// NOTE: dalek's batch compression outputs the compression of each point *doubled*, so it would only
+// help if parents were stored halved. Decompression cannot be batched, since each point needs its own
+// inverse square root.
+pub fn compression_benchmark<M: Measurement>(
+    c: &mut BenchmarkGroup<M>,
+) {
+    const NUM_PARENTS: usize = 256;
+
+    let mut rng = thread_rng();
+    let points: Vec<RistrettoPoint> = (0..NUM_PARENTS)
+        .map(|_| &RISTRETTO_BASEPOINT_TABLE * &Scalar::random(&mut rng))
+        .collect();
+    let compressed: Vec<_> = points.iter().map(|p| p.compress()).collect();
+
+    c.throughput(Throughput::Elements(NUM_PARENTS as u64));
+    c.bench_function("compress", |b| {
+        b.iter(||
+            points.iter().map(|p| p.compress()).collect::<Vec<_>>()
+        )
+    });
+    c.bench_function("double_and_compress_batch", |b| {
+        b.iter(||
+            RistrettoPoint::double_and_compress_batch(&points)
+        )
+    });
+    c.bench_function("decompress", |b| {
+        b.iter(||
+            compressed.iter().map(|c| c.decompress().unwrap()).collect::<Vec<_>>()
+        )
+    });
+}
+
+// Compares verifying the openings of all the nodes on the paths of some leaves one at a time (i.e., one
+// multiexp of size arity per opening) with verifying them all at once, via a random linear combination
+// (i.e., one multiexp of size arity + # of openings).
+pub fn batch_verification_benchmark<M: Measurement>(
+    c: &mut BenchmarkGroup<M>,
+) {
+    const ARITY: usize = 256;
+    const NUM_LEAVES: usize = 1 << 16;
+
+    let mut verkle = new_verkle_from_leaves(ARITY, NUM_LEAVES, deterministic_bases(ARITY));
+    verkle.update_leaves(random_updates(&mut thread_rng(), NUM_LEAVES, NUM_LEAVES));
+
+    for num_leaves in [1, 16, 128] {
+        let positions = (0..num_leaves).map(|_| thread_rng().gen_range(0..NUM_LEAVES)).collect::<Vec<_>>();
+        let proofs = verkle.prove_openings(&positions);
+
+        c.throughput(Throughput::Elements(proofs.len() as u64));
+        c.bench_function(format!("verify_openings/{}", proofs.len()), |b| {
+            b.iter(||
+                assert!(proofs.iter().all(|proof| verkle.hasher.verify_opening(proof)))
+            )
+        });
+        c.bench_function(format!("verify_batch/{}", proofs.len()), |b| {
+            b.iter(||
+                assert!(verkle.hasher.verify_batch(&proofs))
+            )
+        });
+    }
+}
+
 pub fn bench_group(c: &mut Criterion) {
     let mut group = c.benchmark_group("allbases-multiexp");
 
     hash_to_scalar_benchmark(&mut group);
     incrhash_benchmark(&mut group);
+    compression_benchmark(&mut group);
+    batch_verification_benchmark(&mut group);
 
     group.finish();
 }
@@ -0,0 +1,52 @@
+#![no_main]
+
+// Feeds arbitrary tree shapes and batches of updates into AbstractMerkle, and checks its root against a
+// ReferenceMerkle after every batch. Run via:
+//
+//   cargo fuzz run update_leaves
+//
+// The input is decoded as the arity (1 byte), the # of leaves (2 bytes), and then any # of 4-byte
+// updates, each of which is a leaf position (2 bytes, modulo the # of leaves), a byte of leaf data and
+// a byte whose lowest bit ends the current batch of updates.
+
+use libfuzzer_sys::fuzz_target;
+use merkle_race::merkle_abstract::normalize_updates;
+use merkle_race::merkle_crhf::{new_merkle_crhf_from_leaves, TinySha3HashFunc};
+use merkle_race::reference::new_reference_merkle_crhf;
+
+const MAX_ARITY: usize = 17;
+const MAX_LEAVES: usize = 4097;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 3 {
+        return;
+    }
+
+    let arity = 2 + data[0] as usize % (MAX_ARITY - 1);
+    let num_leaves = 1 + u16::from_le_bytes([data[1], data[2]]) as usize % MAX_LEAVES;
+
+    let mut batches = vec![vec![]];
+    for update in data[3..].chunks_exact(4) {
+        let leaf_pos = u16::from_le_bytes([update[0], update[1]]) as usize % num_leaves;
+        batches.last_mut().unwrap().push((leaf_pos, format!("leaf data {}", update[2])));
+
+        if update[3] & 1 == 1 {
+            batches.push(vec![]);
+        }
+    }
+
+    let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+    let mut streamed = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+    let mut reference = new_reference_merkle_crhf::<TinySha3HashFunc>(arity, num_leaves);
+
+    for batch in batches.into_iter().filter(|batch| !batch.is_empty()) {
+        reference.update_leaves(batch.clone());
+
+        merkle.update_leaves(batch.clone());
+        assert_eq!(merkle.root_hash(), reference.root_hash(), "update_leaves() root mismatch");
+
+        // NOTE: The streaming updates must be sorted, so only a leaf's last update is kept
+        streamed.update_leaves_streaming(normalize_updates(batch, |_, later| later));
+        assert_eq!(streamed.root_hash(), reference.root_hash(), "update_leaves_streaming() root mismatch");
+    }
+});
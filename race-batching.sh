@@ -48,8 +48,8 @@ for arity in $arities; do
         # remove commas from '$batch_size', if any
         batch_size=`echo "$batch_size" | tr -d ','`
 
-        #echo "cargo run --release -- -t=$type --arity=$arity -l=$num_leaves -u=$batch_size 2>&1"
-        output=`cargo run --release -- -t=$type --arity=$arity -l=$num_leaves -u=$batch_size 2>&1`
+        #echo "cargo run --release -- bench -t=$type --arity=$arity -l=$num_leaves -u=$batch_size 2>&1"
+        output=`cargo run --release -- bench -t=$type --arity=$arity -l=$num_leaves -u=$batch_size 2>&1`
         #echo "$output"
         #echo "Return: $?"
 
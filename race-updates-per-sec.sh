@@ -55,8 +55,8 @@ for nn in $num_leaves; do
         # remove commas from 'n'
         n=`echo "$nn" | tr -d ','`
 
-        #cargo run --release -- -t=$type -a $a -l $n -u $u 2>&1
-        output=`cargo run --release -- -t=$type -a $a -l $n -u $u 2>&1`
+        #cargo run --release -- bench -t=$type -a $a -l $n -u $u 2>&1
+        output=`cargo run --release -- bench -t=$type -a $a -l $n -u $u 2>&1`
         #echo "Return: $?"
 
         if [ $? -ne 0 ]; then
@@ -0,0 +1,151 @@
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::RunningAverage;
+
+// The machine-readable formats benchmark results can be written in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Csv,
+    Json, // one JSON object per line (i.e., JSON Lines)
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("Unknown output format '{}' (expected csv or json)", s)),
+        }
+    }
+}
+
+// The results of one measurement (e.g., one round of updates), flattened so that they fit in a CSV row.
+// The per-phase averages are only reported by the hashers that measure them, and are over the whole run.
+#[derive(Clone, Debug, Serialize)]
+pub struct BenchRecord {
+    pub tree_type: String,
+    pub mode: String, // e.g., "update", "build" or "sparse_update"
+    pub arity: usize,
+    pub num_leaves: Option<usize>, // None for sparse trees
+    pub num_updates: usize,
+    pub round: usize,
+    pub wall_time_us: u128,
+    pub updates_per_sec: f64,
+    pub num_hashes: usize,
+    pub hashes_per_sec: f64,
+    pub avg_hash_us: Option<f64>,
+    pub avg_push_updates_us: Option<f64>,
+    pub avg_exp_us: Option<f64>,
+    pub avg_accum_us: Option<f64>,
+    pub leaf_size: Option<usize>, // None if leaves are not random data of a configured size
+    pub leaf_distribution: Option<String>, // None if the updated leaves are not picked via a distribution
+    pub num_sibling_groups: Option<usize>, // # of distinct parents of the updated leaves, if known
+    pub avg_proof_bytes: Option<f64>, // serialized size of a single-leaf proof, if any were made
+    pub avg_verify_us: Option<f64>,
+    pub threads: Option<usize>, // None unless the # of threads was set via --threads
+}
+
+impl BenchRecord {
+    // Returns a record for the specified tree, with no measurements yet (see measured()).
+    pub fn new(tree_type: &str, mode: &str, arity: usize, num_leaves: Option<usize>) -> Self {
+        BenchRecord {
+            tree_type: tree_type.to_owned(),
+            mode: mode.to_owned(),
+            arity,
+            num_leaves,
+            num_updates: 0,
+            round: 0,
+            wall_time_us: 0,
+            updates_per_sec: 0.0,
+            num_hashes: 0,
+            hashes_per_sec: 0.0,
+            avg_hash_us: None,
+            avg_push_updates_us: None,
+            avg_exp_us: None,
+            avg_accum_us: None,
+            leaf_size: None,
+            leaf_distribution: None,
+            num_sibling_groups: None,
+            avg_proof_bytes: None,
+            avg_verify_us: None,
+            threads: None,
+        }
+    }
+
+    // returns a copy of this record, for a round that did 'num_updates' updates and 'num_hashes' hashes
+    pub fn measured(&self, round: usize, num_updates: usize, num_hashes: usize, duration: Duration) -> Self {
+        let per_sec = |count: usize| count as f64 / duration.as_secs_f64();
+
+        BenchRecord {
+            num_updates,
+            round,
+            wall_time_us: duration.as_micros(),
+            updates_per_sec: per_sec(num_updates),
+            num_hashes,
+            hashes_per_sec: per_sec(num_hashes),
+            ..self.clone()
+        }
+    }
+}
+
+// returns the average, or None if nothing was measured (e.g., because no round hit that phase)
+pub fn phase_average(avg: &RunningAverage) -> Option<f64> {
+    (avg.total_measurements > 0).then(|| avg.average())
+}
+
+// Appends the records to the file at 'path', creating it if needed, so that the results of many runs
+// can be aggregated in one file. CSV files get a header row when they are created.
+pub fn append_records<P: AsRef<Path>>(path: P, format: OutputFormat, records: &[BenchRecord]) -> io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+
+    match format {
+        OutputFormat::Csv => {
+            let is_new = file.metadata()?.len() == 0;
+            let mut writer = csv::WriterBuilder::new().has_headers(is_new).from_writer(file);
+
+            for record in records {
+                writer.serialize(record)?;
+            }
+            writer.flush()
+        }
+        OutputFormat::Json => {
+            for record in records {
+                serde_json::to_writer(&mut file, record)?;
+                writeln!(file)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_header_is_written_once() {
+        let path = std::env::temp_dir().join(format!("merkle-race-{}-results.csv", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let record = BenchRecord::new("merkle_sha3", "update", 2, Some(8)).measured(1, 4, 7, Duration::from_millis(2));
+        append_records(&path, OutputFormat::Csv, std::slice::from_ref(&record)).unwrap();
+        append_records(&path, OutputFormat::Csv, &[record]).unwrap();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines = contents.lines().collect::<Vec<_>>();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("tree_type,mode,arity,num_leaves"));
+        assert_eq!(lines[1], "merkle_sha3,update,2,8,4,1,2000,2000.0,7,3500.0,,,,,,,,,,");
+        assert_eq!(lines[1], lines[2]);
+    }
+}
@@ -1,16 +1,103 @@
+use crate::node_index::{ChildBuffer, DynArity, TreeArity};
+use std::fmt::{Display, Formatter};
+use std::ops::{AddAssign, Sub};
+
 pub const HASH_LENGTH: usize = 32;
 
 pub trait HashFuncTrait {
     fn new() -> Self;
 
+    // Returns a hasher for the specified domain (e.g., "leaf:" or "internal:"), so that leaf and
+    // internal node hashes never collide. By default, the domain is simply prepended to the input, but
+    // hash functions with a native domain separation mode (e.g., Blake3's derive-key mode) can use it.
+    fn new_with_domain(domain: &str) -> Self
+    where
+        Self: Sized,
+    {
+        let mut hasher = Self::new();
+        hasher.update(domain.as_bytes());
+        hasher
+    }
+
     fn update(&mut self, buf: &[u8]);
 
     fn finalize(self, buf: &mut [u8; HASH_LENGTH]);
 }
 
+// A canonical byte serialization for the hashes stored in a tree, so that roots computed by
+// different implementations (or different runs) can be compared byte-for-byte.
+pub trait CanonicalBytes: Sized {
+    fn to_bytes(&self) -> Vec<u8>;
+
+    // returns None if 'bytes' is not the canonical serialization of any hash
+    fn from_bytes(bytes: &[u8]) -> Option<Self>;
+}
+
+// The work done by a hasher, broken down by the kind of operation. The "computations" each hasher
+// counts in get_num_computations() are different things (e.g., SHA3 calls for Merkle, but Ristretto
+// exponentiations for Verkle), so trees should be compared on these counters instead.
+//
+// NOTE: A leaf hash counts as one symmetric hash, even if the leaf data is first compressed by another
+// hash (e.g., Blake2b before Poseidon).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HasherStats {
+    pub sym_hashes: usize,     // symmetric hashes (e.g., SHA3, Blake2b or Poseidon), including leaf hashes
+    pub group_hashes: usize,   // hashes onto a group (i.e., Merkle++'s incremental hashes)
+    pub group_exps: usize,     // exponentiations, whether done one at a time or as part of a multiexp
+    pub compressions: usize,   // group element compressions (or normalizations to affine coordinates)
+    pub decompressions: usize,
+    pub field_ops: usize,      // field subtractions, which compute the change in each updated child
+}
+
+impl AddAssign for HasherStats {
+    fn add_assign(&mut self, other: Self) {
+        self.sym_hashes += other.sym_hashes;
+        self.group_hashes += other.group_hashes;
+        self.group_exps += other.group_exps;
+        self.compressions += other.compressions;
+        self.decompressions += other.decompressions;
+        self.field_ops += other.field_ops;
+    }
+}
+
+// e.g., to count the work done for a batch of updates, as the stats after minus the stats before
+impl Sub for HasherStats {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        HasherStats {
+            sym_hashes: self.sym_hashes - other.sym_hashes,
+            group_hashes: self.group_hashes - other.group_hashes,
+            group_exps: self.group_exps - other.group_exps,
+            compressions: self.compressions - other.compressions,
+            decompressions: self.decompressions - other.decompressions,
+            field_ops: self.field_ops - other.field_ops,
+        }
+    }
+}
+
+impl Display for HasherStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} symmetric hashes, {} group hashes, {} exps, {} compressions, {} decompressions, {} field ops",
+            self.sym_hashes,
+            self.group_hashes,
+            self.group_exps,
+            self.compressions,
+            self.decompressions,
+            self.field_ops
+        )
+    }
+}
+
 pub trait TreeHasherFunc<LeafDataType, HashType> {
+    // returns the # of the hasher's most expensive operations (e.g., hashes for Merkle, but
+    // exponentiations for Verkle)
     fn get_num_computations(&self) -> usize;
 
+    fn get_stats(&self) -> HasherStats;
+
     // returns true if hash_nodes() does not need *all* the old children hashes to compute the parent's new hash
     // fn is_incremental(&self) -> bool;
 
@@ -29,7 +116,93 @@ pub trait TreeHasherFunc<LeafDataType, HashType> {
     fn hash_nodes(
         &mut self,
         old_parent_hash: HashType,
-        old_children: &mut Vec<HashType>,
-        new_children: &Vec<(usize, HashType)>,
+        old_children: &mut [HashType],
+        new_children: &[(usize, HashType)],
     ) -> HashType;
+
+    // Computes a parent's hash from scratch, given *all* of its children's hashes. We need this when
+    // verifying proofs, since there is no old parent hash to incrementally update.
+    //
+    // NOTE: Empty (i.e., default) children are not passed in as new children, since they are what
+    // the old children are initialized to anyway (and Verkle panics on empty-to-empty updates).
+    fn hash_all_children(&mut self, children: &[HashType]) -> HashType
+    where
+        HashType: Default + Clone + PartialEq,
+    {
+        self.hash_all_children_in(DynArity::from_usize(children.len()), children)
+    }
+
+    // Same as hash_all_children(), but the old and new children are put in 'arity'-sized buffers, which
+    // are on the stack (rather than allocated for every parent) when the arity is a ConstArity.
+    fn hash_all_children_in<Arity: TreeArity>(&mut self, arity: Arity, children: &[HashType]) -> HashType
+    where
+        HashType: Default + Clone + PartialEq,
+    {
+        let (mut old_children, new_children) = split_all_children(arity, children);
+
+        self.hash_nodes(HashType::default(), &mut old_children, &new_children)
+    }
+}
+
+// A hasher whose hash functions can be called concurrently from many threads, which lets AbstractMerkle
+// hash updated leaves, as well as updated parents on the same level, in parallel.
+pub trait ParallelTreeHasherFunc<LeafDataType, HashType>:
+    TreeHasherFunc<LeafDataType, HashType> + Sync
+{
+    // Same as TreeHasherFunc::hash_leaf_data(), but does not update any counters, since it only
+    // borrows the hasher immutably.
+    fn hash_leaf_data_shared(&self, offset: usize, leaf: LeafDataType) -> HashType;
+
+    // Accounts for 'num_leaves' leaves hashed via hash_leaf_data_shared(), exactly as if they had been
+    // hashed via hash_leaf_data().
+    fn count_leaf_hashes(&mut self, num_leaves: usize);
+
+    // Same as TreeHasherFunc::hash_nodes(), but does not update any counters or timing statistics.
+    // Instead, returns the work done, which the caller must account for via count_node_hashes().
+    fn hash_nodes_shared(
+        &self,
+        old_parent_hash: HashType,
+        old_children: &mut [HashType],
+        new_children: &[(usize, HashType)],
+    ) -> (HashType, HasherStats);
+
+    fn count_node_hashes(&mut self, stats: HasherStats);
+
+    // Same as TreeHasherFunc::hash_all_children(), but returns the work done, like hash_nodes_shared().
+    fn hash_all_children_shared(&self, children: &[HashType]) -> (HashType, HasherStats)
+    where
+        HashType: Default + Clone + PartialEq,
+    {
+        self.hash_all_children_shared_in(DynArity::from_usize(children.len()), children)
+    }
+
+    // Same as hash_all_children_in(), but returns the work done, like hash_nodes_shared().
+    fn hash_all_children_shared_in<Arity: TreeArity>(&self, arity: Arity, children: &[HashType]) -> (HashType, HasherStats)
+    where
+        HashType: Default + Clone + PartialEq,
+    {
+        let (mut old_children, new_children) = split_all_children(arity, children);
+
+        self.hash_nodes_shared(HashType::default(), &mut old_children, &new_children)
+    }
+}
+
+// Returns the (all empty) old children and the (non-empty) new children to hash a parent from scratch
+// with, as in TreeHasherFunc::hash_all_children().
+fn split_all_children<Arity: TreeArity, HashType: Default + Clone + PartialEq>(
+    arity: Arity,
+    children: &[HashType],
+) -> (Arity::Buffer<HashType>, Arity::Buffer<(usize, HashType)>) {
+    let empty = HashType::default();
+    let mut old_children = arity.buffer();
+    let mut new_children = arity.buffer();
+
+    for (i, hash) in children.iter().enumerate() {
+        old_children.push(HashType::default());
+        if *hash != empty {
+            new_children.push((i, hash.clone()));
+        }
+    }
+
+    (old_children, new_children)
 }
@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+
+// Where AbstractMerkle keeps the data of its leaves, if anywhere. By default, trees only store
+// hashes and forget the leaf data (see NoLeafStorage), since that is all the benchmarks need.
+pub trait LeafStorage<LeafDataType> {
+    // returns the data of the leaf at position 'leaf_pos', if it was stored
+    fn get(&self, leaf_pos: usize) -> Option<LeafDataType>;
+
+    // NOTE: Takes a reference so that implementations which do not store anything need not clone.
+    fn set(&mut self, leaf_pos: usize, leaf: &LeafDataType);
+
+    // forgets the data of the leaf at position 'leaf_pos' (e.g., because the leaf was deleted)
+    fn remove(&mut self, leaf_pos: usize);
+}
+
+// Stores nothing.
+#[derive(Default)]
+pub struct NoLeafStorage;
+
+impl<LeafDataType> LeafStorage<LeafDataType> for NoLeafStorage {
+    fn get(&self, _leaf_pos: usize) -> Option<LeafDataType> {
+        None
+    }
+
+    fn set(&mut self, _leaf_pos: usize, _leaf: &LeafDataType) {}
+
+    fn remove(&mut self, _leaf_pos: usize) {}
+}
+
+// Stores the data of every leaf that was ever set in memory. Uses a map rather than a vector, since
+// benchmarks typically only set a small fraction of the leaves.
+pub struct MemLeafStorage<LeafDataType> {
+    leaves: HashMap<usize, LeafDataType>,
+}
+
+impl<LeafDataType> MemLeafStorage<LeafDataType> {
+    pub fn new() -> Self {
+        MemLeafStorage {
+            leaves: HashMap::new(),
+        }
+    }
+
+    // returns the number of leaves stored
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+}
+
+impl<LeafDataType> Default for MemLeafStorage<LeafDataType> {
+    fn default() -> Self {
+        MemLeafStorage::new()
+    }
+}
+
+impl<LeafDataType: Clone> LeafStorage<LeafDataType> for MemLeafStorage<LeafDataType> {
+    fn get(&self, leaf_pos: usize) -> Option<LeafDataType> {
+        self.leaves.get(&leaf_pos).cloned()
+    }
+
+    fn set(&mut self, leaf_pos: usize, leaf: &LeafDataType) {
+        self.leaves.insert(leaf_pos, leaf.clone());
+    }
+
+    fn remove(&mut self, leaf_pos: usize) {
+        self.leaves.remove(&leaf_pos);
+    }
+}
@@ -1,85 +1,126 @@
 extern crate core;
 
-use std::fmt::{Display, Formatter};
 use itertools::Itertools;
-use rand::distributions::Alphanumeric;
-use rand::prelude::IteratorRandom;
-use rand::Rng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use std::iter::zip;
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::vec::IntoIter;
-use more_asserts::{debug_assert_gt, assert_gt, debug_assert_lt, assert_lt};
 use thousands::Separable;
+use tracing::{debug, info};
+use crate::merkle_sparse::{hash_to_key, SparseKey};
+use crate::workload::{random_leaf_data, sample_leaf_positions, LeafDistribution, Workload};
 
 //#![feature(is_sorted)]
 
 #[macro_use]
 pub(crate) mod macros;
 
+pub mod bench_output;
 pub mod merkle_abstract;
 pub mod merkle_crhf;
+pub mod merkle_jmt;
+pub mod merkle_keyed;
+pub mod merkle_log;
+pub mod merkle_mpt;
+pub mod merkle_poseidon;
 pub mod merkle_pp;
+pub mod merkle_sparse;
 pub mod node_index;
+pub mod node_store;
+pub mod prehash;
+pub mod proof_stream;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod racer;
+pub mod reference;
+pub mod root_log;
+pub mod ssz;
+pub mod test_vectors;
+pub mod update_tracer;
+pub mod stats;
 pub mod hashing_traits;
+pub mod leaf_storage;
+pub mod lockstep;
+pub mod lthash;
 pub mod verkle;
+pub mod verkle_kzg;
+#[cfg(feature = "wasm-bindgen")]
+pub mod wasm;
+pub mod workload;
 
+pub use stats::{HistogramAverages, RunningAverage};
 
-pub struct RunningAverage {
-    total_time_usec: f64,
-    pub total_measurements: usize,
-}
+// NOTE: Proof verification lives in its own no_std crate, so that CRHF proofs can be verified without std
+pub use merkle_race_verify as verify;
 
-impl RunningAverage {
-    pub fn new() -> Self {
-        RunningAverage {
-            total_time_usec: 0.0,
-            total_measurements: 0,
-        }
-    }
+// Whether long-running operations (e.g., allocating, building or updating a huge tree) periodically
+// log their progress, as info events. Off by default, so that the library stays quiet (see set_progress()).
+static PROGRESS: AtomicBool = AtomicBool::new(false);
 
-    pub fn add(&mut self, time_usec: u128, num_measurements: usize) {
-        self.total_time_usec += time_usec as f64;
-        self.total_measurements += num_measurements;
-    }
+// How often a ProgressLog logs, at most
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
 
-    pub fn average(&self) -> f64 {
-        self.total_time_usec / self.total_measurements as f64
-    }
-
-    pub fn reset(&mut self) {
-        self.total_time_usec = 0.0;
-        self.total_measurements = 0;
-    }
+pub fn set_progress(enabled: bool) {
+    PROGRESS.store(enabled, Ordering::Relaxed);
 }
 
-impl Display for RunningAverage {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:.2} us ({} samples)", self.average(), self.total_measurements)
-    }
+// Periodically logs how much of a long-running operation is done, if progress reporting is enabled
+// via set_progress(); otherwise, does nothing. The total amount of work might not be known up front
+// (e.g., the # of parents affected by some updates).
+pub struct ProgressLog {
+    what: &'static str,
+    total: Option<usize>,
+    done: usize,
+    start: Instant,
+    last_log: Instant,
+    enabled: bool,
 }
 
-pub struct HistogramAverages {
-    average: Vec<RunningAverage>
-}
-
-impl HistogramAverages {
-    pub fn new(num: usize) -> Self {
-        HistogramAverages {
-            average: (0..num).map(|_| RunningAverage::new()).collect()
+impl ProgressLog {
+    pub fn new(what: &'static str, total: Option<usize>) -> Self {
+        let enabled = PROGRESS.load(Ordering::Relaxed);
+        if enabled {
+            match total {
+                Some(total) => info!("{}: starting ({} in total)", what, total.separate_with_commas()),
+                None => info!("{}: starting", what),
+            }
         }
+
+        let now = Instant::now();
+        ProgressLog { what, total, done: 0, start: now, last_log: now, enabled }
     }
 
-    // idx is from 1 to N
-    pub fn add(&mut self, idx: usize, time_usec: u128) {
-        debug_assert_gt!(idx, 0);
-        debug_assert_lt!(idx - 1, self.average.len());
-        self.average[idx - 1].add(time_usec, 1);
+    // accounts for 'n' more units of work being done, logging the progress so far if it was not
+    // logged recently
+    pub fn add(&mut self, n: usize) {
+        if !self.enabled {
+            return;
+        }
+
+        self.done += n;
+        if self.last_log.elapsed() >= PROGRESS_INTERVAL {
+            self.last_log = Instant::now();
+
+            match self.total {
+                Some(total) => info!(
+                    "{}: {} of {} ({:.1}%) after {:?}",
+                    self.what,
+                    self.done.separate_with_commas(),
+                    total.separate_with_commas(),
+                    self.done as f64 / total as f64 * 100.0,
+                    self.start.elapsed()
+                ),
+                None => info!("{}: {} after {:?}", self.what, self.done.separate_with_commas(), self.start.elapsed()),
+            }
+        }
     }
-}
 
-impl Display for HistogramAverages {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        (0..self.average.len()).map(|idx| write!(f, "{} -> {}\n", idx + 1, self.average[idx])).collect()
+    pub fn finish(self) {
+        if self.enabled {
+            info!("{}: done in {:?}", self.what, self.start.elapsed());
+        }
     }
 }
 
@@ -87,39 +128,80 @@ pub fn max_leaves(arity: usize, height: usize) -> usize {
     arity.pow(height as u32)
 }
 
-pub fn random_leaf_positions(max_num_leaves: usize, num_pos: usize) -> IntoIter<usize> {
-    (0..max_num_leaves)
-        .choose_multiple(&mut rand::thread_rng(), num_pos)
-        .into_iter()
-        .sorted()
+// Returns an RNG seeded with 'seed', or with a random seed if none is given. Either way, the seed is
+// logged, so that a run can be reproduced (i.e., update the same leaves and use the same KZG Verkle bases).
+pub fn seeded_rng(seed: Option<u64>) -> StdRng {
+    let seed = seed.unwrap_or_else(|| rand::thread_rng().gen());
+    info!("RNG seed: {}", seed);
+
+    StdRng::seed_from_u64(seed)
 }
 
-const TEST_LEAF_LENGTH: usize = 32 + 64;
+pub fn random_leaf_positions<R: Rng>(rng: &mut R, max_num_leaves: usize, num_pos: usize) -> IntoIter<usize> {
+    sample_leaf_positions(rng, max_num_leaves, num_pos, LeafDistribution::Uniform).into_iter()
+}
 
-pub fn random_updates(max_num_leaves: usize, num_updates: usize) -> Vec<(usize, String)> {
-    println!("Sampling {} out of {} random leaf updates", num_updates.separate_with_commas(), max_num_leaves.separate_with_commas());
+pub const TEST_LEAF_LENGTH: usize = 32 + 64;
 
+pub fn random_updates<R: Rng>(rng: &mut R, max_num_leaves: usize, num_updates: usize) -> Vec<(usize, String)> {
+    let workload = Workload::new(max_num_leaves, LeafDistribution::Uniform);
 
-    let update_prefix = rand::thread_rng()
-        .sample_iter(&Alphanumeric)
-        .take(TEST_LEAF_LENGTH)
-        .map(char::from)
-        .collect::<String>();
-    let update_prefix_slice = update_prefix.as_str();
+    random_workload_updates(rng, &workload, num_updates, TEST_LEAF_LENGTH)
+}
 
-    println!("Leaf prefix: {}", update_prefix_slice);
+// Returns 'num_updates' updates to distinct leaves sampled from the workload, each setting the leaf to
+// 'leaf_size' bytes of random data, sorted by leaf position.
+pub fn random_workload_updates<R: Rng>(
+    rng: &mut R,
+    workload: &Workload,
+    num_updates: usize,
+    leaf_size: usize,
+) -> Vec<(usize, String)> {
+    info!(
+        "Sampling {} out of {} {} random leaf updates",
+        num_updates.separate_with_commas(),
+        workload.num_leaves().separate_with_commas(),
+        workload.distribution()
+    );
 
     let start = Instant::now();
+    let leaves = random_leaf_data(rng, num_updates, leaf_size);
+    if let Some(leaf) = leaves.first() {
+        debug!("First leaf: {}", leaf);
+    }
+
     let updates: Vec<(usize, String)> = zip(
-        random_leaf_positions(max_num_leaves, num_updates),
-        (0..num_updates)
-            .map(|i| update_prefix_slice.to_string() + "/" + &i.to_string())
-            .collect::<Vec<String>>(),
+        workload.sample_positions(rng, num_updates),
+        leaves,
     )
     .collect::<Vec<(usize, String)>>();
 
-    println!(
-        "Sampled {} random updates in {:?}\n",
+    info!(
+        "Sampled {} random updates in {:?}",
+        num_updates.separate_with_commas(),
+        start.elapsed()
+    );
+    updates
+}
+
+// Returns 'num_updates' random updates for a sparse tree, each keyed by the hash of a random account
+// address, sorted by key.
+pub fn random_sparse_updates<R: Rng>(rng: &mut R, num_updates: usize) -> Vec<(SparseKey, String)> {
+    info!("Sampling {} random sparse leaf updates", num_updates.separate_with_commas());
+
+    let start = Instant::now();
+    let updates: Vec<(SparseKey, String)> = (0..num_updates)
+        .map(|_| {
+            let address: [u8; 32] = rng.gen();
+
+            (hash_to_key(&address), hex::encode(address))
+        })
+        .sorted_by(|a, b| a.0.cmp(&b.0))
+        .dedup_by(|a, b| a.0 == b.0)
+        .collect();
+
+    info!(
+        "Sampled {} random sparse updates in {:?}",
         num_updates.separate_with_commas(),
         start.elapsed()
     );
@@ -154,4 +236,16 @@ mod tests {
         assert_eq!(max_leaves(2, 60), max_leaves(4, 30));
         assert_eq!(max_leaves(4, 30), 1152921504606846976);
     }
+
+    #[test]
+    fn same_seed_same_updates() {
+        let updates = random_updates(&mut seeded_rng(Some(42)), 1000, 100);
+
+        assert_eq!(updates, random_updates(&mut seeded_rng(Some(42)), 1000, 100));
+        assert_ne!(updates, random_updates(&mut seeded_rng(Some(43)), 1000, 100));
+        assert_eq!(
+            random_sparse_updates(&mut seeded_rng(Some(42)), 100),
+            random_sparse_updates(&mut seeded_rng(Some(42)), 100)
+        );
+    }
 }
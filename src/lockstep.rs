@@ -0,0 +1,138 @@
+use crate::hashing_traits::TreeHasherFunc;
+use crate::merkle_abstract::AbstractMerkle;
+use crate::node_index::NodeIndex;
+use crate::update_tracer::UpdateTracer;
+
+// Maintains two trees of the same shape over the same leaves in lockstep (e.g., a Merkle++ and a CRHF
+// Merkle tree), as a cross-check of AbstractMerkle's update propagation: however different their
+// hashers, both trees must propagate every batch through the same nodes, in the same order, and both
+// must accept the same proofs for the same leaves (see main.rs's --lockstep).
+//
+// NOTE: Hashes of different trees cannot be compared, so only the structure of the updates and of the
+// proofs is checked, not their contents. Divergences panic, since they are bugs.
+pub struct LockstepMerkle<LeafDataType, LeftHash, LeftHasher, RightHash, RightHasher> {
+    pub left: AbstractMerkle<LeafDataType, LeftHash, LeftHasher>,
+    pub right: AbstractMerkle<LeafDataType, RightHash, RightHasher>,
+}
+
+// The nodes a batch of updates propagated through, in the order they were dequeued and hashed
+#[derive(Debug, Default, PartialEq, Eq)]
+struct NodeTrace {
+    dequeued: Vec<(usize, Option<usize>)>, // (node, leaf position, if a leaf)
+    hashed: Vec<(usize, usize, usize)>,    // (parent, # of updated children, # of children)
+}
+
+impl<HashType> UpdateTracer<HashType> for NodeTrace {
+    fn dequeue(&mut self, node: NodeIndex, leaf_pos: Option<usize>, _hash: &HashType) {
+        self.dequeued.push((node.index(), leaf_pos));
+    }
+
+    fn hash(&mut self, parent: NodeIndex, num_updated: usize, num_children: usize) {
+        self.hashed.push((parent.index(), num_updated, num_children));
+    }
+}
+
+impl<LeafDataType, LeftHash, LeftHasher, RightHash, RightHasher>
+    LockstepMerkle<LeafDataType, LeftHash, LeftHasher, RightHash, RightHasher>
+where
+    LeafDataType: Clone,
+    LeftHash: Default + Clone + PartialEq,
+    LeftHasher: TreeHasherFunc<LeafDataType, LeftHash>,
+    RightHash: Default + Clone + PartialEq,
+    RightHasher: TreeHasherFunc<LeafDataType, RightHash>,
+{
+    pub fn new(
+        left: AbstractMerkle<LeafDataType, LeftHash, LeftHasher>,
+        right: AbstractMerkle<LeafDataType, RightHash, RightHasher>,
+    ) -> Self {
+        assert_eq!(left.shape(), right.shape(), "Trees in lockstep must have the same shape");
+
+        LockstepMerkle { left, right }
+    }
+
+    // updates both trees, and panics if they propagated the updates differently; returns the # of
+    // nodes updated (including the leaves)
+    pub fn update_leaves(&mut self, updates: Vec<(usize, LeafDataType)>) -> usize {
+        let (mut left_trace, mut right_trace) = (NodeTrace::default(), NodeTrace::default());
+        self.left.update_leaves_traced(updates.clone(), &mut left_trace);
+        self.right.update_leaves_traced(updates, &mut right_trace);
+
+        if let Some(i) = (0..left_trace.dequeued.len().max(right_trace.dequeued.len()))
+            .find(|i| left_trace.dequeued.get(*i) != right_trace.dequeued.get(*i))
+        {
+            panic!(
+                "Trees diverged at their #{} dequeued node: {:?} vs. {:?}",
+                i,
+                left_trace.dequeued.get(i),
+                right_trace.dequeued.get(i)
+            );
+        }
+        assert_eq!(left_trace.hashed, right_trace.hashed, "Trees hashed different parents");
+
+        left_trace.dequeued.len()
+    }
+
+    // proves the specified leaves in both trees, and panics unless both proofs have the same # of hashes
+    // and both trees accept them, as well as reject them for the leaves moved to other positions; returns
+    // the # of hashes in the proofs
+    //
+    // NOTE: The leaves must be the trees' current leaves.
+    pub fn check_proofs(&mut self, leaves: &[(usize, LeafDataType)]) -> usize {
+        let positions = leaves.iter().map(|(pos, _)| *pos).collect::<Vec<_>>();
+        let (left_proof, right_proof) = (self.left.prove_batch(&positions), self.right.prove_batch(&positions));
+        assert_eq!(left_proof.len(), right_proof.len(), "Proofs for leaves {:?} have different sizes", positions);
+
+        let (left_root, right_root) = (self.left.root_hash(), self.right.root_hash());
+        assert!(self.left.verify_batch(&left_root, leaves, &left_proof), "Left tree rejected its proof");
+        assert!(self.right.verify_batch(&right_root, leaves, &right_proof), "Right tree rejected its proof");
+
+        // NOTE: The proof is for a different set of nodes once a leaf moves, so both trees must reject it
+        let num_leaves = self.left.num_leaves();
+        if let Some(other_pos) = (0..num_leaves).find(|pos| !positions.contains(pos)) {
+            let mut moved = leaves.to_vec();
+            moved[0].0 = other_pos;
+
+            let left_accepts = self.left.verify_batch(&left_root, &moved, &left_proof);
+            let right_accepts = self.right.verify_batch(&right_root, &moved, &right_proof);
+            assert_eq!(left_accepts, right_accepts, "Only one tree accepted leaf {} moved to {}", positions[0], other_pos);
+        }
+
+        left_proof.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle_crhf::{new_merkle_crhf_from_leaves, Sha3HashFunc};
+    use crate::merkle_pp::new_merklepp_from_leaves;
+    use crate::random_updates;
+    use rand::thread_rng;
+    use rust_incrhash::compressed_ristretto::CompRistBlakeIncHash;
+    use rust_incrhash::ristretto::RistBlakeIncHash;
+
+    #[test]
+    fn merklepp_and_merkle_agree() {
+        for (arity, num_leaves) in [(2, 7), (3, 10), (4, 64)] {
+            let mut lockstep = LockstepMerkle::new(
+                new_merklepp_from_leaves::<CompRistBlakeIncHash, RistBlakeIncHash>(arity, num_leaves),
+                new_merkle_crhf_from_leaves::<Sha3HashFunc>(arity, num_leaves),
+            );
+
+            for _ in 0..3 {
+                let updates = random_updates(&mut thread_rng(), num_leaves, 4);
+                assert!(lockstep.update_leaves(updates.clone()) > updates.len());
+                assert!(lockstep.check_proofs(&updates[..2]) > 0);
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "same shape")]
+    fn shapes_must_match() {
+        LockstepMerkle::new(
+            new_merkle_crhf_from_leaves::<Sha3HashFunc>(2, 8),
+            new_merkle_crhf_from_leaves::<Sha3HashFunc>(2, 9),
+        );
+    }
+}
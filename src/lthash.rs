@@ -0,0 +1,140 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::ops::{AddAssign, SubAssign};
+
+use serde::{Deserialize, Serialize};
+
+// The # of 16-bit lanes in an LtHash, as in Facebook's LtHash16 (i.e., 2 KiB hashes)
+pub const LTHASH_NUM_LANES: usize = 1024;
+
+// A lattice-based incremental hash (i.e., LtHash, from "Securing Update Propagation with Homomorphic
+// Hashing" by Lewi et al.): a byte string is hashed to a vector of 16-bit lanes via an extendable
+// output function, and hashes are added lane-wise modulo 2^16. Its security relies on the hardness of
+// the short integer solution (SIS) problem rather than on discrete logs, and additions are much cheaper
+// than elliptic curve additions, at the cost of much larger hashes.
+//
+// NOTE: Unlike the Ristretto-based hashes, the same representation is used in memory and for summing
+// children hashes, so Merkle++ uses LtHash16 for both of its hash parameters.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "Vec<u16>")]
+pub struct LtHash16 {
+    lanes: Vec<u16>,
+}
+
+impl Default for LtHash16 {
+    fn default() -> Self {
+        LtHash16 {
+            lanes: vec![0u16; LTHASH_NUM_LANES],
+        }
+    }
+}
+
+// rejects vectors of the wrong size, so that deserialized hashes can always be added
+impl TryFrom<Vec<u16>> for LtHash16 {
+    type Error = String;
+
+    fn try_from(lanes: Vec<u16>) -> Result<Self, Self::Error> {
+        if lanes.len() != LTHASH_NUM_LANES {
+            return Err(format!("Expected {} LtHash lanes, got {}", LTHASH_NUM_LANES, lanes.len()));
+        }
+
+        Ok(LtHash16 { lanes })
+    }
+}
+
+impl From<&[u8]> for LtHash16 {
+    fn from(bytes: &[u8]) -> Self {
+        let mut hasher = blake3::Hasher::new_derive_key("merkle-race lthash16");
+        hasher.update(bytes);
+
+        let mut output = [0u8; 2 * LTHASH_NUM_LANES];
+        hasher.finalize_xof().fill(&mut output);
+
+        LtHash16 {
+            lanes: output
+                .chunks_exact(2)
+                .map(|lane| u16::from_le_bytes([lane[0], lane[1]]))
+                .collect(),
+        }
+    }
+}
+
+impl AddAssign for LtHash16 {
+    fn add_assign(&mut self, other: Self) {
+        for (lane, other_lane) in self.lanes.iter_mut().zip(other.lanes) {
+            *lane = lane.wrapping_add(other_lane);
+        }
+    }
+}
+
+impl SubAssign for LtHash16 {
+    fn sub_assign(&mut self, other: Self) {
+        for (lane, other_lane) in self.lanes.iter_mut().zip(other.lanes) {
+            *lane = lane.wrapping_sub(other_lane);
+        }
+    }
+}
+
+// NOTE: Displays a Blake3 digest of the lanes, since the hash itself is 2 KiB
+impl Display for LtHash16 {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut hasher = blake3::Hasher::new();
+        for lane in &self.lanes {
+            hasher.update(&lane.to_le_bytes());
+        }
+
+        write!(f, "lthash16:{}", hasher.finalize().to_hex())
+    }
+}
+
+impl Debug for LtHash16 {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashing_traits::CanonicalBytes;
+    use crate::merkle_pp::{new_merklepp_from_leaves, MerkleppHashValue};
+    use crate::random_updates;
+    use rand::thread_rng;
+
+    #[test]
+    fn lthash_is_homomorphic() {
+        let (a, b) = (LtHash16::from("a".as_bytes()), LtHash16::from("b".as_bytes()));
+
+        let mut sum = LtHash16::default();
+        sum += a.clone();
+        sum += b.clone();
+        assert_ne!(sum, a);
+
+        let mut other = b.clone();
+        other += a.clone();
+        assert_eq!(sum, other);
+
+        sum -= b;
+        assert_eq!(sum, a);
+        sum -= a;
+        assert_eq!(sum, LtHash16::default());
+
+        let bytes = bincode::serialize(&vec![0u16; LTHASH_NUM_LANES - 1]).unwrap();
+        assert!(bincode::deserialize::<LtHash16>(&bytes).is_err());
+    }
+
+    #[test]
+    fn lthash_merklepp_updates_match_build() {
+        let (arity, num_leaves) = (4, 30);
+        let mut merklepp = new_merklepp_from_leaves::<LtHash16, LtHash16>(arity, num_leaves);
+        let mut other = new_merklepp_from_leaves::<LtHash16, LtHash16>(arity, num_leaves);
+
+        let leaves = (0..num_leaves).map(|pos| format!("leaf {}", pos)).collect::<Vec<_>>();
+        merklepp.update_leaves(random_updates(&mut thread_rng(), num_leaves, num_leaves / 2));
+        merklepp.update_leaves(leaves.iter().cloned().enumerate().collect());
+        other.build_from_leaves(leaves);
+
+        let root = merklepp.root_hash();
+        assert_eq!(root, other.root_hash());
+        assert_eq!(MerkleppHashValue::<LtHash16>::from_bytes(&root.to_bytes()), Some(root));
+    }
+}
@@ -1,28 +1,262 @@
-use merkle_race::merkle_abstract::AbstractMerkle;
-use merkle_race::merkle_crhf::{new_merkle_crhf_from_leaves, Blake2sHashFunc, TinySha3HashFunc, Blake2bHashFunc, Sha3HashFunc};
-use merkle_race::hashing_traits::TreeHasherFunc;
-use merkle_race::{max_leaves, random_updates};
-use more_asserts::assert_le;
+use merkle_race::lockstep::LockstepMerkle;
+use merkle_race::merkle_abstract::{saved_shape, AbstractMerkle, BatchProof};
+use merkle_race::merkle_crhf::{new_merkle_crhf_from_leaves, new_merkle_crhf_with_node_store, new_sparse_merkle_crhf, Blake2sHashFunc, TinySha3HashFunc, Blake2bHashFunc, Blake3HashFunc, Keccak256HashFunc, Sha256HashFunc, Sha3HashFunc};
+use merkle_race::merkle_jmt::JellyfishMerkle;
+use merkle_race::merkle_mpt::MerklePatriciaTrie;
+use merkle_race::merkle_poseidon::new_merkle_poseidon_from_leaves;
+use merkle_race::merkle_sparse::SparseMerkle;
+use merkle_race::leaf_storage::NoLeafStorage;
+use merkle_race::node_store::{BlockedNodeStore, NodeLayout, NodeStore};
+#[cfg(feature = "mmap")]
+use merkle_race::{hashing_traits::HASH_LENGTH, node_store::MmapNodeStore};
+#[cfg(feature = "disk")]
+use merkle_race::node_store::DiskNodeStore;
+use merkle_race::racer::{MerkleRacer, RacerRegistry};
+use merkle_race::hashing_traits::{CanonicalBytes, HashFuncTrait, HasherStats, ParallelTreeHasherFunc, TreeHasherFunc};
+use merkle_race::test_vectors::{default_vector_types, generate_vectors, read_vectors, verify_vectors, write_vectors, DEFAULT_SHAPES};
+use merkle_race::bench_output::{append_records, phase_average, BenchRecord, OutputFormat};
+use merkle_race::workload::{num_sibling_groups, random_leaf_data, LeafDistribution, Workload};
+use merkle_race::{max_leaves, RunningAverage, random_sparse_updates, random_updates, random_workload_updates, seeded_rng, TEST_LEAF_LENGTH};
+use more_asserts::{assert_le, assert_lt};
 use std::fmt::Debug;
-use std::time::Instant;
+use std::fs::File;
+use std::mem::size_of;
+use std::ops::AddAssign;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use thousands::Separable;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::EnvFilter;
 
-use clap::Parser;
-use curve25519_dalek::constants::RISTRETTO_BASEPOINT_TABLE;
-use curve25519_dalek::ristretto::RistrettoPoint;
-use curve25519_dalek::scalar::Scalar;
+use clap::{Parser, Subcommand};
+use rand::rngs::StdRng;
+#[cfg(feature = "parallel")]
 use rand::thread_rng;
-use merkle_race::merkle_pp::new_merklepp_from_leaves;
+use rand::{Rng, SeedableRng};
+use rand::seq::SliceRandom;
+use merkle_race::merkle_pp::{
+    new_fat_merklepp_from_leaves, new_merklepp_from_leaves, new_sparse_merklepp, IncrementalHash, IncrementalHasher,
+    MerkleppNode,
+};
+use merkle_race::lthash::LtHash16;
 use rust_incrhash::compressed_ristretto::CompRistBlakeIncHash;
 use rust_incrhash::ristretto::RistBlakeIncHash;
-use merkle_race::verkle::new_verkle_from_leaves;
+use merkle_race::verkle::{
+    deterministic_bases, new_verkle_from_leaves, FatVerkleComm, VerkleBases, VerkleComm, VerkleConfig, VerkleHasher,
+    VerkleNode,
+};
+use merkle_race::verkle_kzg::{lagrange_bases, new_kzg_verkle_from_leaves, seeded_lagrange_bases, DEFAULT_SRS_SEED};
 
 /// Program to benchmark three types of Merkle trees: traditional CRHF-based Merkle,
 /// incrementally-hashed Merkle (or Merkle++), and VC-based Merkle (or Verkle)
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Benchmark updating (or building) a tree
+    // NOTE: -h is taken by --height, so help is -? instead
+    #[clap(mut_arg("help", |arg| arg.short('?')))]
+    Bench(Box<Args>),
+
+    /// Build a tree with random leaves and save it to a file
+    Build {
+        #[clap(flatten)]
+        tree: TreeFileArgs,
+
+        /// Tree arity
+        #[clap(short, long)]
+        arity: usize,
+
+        /// Number of leaves
+        #[clap(short('l'), long)]
+        num_leaves: usize,
+
+        /// Seed for the RNG that picks the leaves (random by default)
+        #[clap(long)]
+        seed: Option<u64>,
+    },
+
+    /// Set some leaves of a saved tree, and save it back
+    Update {
+        #[clap(flatten)]
+        tree: TreeFileArgs,
+
+        /// The leaves to set, as POSITION=DATA
+        #[clap(required = true, parse(try_from_str = parse_leaf))]
+        leaves: Vec<(usize, String)>,
+    },
+
+    /// Prove some leaves of a saved tree, saving the proof to a file
+    Prove {
+        #[clap(flatten)]
+        tree: TreeFileArgs,
+
+        /// The file to save the proof to
+        #[clap(short, long)]
+        proof: String,
+
+        /// The positions of the leaves to prove
+        #[clap(required = true)]
+        positions: Vec<usize>,
+    },
+
+    /// Verify a proof (saved via prove) for some leaves against a root hash. The tree itself is not
+    /// needed, only its type and shape.
+    Verify {
+        #[clap(short, long)]
+        _type: String,
+
+        /// Tree arity
+        #[clap(short, long)]
+        arity: usize,
+
+        /// Number of leaves
+        #[clap(short('l'), long)]
+        num_leaves: usize,
+
+        /// The hex-encoded root hash (as printed by the other commands)
+        #[clap(short, long)]
+        root: String,
+
+        /// The file the proof was saved to
+        #[clap(short, long)]
+        proof: String,
+
+        /// The proven leaves, as POSITION=DATA
+        #[clap(required = true, parse(try_from_str = parse_leaf))]
+        leaves: Vec<(usize, String)>,
+    },
+
+    /// Print the shape and root hash of a saved tree
+    Inspect {
+        #[clap(flatten)]
+        tree: TreeFileArgs,
+
+        /// Print the top levels of the tree as a Graphviz graph instead (e.g., pipe it into `dot -Tsvg`)
+        #[clap(long)]
+        dot: bool,
+
+        /// The deepest level to include in the graph (the root is at level 0)
+        #[clap(long, default_value_t = 4, requires = "dot")]
+        max_depth: usize,
+    },
+
+    /// Print (arity, leaves, updates, expected root) JSON vectors for each type of tree, so that other
+    /// implementations can be cross-checked against this one, or check this one against such vectors
+    Testvectors {
+        /// The types of trees to print vectors for, comma-separated (by default, all the types whose
+        /// roots only depend on their leaves, i.e., all but verkle_kzg)
+        #[clap(short, long, use_value_delimiter = true)]
+        types: Option<Vec<String>>,
+
+        /// Seed for the RNG that picks the updated leaves
+        #[clap(long, default_value_t = 0)]
+        seed: u64,
+
+        /// Write the vectors to this file, rather than to stdout
+        #[clap(short, long, conflicts_with = "check")]
+        output: Option<String>,
+
+        /// Check the vectors in this file instead, and fail unless all of them match
+        #[clap(long)]
+        check: Option<String>,
+    },
+}
+
+// The arguments shared by the commands that work on saved trees
+#[derive(clap::Args, Debug)]
+struct TreeFileArgs {
+    /// The type of the saved tree (see the bench command). KZG Verkle trees use bases derived from a
+    /// fixed seed here, rather than the random ones the bench command uses.
+    #[clap(short, long)]
+    _type: String,
+
+    /// The file the tree is saved in
+    file: String,
+}
+
+// The well-known designs that --preset configures the benchmark to match
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Preset {
+    EthereumVerkle,
+    AptosJmtLike,
+}
+
+impl Preset {
+    // returns the options this preset stands for
+    fn args(self) -> &'static [&'static str] {
+        // NOTE: 2^24 leaves, so that the trees are as tall as they would be with the # of accounts (or
+        // keys) of a large chain, while still fitting in memory (around 1.1 GB of fat Verkle nodes)
+        match self {
+            Preset::EthereumVerkle => &["--type", "verkle", "--arity", "256", "--num-leaves", "16777216", "--fat-nodes"],
+            Preset::AptosJmtLike => &["--type", "merkle_sha3", "--arity", "16", "--num-leaves", "16777216"],
+        }
+    }
+}
+
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ethereum-verkle" => Ok(Preset::EthereumVerkle),
+            "aptos-jmt-like" => Ok(Preset::AptosJmtLike),
+            _ => Err(format!("Unknown preset '{}' (expected ethereum-verkle or aptos-jmt-like)", s)),
+        }
+    }
+}
+
+// Inserts the options a bench command's --preset stands for right after 'bench', so that any options
+// given explicitly come later and override them
+fn expand_preset(mut argv: Vec<String>) -> Vec<String> {
+    let preset = argv.iter().enumerate().find_map(|(i, arg)| match arg.strip_prefix("--preset") {
+        Some("") => argv.get(i + 1).cloned(),
+        Some(value) => value.strip_prefix('=').map(str::to_owned),
+        None => None,
+    });
+    let bench = argv.iter().position(|arg| arg == "bench");
+
+    if let (Some(name), Some(bench)) = (preset, bench) {
+        // NOTE: checked here, since otherwise clap would first complain about the missing --type
+        let preset = Preset::from_str(&name).unwrap_or_else(|err| panic!("{}", err));
+        argv.splice(bench + 1..bench + 1, preset.args().iter().map(|arg| arg.to_string()));
+    }
+
+    argv
+}
+
+// parses a POSITION=DATA leaf
+fn parse_leaf(s: &str) -> Result<(usize, String), String> {
+    let (pos, data) = s.split_once('=').ok_or_else(|| format!("expected POSITION=DATA, got '{}'", s))?;
+    let pos = pos.parse::<usize>().map_err(|e| format!("invalid leaf position '{}': {}", pos, e))?;
+
+    Ok((pos, data.to_owned()))
+}
+
+// NOTE: Options given more than once take their last value, so that explicit options override the ones
+// a --preset stands for (see expand_preset())
+#[derive(clap::Args, Clone, Debug)]
+#[clap(args_override_self = true)]
 struct Args {
-    /// Can be either: merkle_sha3, merkle_blake2s, merkle_blake2b, merkle++, merkle++naive, or verkle
+    /// Configure the benchmark to match a well-known design: ethereum-verkle (-t verkle -a 256
+    /// -l 16777216 --fat-nodes, i.e., a 256-ary Verkle tree that is three levels deep, whose nodes cache
+    /// the hashes of their commitments, as in EIP-6800) or aptos-jmt-like (-t merkle_sha3 -a 16
+    /// -l 16777216, i.e., a dense, 16-ary SHA3-256 tree like Aptos's Jellyfish Merkle tree; see -t jmt
+    /// for a sparse one). Any of these options can still be given explicitly, which overrides the preset.
+    #[clap(long)]
+    preset: Option<Preset>,
+
+    /// Can be either: merkle_sha3, merkle_sha256, merkle_keccak256, merkle_blake2s, merkle_blake2b,
+    /// merkle_blake3, merkle_poseidon, merkle++, merkle++naive, merkle++fat (nodes cache their hashes
+    /// as children), merkle++lthash (lattice-based incremental hashing), verkle, verkle_kzg, mpt
+    /// (Ethereum's hexary Merkle Patricia Trie), or jmt (Diem's Jellyfish Merkle Tree). The last two
+    /// only work with --sparse and an arity of 16. Use all to race every (non-sparse) type on the same
+    /// updates and print a summary table (the per-round results of every type can be saved via --output)
     #[clap(short, long)]
     _type: String, // TODO: list options
 
@@ -31,23 +265,281 @@ struct Args {
     arity: usize,
 
     /// Tree height
-    #[clap(short, long, required_unless_present("num-leaves"))]
+    #[clap(short, long, required_unless_present_any(&["num-leaves", "sparse"]))]
     height: Option<usize>,
 
     /// Number of leaves
-    #[clap(short('l'), long, required_unless_present("height"))]
+    #[clap(short('l'), long, required_unless_present_any(&["height", "sparse"]))]
     num_leaves: Option<usize>,
 
     /// Number of leaves to update
     #[clap(short('u'), long)]
     num_updates: usize,
+
+    /// Size of the updated leaves' data, in bytes
+    #[clap(long, default_value_t = TEST_LEAF_LENGTH)]
+    leaf_size: usize,
+
+    /// How the updated leaves are picked: uniform, zipfian[:S] (the ith most popular leaf is updated
+    /// with probability proportional to 1/i^S; S is 1 by default) or hotset[:F:P] (a fraction P of the
+    /// updates go to a hot set of a fraction F of the leaves; 0.9 of them go to 0.1 of the leaves by
+    /// default). Only used for non-sparse trees.
+    #[clap(long, default_value = "uniform")]
+    leaf_distribution: LeafDistribution,
+
+    /// Number of successive batches of random updates to apply to the same tree, each of which is
+    /// reported separately
+    #[clap(long, default_value_t = 1)]
+    rounds: usize,
+
+    /// Number of leaves updated by the last round to prove (one proof per leaf) and verify, in order to
+    /// report proof sizes and verification times. NOTE: Verifying calls the hasher, so the per-phase
+    /// averages reported by some trees (e.g., Verkle) also include verification.
+    #[clap(long, default_value_t = 0)]
+    num_proofs: usize,
+
+    /// After updating (or building) the tree, check its root against one recomputed from scratch,
+    /// from the leaf hashes, by a simple reference implementation. Not for sparse trees. NOTE: Like
+    /// --num-proofs, this calls the hasher, so its per-phase averages also include the recomputation.
+    #[clap(long)]
+    verify_root: bool,
+
+    /// Also maintain a SHA3 Merkle tree over the same leaves as the merkle++ tree, and check after every
+    /// round that both propagated the updates through the same nodes and accept same-size proofs for
+    /// the same leaves. Only checks the trees, so no timings are reported.
+    #[clap(long)]
+    lockstep: bool,
+
+    /// Benchmark populating all the leaves of an empty tree from scratch (e.g., as in an initial sync),
+    /// rather than updating some of them
+    #[clap(long)]
+    build: bool,
+
+    /// Load the tree's nodes from this file (saved via --save) before updating it. Since KZG Verkle
+    /// bases are picked at random, loaded verkle_kzg trees are only good for timing, unless they were
+    /// saved with the same --seed.
+    #[clap(long)]
+    load: Option<String>,
+
+    /// Save the tree's nodes to this file after updating it
+    #[clap(long)]
+    save: Option<String>,
+
+    /// Write the root hash after every round, with a timestamp and the # of updates, to this file as a
+    /// JSON array (e.g., to find the first round in which two runs with the same --seed diverged)
+    #[clap(long)]
+    root_log: Option<String>,
+
+    /// Use a sparse tree with 2^256 leaves, keyed by the hash of an account address (the arity must be
+    /// a power of two)
+    #[clap(long)]
+    sparse: bool,
+
+    /// Seed for the RNG that picks the updated leaves and the KZG Verkle bases, so that runs with the same
+    /// seed are comparable across tree types (random by default; printed either way)
+    #[clap(long)]
+    seed: Option<u64>,
+
+    /// Have Verkle nodes store the scalar hashes of their commitments, which doubles their size but
+    /// saves recomputing these hashes when updating their parents
+    #[clap(long)]
+    fat_nodes: bool,
+
+    /// Run the benchmark with 1, 2, 4, ... and finally this many threads (requires the parallel
+    /// feature), and report how updates scale with the # of threads. All runs update the same leaves.
+    #[clap(long)]
+    threads: Option<usize>,
+
+    /// Update a Verkle parent via individual exponentiations, rather than a multiexp, when at most this
+    /// many of its children changed. By default, this is calibrated at startup (up to 64).
+    #[clap(long)]
+    multiexp_cutoff: Option<usize>,
+
+    /// Do Verkle's individual exponentiations without precomputed tables for the bases, which saves
+    /// around 30 KiB of memory per base
+    #[clap(long)]
+    no_base_tables: bool,
+
+    /// Load Verkle's bases and their precomputed tables from this file, or, if it does not exist, save
+    /// them to it, so that they are only computed once (per arity). The bases are hashed to the curve,
+    /// so they are the same with or without this file.
+    #[clap(long)]
+    bases_file: Option<String>,
+
+    /// How the nodes of merkle_* trees (except merkle_poseidon) are laid out in memory: heap (level by
+    /// level) or blocked[:LEVELS] (in blocks of subtrees with LEVELS levels, 2 by default, so that the
+    /// nodes on a path are close together)
+    #[clap(long, default_value = "heap")]
+    layout: NodeLayout,
+
+    /// Periodically print the progress of long-running steps (e.g., allocating, building or updating
+    /// a huge tree), so that a long run can be told apart from a hung one
+    #[clap(long)]
+    progress: bool,
+
+    /// Append the results (one row per round) to a file, as either csv or json (i.e., JSON Lines), so
+    /// that many runs can be aggregated and plotted
+    #[clap(long, number_of_values = 2, value_names = &["FORMAT", "PATH"])]
+    output: Option<Vec<String>>,
+
+    /// Keep the nodes of merkle_* trees (except merkle_poseidon) in a memory-mapped file at this path
+    /// (created, or truncated, with one 33-byte slot per node), rather than in memory, so that the OS
+    /// pages them in and out as needed and trees larger than RAM can be benchmarked. Requires the mmap
+    /// feature, and the default (heap) layout.
+    #[clap(long, value_name = "PATH")]
+    mmap: Option<String>,
+
+    /// Keep the nodes of merkle_* trees (except merkle_poseidon) in an on-disk key-value database
+    /// (sled) in this directory, rather than in memory, caching at most --disk-cache-mb of them in
+    /// memory, so that trees larger than RAM can be benchmarked. Any nodes already in the database are
+    /// discarded. Requires the disk feature, and the default (heap) layout.
+    #[clap(long, value_name = "DIR", conflicts_with = "mmap")]
+    disk: Option<String>,
+
+    /// How much of the --disk database to cache in memory, in MiB
+    #[clap(long, default_value_t = 256, requires = "disk")]
+    disk_cache_mb: u64,
 }
 
 fn main() {
-    let args = Args::parse();
+    // NOTE: The library logs what it is doing via tracing, at the info level by default. Set RUST_LOG to
+    // debug to also time each level of updates (via span close events), or to trace to follow every node.
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with_span_events(FmtSpan::CLOSE)
+        .with_target(false)
+        .with_timer(())
+        .init();
+
+    let args = match Cli::parse_from(expand_preset(std::env::args().collect())).command {
+        Command::Bench(args) => *args,
+        Command::Testvectors { types, seed, output, check } => {
+            run_test_vectors(types.as_deref(), seed, output.as_deref(), check.as_deref());
+            return;
+        }
+        command => {
+            run_tool(&command);
+            return;
+        }
+    };
+
+    assert!(
+        (args.mmap.is_none() && args.disk.is_none())
+            || (!args.sparse && args._type.starts_with("merkle_") && args._type != "merkle_poseidon"),
+        "--mmap and --disk are only supported by (non-sparse) merkle_* trees (except merkle_poseidon)"
+    );
+
+    merkle_race::set_progress(args.progress);
+
+    if let Some(preset) = args.preset {
+        println!("Using the preset {} (unless overridden)\n", preset.args().join(" "));
+    }
+
+    // NOTE: checked before benchmarking, so a typo does not throw away a long run
+    let output = args.output.as_ref().map(|values| {
+        let format = OutputFormat::from_str(&values[0]).unwrap_or_else(|err| panic!("{}", err));
+        (format, values[1].clone())
+    });
+
+    let records = match args.threads {
+        Some(max_threads) => run_scaling(&args, max_threads),
+        None => run_bench(&args),
+    };
+
+    if let Some((format, path)) = output {
+        append_records(&path, format, &records).expect("Could not write benchmark results");
+        println!("Appended {} result(s) to {}", records.len(), path);
+    }
+}
+
+fn run_bench(args: &Args) -> Vec<BenchRecord> {
+    if args.sparse {
+        run_sparse(args)
+    } else {
+        run(args)
+    }
+}
+
+// Runs the benchmark on a fresh tree for each # of threads, in its own rayon thread pool, and prints
+// the resulting updates per second side by side.
+#[cfg(feature = "parallel")]
+fn run_scaling(args: &Args, max_threads: usize) -> Vec<BenchRecord> {
+    assert!(max_threads > 0, "Need at least one thread");
+
+    // NOTE: Every run must update the same leaves (and use the same Verkle bases)
+    let args = Args {
+        seed: Some(args.seed.unwrap_or_else(|| thread_rng().gen())),
+        ..args.clone()
+    };
+
+    let mut thread_counts = std::iter::successors(Some(1), |n| Some(n * 2))
+        .take_while(|n| *n < max_threads)
+        .collect::<Vec<usize>>();
+    thread_counts.push(max_threads);
+
+    let mut records = Vec::new();
+    let mut scaling = Vec::with_capacity(thread_counts.len());
+    for threads in thread_counts {
+        println!("Running with {} thread(s)", threads);
+
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+        let run_records = pool.install(|| run_bench(&args));
+
+        // averaged over all rounds, since each run has the same cold and warm rounds
+        let updates_per_sec = run_records.iter().map(|record| record.updates_per_sec).sum::<f64>()
+            / run_records.len() as f64;
+        scaling.push((threads, updates_per_sec));
+
+        records.extend(run_records.into_iter().map(|record| BenchRecord { threads: Some(threads), ..record }));
+    }
+
+    println!("Threads | Updates per second | Speedup");
+    for (threads, updates_per_sec) in &scaling {
+        println!(
+            "{:>7} | {:>18} | {:.2}x",
+            threads,
+            (*updates_per_sec as usize).separate_with_commas(),
+            updates_per_sec / scaling[0].1
+        );
+    }
+
+    records
+}
+
+#[cfg(not(feature = "parallel"))]
+fn run_scaling(_args: &Args, _max_threads: usize) -> Vec<BenchRecord> {
+    panic!("--threads requires building with the parallel feature");
+}
+
+// Updates a Merkle++ and a SHA3 Merkle tree in lockstep for every round, which panics as soon as they
+// diverge (see LockstepMerkle)
+fn run_lockstep(num_leaves: usize, args: &Args, rng: &mut StdRng) -> Vec<BenchRecord> {
+    assert_eq!(args._type, "merkle++", "--lockstep is only supported by merkle++ trees");
+    assert_le!(args.num_updates, num_leaves);
+
+    let mut lockstep = LockstepMerkle::new(
+        new_merklepp_from_leaves::<CompRistBlakeIncHash, RistBlakeIncHash>(args.arity, num_leaves),
+        new_merkle_crhf_from_leaves::<Sha3HashFunc>(args.arity, num_leaves),
+    );
+    let workload = Workload::new(num_leaves, args.leaf_distribution);
+    workload.check_batch_size(args.num_updates).unwrap_or_else(|err| panic!("{}", err));
+    for round in 1..=args.rounds {
+        let updates = random_workload_updates(rng, &workload, args.num_updates, args.leaf_size);
+        let proven_leaves: Vec<_> = updates.choose_multiple(rng, args.num_proofs.max(1)).cloned().collect();
 
-    let num_updates: usize = args.num_updates;
+        let num_nodes = lockstep.update_leaves(updates);
+        print!("Round {}: merkle++ and merkle_sha3 updated the same {} nodes", round, num_nodes.separate_with_commas());
+        if !proven_leaves.is_empty() {
+            let proof_len = lockstep.check_proofs(&proven_leaves);
+            print!(", and proved {} of the leaves with {} hashes", proven_leaves.len(), proof_len);
+        }
+        println!();
+    }
 
+    vec![]
+}
+
+fn run(args: &Args) -> Vec<BenchRecord> {
     let num_leaves;
     match (args.height, args.num_leaves) {
         (Some(h), None) => num_leaves = max_leaves(args.arity, h),
@@ -65,99 +557,834 @@ fn main() {
     //     max_leaves.separate_with_commas()
     // );
     println!();
+    let mut rng = seeded_rng(args.seed);
+    if args.lockstep {
+        return run_lockstep(num_leaves, args, &mut rng);
+    }
+    assert!(
+        args.layout == NodeLayout::Heap || (args._type.starts_with("merkle_") && args._type != "merkle_poseidon"),
+        "--layout is only supported by merkle_* trees (except merkle_poseidon)"
+    );
+    assert!(
+        (args.mmap.is_none() && args.disk.is_none()) || args.layout == NodeLayout::Heap,
+        "--mmap and --disk cannot be combined with --layout"
+    );
 
     match args._type.as_str() {
-        "merkle_tiny_sha3" => {
-            let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(args.arity, num_leaves);
+        "merkle_tiny_sha3" => bench_merkle_crhf::<TinySha3HashFunc>(num_leaves, args, &mut rng),
+        "merkle_sha3" => bench_merkle_crhf::<Sha3HashFunc>(num_leaves, args, &mut rng),
+        "merkle_sha256" => bench_merkle_crhf::<Sha256HashFunc>(num_leaves, args, &mut rng),
+        "merkle_keccak256" => bench_merkle_crhf::<Keccak256HashFunc>(num_leaves, args, &mut rng),
+        "merkle_blake2s" => bench_merkle_crhf::<Blake2sHashFunc>(num_leaves, args, &mut rng),
+        "merkle_blake2b" => bench_merkle_crhf::<Blake2bHashFunc>(num_leaves, args, &mut rng),
+        "merkle_blake3" => bench_merkle_crhf::<Blake3HashFunc>(num_leaves, args, &mut rng),
+        "merkle_poseidon" => {
+            let mut merkle = new_merkle_poseidon_from_leaves(args.arity, num_leaves);
 
-            bench_merkle(&mut merkle, num_leaves, num_updates);
-        }
-        "merkle_sha3" => {
-            let mut merkle = new_merkle_crhf_from_leaves::<Sha3HashFunc>(args.arity, num_leaves);
-
-            bench_merkle(&mut merkle, num_leaves, num_updates);
-        }
-        "merkle_blake2s" => {
-            let mut merkle = new_merkle_crhf_from_leaves::<Blake2sHashFunc>(args.arity, num_leaves);
-
-            bench_merkle(&mut merkle, num_leaves, num_updates);
-        }
-        "merkle_blake2b" => {
-            let mut merkle = new_merkle_crhf_from_leaves::<Blake2bHashFunc>(args.arity, num_leaves);
-
-            bench_merkle(&mut merkle, num_leaves, num_updates);
+            bench_merkle(&mut merkle, num_leaves, args, &mut rng)
         }
         "merkle++" => {
             let mut merklepp = new_merklepp_from_leaves::<CompRistBlakeIncHash, RistBlakeIncHash>(
                 args.arity, num_leaves,
             );
 
-            bench_merkle(&mut merklepp, num_leaves, num_updates);
+            bench_merklepp(&mut merklepp, num_leaves, args, &mut rng)
+        }
+        "merkle++fat" => {
+            let mut merklepp = new_fat_merklepp_from_leaves::<CompRistBlakeIncHash, RistBlakeIncHash>(
+                args.arity, num_leaves,
+            );
 
-            println!("Average time per incremental hash: {}", merklepp.hasher.avg_hash_time);
-            println!("Average time per accumulation (compress/decompress): {}", merklepp.hasher.avg_accum_time);
-            println!("hash_nodes histogram:\n{}", merklepp.hasher.hash_nodes_histogram);
+            bench_merklepp(&mut merklepp, num_leaves, args, &mut rng)
         }
         "merkle++naive" => {
             let mut merklepp = new_merklepp_from_leaves::<RistBlakeIncHash, RistBlakeIncHash>(
                 args.arity, num_leaves,
             );
 
-            bench_merkle(&mut merklepp, num_leaves, num_updates);
+            bench_merkle(&mut merklepp, num_leaves, args, &mut rng)
+        }
+        "merkle++lthash" => {
+            let mut merklepp = new_merklepp_from_leaves::<LtHash16, LtHash16>(args.arity, num_leaves);
+
+            bench_merklepp(&mut merklepp, num_leaves, args, &mut rng)
         }
         "verkle" => {
-            let mut rng = thread_rng();
-            let bases = (0..args.arity).map(|_|
-                (&Scalar::random(&mut rng) * &RISTRETTO_BASEPOINT_TABLE)).collect::<Vec<RistrettoPoint>>();
+            if args.fat_nodes {
+                let hasher = verkle_hasher::<FatVerkleComm>(args);
+                let mut verkle = AbstractMerkle::with_num_leaves(args.arity, num_leaves, hasher);
+                bench_verkle(&mut verkle, num_leaves, args, &mut rng)
+            } else {
+                let hasher = verkle_hasher::<VerkleComm>(args);
+                let mut verkle = AbstractMerkle::with_num_leaves(args.arity, num_leaves, hasher);
+                bench_verkle(&mut verkle, num_leaves, args, &mut rng)
+            }
+        }
+        "verkle_kzg" => {
+            let mut verkle = new_kzg_verkle_from_leaves(
+                args.arity, num_leaves, lagrange_bases(&mut rng, args.arity),
+            );
+
+            let records = bench_merkle(&mut verkle, num_leaves, args, &mut rng);
 
-            let mut verkle = new_verkle_from_leaves(
-                args.arity, num_leaves, bases,
+            println!("Average time per exponentiation: {:.2}", verkle.hasher.avg_exp_time);
+            println!("Average time per accumulation (add/normalize): {:.2}", verkle.hasher.avg_accum_time);
+            println!("hash_nodes histogram:\n{}", verkle.hasher.hash_nodes_histogram);
+
+            records
+                .into_iter()
+                .map(|record| BenchRecord {
+                    avg_exp_us: phase_average(&verkle.hasher.avg_exp_time),
+                    avg_accum_us: phase_average(&verkle.hasher.avg_accum_time),
+                    ..record
+                })
+                .collect()
+        }
+        "all" => race_all(num_leaves, args, &mut rng),
+        // NOTE: Only reached by contestants that were registered but have no dedicated benchmark above
+        _ => match RacerRegistry::with_default_racers().create(&args._type, args.arity, num_leaves, &mut rng) {
+            Some(mut racer) => bench_racer(racer.as_mut(), &args._type, args, &mut rng).0,
+            None => {
+                println!("Unknown type of Merkle tree provided: {}", args._type);
+                vec![]
+            }
+        },
+    }
+}
+
+// Races every registered contestant with the same arity, # of leaves and updates, and prints a
+// side-by-side summary of how they did. Each round of each contestant is also a separate record (e.g.,
+// for --output).
+fn race_all(num_leaves: usize, args: &Args, rng: &mut StdRng) -> Vec<BenchRecord> {
+    assert!(
+        !args.build && args.load.is_none() && args.save.is_none(),
+        "--build, --load and --save are not supported when racing all types"
+    );
+
+    let registry = RacerRegistry::with_default_racers();
+    let seed = rng.gen::<u64>();
+    let mut records = Vec::new();
+    let mut summary = Vec::new();
+
+    for name in registry.names() {
+        println!("Racing {}:", name);
+
+        // NOTE: Every contestant gets its own RNG with the same seed, so that they all update the same leaves
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut racer = registry.create(name, args.arity, num_leaves, &mut rng).unwrap();
+        let (racer_records, work) = bench_racer(racer.as_mut(), name, args, &mut rng);
+
+        summary.push(RaceSummary::new(name, &racer_records, racer.stats().node_bytes, work));
+        records.extend(racer_records);
+    }
+
+    println!(
+        "{:>16} | {:>18} | {:>14} | {:>13} | {:>14} | {:>12}",
+        "Type", "Updates per second", "Hashes", "Time per hash", "Node storage", "Proof size"
+    );
+    for row in &summary {
+        println!(
+            "{:>16} | {:>18} | {:>14} | {:>10.2} us | {:>14} | {:>12}",
+            row.name,
+            (row.updates_per_sec as usize).separate_with_commas(),
+            row.num_hashes.separate_with_commas(),
+            row.us_per_hash,
+            format!("{} KiB", (row.node_bytes / 1024).separate_with_commas()),
+            row.avg_proof_bytes.map_or("-".to_owned(), |bytes| format!("{:.2} bytes", bytes))
+        );
+    }
+    println!();
+
+    // NOTE: The "hashes" above are different operations for different types (e.g., exponentiations for
+    // Verkle), so this breaks them down by the kind of operation.
+    println!(
+        "{:>16} | {:>16} | {:>12} | {:>9} | {:>12} | {:>14} | {:>9}",
+        "Type", "Symmetric hashes", "Group hashes", "Exps", "Compressions", "Decompressions", "Field ops"
+    );
+    for row in &summary {
+        let per_update = |count: usize| count as f64 / row.num_updates as f64;
+
+        println!(
+            "{:>16} | {:>16.2} | {:>12.2} | {:>9.2} | {:>12.2} | {:>14.2} | {:>9.2}",
+            row.name,
+            per_update(row.work.sym_hashes),
+            per_update(row.work.group_hashes),
+            per_update(row.work.group_exps),
+            per_update(row.work.compressions),
+            per_update(row.work.decompressions),
+            per_update(row.work.field_ops)
+        );
+    }
+    println!("(per updated leaf)\n");
+
+    records
+}
+
+// A contestant's results, over all rounds, as reported by race_all()
+struct RaceSummary<'a> {
+    name: &'a str,
+    num_updates: usize,
+    updates_per_sec: f64,
+    num_hashes: usize,
+    us_per_hash: f64,
+    node_bytes: usize,
+    avg_proof_bytes: Option<f64>,
+    work: HasherStats, // the work done for the updates, over all rounds
+}
+
+impl<'a> RaceSummary<'a> {
+    fn new(name: &'a str, records: &[BenchRecord], node_bytes: usize, work: HasherStats) -> Self {
+        let num_updates = records.iter().map(|record| record.num_updates).sum::<usize>();
+        let num_hashes = records.iter().map(|record| record.num_hashes).sum::<usize>();
+        let wall_time_us = records.iter().map(|record| record.wall_time_us).sum::<u128>() as f64;
+
+        RaceSummary {
+            name,
+            num_updates,
+            updates_per_sec: num_updates as f64 / wall_time_us * 1000.0 * 1000.0,
+            num_hashes,
+            us_per_hash: wall_time_us / num_hashes as f64,
+            node_bytes,
+            avg_proof_bytes: records.last().and_then(|record| record.avg_proof_bytes),
+            work,
+        }
+    }
+}
+
+// Benchmarks any contestant via the MerkleRacer trait. Unlike bench_merkle(), this cannot report any
+// contestant-specific statistics (e.g., time per exponentiation), nor separate out preprocessing.
+//
+// Returns the records, as well as the work done for the updates (i.e., not counting proof verification).
+fn bench_racer(
+    racer: &mut dyn MerkleRacer,
+    name: &str,
+    args: &Args,
+    rng: &mut StdRng,
+) -> (Vec<BenchRecord>, HasherStats) {
+    let num_leaves = racer.num_leaves();
+    let num_updates = args.num_updates;
+    assert_le!(num_updates, num_leaves);
+
+    let workload = Workload::new(num_leaves, args.leaf_distribution);
+    workload.check_batch_size(args.num_updates).unwrap_or_else(|err| panic!("{}", err));
+    let template = BenchRecord {
+        leaf_size: Some(args.leaf_size),
+        leaf_distribution: Some(args.leaf_distribution.to_string()),
+        ..BenchRecord::new(name, "update", args.arity, Some(num_leaves))
+    };
+    let mut records = Vec::with_capacity(args.rounds);
+    let mut proven_leaves = Vec::new();
+    let mut work = HasherStats::default();
+
+    for round in 1..=args.rounds {
+        let updates = random_workload_updates(rng, &workload, num_updates, args.leaf_size);
+        if round == args.rounds {
+            proven_leaves = updates.choose_multiple(rng, args.num_proofs).cloned().collect();
+        }
+        let stats = racer.stats();
+
+        let start = Instant::now();
+        racer.update_batch(updates);
+        let duration = start.elapsed();
+
+        let num_computations = racer.stats().num_hashes - stats.num_hashes;
+        work += racer.stats().hasher - stats.hasher;
+        records.push(template.measured(round, num_updates, num_computations, duration));
+
+        println!(
+            "Round {} of {}: updated {} leaves in {:?} ({} updates per second, {} hashes)",
+            round,
+            args.rounds,
+            num_updates.separate_with_commas(),
+            duration,
+            (((num_updates as f64 / duration.as_micros() as f64) * 1000.0 * 1000.0) as usize)
+                .separate_with_commas(),
+            num_computations.separate_with_commas()
+        );
+    }
+
+    if !proven_leaves.is_empty() {
+        let root = racer.root();
+        let mut proof_bytes = 0;
+        let mut verify_duration = Duration::ZERO;
+
+        for (pos, leaf_data) in &proven_leaves {
+            let proof = racer.prove(&[*pos]);
+            proof_bytes += proof.len();
+
+            let start = Instant::now();
+            let is_valid = racer.verify(&root, &[(*pos, leaf_data.clone())], &proof);
+            verify_duration += start.elapsed();
+
+            assert!(is_valid, "Proof for leaf {} did not verify", pos);
+        }
+
+        let num_proofs = proven_leaves.len() as f64;
+        let (avg_proof_bytes, avg_verify_us) =
+            (proof_bytes as f64 / num_proofs, verify_duration.as_micros() as f64 / num_proofs);
+        println!("Average proof size: {:.2} bytes, verified in {:.2} us", avg_proof_bytes, avg_verify_us);
+
+        records = records
+            .into_iter()
+            .map(|record| BenchRecord {
+                avg_proof_bytes: Some(avg_proof_bytes),
+                avg_verify_us: Some(avg_verify_us),
+                ..record
+            })
+            .collect();
+    }
+    println!();
+
+    if args.verify_root {
+        verify_root(racer.root(), || racer.recompute_root());
+    }
+
+    (records, work)
+}
+
+// Runs one of the commands that work on saved trees, all of which need a tree of the right type and
+// shape to load the saved nodes into (or, for verify, to verify against)
+fn run_tool(command: &Command) {
+    let (tree_type, arity, num_leaves) = match command {
+        Command::Bench(_) | Command::Testvectors { .. } => unreachable!("benchmarks and test vectors are run by main()"),
+        Command::Build { tree, arity, num_leaves, .. } => (&tree._type, *arity, *num_leaves),
+        Command::Verify { _type, arity, num_leaves, .. } => (_type, *arity, *num_leaves),
+        Command::Update { tree, .. } | Command::Prove { tree, .. } | Command::Inspect { tree, .. } => {
+            let (arity, num_leaves) = saved_shape(&tree.file).expect("Could not read saved tree");
+            (&tree._type, arity, num_leaves)
+        }
+    };
+
+    match tree_type.as_str() {
+        "merkle_tiny_sha3" => {
+            run_tool_on(&mut new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves), command)
+        }
+        "merkle_sha3" => run_tool_on(&mut new_merkle_crhf_from_leaves::<Sha3HashFunc>(arity, num_leaves), command),
+        "merkle_sha256" => {
+            run_tool_on(&mut new_merkle_crhf_from_leaves::<Sha256HashFunc>(arity, num_leaves), command)
+        }
+        "merkle_keccak256" => {
+            run_tool_on(&mut new_merkle_crhf_from_leaves::<Keccak256HashFunc>(arity, num_leaves), command)
+        }
+        "merkle_blake2s" => {
+            run_tool_on(&mut new_merkle_crhf_from_leaves::<Blake2sHashFunc>(arity, num_leaves), command)
+        }
+        "merkle_blake2b" => {
+            run_tool_on(&mut new_merkle_crhf_from_leaves::<Blake2bHashFunc>(arity, num_leaves), command)
+        }
+        "merkle_blake3" => {
+            run_tool_on(&mut new_merkle_crhf_from_leaves::<Blake3HashFunc>(arity, num_leaves), command)
+        }
+        "merkle_poseidon" => run_tool_on(&mut new_merkle_poseidon_from_leaves(arity, num_leaves), command),
+        "merkle++" => run_tool_on(
+            &mut new_merklepp_from_leaves::<CompRistBlakeIncHash, RistBlakeIncHash>(arity, num_leaves),
+            command,
+        ),
+        "merkle++lthash" => run_tool_on(&mut new_merklepp_from_leaves::<LtHash16, LtHash16>(arity, num_leaves), command),
+        "verkle" => run_tool_on(&mut new_verkle_from_leaves(arity, num_leaves, deterministic_bases(arity)), command),
+        // NOTE: Unlike the bench command's, these bases are derived from a fixed seed, so that saved trees
+        // and their proofs can be used across runs
+        "verkle_kzg" => run_tool_on(
+            &mut new_kzg_verkle_from_leaves(arity, num_leaves, seeded_lagrange_bases(DEFAULT_SRS_SEED, arity)),
+            command,
+        ),
+        _ => {
+            println!("Unknown type of Merkle tree provided: {}", tree_type)
+        }
+    }
+}
+
+fn run_tool_on<HashType, Hasher>(merkle: &mut AbstractMerkle<String, HashType, Hasher>, command: &Command)
+where
+    HashType: CanonicalBytes + Clone + Debug + Default + PartialEq + Send + Sync,
+    Hasher: ParallelTreeHasherFunc<String, HashType>,
+{
+    let load = |merkle: &mut AbstractMerkle<String, HashType, Hasher>, path: &str| {
+        merkle.load(path).expect("Could not load tree");
+    };
+    let save = |merkle: &AbstractMerkle<String, HashType, Hasher>, path: &str| {
+        merkle.save(path).expect("Could not save tree");
+        println!("Saved tree with root {} to {}", hex::encode(merkle.root_hash().to_bytes()), path);
+    };
+
+    match command {
+        Command::Bench(_) | Command::Testvectors { .. } => unreachable!("benchmarks and test vectors are run by main()"),
+        Command::Build { tree, num_leaves, seed, .. } => {
+            let leaves = random_updates(&mut seeded_rng(*seed), *num_leaves, *num_leaves)
+                .into_iter()
+                .map(|(_, leaf_data)| leaf_data)
+                .collect::<Vec<String>>();
+
+            #[cfg(feature = "parallel")]
+            merkle.build_from_leaves_parallel(leaves);
+            #[cfg(not(feature = "parallel"))]
+            merkle.build_from_leaves(leaves);
+
+            save(merkle, &tree.file);
+        }
+        Command::Update { tree, leaves } => {
+            load(merkle, &tree.file);
+            for (pos, _) in leaves {
+                assert_lt!(*pos, merkle.num_leaves(), "Leaf position is out of range");
+            }
+
+            merkle.update_leaves(leaves.clone());
+            save(merkle, &tree.file);
+        }
+        Command::Prove { tree, proof, positions } => {
+            load(merkle, &tree.file);
+            for pos in positions {
+                assert_lt!(*pos, merkle.num_leaves(), "Leaf position is out of range");
+            }
+
+            let bytes = merkle.prove_batch(positions).to_bytes();
+            std::fs::write(proof, &bytes).expect("Could not save proof");
+            println!(
+                "Saved {}-byte proof for root {} to {}",
+                bytes.len().separate_with_commas(),
+                hex::encode(merkle.root_hash().to_bytes()),
+                proof
             );
+        }
+        Command::Verify { root, proof, leaves, .. } => {
+            let root = hex::decode(root)
+                .ok()
+                .and_then(|bytes| HashType::from_bytes(&bytes))
+                .expect("Invalid root hash");
+            let bytes = std::fs::read(proof).expect("Could not read proof");
+            let proof = BatchProof::from_bytes(&bytes).expect("Invalid proof");
 
-            bench_merkle(&mut verkle, num_leaves, num_updates);
+            if merkle.verify_batch(&root, leaves, &proof) {
+                println!("Proof is valid");
+            } else {
+                println!("Proof is NOT valid");
+                std::process::exit(1);
+            }
+        }
+        Command::Inspect { tree, dot: true, max_depth } => {
+            load(merkle, &tree.file);
+            merkle.to_dot(&mut std::io::stdout().lock(), *max_depth).expect("Could not write graph");
+        }
+        Command::Inspect { tree, dot: false, .. } => {
+            load(merkle, &tree.file);
+
+            println!(
+                "Tree of type {}
+                 * Arity: {}
+                 * Leaves: {}
+                 * Nodes: {}
+                 * Root: {}",
+                tree._type,
+                merkle.arity(),
+                merkle.num_leaves().separate_with_commas(),
+                merkle.num_nodes().separate_with_commas(),
+                hex::encode(merkle.root_hash().to_bytes())
+            );
+        }
+    }
+}
 
-            println!("Average time to push updates (Vec::new, hash_to_scalar): {:.2}", verkle.hasher.avg_push_updates_time);
+fn run_test_vectors(types: Option<&[String]>, seed: u64, output: Option<&str>, check: Option<&str>) {
+    let registry = RacerRegistry::with_default_racers();
 
-            println!("Average time per *single* exponentiation: {:.2}", verkle.hasher.avg_single_exp_time);
+    if let Some(path) = check {
+        let vectors = read_vectors(File::open(path).expect("Could not open vectors")).expect("Invalid vectors");
 
-            println!("Average exponentiation time via *multiexps*: {:.2}", verkle.hasher.avg_multi_exp_time);
+        match verify_vectors(&registry, &vectors) {
+            Ok(num_vectors) => println!("All {} vectors match", num_vectors),
+            Err(err) => {
+                println!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
 
-            println!("Average time per (any) exponentiation: {:.2}", verkle.hasher.avg_exp_time);
-            // println!(" * Average time per clone: {:.2}", verkle.hasher.avg_clone_time);
+    let types = types.map_or_else(|| default_vector_types(&registry), |types| types.to_vec());
+    let vectors = generate_vectors(&registry, &types, DEFAULT_SHAPES, seed).unwrap_or_else(|err| panic!("{}", err));
 
-            println!("Average time per accumulation (compress/decompress): {:.2}", verkle.hasher.avg_accum_time);
-            println!("hash_nodes histogram:\n{}", verkle.hasher.hash_nodes_histogram);
+    match output {
+        Some(path) => {
+            write_vectors(File::create(path).expect("Could not create vectors file"), &vectors)
+                .expect("Could not write vectors");
+            println!("Wrote {} vectors to {}", vectors.len(), path);
+        }
+        None => {
+            write_vectors(std::io::stdout().lock(), &vectors).expect("Could not write vectors");
+            println!();
+        }
+    }
+}
+
+fn run_sparse(args: &Args) -> Vec<BenchRecord> {
+    println!();
+    let mut rng = seeded_rng(args.seed);
+    assert!(!args.verify_root, "--verify-root is not supported by sparse trees");
+
+    match args._type.as_str() {
+        "merkle_tiny_sha3" => {
+            bench_sparse_merkle(&mut new_sparse_merkle_crhf::<TinySha3HashFunc>(args.arity), args, &mut rng)
+        }
+        "merkle_sha3" => {
+            bench_sparse_merkle(&mut new_sparse_merkle_crhf::<Sha3HashFunc>(args.arity), args, &mut rng)
+        }
+        "merkle_sha256" => {
+            bench_sparse_merkle(&mut new_sparse_merkle_crhf::<Sha256HashFunc>(args.arity), args, &mut rng)
+        }
+        "merkle_keccak256" => {
+            bench_sparse_merkle(&mut new_sparse_merkle_crhf::<Keccak256HashFunc>(args.arity), args, &mut rng)
+        }
+        "merkle_blake2s" => {
+            bench_sparse_merkle(&mut new_sparse_merkle_crhf::<Blake2sHashFunc>(args.arity), args, &mut rng)
+        }
+        "merkle_blake2b" => {
+            bench_sparse_merkle(&mut new_sparse_merkle_crhf::<Blake2bHashFunc>(args.arity), args, &mut rng)
+        }
+        "merkle_blake3" => {
+            bench_sparse_merkle(&mut new_sparse_merkle_crhf::<Blake3HashFunc>(args.arity), args, &mut rng)
+        }
+        "merkle++" => {
+            let mut merklepp = new_sparse_merklepp::<CompRistBlakeIncHash, RistBlakeIncHash>(args.arity);
+
+            bench_sparse_merkle(&mut merklepp, args, &mut rng)
+        }
+        "merkle++lthash" => {
+            bench_sparse_merkle(&mut new_sparse_merklepp::<LtHash16, LtHash16>(args.arity), args, &mut rng)
+        }
+        "verkle" => {
+            let mut verkle = SparseMerkle::new(args.arity, verkle_hasher::<VerkleComm>(args));
+
+            bench_sparse_merkle(&mut verkle, args, &mut rng)
+        }
+        "mpt" => {
+            assert_eq!(args.arity, 16, "Merkle Patricia Tries are hexary");
+
+            bench_mpt(&mut MerklePatriciaTrie::new(), args, &mut rng)
+        }
+        "jmt" => {
+            assert_eq!(args.arity, 16, "Jellyfish Merkle Trees are 16-ary");
+
+            bench_jmt(&mut JellyfishMerkle::<Sha3HashFunc>::new(), args, &mut rng)
         }
         _ => {
-            println!("Unknown type of Merkle tree provided: {}", args._type)
+            println!("Unknown type of sparse Merkle tree provided: {}", args._type);
+            vec![]
         }
     }
 }
 
-fn bench_merkle<HashType, Hasher>(
-    merkle: &mut AbstractMerkle<String, HashType, Hasher>,
-    num_leaves: usize,
-    num_updates: usize,
-) where
-    HashType: Clone + Debug + Default,
+// Benchmarks a Merkle tree over 'HashFunc', whose nodes are laid out as per --layout, or kept in the
+// file given via --mmap, or in the database given via --disk
+fn bench_merkle_crhf<HashFunc>(num_leaves: usize, args: &Args, rng: &mut StdRng) -> Vec<BenchRecord>
+where
+    HashFunc: HashFuncTrait + Sync,
+{
+    #[cfg(feature = "mmap")]
+    if let Some(path) = &args.mmap {
+        // NOTE: Each slot holds a hash's length byte, followed by the hash itself
+        let nodes = MmapNodeStore::create(path, HASH_LENGTH + 1).expect("Could not create the node file");
+        let mut merkle = new_merkle_crhf_with_node_store::<HashFunc, _>(args.arity, num_leaves, nodes);
+        println!("Keeping the tree's nodes in {}", path);
+
+        return bench_merkle(&mut merkle, num_leaves, args, rng);
+    }
+    #[cfg(not(feature = "mmap"))]
+    assert!(args.mmap.is_none(), "--mmap requires the mmap feature (e.g., cargo run --release --features mmap -- bench ...)");
+
+    #[cfg(feature = "disk")]
+    if let Some(path) = &args.disk {
+        let nodes = DiskNodeStore::open(path, args.disk_cache_mb << 20).expect("Could not open the node database");
+        let mut merkle = new_merkle_crhf_with_node_store::<HashFunc, _>(args.arity, num_leaves, nodes);
+        println!("Keeping the tree's nodes in the database in {}", path);
+
+        return bench_merkle(&mut merkle, num_leaves, args, rng);
+    }
+    #[cfg(not(feature = "disk"))]
+    assert!(args.disk.is_none(), "--disk requires the disk feature (e.g., cargo run --release --features disk -- bench ...)");
+
+    match args.layout {
+        NodeLayout::Heap => {
+            let mut merkle = new_merkle_crhf_from_leaves::<HashFunc>(args.arity, num_leaves);
+
+            bench_merkle(&mut merkle, num_leaves, args, rng)
+        }
+        NodeLayout::Blocked { levels } => {
+            let nodes = BlockedNodeStore::new(args.arity, levels);
+            let mut merkle = new_merkle_crhf_with_node_store::<HashFunc, _>(args.arity, num_leaves, nodes);
+
+            bench_merkle(&mut merkle, num_leaves, args, rng)
+        }
+    }
+}
+
+// Returns a Verkle hasher configured via the command line, whose multiexp cutoff is calibrated on this
+// machine unless one was given
+fn verkle_hasher<Node>(args: &Args) -> VerkleHasher<Node> {
+    let config = VerkleConfig {
+        multiexp_cutoff: args.multiexp_cutoff.unwrap_or_default(),
+        base_tables: !args.no_base_tables,
+    };
+
+    let mut hasher = match &args.bases_file {
+        Some(path) => {
+            let start = Instant::now();
+            let (bases, loaded) = VerkleBases::load_or_create(path, args.arity).expect("Could not load or save the Verkle bases");
+            if loaded {
+                println!("Loaded the Verkle bases from {} in {:?}\n", path, start.elapsed());
+            } else {
+                println!("Computed the Verkle bases and saved them to {} in {:?}\n", path, start.elapsed());
+            }
+
+            VerkleHasher::with_bases(bases, config)
+        }
+        None => VerkleHasher::with_config(args.arity, deterministic_bases(args.arity), config),
+    };
+
+    if args.multiexp_cutoff.is_none() {
+        let start = Instant::now();
+        let cutoff = hasher.calibrate_multiexp_cutoff();
+        println!("Calibrated the multiexp cutoff to {} exponentiations in {:?}\n", cutoff, start.elapsed());
+    }
+
+    hasher
+}
+
+fn bench_sparse_merkle<HashType, Hasher>(
+    merkle: &mut SparseMerkle<String, HashType, Hasher>,
+    args: &Args,
+    rng: &mut StdRng,
+) -> Vec<BenchRecord>
+where
+    HashType: Clone + Default + PartialEq,
     Hasher: TreeHasherFunc<String, HashType>,
 {
-    let updates = random_updates(num_leaves, num_updates);
+    let updates = random_sparse_updates(rng, args.num_updates);
+    let num_updates = updates.len();
 
-    assert_le!(num_updates, merkle.num_leaves());
+    // NOTE: computing the empty subtree hashes counted towards the hasher's computations
+    let num_computations = merkle.hasher.get_num_computations();
 
-    // NOTE: accounting for preprocessing does reduce time from 860us to 800us in a tree of 2^28 leaves with 200K updates
-    let (queue, pre_duration) = merkle.preprocess_leaves(updates);
     let start = Instant::now();
-    merkle.update_preprocessed_leaves(queue);
-    let duration = start.elapsed() + pre_duration;
+    merkle.update_leaves(updates);
+    let duration = start.elapsed();
+
+    let num_computations = merkle.hasher.get_num_computations() - num_computations;
 
     println!(
-        "Updated {} leaves in {:?}\n\
-         * Updates per second: {}",
+        "Updated {} leaves of a height-{} sparse tree in {:?}\n\
+         * Updates per second: {}\n\
+         * Stored nodes: {}",
         num_updates.separate_with_commas(),
+        merkle.height(),
         duration,
         (((num_updates as f64 / duration.as_micros() as f64) * 1000.0 * 1000.0) as usize)
+            .separate_with_commas(),
+        merkle.num_stored_nodes().separate_with_commas()
+    );
+
+    println!(
+        "Total hashes computed: {}\n\
+         * Hashes per second: {}\n\
+         * Time per hash: {:.2} us\n",
+        num_computations.separate_with_commas(),
+        (((num_computations as f64 / duration.as_micros() as f64) * 1000.0 * 1000.0) as usize)
+            .separate_with_commas(),
+        duration.as_micros() as f64 / num_computations as f64
+    );
+
+    vec![BenchRecord::new(&args._type, "sparse_update", args.arity, None).measured(
+        1,
+        num_updates,
+        num_computations,
+        duration,
+    )]
+}
+
+fn bench_merklepp<Node, FastIncHash>(
+    merklepp: &mut AbstractMerkle<String, Node, IncrementalHasher<FastIncHash>>,
+    num_leaves: usize,
+    args: &Args,
+    rng: &mut StdRng,
+) -> Vec<BenchRecord>
+where
+    Node: MerkleppNode<FastIncHash> + CanonicalBytes + Debug + Send + Sync,
+    Node::SmallIncHash: AddAssign<FastIncHash>,
+    FastIncHash: IncrementalHash + Sync,
+{
+    let records = bench_merkle(merklepp, num_leaves, args, rng);
+
+    println!("Average time per incremental hash: {}", merklepp.hasher.avg_hash_time);
+    println!("Average time per accumulation (compress/decompress): {}", merklepp.hasher.avg_accum_time);
+    println!("hash_nodes histogram:\n{}", merklepp.hasher.hash_nodes_histogram);
+
+    println!(
+        "Node storage: {} bytes per node, {} bytes in total",
+        size_of::<Node>(),
+        (size_of::<Node>() * merklepp.num_nodes()).separate_with_commas()
+    );
+
+    records
+        .into_iter()
+        .map(|record| BenchRecord {
+            avg_hash_us: phase_average(&merklepp.hasher.avg_hash_time),
+            avg_accum_us: phase_average(&merklepp.hasher.avg_accum_time),
+            ..record
+        })
+        .collect()
+}
+
+fn bench_verkle<Node>(
+    verkle: &mut AbstractMerkle<String, Node, VerkleHasher<Node>>,
+    num_leaves: usize,
+    args: &Args,
+    rng: &mut StdRng,
+) -> Vec<BenchRecord>
+where
+    Node: VerkleNode + CanonicalBytes + Debug,
+{
+    let records = bench_merkle(verkle, num_leaves, args, rng);
+
+    println!("Average time to push updates (Vec::new, hash_to_scalar): {:.2}", verkle.hasher.avg_push_updates_time);
+
+    println!("Average time per *single* exponentiation: {:.2}", verkle.hasher.avg_single_exp_time);
+
+    println!("Average exponentiation time via *multiexps*: {:.2}", verkle.hasher.avg_multi_exp_time);
+
+    println!("Average time per (any) exponentiation: {:.2}", verkle.hasher.avg_exp_time);
+    // println!(" * Average time per clone: {:.2}", verkle.hasher.avg_clone_time);
+
+    println!("Average time per accumulation (compress/decompress): {:.2}", verkle.hasher.avg_accum_time);
+    println!("hash_nodes histogram:\n{}", verkle.hasher.hash_nodes_histogram);
+
+    println!(
+        "Node storage: {} bytes per node, {} bytes in total",
+        size_of::<Node>(),
+        (size_of::<Node>() * verkle.num_nodes()).separate_with_commas()
+    );
+
+    records
+        .into_iter()
+        .map(|record| BenchRecord {
+            avg_push_updates_us: phase_average(&verkle.hasher.avg_push_updates_time),
+            avg_exp_us: phase_average(&verkle.hasher.avg_exp_time),
+            avg_accum_us: phase_average(&verkle.hasher.avg_accum_time),
+            ..record
+        })
+        .collect()
+}
+
+fn bench_mpt(mpt: &mut MerklePatriciaTrie, args: &Args, rng: &mut StdRng) -> Vec<BenchRecord> {
+    let updates = random_sparse_updates(rng, args.num_updates);
+    let num_updates = updates.len();
+
+    // NOTE: the MPT only hashes when asked for its root, so that is part of the update time
+    let start = Instant::now();
+    mpt.update_leaves(updates);
+    mpt.root_hash();
+    let duration = start.elapsed();
+
+    let num_computations = mpt.get_num_computations();
+
+    println!(
+        "Updated {} leaves of a Merkle Patricia Trie in {:?}\n\
+         * Updates per second: {}\n\
+         * Stored nodes: {}",
+        num_updates.separate_with_commas(),
+        duration,
+        (((num_updates as f64 / duration.as_micros() as f64) * 1000.0 * 1000.0) as usize)
+            .separate_with_commas(),
+        mpt.num_stored_nodes().separate_with_commas()
+    );
+
+    println!(
+        "Total hashes computed: {}\n\
+         * Hashes per second: {}\n\
+         * Time per hash: {:.2} us\n",
+        num_computations.separate_with_commas(),
+        (((num_computations as f64 / duration.as_micros() as f64) * 1000.0 * 1000.0) as usize)
+            .separate_with_commas(),
+        duration.as_micros() as f64 / num_computations as f64
+    );
+
+    vec![BenchRecord::new(&args._type, "sparse_update", args.arity, None).measured(
+        1,
+        num_updates,
+        num_computations,
+        duration,
+    )]
+}
+
+fn bench_jmt<HashFunc: HashFuncTrait>(
+    jmt: &mut JellyfishMerkle<HashFunc>,
+    args: &Args,
+    rng: &mut StdRng,
+) -> Vec<BenchRecord> {
+    let updates = random_sparse_updates(rng, args.num_updates);
+    let num_updates = updates.len();
+
+    let start = Instant::now();
+    let version = jmt.update_leaves(updates);
+    let duration = start.elapsed();
+
+    let num_computations = jmt.get_num_computations();
+
+    println!(
+        "Updated {} leaves of a Jellyfish Merkle Tree (version {}) in {:?}\n\
+         * Updates per second: {}\n\
+         * Stored nodes: {} ({} stale)",
+        num_updates.separate_with_commas(),
+        version,
+        duration,
+        (((num_updates as f64 / duration.as_micros() as f64) * 1000.0 * 1000.0) as usize)
+            .separate_with_commas(),
+        jmt.num_stored_nodes().separate_with_commas(),
+        jmt.num_stale_nodes().separate_with_commas()
+    );
+
+    println!(
+        "Total hashes computed: {}\n\
+         * Hashes per second: {}\n\
+         * Time per hash: {:.2} us\n",
+        num_computations.separate_with_commas(),
+        (((num_computations as f64 / duration.as_micros() as f64) * 1000.0 * 1000.0) as usize)
+            .separate_with_commas(),
+        duration.as_micros() as f64 / num_computations as f64
+    );
+
+    vec![BenchRecord::new(&args._type, "sparse_update", args.arity, None).measured(
+        1,
+        num_updates,
+        num_computations,
+        duration,
+    )]
+}
+
+fn bench_build<HashType, Hasher, Nodes>(
+    merkle: &mut AbstractMerkle<String, HashType, Hasher, NoLeafStorage, Nodes>,
+    num_leaves: usize,
+    args: &Args,
+    rng: &mut StdRng,
+) -> BenchRecord
+where
+    HashType: Clone + Debug + Default + PartialEq + Send + Sync,
+    Hasher: ParallelTreeHasherFunc<String, HashType>,
+    Nodes: NodeStore<HashType> + Sync,
+{
+    let leaves = random_leaf_data(rng, num_leaves, args.leaf_size);
+
+    let start = Instant::now();
+    #[cfg(feature = "parallel")]
+    merkle.build_from_leaves_parallel(leaves);
+    #[cfg(not(feature = "parallel"))]
+    merkle.build_from_leaves(leaves);
+    let duration = start.elapsed();
+
+    println!(
+        "Built tree with {} leaves in {:?}\n\
+         * Leaves per second: {}",
+        num_leaves.separate_with_commas(),
+        duration,
+        (((num_leaves as f64 / duration.as_micros() as f64) * 1000.0 * 1000.0) as usize)
             .separate_with_commas()
     );
 
@@ -171,4 +1398,236 @@ fn bench_merkle<HashType, Hasher>(
             .separate_with_commas(),
         duration.as_micros() as f64 / merkle.hasher.get_num_computations() as f64
     );
+
+    let num_computations = merkle.hasher.get_num_computations();
+    if args.verify_root {
+        verify_root(merkle.root_hash(), || merkle.recompute_root());
+    }
+
+    let record = BenchRecord {
+        leaf_size: Some(args.leaf_size),
+        ..BenchRecord::new(&args._type, "build", args.arity, Some(num_leaves))
+    };
+
+    record.measured(1, num_leaves, num_computations, duration)
+}
+
+// Asserts that the root maintained by the incremental updates matches the one recomputed from scratch
+// (see AbstractMerkle::recompute_root()), so that optimizing the updates cannot silently break them
+fn verify_root<HashType, Recompute>(root: HashType, recompute: Recompute)
+where
+    HashType: Debug + PartialEq,
+    Recompute: FnOnce() -> HashType,
+{
+    let start = Instant::now();
+    let recomputed = recompute();
+
+    assert_eq!(root, recomputed, "The root does not match the one recomputed from scratch");
+    println!("Verified the root against one recomputed from scratch in {:?}\n", start.elapsed());
+}
+
+fn bench_merkle<HashType, Hasher, Nodes>(
+    merkle: &mut AbstractMerkle<String, HashType, Hasher, NoLeafStorage, Nodes>,
+    num_leaves: usize,
+    args: &Args,
+    rng: &mut StdRng,
+) -> Vec<BenchRecord>
+where
+    HashType: CanonicalBytes + Clone + Debug + Default + PartialEq + Send + Sync,
+    Hasher: ParallelTreeHasherFunc<String, HashType>,
+    Nodes: NodeStore<HashType> + Sync,
+{
+    let num_updates = args.num_updates;
+
+    if args.build {
+        return vec![bench_build(merkle, num_leaves, args, rng)];
+    }
+
+    if let Some(path) = &args.load {
+        let start = Instant::now();
+        merkle.load(path).expect("Could not load tree");
+        println!("Loaded tree from {} in {:?}", path, start.elapsed());
+    }
+
+    assert_le!(num_updates, merkle.num_leaves());
+    if args.root_log.is_some() {
+        merkle.enable_root_log();
+    }
+
+    // NOTE: The first round updates an empty tree (unless it was loaded), whose parents are all empty,
+    // while later rounds update a "warm" tree. e.g., for Verkle, only the latter decompress parents.
+    let mut warm_durations = Vec::with_capacity(args.rounds);
+    let mut records = Vec::with_capacity(args.rounds);
+    let workload = Workload::new(num_leaves, args.leaf_distribution);
+    workload.check_batch_size(args.num_updates).unwrap_or_else(|err| panic!("{}", err));
+    let mut proven_leaves = Vec::new();
+    let template = BenchRecord {
+        leaf_size: Some(args.leaf_size),
+        leaf_distribution: Some(args.leaf_distribution.to_string()),
+        ..BenchRecord::new(&args._type, "update", args.arity, Some(num_leaves))
+    };
+    for round in 1..=args.rounds {
+        let updates = random_workload_updates(rng, &workload, num_updates, args.leaf_size);
+        let positions = updates.iter().map(|(pos, _)| *pos).collect::<Vec<usize>>();
+        let num_groups = num_sibling_groups(&positions, args.arity);
+        if round == args.rounds {
+            proven_leaves = updates.choose_multiple(rng, args.num_proofs).cloned().collect();
+        }
+        let num_computations = merkle.hasher.get_num_computations();
+        let stats = merkle.hasher.get_stats();
+
+        // NOTE: accounting for preprocessing does reduce time from 860us to 800us in a tree of 2^28 leaves with 200K updates
+        #[cfg(feature = "parallel")]
+        let (queue, pre_duration) = merkle.preprocess_leaves_parallel(updates);
+        #[cfg(not(feature = "parallel"))]
+        let (queue, pre_duration) = merkle.preprocess_leaves(updates);
+        let start = Instant::now();
+        #[cfg(feature = "parallel")]
+        merkle.update_preprocessed_leaves_parallel(queue);
+        #[cfg(not(feature = "parallel"))]
+        merkle.update_preprocessed_leaves(queue);
+        let duration = start.elapsed() + pre_duration;
+        merkle.log_root(num_updates);
+
+        let num_computations = merkle.hasher.get_num_computations() - num_computations;
+        let stats = merkle.hasher.get_stats() - stats;
+        let is_warm = round > 1 || args.load.is_some();
+        if is_warm {
+            warm_durations.push(duration);
+        }
+
+        records.push(BenchRecord {
+            num_sibling_groups: Some(num_groups),
+            ..template.measured(round, num_updates, num_computations, duration)
+        });
+
+        if args.rounds > 1 {
+            println!("Round {} of {} ({} tree):", round, args.rounds, if is_warm { "warm" } else { "cold" });
+        }
+
+        println!(
+            "Updated {} leaves in {:?}\n\
+             * Updates per second: {}\n\
+             * Sibling groups updated: {} ({:.2} updates per group)",
+            num_updates.separate_with_commas(),
+            duration,
+            (((num_updates as f64 / duration.as_micros() as f64) * 1000.0 * 1000.0) as usize)
+                .separate_with_commas(),
+            num_groups.separate_with_commas(),
+            num_updates as f64 / num_groups as f64
+        );
+
+        println!(
+            "Total hashes computed: {}\n\
+             * Hashes per second: {}\n\
+             * Time per hash: {:.2} us\n\
+             * Breakdown: {}\n",
+            num_computations.separate_with_commas(),
+            (((num_computations as f64 / duration.as_micros() as f64) * 1000.0 * 1000.0) as usize)
+                .separate_with_commas(),
+            duration.as_micros() as f64 / num_computations as f64,
+            stats
+        );
+    }
+
+    if args.rounds > 1 && !warm_durations.is_empty() {
+        let avg_duration = warm_durations.iter().sum::<Duration>() / warm_durations.len() as u32;
+
+        let mut round_latency = RunningAverage::new();
+        for duration in &warm_durations {
+            round_latency.add(duration.as_micros(), 1);
+        }
+
+        println!(
+            "Average over {} warm round(s): {:?} per round, {} updates per second\n\
+             * Latency per round: {}\n",
+            warm_durations.len(),
+            avg_duration,
+            (((num_updates as f64 / avg_duration.as_micros() as f64) * 1000.0 * 1000.0) as usize)
+                .separate_with_commas(),
+            round_latency
+        );
+    }
+
+    if !proven_leaves.is_empty() {
+        let (avg_proof_bytes, avg_verify_us) = bench_proofs(merkle, &proven_leaves);
+
+        records = records
+            .into_iter()
+            .map(|record| BenchRecord {
+                avg_proof_bytes: Some(avg_proof_bytes),
+                avg_verify_us: Some(avg_verify_us),
+                ..record
+            })
+            .collect();
+    }
+
+    if args.verify_root {
+        verify_root(merkle.root_hash(), || merkle.recompute_root());
+    }
+
+    if let Some(path) = &args.save {
+        let start = Instant::now();
+        merkle.save(path).expect("Could not save tree");
+        println!("Saved tree to {} in {:?}", path, start.elapsed());
+    }
+
+    if let Some(path) = &args.root_log {
+        let root_log = merkle.take_root_log().unwrap();
+        root_log.write_json(File::create(path).expect("Could not create root log")).expect("Could not write root log");
+        println!("Wrote the roots after all {} rounds to {}", root_log.len(), path);
+    }
+
+    records
+}
+
+// Proves each of the specified leaves on its own and verifies these proofs, returning the average
+// serialized proof size (in bytes) and verification time (in microseconds).
+fn bench_proofs<HashType, Hasher, Nodes>(
+    merkle: &mut AbstractMerkle<String, HashType, Hasher, NoLeafStorage, Nodes>,
+    leaves: &[(usize, String)],
+) -> (f64, f64)
+where
+    HashType: CanonicalBytes + Clone + Default + PartialEq,
+    Hasher: TreeHasherFunc<String, HashType>,
+    Nodes: NodeStore<HashType> + Sync,
+{
+    let root = merkle.root_hash();
+    let mut proof_bytes = 0;
+    let mut proof_hashes = 0;
+    let mut prove_duration = Duration::ZERO;
+    let mut verify_duration = Duration::ZERO;
+
+    for (pos, leaf_data) in leaves {
+        let start = Instant::now();
+        let proof = merkle.prove_batch(&[*pos]);
+        prove_duration += start.elapsed();
+
+        proof_bytes += proof.to_bytes().len();
+        proof_hashes += proof.len();
+
+        let start = Instant::now();
+        let is_valid = merkle.verify_batch(&root, &[(*pos, leaf_data.clone())], &proof);
+        verify_duration += start.elapsed();
+
+        assert!(is_valid, "Proof for leaf {} did not verify", pos);
+    }
+
+    let num_proofs = leaves.len() as f64;
+    let avg_proof_bytes = proof_bytes as f64 / num_proofs;
+    let avg_verify_us = verify_duration.as_micros() as f64 / num_proofs;
+
+    println!(
+        "Proved and verified {} leaves, one at a time\n\
+         * Average proof size: {:.2} bytes ({:.2} hashes)\n\
+         * Average time to prove: {:.2} us\n\
+         * Average time to verify: {:.2} us\n",
+        leaves.len(),
+        avg_proof_bytes,
+        proof_hashes as f64 / num_proofs,
+        prove_duration.as_micros() as f64 / num_proofs,
+        avg_verify_us
+    );
+
+    (avg_proof_bytes, avg_verify_us)
 }
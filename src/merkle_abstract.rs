@@ -1,20 +1,283 @@
-use std::collections::{HashSet, VecDeque};
+use std::collections::{BTreeSet, BinaryHeap, HashSet, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
 use std::marker::PhantomData;
+use std::ops::Range;
 use std::mem::size_of;
+use std::path::Path;
 use std::time::Duration;
 
-use more_asserts::{assert_le, debug_assert_le};
+use more_asserts::{assert_le, assert_lt, debug_assert_le};
+use tracing::{debug, debug_span, trace};
+
+use crate::{max_leaves, ProgressLog};
+use crate::node_index::{ChildBuffer, DynArity, NodeIndex, TreeArity};
+use crate::hashing_traits::{CanonicalBytes, ParallelTreeHasherFunc, TreeHasherFunc};
+use crate::leaf_storage::{LeafStorage, NoLeafStorage};
+use crate::node_store::{CowNodeStore, MemNodeStore, NodeStore, NodeStoreRef};
+use crate::prehash::{prehash_leaves, PrehashedLeaves};
+use crate::root_log::RootLog;
+use crate::update_tracer::{LogTracer, UpdateTracer};
+use crate::verify::{self, TreeShape};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+
+// A proof for a batch of leaves, consisting of all the hashes needed to recompute the root from
+// those leaves. Hashes shared by the paths of several leaves are only included once.
+//
+// NOTE: The hashes are stored in the order in which the verifier consumes them, which is fully
+// determined by the leaf positions being proven, so we need not also send their node indices.
+#[derive(Clone, Debug)]
+pub struct BatchProof<HashType> {
+    pub hashes: Vec<HashType>,
+}
+
+impl<HashType> BatchProof<HashType> {
+    // returns the number of hashes in the proof (i.e., its size, in hashes)
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hashes.is_empty()
+    }
+}
+
+// serialized as each hash's canonical serialization, prefixed by its length as a single byte (as in
+// AbstractMerkle::save())
+impl<HashType: CanonicalBytes> CanonicalBytes for BatchProof<HashType> {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for hash in &self.hashes {
+            let hash_bytes = hash.to_bytes();
+            assert_le!(hash_bytes.len(), u8::MAX as usize);
+
+            bytes.push(hash_bytes.len() as u8);
+            bytes.extend_from_slice(&hash_bytes);
+        }
+
+        bytes
+    }
+
+    fn from_bytes(mut bytes: &[u8]) -> Option<Self> {
+        let mut hashes = Vec::new();
+        while let Some((len, rest)) = bytes.split_first() {
+            let len = *len as usize;
+            if rest.len() < len {
+                return None;
+            }
+
+            hashes.push(HashType::from_bytes(&rest[..len])?);
+            bytes = &rest[len..];
+        }
+
+        Some(BatchProof { hashes })
+    }
+}
+
+// Some leaves (i.e., their positions and data), together with a proof for them
+pub type LeavesWithProof<LeafDataType, HashType> = (Vec<(usize, LeafDataType)>, BatchProof<HashType>);
+
+// A batch of updates whose leaves were hashed via AbstractMerkle::stage_updates(), but which was not
+// applied to the tree yet. Dropping it (e.g., via discard()) leaves the tree as is.
+pub struct StagedUpdate<LeafDataType, HashType> {
+    shape: TreeShape, // of the tree it was staged for
+    leaves: PrehashedLeaves<LeafDataType, HashType>,
+}
+
+impl<LeafDataType, HashType> StagedUpdate<LeafDataType, HashType> {
+    // returns the # of distinct leaves updated
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn discard(self) {}
+}
+
+// Why a transaction could not be started or ended (see AbstractMerkle::update_leaves_txn())
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxnError {
+    TxnInProgress,         // the previous transaction must be committed or rolled back first
+    NoTxn,                 // there is no transaction to commit or roll back
+    LeafOutOfRange(usize), // the position of a leaf which is not in the tree
+}
+
+impl Display for TxnError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TxnError::TxnInProgress => write!(f, "A transaction is already in progress"),
+            TxnError::NoTxn => write!(f, "No transaction is in progress"),
+            TxnError::LeafOutOfRange(leaf_pos) => write!(f, "Leaf position {} is out of range", leaf_pos),
+        }
+    }
+}
+
+impl std::error::Error for TxnError {}
+
+// What a transaction overwrote, so it can be rolled back: the old hash of every node on the path of an
+// updated leaf, the old data of every updated leaf, and the length of the root log (if any).
+struct UndoLog<LeafDataType, HashType> {
+    old_hashes: Vec<(NodeIndex, HashType)>,
+    old_leaves: Vec<(usize, Option<LeafDataType>)>,
+    root_log_len: usize,
+}
+
+// A read-only version of an AbstractMerkle, as returned by AbstractMerkle::snapshot(). It shares its
+// unchanged nodes with the tree (and with any other versions), so keeping many versions around only
+// costs memory for the nodes that changed in between them.
+//
+// NOTE: Proofs for a version can be verified via the tree's verify_batch(), against the version's root,
+// since verification only depends on the tree's shape.
+pub struct TreeVersion<HashType> {
+    arity: usize,
+    num_internal_nodes: usize,
+    num_leaves: usize,
+    nodes: CowNodeStore<HashType>,
+}
+
+impl<HashType: Default + Clone> TreeVersion<HashType> {
+    pub fn num_leaves(&self) -> usize {
+        self.num_leaves
+    }
+
+    // returns the root hash of the tree, as of this version
+    pub fn root_hash(&self) -> HashType {
+        self.nodes.get(NodeIndex::root_node().0).unwrap()
+    }
+
+    // returns the hash of the subtree rooted at 'node' as of this version, or None if there is no such node
+    pub fn subtree_root(&self, node: &NodeIndex) -> Option<HashType> {
+        self.nodes.get(node.0)
+    }
+
+    // same as AbstractMerkle::prove_batch(), but against this version of the tree
+    pub fn prove_batch(&self, positions: &[usize]) -> BatchProof<HashType> {
+        prove_batch_in(DynArity::from_usize(self.arity), self.num_internal_nodes, self.num_leaves, &self.nodes, positions)
+    }
+
+    // returns the underlying node storage, e.g., to see how much of it is shared with other versions
+    pub fn nodes(&self) -> &CowNodeStore<HashType> {
+        &self.nodes
+    }
+}
+
+// Returns a deduplicated proof for the leaves at the specified positions (in [0, n), where n is the #
+// of leaves) in a tree with the specified shape, whose nodes are in 'nodes'.
+//
+// We walk up from the leaves, always processing the node with the highest index next. Since a node's
+// descendants all have higher indices than it, by the time we process a node, all of its siblings that
+// the verifier can compute on its own have been computed. The rest of the siblings go in the proof.
+fn prove_batch_in<HashType, Nodes: NodeStore<HashType>, Arity: TreeArity>(
+    arity: Arity,
+    num_internal_nodes: usize,
+    num_leaves: usize,
+    nodes: &Nodes,
+    positions: &[usize],
+) -> BatchProof<HashType> {
+    let mut hashes = Vec::new();
+    let mut known: HashSet<NodeIndex> = HashSet::new();
+    let mut heap: BinaryHeap<usize> = BinaryHeap::new();
+
+    for pos in positions {
+        assert_lt!(*pos, num_leaves);
+
+        let leaf_idx = NodeIndex(num_internal_nodes + *pos);
+        if known.insert(leaf_idx) {
+            heap.push(leaf_idx.0);
+        }
+    }
+
+    while let Some(idx) = heap.pop() {
+        let node = NodeIndex(idx);
+        if node.is_root() {
+            break;
+        }
+
+        // if the parent is known, it was already processed via a sibling with a higher index
+        let parent_idx = node.parent(arity.get());
+        if !known.insert(parent_idx) {
+            continue;
+        }
+
+        let children = (0..arity.get())
+            .map(|i| parent_idx.child(arity.get(), i))
+            .take_while(|child_idx| child_idx.0 < nodes.len());
+
+        for child_idx in children {
+            if !known.contains(&child_idx) {
+                hashes.push(nodes.get(child_idx.0).unwrap());
+            }
+        }
+
+        heap.push(parent_idx.0);
+    }
+
+    BatchProof { hashes }
+}
+
+// For each level, the parent currently being updated on the level above, if any, and its updated
+// children (see update_leaves_streaming())
+type PendingParents<HashType, Arity> = Vec<Option<(NodeIndex, <Arity as TreeArity>::Buffer<(usize, HashType)>)>>;
+
+// Sorts the updates by leaf position (or key) and merges the updates to the same leaf into a single
+// one via 'combine(earlier_data, later_data)', so that the result can be passed to update_leaves().
+//
+// e.g., to have the last update to a leaf win, use |_, later| later
+pub fn normalize_updates<Key, LeafDataType, Combine>(
+    mut updates: Vec<(Key, LeafDataType)>,
+    mut combine: Combine,
+) -> Vec<(Key, LeafDataType)>
+where
+    Key: Ord,
+    Combine: FnMut(LeafDataType, LeafDataType) -> LeafDataType,
+{
+    // NOTE: The sort is stable, so updates to the same leaf remain in the order they were given in.
+    // It is also linear on already-sorted updates, which is the common case.
+    updates.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut merged: Vec<(Key, LeafDataType)> = Vec::with_capacity(updates.len());
+    for (key, leaf_data) in updates {
+        if matches!(merged.last(), Some((last_key, _)) if *last_key == key) {
+            let (key, earlier_data) = merged.pop().unwrap();
+
+            merged.push((key, combine(earlier_data, leaf_data)));
+        } else {
+            merged.push((key, leaf_data));
+        }
+    }
 
-use crate::max_leaves;
-use crate::node_index::NodeIndex;
-use crate::hashing_traits::TreeHasherFunc;
+    merged
+}
+
+// Returns the arity and # of leaves of the tree saved at 'path' via AbstractMerkle::save(), so that a
+// tree of the right shape can be created to load() it into.
+pub fn saved_shape<P: AsRef<Path>>(path: P) -> std::io::Result<(usize, usize)> {
+    let reader = BufReader::new(File::open(path)?);
+
+    let (arity, num_leaves, _num_nodes): (usize, usize, usize) = bincode::deserialize_from(reader)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    Ok((arity, num_leaves))
+}
 
 // An abstract, "perfect" Merkle tree of arity k and height h, where "perfect" means the tree has
 // *exactly* k^h leaves
-pub struct AbstractMerkle<LeafDataType, HashType, Hasher> {
+pub struct AbstractMerkle<
+    LeafDataType,
+    HashType,
+    Hasher,
+    Storage = NoLeafStorage,
+    Nodes = MemNodeStore<HashType>,
+    Arity = DynArity,
+> {
     //perfect: bool, // set to true when the # of leaves == arity^height
 
-    arity: usize, // the tree's arity
+    arity: Arity, // the tree's arity, which can be fixed at compile time (see ConstArity)
 
     //height: usize, // tree height, including the last level which might not be completely filled
 
@@ -32,7 +295,9 @@ pub struct AbstractMerkle<LeafDataType, HashType, Hasher> {
     //                              0
     //    1             2                3               4
     // 5 6 7 8      9 10 11 12      13 14 15 16     17 18 19 20
-    nodes: Vec<HashType>,
+    //
+    // NOTE: The array itself lives in a NodeStore (in memory, by default).
+    nodes: Nodes,
 
     // The function used to update parent hashes when some (or all) of the k children change.
     // Recall that in an incremental Merkle (but not Verkle) tree, the hash of the parent when the
@@ -52,8 +317,12 @@ pub struct AbstractMerkle<LeafDataType, HashType, Hasher> {
     // recompute the parent from scratch.
     pub hasher: Hasher,
 
+    // Where the leaves' data is stored, if anywhere (by default, it is not stored; only the leaves'
+    // hashes are).
+    leaf_storage: Storage,
+
     // Rust is weird.
-    phantom: PhantomData<LeafDataType>,
+    phantom: PhantomData<(LeafDataType, HashType)>,
 
     // This is the node index of the first leaf on the last level h of the tree, since in some cases
     // we might also have leaves on level h-1.
@@ -63,134 +332,423 @@ pub struct AbstractMerkle<LeafDataType, HashType, Hasher> {
 
     // This is a debugging tool: we use it to make sure we never compute a node's hash twice
     _hashed_nodes: HashSet<NodeIndex>,
+
+    // The hashes (and leaf data) overwritten by the open transaction, if any (see update_leaves_txn())
+    undo_log: Option<UndoLog<LeafDataType, HashType>>,
+
+    // The root after every batch of updates, if enabled (see enable_root_log())
+    root_log: Option<RootLog<HashType>>,
 }
 
-impl<LeafDataType, HashType, Hasher> AbstractMerkle<LeafDataType, HashType, Hasher>
+impl<LeafDataType, HashType, Hasher, Storage, Nodes, Arity>
+    AbstractMerkle<LeafDataType, HashType, Hasher, Storage, Nodes, Arity>
     where
         LeafDataType: Clone,
         HashType: Default + Clone,
         Hasher: TreeHasherFunc<LeafDataType, HashType>,
+        Storage: LeafStorage<LeafDataType> + Default,
+        Nodes: NodeStore<HashType>,
+        Arity: TreeArity,
 {
-    pub fn with_num_leaves(arity: usize, num_leaves: usize, hasher: Hasher) -> Self {
-        dbg!(size_of::<HashType>());
-
-        let mut height: usize = 0;
-        let mut n = num_leaves;
-
-        // compute the tree's height
-        while n / arity > 0 {
-            height += 1;
-
-            n /= arity;
-        }
+    pub fn with_num_leaves(arity: usize, num_leaves: usize, hasher: Hasher) -> Self
+    where
+        Nodes: Default,
+    {
+        AbstractMerkle::with_node_store(arity, num_leaves, hasher, Nodes::default())
+    }
 
+    // Like with_num_leaves(), but keeps the tree's nodes in 'nodes', whose previous contents are
+    // discarded.
+    pub fn with_node_store(arity: usize, num_leaves: usize, hasher: Hasher, mut nodes: Nodes) -> Self {
         // We need to handle case where leaves do *not* fully fit on last level. For example, when
-        // arity = 3 and num_leaves = 10, we'll get n / arity = 10 / 3 > 0 => { height = 1, n = 3 }
-        // Then, 3 / 3 > 0 => { height = 2, n = 1 }. Then, 1 / 3 = 0, so height stays 2. However,
+        // arity = 3 and num_leaves = 10, the tallest perfect tree has height 2, with 9 leaves. However,
         // this tree has height 3: 1 root, 3 children, 9 children and the last level could have all
-        // the 10 leaves or they might be split amongst this last level and the second-to-last. So
-        // we need to decide how to handle this. How about this:
+        // the 10 leaves or they might be split amongst this last level and the second-to-last.
         //
         // If all leafs fit on the last level, we are done. Otherwise, we split the leaves across
         // level 'height' and 'height+1' as per https://hackmd.io/54A_Zk58SHqxpBQwwfx0Cg
-        let max_leaves = max_leaves(arity, height);
-        let mut num_internal_nodes = (max_leaves - 1) / (arity - 1);
-        let mut total_nodes = num_internal_nodes + num_leaves;
-        //let mut perfect = true;
-        let mut first_last_level_leaf = NodeIndex(num_internal_nodes);
-
-        if num_leaves > max_leaves {
-            //perfect = false;
-            let last_level_max_size = max_leaves * arity;
-            let num_last: usize;
-            let num_second_to_last: usize;
-            if last_level_max_size - num_leaves >= arity {
-                let mut epsilon = arity;
-                let r_num_f = |e: usize| {
-                    // dbg!(last_level_max_size);
-                    // dbg!(num_leaves);
-                    // dbg!(arity);
-                    // dbg!(e);
-
-                    last_level_max_size - num_leaves - (arity - e)
-                };
-                let mut r_num = r_num_f(epsilon);
-                let r_denom = arity - 1;
-
-                while r_num % r_denom != 0 {
-                    epsilon -= 1;
-                    if epsilon == 0 {
-                        panic!("Alin math fail: epsilon was supposed to stay in [1, arity]");
-                    }
-                    r_num = r_num_f(epsilon);
-                }
-                assert_eq!(r_num % r_denom, 0);
-
-                num_second_to_last = r_num / r_denom;
-                num_last = (max_leaves - num_second_to_last - 1) * arity + epsilon;
-            } else {
-                num_second_to_last = 0;
-                num_last = num_leaves;
-            }
-
-            assert_eq!(num_second_to_last + num_last, num_leaves);
-
-            // dbg!(num_second_to_last);
-            // dbg!(num_last);
-
-            //height += 1;
-            num_internal_nodes = (max_leaves - 1) / (arity - 1) + max_leaves - num_second_to_last; // because the last R nodes on level 'h' are leaves
-            total_nodes = num_internal_nodes + num_leaves; // by definition
-            first_last_level_leaf = NodeIndex(num_internal_nodes + num_second_to_last);
+        //
+        // NOTE: The math lives in the no_std verify crate, since verifiers need the tree's shape too.
+        let shape = TreeShape::new(arity, num_leaves);
+        let num_internal_nodes = shape.num_internal_nodes;
+        let total_nodes = shape.num_nodes();
+        let first_last_level_leaf = NodeIndex(shape.first_last_level_leaf());
+
+        if shape.num_second_to_last > 0 {
+            debug!(
+                num_second_to_last = shape.num_second_to_last,
+                num_last = num_leaves - shape.num_second_to_last,
+                "Leaves are split across the last two levels"
+            );
         } else {
-            println!("Leaves perfectly fit on last level!");
+            debug!("Leaves perfectly fit on last level!");
         }
 
-        // dbg!(height);
-        // dbg!(max_leaves);
-        // dbg!(num_internal_nodes);
-        // dbg!(first_last_level_leaf);
-        //
-        // println!(
-        //     "arity {}, height {}, # leaves {}, internal nodes {}, total nodes {}",
-        //     arity, height, num_leaves, num_internal_nodes, total_nodes
-        // );
+        debug!(
+            arity,
+            num_leaves,
+            num_internal_nodes,
+            total_nodes,
+            first_last_level_leaf = first_last_level_leaf.0,
+            node_size = size_of::<HashType>(),
+            "Allocating tree"
+        );
+
+        let progress = ProgressLog::new("Allocating nodes", Some(total_nodes));
+        nodes.allocate(total_nodes);
+        progress.finish();
 
         AbstractMerkle {
             //perfect,
-            arity,
+            arity: Arity::from_usize(arity),
             //height,
             num_internal_nodes,
             num_leaves,
-            nodes: vec![HashType::default(); total_nodes],
+            nodes,
             hasher,
+            leaf_storage: Storage::default(),
             phantom: Default::default(),
             first_last_level_leaf,
             _hashed_nodes: HashSet::new(),
+            undo_log: None,
+            root_log: None,
         }
     }
 
-    pub fn new(arity: usize, height: usize, hasher: Hasher) -> Self {
+    pub fn new(arity: usize, height: usize, hasher: Hasher) -> Self
+    where
+        Nodes: Default,
+    {
         AbstractMerkle::with_num_leaves(arity, max_leaves(arity, height), hasher)
     }
 
+    pub fn arity(&self) -> usize {
+        self.arity.get()
+    }
+
     pub fn num_leaves(&self) -> usize {
         self.num_leaves
     }
 
+    // returns the # of nodes (internal and leaves) in the tree
+    pub fn num_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    // returns the tree's shape, which is all a verifier needs to know about it (see verify::verify_batch())
+    pub fn shape(&self) -> TreeShape {
+        TreeShape {
+            arity: self.arity(),
+            num_leaves: self.num_leaves,
+            num_internal_nodes: self.num_internal_nodes,
+            num_second_to_last: self.first_last_level_leaf.0 - self.num_internal_nodes,
+        }
+    }
+
+    // Returns the same tree, but storing its leaves' data in 'leaf_storage' from now on.
+    //
+    // NOTE: Leaves set before this call are not moved over to 'leaf_storage', so call it on a fresh
+    // tree.
+    pub fn with_leaf_storage<NewStorage>(
+        self,
+        leaf_storage: NewStorage,
+    ) -> AbstractMerkle<LeafDataType, HashType, Hasher, NewStorage, Nodes, Arity> {
+        AbstractMerkle {
+            arity: self.arity,
+            num_internal_nodes: self.num_internal_nodes,
+            num_leaves: self.num_leaves,
+            nodes: self.nodes,
+            hasher: self.hasher,
+            leaf_storage,
+            phantom: Default::default(),
+            first_last_level_leaf: self.first_last_level_leaf,
+            _hashed_nodes: self._hashed_nodes,
+            undo_log: self.undo_log,
+            root_log: self.root_log,
+        }
+    }
+
+    // returns the data of the leaf at position 'leaf_pos' (in [0, n), where n is the # of leaves), if
+    // the tree stores its leaves' data and this leaf was ever set
+    pub fn get_leaf(&self, leaf_pos: usize) -> Option<LeafDataType> {
+        assert_lt!(leaf_pos, self.num_leaves);
+
+        self.leaf_storage.get(leaf_pos)
+    }
+
+    // Saves the tree's nodes to 'path', so that they can be loaded back later via load() instead of
+    // being recomputed.
+    //
+    // The file starts with the bincode-serialized (arity, # of leaves, # of nodes), followed by each
+    // node's canonical serialization, prefixed by its length as a single byte.
+    //
+    // NOTE: The leaves' data (if stored) and the hasher's state are not saved.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()>
+    where
+        HashType: CanonicalBytes,
+    {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        bincode::serialize_into(&mut writer, &(self.arity(), self.num_leaves, self.nodes.len()))
+            .map_err(std::io::Error::other)?;
+
+        for idx in 0..self.nodes.len() {
+            let bytes = self.nodes.get(idx).unwrap().to_bytes();
+            assert_le!(bytes.len(), u8::MAX as usize);
+
+            writer.write_all(&[bytes.len() as u8])?;
+            writer.write_all(&bytes)?;
+        }
+
+        writer.flush()
+    }
+
+    // Replaces this tree's nodes with the ones saved at 'path' via save(). The saved tree must have the
+    // same arity and # of leaves as this one.
+    //
+    // NOTE: We load into an existing tree, rather than creating a new one, since hashers cannot be
+    // saved. As a result, the caller must make sure this tree's hasher matches the saved tree's (e.g.,
+    // a Verkle tree must use the same bases), or else future updates will be inconsistent.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> std::io::Result<()>
+    where
+        HashType: CanonicalBytes,
+    {
+        let invalid_data = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_owned());
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let (arity, num_leaves, num_nodes): (usize, usize, usize) = bincode::deserialize_from(&mut reader)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        if arity != self.arity() || num_leaves != self.num_leaves || num_nodes != self.nodes.len() {
+            return Err(invalid_data("saved tree has a different shape"));
+        }
+
+        let mut len = [0u8; 1];
+        let mut bytes = vec![0u8; u8::MAX as usize];
+        for idx in 0..num_nodes {
+            reader.read_exact(&mut len)?;
+
+            let bytes = &mut bytes[..len[0] as usize];
+            reader.read_exact(bytes)?;
+
+            let hash = HashType::from_bytes(bytes).ok_or_else(|| invalid_data("invalid node hash"))?;
+            self.nodes.set(idx, hash);
+        }
+
+        Ok(())
+    }
+
+    // returns the current root hash of the tree
+    pub fn root_hash(&self) -> HashType {
+        self.get_node_hash(&NodeIndex::root_node()).unwrap()
+    }
+
+    // starts logging the root after every batch of updates (see RootLog), unless it already is
+    pub fn enable_root_log(&mut self) {
+        self.root_log.get_or_insert_with(RootLog::new);
+    }
+
+    pub fn root_log(&self) -> Option<&RootLog<HashType>> {
+        self.root_log.as_ref()
+    }
+
+    // stops logging the root, and returns the log so far
+    pub fn take_root_log(&mut self) -> Option<RootLog<HashType>> {
+        self.root_log.take()
+    }
+
+    // Logs the current root, if the root log is enabled. Every update function does so after each batch,
+    // except for update_preprocessed_leaves() (and its parallel version), which does not know how many
+    // updates it was given, so callers who split updates that way must call this themselves.
+    pub fn log_root(&mut self, num_updates: usize) {
+        if let Some(root_log) = &mut self.root_log {
+            root_log.record(self.nodes.get(NodeIndex::root_node().0).unwrap(), num_updates);
+        }
+    }
+
+    // returns the hash of the subtree rooted at 'node', or None if the tree has no such node
+    pub fn subtree_root(&self, node: &NodeIndex) -> Option<HashType> {
+        self.get_node_hash(node)
+    }
+
+    // returns the NodeIndex of every node on the given level, from left to right (the root is at
+    // level 0); the last level might be only partially filled, or empty if the tree is not that tall
+    pub fn level_nodes(&self, level: usize) -> Vec<NodeIndex> {
+        self.level_range(level).map(NodeIndex).collect()
+    }
+
+    // returns the range of indices of the nodes on the given level (see level_nodes())
+    fn level_range(&self, level: usize) -> Range<usize> {
+        let first = NodeIndex::first_on_level(self.arity(), level).map_or(usize::MAX, |node| node.0);
+        let end = level.checked_add(1)
+            .and_then(|next| NodeIndex::first_on_level(self.arity(), next))
+            .map_or(usize::MAX, |node| node.0);
+
+        first.min(self.num_nodes())..end.min(self.num_nodes())
+    }
+
+    // Returns every node on the given level along with its hash, from left to right (see level_nodes()).
+    // Unlike level_roots(), the hashes are lent out rather than cloned, so the node store must allow it.
+    pub fn iter_level(&self, level: usize) -> impl Iterator<Item = (NodeIndex, &HashType)> + '_
+    where
+        Nodes: NodeStoreRef<HashType>,
+    {
+        self.iter_range(self.level_range(level))
+    }
+
+    // Returns every leaf along with its hash, by increasing leaf position (i.e., the leaves on the
+    // second-to-last level, if any, come first), so the ith item is the leaf at position i.
+    pub fn iter_leaves(&self) -> impl Iterator<Item = (NodeIndex, &HashType)> + '_
+    where
+        Nodes: NodeStoreRef<HashType>,
+    {
+        self.iter_range(self.num_internal_nodes..self.num_nodes())
+    }
+
+    // Returns every node (internal and leaves) along with its hash, in level order, starting at the
+    // root; e.g., to dump or export the whole tree.
+    pub fn iter_nodes(&self) -> impl Iterator<Item = (NodeIndex, &HashType)> + '_
+    where
+        Nodes: NodeStoreRef<HashType>,
+    {
+        self.iter_range(0..self.num_nodes())
+    }
+
+    fn iter_range(&self, range: Range<usize>) -> impl Iterator<Item = (NodeIndex, &HashType)> + '_
+    where
+        Nodes: NodeStoreRef<HashType>,
+    {
+        range.map(move |idx| (NodeIndex(idx), self.nodes.get_ref(idx).unwrap()))
+    }
+
+    // Writes a Graphviz graph of the top 'max_depth' + 1 levels of the tree (e.g., for `dot -Tsvg`),
+    // where every node is labeled by its NodeIndex (and leaves also by their position) and the first
+    // few bytes of its hash. Internal nodes whose children are cut off are dashed.
+    //
+    // NOTE: Meant for eyeballing small trees (e.g., where the leaves on two levels end up), since
+    // every node up to 'max_depth' is written out.
+    pub fn to_dot<W: Write>(&self, writer: &mut W, max_depth: usize) -> std::io::Result<()>
+    where
+        HashType: CanonicalBytes,
+    {
+        const HASH_PREFIX_BYTES: usize = 4;
+
+        writeln!(writer, "digraph merkle {{")?;
+        writeln!(writer, "    node [fontname=\"monospace\"];")?;
+
+        for level in 0..=max_depth {
+            let range = self.level_range(level);
+            if range.is_empty() {
+                break;
+            }
+
+            for node in range.map(NodeIndex) {
+                let hash = self.get_node_hash(&node).unwrap().to_bytes();
+                let hash = hex::encode(&hash[..hash.len().min(HASH_PREFIX_BYTES)]);
+
+                if self.is_leaf(&node) {
+                    let pos = self.get_leaf_pos(&node);
+                    writeln!(writer, "    n{} [shape=box, label=\"{} (leaf {})\\n{}\"];", node.0, node.0, pos, hash)?;
+                } else {
+                    let style = if level == max_depth { ", style=dashed" } else { "" };
+                    writeln!(writer, "    n{} [label=\"{}\\n{}\"{}];", node.0, node.0, hash, style)?;
+                }
+
+                if !node.is_root() {
+                    writeln!(writer, "    n{} -> n{};", self.parent_node(&node).0, node.0)?;
+                }
+            }
+        }
+
+        writeln!(writer, "}}")
+    }
+
+    // returns the roots of all subtrees on the given level, from left to right (e.g., level 1 gives
+    // the 'arity' shards right below the root), so that two trees can be compared shard by shard
+    pub fn level_roots(&self, level: usize) -> Vec<HashType> {
+        self.level_nodes(level)
+            .iter()
+            .map(|node| self.get_node_hash(node).unwrap())
+            .collect()
+    }
+
+    // Walks both trees top-down, only descending into subtrees whose roots differ, and returns the
+    // NodeIndex of every node that differs between the two, level by level (starting with the root's),
+    // from left to right. Useful for syncing one tree with another by fetching whole differing subtrees
+    // from whichever level is cheapest (e.g., the first level where only a few nodes differ).
+    //
+    // NOTE: Both trees must have the same shape (i.e., the same arity and # of leaves).
+    pub fn diff(&self, other: &Self) -> Vec<Vec<NodeIndex>>
+    where
+        HashType: PartialEq,
+    {
+        assert_eq!(self.arity(), other.arity(), "Cannot diff trees of different arities");
+        assert_eq!(self.num_leaves, other.num_leaves, "Cannot diff trees with a different # of leaves");
+
+        let mut levels = vec![];
+        let mut curr_level = vec![NodeIndex::root_node()];
+
+        while !curr_level.is_empty() {
+            let differing = curr_level
+                .into_iter()
+                .filter(|node| self.get_node_hash(node) != other.get_node_hash(node))
+                .collect::<Vec<NodeIndex>>();
+            if differing.is_empty() {
+                break;
+            }
+
+            // the children of nodes from left to right are themselves from left to right
+            curr_level = differing
+                .iter()
+                .filter(|node| !self.is_leaf(node))
+                .flat_map(|node| self.children(node))
+                .collect();
+            levels.push(differing);
+        }
+
+        levels
+    }
+
+    // Like diff(), but only returns the positions (in [0, n)) of the leaves that differ, in increasing order
+    pub fn diff_leaves(&self, other: &Self) -> Vec<usize>
+    where
+        HashType: PartialEq,
+    {
+        // NOTE: Leaves on the second-to-last level come after the ones on the last level
+        let mut differing = self
+            .diff(other)
+            .into_iter()
+            .flatten()
+            .filter(|node| self.is_leaf(node))
+            .map(|leaf| self.get_leaf_pos(&leaf))
+            .collect::<Vec<usize>>();
+        differing.sort_unstable();
+        differing
+    }
+
     // returns this node's child offset; i.e., its position i relative to its parent, where i \in [0, arity)
-    fn child_offset(&self, node: &NodeIndex) -> usize {
-        node.child_offset(self.arity)
+    pub(crate) fn child_offset(&self, node: &NodeIndex) -> usize {
+        node.child_offset(self.arity())
     }
 
     // returns the parent's NodeIndex
-    fn parent_node(&self, node: &NodeIndex) -> NodeIndex {
-        node.parent(self.arity)
+    pub(crate) fn parent_node(&self, node: &NodeIndex) -> NodeIndex {
+        node.parent(self.arity())
     }
 
     // returns the NodeIndex of the ith child, where i \in [0, arity)
     fn child_node(&self, node: &NodeIndex, i: usize) -> NodeIndex {
-        node.child(self.arity, i)
+        node.child(self.arity(), i)
+    }
+
+    // returns the NodeIndex of every child this node actually has; a parent on the second-to-last
+    // level might have less than 'arity' children
+    pub(crate) fn children(&self, node: &NodeIndex) -> impl Iterator<Item = NodeIndex> + '_ {
+        let node = *node;
+
+        (0..self.arity())
+            .map(move |i| self.child_node(&node, i))
+            .take_while(|child_idx| child_idx.0 < self.nodes.len())
     }
 
     // returns true if this node is a sibling of 'other'
@@ -198,28 +756,20 @@ impl<LeafDataType, HashType, Hasher> AbstractMerkle<LeafDataType, HashType, Hash
     //     first.is_sibling(self.arity, second)
     // }
 
-    fn get_node_hash(&self, node: &NodeIndex) -> Option<HashType> {
-        self.nodes.get(node.0).cloned()
+    pub(crate) fn get_node_hash(&self, node: &NodeIndex) -> Option<HashType> {
+        self.nodes.get(node.0)
     }
 
     fn set_node_hash(&mut self, node: &NodeIndex, hash: HashType) {
-        self.nodes[node.0] = hash;
+        self.nodes.set(node.0, hash);
     }
 
     fn is_leaf(&self, node: &NodeIndex) -> bool {
-        node.0 > self.num_internal_nodes - 1
+        node.0 >= self.num_internal_nodes
     }
 
     fn get_node_height(&self, node: &NodeIndex) -> usize {
-        let mut curr = *node;
-        let mut h = 0;
-
-        while !curr.is_root() {
-            curr = self.parent_node(&curr);
-            h += 1
-        }
-
-        h
+        node.level(self.arity())
     }
 
     pub fn has_leaves_on_two_levels(&self) -> bool {
@@ -248,48 +798,29 @@ impl<LeafDataType, HashType, Hasher> AbstractMerkle<LeafDataType, HashType, Hash
     //
     // As a result, numbering starts with the leaves at level h+1, and then goes to the leaves at level
     // h. This is more complicated but avoids the problem mentioned above!
-    fn get_leaf_idx(&self, leaf_pos: usize) -> NodeIndex {
+    pub fn get_leaf_idx(&self, leaf_pos: usize) -> NodeIndex {
         // NOTE: If the tree were "perfect" (i.e., exactly arity^height leaves), then the first leaf
         // would be at index (self.num_leaves() - 1) / (self.arity - 1)
 
         NodeIndex(self.num_internal_nodes + leaf_pos)
     }
 
-    #[allow(dead_code)]
-    fn get_leaf_pos(&self, leaf_idx: &NodeIndex) -> usize {
+    pub fn get_leaf_pos(&self, leaf_idx: &NodeIndex) -> usize {
         leaf_idx.0 - self.num_internal_nodes
     }
 
-    fn pop_sibling(
+    fn pop_sibling<Tracer: UpdateTracer<HashType>>(
         self: &mut Self,
         queue: &mut VecDeque<(NodeIndex, HashType)>,
-        siblings: &mut Vec<(usize, HashType)>,
+        siblings: &mut Arity::Buffer<(usize, HashType)>,
+        tracer: &mut Tracer,
     ) -> NodeIndex {
         let sib = queue.pop_front().unwrap();
         let sib_idx = sib.0;
         let sib_offset = self.child_offset(&sib_idx);
 
-        // NOTE: Uncomment for debugging
-        if !sib_idx.is_root() {
-            if !self.is_leaf(&sib_idx) {
-                // println!(
-                //     "Dequeing new children {} (offset {}) of parent {}",
-                //     sib_idx.0,
-                //     sib_offset,
-                //     self.parent_node(&sib_idx).0
-                // );
-            } else {
-                // println!(
-                //     "Dequeing new leaf {} (offset {}, leaf #{}) of parent {}",
-                //     sib_idx.0,
-                //     sib_offset,
-                //     self.get_leaf_pos(&sib_idx),
-                //     self.parent_node(&sib_idx).0
-                // );
-            }
-        } else {
-            // println!("Dequeing new root {}", sib_idx.0);
-        }
+        let leaf_pos = self.is_leaf(&sib_idx).then(|| self.get_leaf_pos(&sib_idx));
+        tracer.dequeue(sib_idx, leaf_pos, &sib.1);
 
         siblings.push((sib_offset, sib.1));
 
@@ -298,16 +829,43 @@ impl<LeafDataType, HashType, Hasher> AbstractMerkle<LeafDataType, HashType, Hash
     }
 
     // TODO: Generate the leaf data here pseudo-randomly: e.g., for strings "abcdef|" + leaf_no
+    //
+    // The updates need not be sorted and, if a leaf is updated more than once, the last update wins.
     pub fn preprocess_leaves(
         &mut self,
         updates: Vec<(usize, LeafDataType)>,
     ) -> (VecDeque<(NodeIndex, HashType)>, Duration) {
+        let updates = normalize_updates(updates, |_, later| later);
+        self._store_leaves(&updates);
+
+        let mut tracer = LogTracer::new(self.arity());
+        self._preprocess_leaves(updates, Self::_queuefy, &mut tracer)
+    }
+
+    fn _store_leaves(&mut self, updates: &[(usize, LeafDataType)]) {
+        for (leaf_pos, leaf_data) in updates {
+            self.leaf_storage.set(*leaf_pos, leaf_data);
+        }
+    }
+
+    // 'queuefy' computes the new hashes of the updated leaves and returns them as a queue of updates
+    fn _preprocess_leaves<Update, Queuefy, Tracer>(
+        &mut self,
+        updates: Vec<(usize, Update)>,
+        queuefy: Queuefy,
+        tracer: &mut Tracer,
+    ) -> (VecDeque<(NodeIndex, HashType)>, Duration)
+    where
+        Queuefy: Fn(&mut Self, &[(usize, Update)]) -> VecDeque<(NodeIndex, HashType)>,
+        Tracer: UpdateTracer<HashType>,
+    {
         // clear the map of nodes we hashed
         self._hashed_nodes.clear();
+        let progress = ProgressLog::new("Preprocessing leaves", Some(updates.len()));
 
         // Assert that leaf updates are sorted by index
         // NOTE: debug_assert_* calls are disabled for benchmarks!
-        debug_assert!((0..updates.len() - 1).all(|i| updates[i].0 <= updates[i + 1].0));
+        debug_assert!(updates.windows(2).all(|w| w[0].0 <= w[1].0));
 
         let mut upd_queue: VecDeque<(NodeIndex, HashType)> = VecDeque::new();
 
@@ -316,7 +874,7 @@ impl<LeafDataType, HashType, Hasher> AbstractMerkle<LeafDataType, HashType, Hash
         // Otherwise, we need to skip over the second-to-last level leaves and find it in 'updates'
         // TODO: I think this can be simplified a little
         if self.has_leaves_on_two_levels() {
-            // println!("Has two levels of leaves");
+            trace!("Has two levels of leaves");
             let opt = updates
                 .iter()
                 .position(|(leaf_pos, _)| {
@@ -324,8 +882,7 @@ impl<LeafDataType, HashType, Hasher> AbstractMerkle<LeafDataType, HashType, Hash
                 });
 
             let first_last_level_leaf = opt.unwrap_or(0);
-            // println!("updates[{}] = {} is the first last level leaf", first_last_level_leaf,
-            //          updates[first_last_level_leaf].0);
+            trace!("updates[{}] is the first last level leaf", first_last_level_leaf);
 
             let (second_to_last, last) = updates.split_at(first_last_level_leaf);
 
@@ -333,20 +890,22 @@ impl<LeafDataType, HashType, Hasher> AbstractMerkle<LeafDataType, HashType, Hash
 
             // If there are leaves on the second-to-last level, we need to compute all updated parents
             // of the updated last level leaves, so that all updates are on the second-to-last level
-            let mut tmp_queue: VecDeque<(NodeIndex, HashType)> = self._queuefy(last);
-            self._process_update_queue(&mut tmp_queue, Some(&mut upd_queue));
+            let mut tmp_queue: VecDeque<(NodeIndex, HashType)> = queuefy(self, last);
+            self._process_update_queue(&mut tmp_queue, Some(&mut upd_queue), tracer);
 
             let duration = start.elapsed();
 
             // remove the last level leaves from 'updates' so we can move the remaining second-to-last
             // level leaves into the update queue
-            upd_queue.append(&mut self._queuefy(second_to_last));
-            // println!("Done pre-processing last level of leaves")
+            upd_queue.append(&mut queuefy(self, second_to_last));
+            trace!("Done pre-processing last level of leaves");
+            progress.finish();
 
             (upd_queue, duration)
         } else {
-            upd_queue.append(&mut self._queuefy(updates.as_slice()));
-            // println!("Does NOT have two levels of leaves");
+            upd_queue.append(&mut queuefy(self, updates.as_slice()));
+            trace!("Does NOT have two levels of leaves");
+            progress.finish();
 
             (upd_queue, Duration::ZERO)
         }
@@ -364,9 +923,8 @@ impl<LeafDataType, HashType, Hasher> AbstractMerkle<LeafDataType, HashType, Hash
                 let leaf_idx = self.get_leaf_idx(*leaf_pos);
                 let child_offset: usize = self.child_offset(&leaf_idx);
 
-                // NOTE: Uncomment for debugging
                 debug_assert!(self.is_leaf(&leaf_idx));
-                //println!("Hashing and queueing leaf idx {} (leaf #{})", leaf_idx.0, *leaf_pos);
+                trace!("Hashing and queueing leaf idx {} (leaf #{})", leaf_idx.0, *leaf_pos);
                 debug_assert!(self._hashed_nodes.insert(leaf_idx));
 
                 (
@@ -377,79 +935,486 @@ impl<LeafDataType, HashType, Hasher> AbstractMerkle<LeafDataType, HashType, Hash
             .collect::<VecDeque<_>>()
     }
 
-    pub fn update_leaves(&mut self, new_leaves: Vec<(usize, LeafDataType)>) {
-        // takes care of cases where the leaves are split amongst the last and second to last level
-        let (mut curr_updates, _) = self.preprocess_leaves(new_leaves);
+    fn _queuefy_deletions(&mut self, dels: &[(usize, ())]) -> VecDeque<(NodeIndex, HashType)> {
+        dels.iter()
+            .map(|(leaf_pos, _)| {
+                let leaf_idx = self.get_leaf_idx(*leaf_pos);
+
+                debug_assert!(self.is_leaf(&leaf_idx));
+                debug_assert!(self._hashed_nodes.insert(leaf_idx));
 
-        self._process_update_queue(&mut curr_updates, None);
+                (leaf_idx, HashType::default())
+            })
+            .collect::<VecDeque<_>>()
     }
 
-    pub fn update_preprocessed_leaves(&mut self, mut curr_updates: VecDeque<(NodeIndex, HashType)>) {
+    fn _queuefy_prehashed(&mut self, upds: &[(usize, HashType)]) -> VecDeque<(NodeIndex, HashType)> {
+        upds.iter()
+            .map(|(leaf_pos, hash)| {
+                let leaf_idx = self.get_leaf_idx(*leaf_pos);
+
+                debug_assert!(self.is_leaf(&leaf_idx));
+                debug_assert!(self._hashed_nodes.insert(leaf_idx));
 
-        self._process_update_queue(&mut curr_updates, None);
+                (leaf_idx, hash.clone())
+            })
+            .collect::<VecDeque<_>>()
     }
 
-    fn _process_update_queue(
+    // Like preprocess_leaves(), but for leaves already hashed by a LeafPrehasher (e.g., in the background,
+    // while the previous batch was being applied), so only their ancestors are left to hash.
+    pub fn preprocess_prehashed_leaves(
         &mut self,
-        dequeue: &mut VecDeque<(NodeIndex, HashType)>,
-        mut enqueue_opt: Option<&mut VecDeque<(NodeIndex, HashType)>>,
-    ) {
-        let mut new_siblings = Vec::with_capacity(self.arity);
-        let mut old_siblings: Vec<HashType> = Vec::with_capacity(self.arity);
+        leaves: PrehashedLeaves<LeafDataType, HashType>,
+    ) -> (VecDeque<(NodeIndex, HashType)>, Duration)
+    where
+        Hasher: ParallelTreeHasherFunc<LeafDataType, HashType>,
+    {
+        assert_eq!(leaves.updates.len(), leaves.hashes.len());
+        self._store_leaves(&leaves.updates);
+        self.hasher.count_leaf_hashes(leaves.len());
 
-        while !dequeue.is_empty() {
-            new_siblings.clear();
-            old_siblings.clear();
+        let hashes = leaves.updates.into_iter().map(|(leaf_pos, _)| leaf_pos).zip(leaves.hashes).collect();
 
-            // pop the first sibling off the queue
-            let first_sib_idx = self.pop_sibling(dequeue, &mut new_siblings);
+        let mut tracer = LogTracer::new(self.arity());
+        self._preprocess_leaves(hashes, Self::_queuefy_prehashed, &mut tracer)
+    }
 
-            // if this sibling is actually the root node, we are done
-            if !first_sib_idx.is_root() {
-                let parent_idx = self.parent_node(&first_sib_idx);
+    pub fn update_prehashed_leaves(&mut self, leaves: PrehashedLeaves<LeafDataType, HashType>)
+    where
+        Hasher: ParallelTreeHasherFunc<LeafDataType, HashType>,
+    {
+        let num_updates = leaves.len();
+        let (curr_updates, _) = self.preprocess_prehashed_leaves(leaves);
 
-                // pop all other siblings off the queue
-                while !dequeue.is_empty() {
-                    let (potential_sib, _) = dequeue.front().unwrap();
+        self.update_preprocessed_leaves(curr_updates);
+        self.log_root(num_updates);
+    }
+
+    // Hashes the leaves of a batch of updates ahead of time, without changing the tree, so several
+    // batches can be staged and then applied one after the other via apply(), or discarded. The
+    // ancestors of the leaves are only hashed once the batch is applied, since they depend on the
+    // batches applied before it.
+    //
+    // NOTE: Staging borrows the tree, so it cannot overlap with applying a batch. To hash leaves while
+    // the tree is being updated, use a LeafPrehasher instead.
+    pub fn stage_updates(&self, updates: Vec<(usize, LeafDataType)>) -> StagedUpdate<LeafDataType, HashType>
+    where
+        Hasher: ParallelTreeHasherFunc<LeafDataType, HashType>,
+    {
+        assert!(updates.iter().all(|(leaf_pos, _)| *leaf_pos < self.num_leaves), "Leaf position is out of range");
+
+        let shape = self.shape();
+        StagedUpdate { shape, leaves: prehash_leaves(&shape, &self.hasher, updates) }
+    }
+
+    // applies a batch staged via stage_updates(), as if it had been passed to update_leaves() instead
+    pub fn apply(&mut self, staged: StagedUpdate<LeafDataType, HashType>)
+    where
+        Hasher: ParallelTreeHasherFunc<LeafDataType, HashType>,
+    {
+        assert_eq!(staged.shape, self.shape(), "Batch was staged for a tree of a different shape");
+
+        self.update_prehashed_leaves(staged.leaves);
+    }
+
+    // Resets the leaves at the specified positions to the empty (i.e., default) hash, as if they were
+    // never set, and updates their ancestors.
+    pub fn delete_leaves(&mut self, positions: &[usize]) {
+        let num_updates = positions.len();
+        let mut positions = positions.to_vec();
+        positions.sort_unstable();
+        positions.dedup();
+
+        for leaf_pos in &positions {
+            assert_lt!(*leaf_pos, self.num_leaves);
+            self.leaf_storage.remove(*leaf_pos);
+        }
+
+        let deletions = positions.into_iter().map(|leaf_pos| (leaf_pos, ())).collect();
+        let mut tracer = LogTracer::new(self.arity());
+        let (mut curr_updates, _) = self._preprocess_leaves(deletions, Self::_queuefy_deletions, &mut tracer);
+
+        self._process_update_queue(&mut curr_updates, None, &mut tracer);
+        self.log_root(num_updates);
+    }
+
+    // Sets *all* the leaves of the tree, where 'leaves[i]' is the data of the leaf at position i, by
+    // hashing every node bottom-up, from scratch. Unlike update_leaves(), this does not involve the
+    // old hashes at all, which is what we want when first populating a tree.
+    pub fn build_from_leaves(&mut self, leaves: Vec<LeafDataType>)
+    where
+        HashType: PartialEq,
+    {
+        assert_eq!(leaves.len(), self.num_leaves);
+        let mut progress = ProgressLog::new("Building nodes", Some(self.num_nodes()));
+
+        for (leaf_pos, leaf_data) in leaves.into_iter().enumerate() {
+            self.leaf_storage.set(leaf_pos, &leaf_data);
+
+            let leaf_idx = self.get_leaf_idx(leaf_pos);
+            let child_offset = self.child_offset(&leaf_idx);
+            let hash = self.hasher.hash_leaf_data(child_offset, leaf_data);
+            self.set_node_hash(&leaf_idx, hash);
+            progress.add(1);
+        }
+
+        // NOTE: A node's children always have higher indices than it, so by going through the internal
+        // nodes in decreasing order of their indices, we always hash the children before the parent.
+        let mut children: Arity::Buffer<HashType> = self.arity.buffer();
+        for idx in (0..self.num_internal_nodes).rev() {
+            let node = NodeIndex(idx);
+
+            children.clear();
+            for child_idx in self.children(&node) {
+                children.push(self.get_node_hash(&child_idx).unwrap());
+            }
+
+            let hash = self.hasher.hash_all_children_in(self.arity, &children);
+            self.set_node_hash(&node, hash);
+            progress.add(1);
+        }
+
+        progress.finish();
+    }
+
+    // Recomputes the root hash from scratch, from the leaf hashes stored in the tree, by hashing every
+    // internal node bottom-up into a separate buffer (so the tree itself is left as is). This is a
+    // deliberately simple reference implementation, which the root maintained by the (much more
+    // involved) incremental updates can be checked against.
+    //
+    // NOTE: This calls the hasher, so it counts towards its computations. It also takes one hash per
+    // internal node in memory, which is around as many as there are leaves in a binary tree.
+    pub fn recompute_root(&mut self) -> HashType
+    where
+        HashType: PartialEq,
+    {
+        let mut hashes: Vec<HashType> = vec![HashType::default(); self.num_internal_nodes];
+        let mut progress = ProgressLog::new("Recomputing nodes", Some(self.num_internal_nodes));
+
+        let mut children: Arity::Buffer<HashType> = self.arity.buffer();
+        for idx in (0..self.num_internal_nodes).rev() {
+            children.clear();
+            for child_idx in self.children(&NodeIndex(idx)) {
+                let hash = if child_idx.0 < self.num_internal_nodes {
+                    hashes[child_idx.0].clone()
+                } else {
+                    self.get_node_hash(&child_idx).unwrap()
+                };
+                children.push(hash);
+            }
+
+            hashes[idx] = self.hasher.hash_all_children_in(self.arity, &children);
+            progress.add(1);
+        }
+
+        progress.finish();
+
+        // NOTE: A tree with a single leaf has no internal nodes, and that leaf is its root
+        match hashes.into_iter().next() {
+            Some(root) => root,
+            None => self.root_hash(),
+        }
+    }
+
+    // Like update_leaves(), but if a leaf is updated more than once, its updates are merged via
+    // 'combine(earlier_data, later_data)' (see normalize_updates()).
+    pub fn update_leaves_with<Combine>(&mut self, new_leaves: Vec<(usize, LeafDataType)>, combine: Combine)
+    where
+        Combine: FnMut(LeafDataType, LeafDataType) -> LeafDataType,
+    {
+        self.update_leaves(normalize_updates(new_leaves, combine));
+    }
+
+    pub fn update_leaves(&mut self, new_leaves: Vec<(usize, LeafDataType)>) {
+        let mut tracer = LogTracer::new(self.arity());
+
+        self.update_leaves_traced(new_leaves, &mut tracer);
+    }
+
+    // Like update_leaves(), but first saves everything the updates overwrite, so that they can be undone
+    // via rollback() (e.g., if the batch turns out to be invalid), until they are kept via commit().
+    // Returns the new root hash.
+    //
+    // NOTE: Only one transaction can be open at a time. Updating the tree by other means while it is open
+    // is not undone by rollback(), except for the nodes the transaction also overwrote.
+    pub fn update_leaves_txn(&mut self, new_leaves: Vec<(usize, LeafDataType)>) -> Result<HashType, TxnError> {
+        if self.undo_log.is_some() {
+            return Err(TxnError::TxnInProgress);
+        }
+        if let Some((leaf_pos, _)) = new_leaves.iter().find(|(leaf_pos, _)| *leaf_pos >= self.num_leaves) {
+            return Err(TxnError::LeafOutOfRange(*leaf_pos));
+        }
+
+        // NOTE: Updates only ever overwrite the updated leaves and their ancestors
+        let arity = self.arity();
+        let positions = new_leaves.iter().map(|(leaf_pos, _)| *leaf_pos).collect::<BTreeSet<_>>();
+        let nodes = positions
+            .iter()
+            .flat_map(|leaf_pos| self.get_leaf_idx(*leaf_pos).path_to_root(arity))
+            .map(|node| node.0)
+            .collect::<BTreeSet<_>>();
+
+        self.undo_log = Some(UndoLog {
+            old_hashes: nodes.into_iter().map(|idx| (NodeIndex(idx), self.nodes.get(idx).unwrap())).collect(),
+            old_leaves: positions.into_iter().map(|leaf_pos| (leaf_pos, self.leaf_storage.get(leaf_pos))).collect(),
+            root_log_len: self.root_log.as_ref().map_or(0, RootLog::len),
+        });
+
+        self.update_leaves(new_leaves);
+        Ok(self.root_hash())
+    }
+
+    // keeps the updates of the open transaction; returns the root hash
+    pub fn commit(&mut self) -> Result<HashType, TxnError> {
+        self.undo_log.take().ok_or(TxnError::NoTxn)?;
+
+        Ok(self.root_hash())
+    }
+
+    // undoes the updates of the open transaction; returns the (restored) root hash
+    pub fn rollback(&mut self) -> Result<HashType, TxnError> {
+        let undo_log = self.undo_log.take().ok_or(TxnError::NoTxn)?;
+
+        for (node, hash) in undo_log.old_hashes {
+            self.set_node_hash(&node, hash);
+        }
+        for (leaf_pos, leaf) in undo_log.old_leaves {
+            match leaf {
+                Some(leaf) => self.leaf_storage.set(leaf_pos, &leaf),
+                None => self.leaf_storage.remove(leaf_pos),
+            }
+        }
+        if let Some(root_log) = &mut self.root_log {
+            root_log.truncate(undo_log.root_log_len);
+        }
+
+        Ok(self.root_hash())
+    }
+
+    // Like update_leaves(), but reports every node dequeued, hashed and queued up along the way to
+    // 'tracer' (e.g., a PropagationReport), to see how the updates propagate up the tree.
+    pub fn update_leaves_traced<Tracer>(&mut self, new_leaves: Vec<(usize, LeafDataType)>, tracer: &mut Tracer)
+    where
+        Tracer: UpdateTracer<HashType>,
+    {
+        let num_updates = new_leaves.len();
+        let updates = normalize_updates(new_leaves, |_, later| later);
+        self._store_leaves(&updates);
+
+        // takes care of cases where the leaves are split amongst the last and second to last level
+        let (mut curr_updates, _) = self._preprocess_leaves(updates, Self::_queuefy, tracer);
+
+        self._process_update_queue(&mut curr_updates, None, tracer);
+        self.log_root(num_updates);
+    }
+
+    // Like update_leaves(), but consumes the updates lazily, so that neither the updates nor the queue of
+    // updated nodes are ever fully materialized. Instead, we only keep the updated children of the
+    // current parent on each level, which is enough since parents are updated in order.
+    //
+    // NOTE: When the leaves are on two levels, the second-to-last level leaves have the smallest
+    // positions, but their parents must be updated after the parents of the last level leaves. So we
+    // do have to keep the hashes of the updated second-to-last level leaves until the end.
+    //
+    // The updates must be sorted by leaf position. If a leaf is updated several times in a row, the
+    // last update wins.
+    pub fn update_leaves_streaming<I>(&mut self, updates: I)
+    where
+        I: IntoIterator<Item = (usize, LeafDataType)>,
+    {
+        self._hashed_nodes.clear();
+
+        let leaf_depth = self.get_node_height(&self.first_last_level_leaf);
+        let mut pending: PendingParents<HashType, Arity> = (0..=leaf_depth).map(|_| None).collect();
+        let mut second_to_last: Vec<(NodeIndex, HashType)> = Vec::new();
+
+        // we hold back each update until we know the next one is not for the same leaf
+        let mut held_back: Option<(usize, LeafDataType)> = None;
+        let mut num_updates = 0;
+        for (leaf_pos, leaf_data) in updates {
+            assert_lt!(leaf_pos, self.num_leaves);
+            num_updates += 1;
+
+            if let Some((prev_pos, prev_data)) = held_back.take() {
+                assert!(prev_pos <= leaf_pos, "Streamed updates must be sorted by leaf position");
+
+                if prev_pos != leaf_pos {
+                    self._stream_leaf(&mut pending, &mut second_to_last, leaf_depth, prev_pos, prev_data);
+                }
+            }
+
+            held_back = Some((leaf_pos, leaf_data));
+        }
+
+        if let Some((leaf_pos, leaf_data)) = held_back {
+            self._stream_leaf(&mut pending, &mut second_to_last, leaf_depth, leaf_pos, leaf_data);
+        }
+
+        for (leaf_idx, hash) in second_to_last {
+            self._push_streamed(&mut pending, leaf_depth - 1, leaf_idx, hash);
+        }
+
+        // no more updates are coming, so all remaining parents can be updated, bottom-up
+        for depth in (1..pending.len()).rev() {
+            self._flush_streamed(&mut pending, depth);
+        }
+
+        self.log_root(num_updates);
+    }
+
+    fn _stream_leaf(
+        &mut self,
+        pending: &mut PendingParents<HashType, Arity>,
+        second_to_last: &mut Vec<(NodeIndex, HashType)>,
+        leaf_depth: usize,
+        leaf_pos: usize,
+        leaf_data: LeafDataType,
+    ) {
+        self.leaf_storage.set(leaf_pos, &leaf_data);
+
+        let leaf_idx = self.get_leaf_idx(leaf_pos);
+        let hash = self.hasher.hash_leaf_data(self.child_offset(&leaf_idx), leaf_data);
+        debug_assert!(self._hashed_nodes.insert(leaf_idx));
+
+        if self.is_last_level_leaf(&leaf_idx) {
+            self._push_streamed(pending, leaf_depth, leaf_idx, hash);
+        } else {
+            second_to_last.push((leaf_idx, hash));
+        }
+    }
+
+    // Adds the new hash of the node at depth 'depth' to the updated children of its parent. Nodes must
+    // be pushed in increasing order of their indices on every level.
+    fn _push_streamed(
+        &mut self,
+        pending: &mut PendingParents<HashType, Arity>,
+        depth: usize,
+        node: NodeIndex,
+        hash: HashType,
+    ) {
+        if depth == 0 {
+            debug_assert!(node.is_root());
+
+            self.set_node_hash(&node, hash);
+            return;
+        }
+
+        // NOTE: Since nodes are pushed in order, the parents pending on deeper levels cannot get any
+        // more updated children (e.g., after the last-level leaves come the second-to-last level ones).
+        for deeper in (depth + 1..pending.len()).rev() {
+            self._flush_streamed(pending, deeper);
+        }
+
+        let parent_idx = self.parent_node(&node);
+        if matches!(&pending[depth], Some((pending_parent, _)) if *pending_parent != parent_idx) {
+            self._flush_streamed(pending, depth);
+        }
+
+        pending[depth]
+            .get_or_insert_with(|| (parent_idx, self.arity.buffer()))
+            .1
+            .push((self.child_offset(&node), hash));
+    }
+
+    // Updates the parent pending on level 'depth - 1', if any, using its updated children on level
+    // 'depth', and pushes it to its own parent.
+    fn _flush_streamed(
+        &mut self,
+        pending: &mut PendingParents<HashType, Arity>,
+        depth: usize,
+    ) {
+        if let Some((parent_idx, new_children)) = pending[depth].take() {
+            // as in _process_update_queue(), we always give *all* the *old* hashes of the siblings
+            let mut old_children = self
+                .children(&parent_idx)
+                .map(|child_idx| self.get_node_hash(&child_idx).unwrap())
+                .collect::<Arity::Buffer<HashType>>();
+
+            debug_assert!(self._hashed_nodes.insert(parent_idx));
+            let hash = self.hasher.hash_nodes(
+                self.get_node_hash(&parent_idx).unwrap(),
+                &mut old_children,
+                &new_children,
+            );
+
+            for (offset, child_hash) in new_children.iter() {
+                let child_idx = self.child_node(&parent_idx, *offset);
+                self.set_node_hash(&child_idx, child_hash.clone());
+            }
+
+            self._push_streamed(pending, depth - 1, parent_idx, hash);
+        }
+    }
+
+    pub fn update_preprocessed_leaves(&mut self, mut curr_updates: VecDeque<(NodeIndex, HashType)>) {
+        let mut tracer = LogTracer::new(self.arity());
+
+        self._process_update_queue(&mut curr_updates, None, &mut tracer);
+    }
+
+    fn _process_update_queue<Tracer: UpdateTracer<HashType>>(
+        &mut self,
+        dequeue: &mut VecDeque<(NodeIndex, HashType)>,
+        mut enqueue_opt: Option<&mut VecDeque<(NodeIndex, HashType)>>,
+        tracer: &mut Tracer,
+    ) {
+        // NOTE: The sibling buffers are reused for every parent. When the arity is known at compile time,
+        // they are ArrayVecs on the stack (see TreeArity::Buffer).
+        let mut new_siblings: Arity::Buffer<(usize, HashType)> = self.arity.buffer();
+        let mut old_siblings: Arity::Buffer<HashType> = self.arity.buffer();
+        let mut progress = ProgressLog::new("Updating parents", None);
+        let _span = debug_span!("process_update_queue", num_updates = dequeue.len()).entered();
+
+        // the nodes are dequeued level by level, bottom-up, so we time each level in its own span,
+        // which starts when the first node of the level above is dequeued
+        let mut level_span = None;
+        let mut level_start = usize::MAX;
+
+        while !dequeue.is_empty() {
+            new_siblings.clear();
+            old_siblings.clear();
+            progress.add(1);
+
+            let next_idx = dequeue.front().unwrap().0;
+            if next_idx.0 < level_start {
+                let level = self.get_node_height(&next_idx);
+                level_start = NodeIndex::first_on_level(self.arity(), level).unwrap().0;
+
+                // NOTE: Drop the previous level's span first, so the spans do not nest
+                drop(level_span.take());
+                level_span = Some(debug_span!("level", level).entered());
+            }
+
+            // pop the first sibling off the queue
+            let first_sib_idx = self.pop_sibling(dequeue, &mut new_siblings, tracer);
+
+            // if this sibling is actually the root node, we are done
+            if !first_sib_idx.is_root() {
+                let parent_idx = self.parent_node(&first_sib_idx);
+
+                // pop all other siblings off the queue
+                while !dequeue.is_empty() {
+                    let (potential_sib, _) = dequeue.front().unwrap();
 
                     // if this is an actual sibling, track it so we can use it to update the parent
                     if self.parent_node(&potential_sib) == parent_idx {
-                        self.pop_sibling(dequeue, &mut new_siblings);
+                        self.pop_sibling(dequeue, &mut new_siblings, tracer);
                     } else {
                         break;
                     }
                 }
 
                 // now we have all siblings that were updated in 'old_siblings'
-                debug_assert_le!(new_siblings.len(), self.arity);
+                debug_assert_le!(new_siblings.len(), self.arity());
 
                 // we always give *all* the *old* hashes of the siblings, since Merkle++ requires them
                 // to speed up parent updates when more than arity/2 children are updated
-                for i in 0..self.arity {
+                for i in 0..self.arity() {
                     let child_idx = self.child_node(&parent_idx, i);
                     if let Some(opt_child_hash) = self.get_node_hash(&child_idx) {
-                        // NOTE: Uncomment for debugging
-                        // if !self.is_leaf(&child_idx) {
-                        //     println!(
-                        //         "Including old children {} (offset {}) of parent {}",
-                        //         child_idx.0, i, parent_idx.0
-                        //     );
-                        // } else {
-                        //     println!(
-                        //         "Including old leaf {} (offset {}, leaf #{}) of parent {}",
-                        //         child_idx.0,
-                        //         i,
-                        //         self.get_leaf_pos(&child_idx),
-                        //         self.parent_node(&child_idx).0
-                        //     );
-                        // }
-
                         old_siblings.push(opt_child_hash);
                     } else {
-                        // NOTE: Uncomment for debugging
-                        // println!("Parent {} has no child #{}, breaking...", parent_idx.0, i);
-                        // println!("Breaking at missing child {}. len(old_siblings) = {}", child_idx.0, old_siblings.len());
-
                         // If the parent has no child i, it has no children > i
                         //
                         // NOTE(Alin): It's possible for a parent on the second-to-last level, to
@@ -461,13 +1426,15 @@ impl<LeafDataType, HashType, Hasher> AbstractMerkle<LeafDataType, HashType, Hash
                 }
 
                 // first, compute the updated parent hash and schedule it to be processed later
-                //println!("Hashing and queueing parent {}", parent_idx.0);
                 debug_assert!(self._hashed_nodes.insert(parent_idx));
+                let num_children = old_siblings.len();
                 let hash = self.hasher.hash_nodes(
                     self.get_node_hash(&parent_idx).unwrap(),
                     &mut old_siblings,
                     &new_siblings,
                 );
+                tracer.hash(parent_idx, new_siblings.len(), num_children);
+                tracer.enqueue(parent_idx, &hash);
 
                 // NOTE(Alin): I did not understand why/how this 'as_deref_mut' works. Alden helped
                 // convince me there is a default implementation of DerefMut for any mutable
@@ -490,7 +1457,7 @@ impl<LeafDataType, HashType, Hasher> AbstractMerkle<LeafDataType, HashType, Hash
                 }
 
                 // second, update tree with new sibling hashes
-                for (idx, hash) in &new_siblings {
+                for (idx, hash) in new_siblings.iter() {
                     // TODO(Perf): Recomputing child node idx
                     let child_idx = self.child_node(&parent_idx, *idx);
                     self.set_node_hash(&child_idx, hash.clone());
@@ -500,8 +1467,1259 @@ impl<LeafDataType, HashType, Hasher> AbstractMerkle<LeafDataType, HashType, Hash
             } else {
                 debug_assert_eq!(new_siblings.len(), 1);
 
-                self.set_node_hash(&NodeIndex::root_node(), new_siblings.pop().unwrap().1)
+                self.set_node_hash(&NodeIndex::root_node(), new_siblings[0].1.clone())
+            }
+        }
+
+        drop(level_span);
+        progress.finish();
+    }
+
+    // Returns a deduplicated proof for the leaves at the specified positions (in [0, n), where n is
+    // the # of leaves). See prove_batch_in() for details.
+    pub fn prove_batch(&self, positions: &[usize]) -> BatchProof<HashType> {
+        prove_batch_in(self.arity, self.num_internal_nodes, self.num_leaves, &self.nodes, positions)
+    }
+
+    // Returns the stored data of the leaves at the specified positions, together with a proof for
+    // them, or None if some of these leaves' data is not stored.
+    pub fn prove_stored_leaves(
+        &self,
+        positions: &[usize],
+    ) -> Option<LeavesWithProof<LeafDataType, HashType>> {
+        let leaves = positions
+            .iter()
+            .map(|pos| Some((*pos, self.get_leaf(*pos)?)))
+            .collect::<Option<Vec<_>>>()?;
+
+        Some((leaves, self.prove_batch(positions)))
+    }
+
+    // Verifies a proof returned by prove_batch() for the specified leaves against the specified root.
+    //
+    // NOTE: Only the tree's shape (i.e., arity and # of leaves) and its hasher are used here, not the
+    // tree's nodes.
+    pub fn verify_batch(
+        &mut self,
+        root: &HashType,
+        leaves: &[(usize, LeafDataType)],
+        proof: &BatchProof<HashType>,
+    ) -> bool
+    where
+        HashType: PartialEq,
+    {
+        let leaf_hashes = match self.hash_proven_leaves(leaves) {
+            Some(leaf_hashes) => leaf_hashes,
+            None => return false,
+        };
+
+        let (shape, arity) = (self.shape(), self.arity);
+        let hasher = &mut self.hasher;
+
+        verify::verify_batch(&shape, root, &leaf_hashes, &proof.hashes, |children| hasher.hash_all_children_in(arity, children))
+    }
+
+    // returns the (position, hash) of each leaf, as a verifier needs them, or None if some leaf is out
+    // of range
+    pub(crate) fn hash_proven_leaves(&mut self, leaves: &[(usize, LeafDataType)]) -> Option<Vec<(usize, HashType)>> {
+        let mut leaf_hashes = Vec::with_capacity(leaves.len());
+        for (pos, leaf_data) in leaves {
+            if *pos >= self.num_leaves {
+                return None;
+            }
+
+            let child_offset = self.child_offset(&self.get_leaf_idx(*pos));
+            leaf_hashes.push((*pos, self.hasher.hash_leaf_data(child_offset, leaf_data.clone())));
+        }
+
+        Some(leaf_hashes)
+    }
+}
+
+impl<LeafDataType, HashType, Hasher, Storage, Arity>
+    AbstractMerkle<LeafDataType, HashType, Hasher, Storage, CowNodeStore<HashType>, Arity>
+    where
+        LeafDataType: Clone,
+        HashType: Default + Clone,
+        Hasher: TreeHasherFunc<LeafDataType, HashType>,
+        Storage: LeafStorage<LeafDataType> + Default,
+        Arity: TreeArity,
+{
+    // Returns a read-only version of the tree as it is now, which remains unchanged by later updates
+    // to the tree. This is cheap, since the version shares all of its nodes with the tree, until the
+    // tree updates them.
+    pub fn snapshot(&self) -> TreeVersion<HashType> {
+        TreeVersion {
+            arity: self.arity(),
+            num_internal_nodes: self.num_internal_nodes,
+            num_leaves: self.num_leaves,
+            nodes: self.nodes.clone(),
+        }
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl<LeafDataType, HashType, Hasher, Storage, Nodes, Arity>
+    AbstractMerkle<LeafDataType, HashType, Hasher, Storage, Nodes, Arity>
+    where
+        LeafDataType: Clone + Sync,
+        HashType: Default + Clone + Send + Sync,
+        Hasher: ParallelTreeHasherFunc<LeafDataType, HashType>,
+        Storage: LeafStorage<LeafDataType> + Default,
+        Nodes: NodeStore<HashType> + Sync,
+        Arity: TreeArity,
+{
+    // Like update_leaves(), but hashes the leaves, as well as all updated parents on the same level,
+    // in parallel.
+    pub fn update_leaves_parallel(&mut self, new_leaves: Vec<(usize, LeafDataType)>) {
+        let num_updates = new_leaves.len();
+        let (curr_updates, _) = self.preprocess_leaves_parallel(new_leaves);
+
+        self.update_preprocessed_leaves_parallel(curr_updates);
+        self.log_root(num_updates);
+    }
+
+    pub fn update_preprocessed_leaves_parallel(&mut self, curr_updates: VecDeque<(NodeIndex, HashType)>) {
+        self._process_update_levels(Vec::from(curr_updates));
+    }
+
+    // Processes the updates level by level, starting from 'level_updates', which must all be on the
+    // same level and sorted by node index. (This is the case for the queue returned by
+    // preprocess_leaves().)
+    //
+    // Unlike _process_update_queue(), we wait for all updated parents on a level to be computed
+    // before moving on to their parents. Since parents on the same level are independent of one
+    // another, they can be computed in parallel.
+    fn _process_update_levels(&mut self, mut level_updates: Vec<(NodeIndex, HashType)>) {
+        let arity = self.arity();
+        let mut progress = ProgressLog::new("Updating parents", None);
+        let _span = debug_span!("process_update_levels", num_updates = level_updates.len()).entered();
+
+        while !level_updates.is_empty() {
+            let _level_span = debug_span!("level", num_updates = level_updates.len()).entered();
+
+            if level_updates[0].0.is_root() {
+                debug_assert_eq!(level_updates.len(), 1);
+
+                let (root_idx, hash) = level_updates.pop().unwrap();
+                self.set_node_hash(&root_idx, hash);
+                break;
+            }
+
+            // group the updated siblings together, since they share the same parent
+            let mut groups: Vec<(usize, usize)> = Vec::new();
+            let mut group_start = 0;
+            for i in 1..=level_updates.len() {
+                if i == level_updates.len()
+                    || level_updates[i].0.parent(arity) != level_updates[group_start].0.parent(arity) {
+                    groups.push((group_start, i));
+                    group_start = i;
+                }
+            }
+            progress.add(groups.len());
+
+            let hasher = &self.hasher;
+            let nodes = &self.nodes;
+            let updates = &level_updates;
+
+            let parent_updates = groups
+                .par_iter()
+                .map(|(start, end)| {
+                    let parent_idx = updates[*start].0.parent(arity);
+
+                    let new_siblings = updates[*start..*end]
+                        .iter()
+                        .map(|(idx, hash)| (idx.child_offset(arity), hash.clone()))
+                        .collect::<Vec<(usize, HashType)>>();
+
+                    // as in _process_update_queue(), we always give *all* the *old* hashes of the siblings
+                    let mut old_siblings = (0..arity)
+                        .map(|i| parent_idx.child(arity, i))
+                        .take_while(|child_idx| child_idx.0 < nodes.len())
+                        .map(|child_idx| nodes.get(child_idx.0).unwrap())
+                        .collect::<Arity::Buffer<HashType>>();
+
+                    let (hash, stats) = hasher.hash_nodes_shared(
+                        nodes.get(parent_idx.0).unwrap(),
+                        &mut old_siblings,
+                        &new_siblings,
+                    );
+
+                    ((parent_idx, hash), new_siblings, stats)
+                })
+                .collect::<Vec<_>>();
+
+            // now that the parents are computed, update the tree with the new sibling hashes
+            //
+            // NOTE: We store the siblings that were given to the hasher, rather than the ones in
+            // 'level_updates', since hashing them may have cached something in them (see
+            // FatMerkleppNode).
+            level_updates.clear();
+            level_updates.reserve(parent_updates.len());
+            for ((parent_idx, hash), new_siblings, stats) in parent_updates {
+                for (offset, sibling_hash) in new_siblings {
+                    self.set_node_hash(&parent_idx.child(arity, offset), sibling_hash);
+                }
+
+                debug_assert!(self._hashed_nodes.insert(parent_idx));
+
+                self.hasher.count_node_hashes(stats);
+                level_updates.push((parent_idx, hash));
+            }
+        }
+
+        progress.finish();
+    }
+
+    // Like build_from_leaves(), but hashes all the leaves, and then all the internal nodes on each
+    // level, in parallel.
+    pub fn build_from_leaves_parallel(&mut self, leaves: Vec<LeafDataType>)
+    where
+        HashType: PartialEq,
+    {
+        assert_eq!(leaves.len(), self.num_leaves);
+
+        for (leaf_pos, leaf_data) in leaves.iter().enumerate() {
+            self.leaf_storage.set(leaf_pos, leaf_data);
+        }
+
+        let hasher = &self.hasher;
+        let arity = self.arity();
+        let num_internal_nodes = self.num_internal_nodes;
+        let mut progress = ProgressLog::new("Building nodes", Some(self.num_nodes()));
+
+        let leaf_hashes = leaves
+            .par_iter()
+            .enumerate()
+            .map(|(leaf_pos, leaf_data)| {
+                let child_offset = NodeIndex(num_internal_nodes + leaf_pos).child_offset(arity);
+
+                hasher.hash_leaf_data_shared(child_offset, leaf_data.clone())
+            })
+            .collect::<Vec<HashType>>();
+        self.hasher.count_leaf_hashes(leaf_hashes.len());
+
+        for (leaf_pos, hash) in leaf_hashes.into_iter().enumerate() {
+            self.set_node_hash(&NodeIndex(num_internal_nodes + leaf_pos), hash);
+        }
+        progress.add(self.num_leaves);
+
+        // the ranges of internal node indices on each level, from the root level downwards
+        let mut levels = Vec::new();
+        let (mut level_start, mut level_size) = (0, 1);
+        while level_start < num_internal_nodes {
+            levels.push(level_start..num_internal_nodes.min(level_start + level_size));
+
+            level_start += level_size;
+            level_size *= arity;
+        }
+
+        // the nodes on a level only depend on the nodes on the levels below, which were hashed already
+        for level in levels.into_iter().rev() {
+            let hasher = &self.hasher;
+            let nodes = &self.nodes;
+
+            let hashes = level
+                .clone()
+                .into_par_iter()
+                .map(|idx| {
+                    let children = (0..arity)
+                        .map(|i| NodeIndex(idx).child(arity, i))
+                        .take_while(|child_idx| child_idx.0 < nodes.len())
+                        .map(|child_idx| nodes.get(child_idx.0).unwrap())
+                        .collect::<Arity::Buffer<HashType>>();
+
+                    hasher.hash_all_children_shared_in(Arity::from_usize(arity), &children)
+                })
+                .collect::<Vec<_>>();
+
+            progress.add(level.len());
+            for (idx, (hash, stats)) in level.zip(hashes) {
+                self.hasher.count_node_hashes(stats);
+                self.set_node_hash(&NodeIndex(idx), hash);
+            }
+        }
+
+        progress.finish();
+    }
+
+    // Like preprocess_leaves(), but hashes the updated leaves in parallel.
+    pub fn preprocess_leaves_parallel(
+        &mut self,
+        updates: Vec<(usize, LeafDataType)>,
+    ) -> (VecDeque<(NodeIndex, HashType)>, Duration) {
+        let updates = normalize_updates(updates, |_, later| later);
+        self._store_leaves(&updates);
+
+        let mut tracer = LogTracer::new(self.arity());
+        self._preprocess_leaves(updates, Self::_queuefy_parallel, &mut tracer)
+    }
+
+    fn _queuefy_parallel(&mut self, upds: &[(usize, LeafDataType)]) -> VecDeque<(NodeIndex, HashType)> {
+        // NOTE: The leaves are independent of one another and of the tree, so the only thing the
+        // threads share is the hasher.
+        let hasher = &self.hasher;
+        let arity = self.arity();
+        let num_internal_nodes = self.num_internal_nodes;
+
+        let queue = upds
+            .par_iter()
+            .map(|(leaf_pos, leaf_data)| {
+                let leaf_idx = NodeIndex(num_internal_nodes + *leaf_pos);
+                let child_offset = leaf_idx.child_offset(arity);
+
+                (
+                    leaf_idx,
+                    hasher.hash_leaf_data_shared(child_offset, leaf_data.clone()),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        self.hasher.count_leaf_hashes(upds.len());
+
+        // NOTE: Uncomment for debugging
+        for (leaf_idx, _) in &queue {
+            debug_assert!(self.is_leaf(leaf_idx));
+            debug_assert!(self._hashed_nodes.insert(*leaf_idx));
+        }
+
+        VecDeque::from(queue)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::leaf_storage::MemLeafStorage;
+    use more_asserts::assert_gt;
+    use std::collections::HashMap;
+    #[cfg(feature = "parallel")]
+    use std::fmt::Debug;
+    use crate::merkle_crhf::{new_merkle_crhf_from_leaves, HasherCRHF, MerkleHashValue, TinySha3HashFunc};
+    use crate::merkle_pp::new_merklepp_from_leaves;
+    use crate::random_leaf_positions;
+    use crate::verkle::new_verkle_from_leaves;
+    use curve25519_dalek::constants::RISTRETTO_BASEPOINT_TABLE;
+    use curve25519_dalek::ristretto::RistrettoPoint;
+    use curve25519_dalek::scalar::Scalar;
+    use rand::thread_rng;
+    use rust_incrhash::compressed_ristretto::CompRistBlakeIncHash;
+    use rust_incrhash::ristretto::RistBlakeIncHash;
+
+    fn leaf_data(pos: usize) -> String {
+        format!("leaf #{}", pos)
+    }
+
+    // returns random bases for a Verkle tree of the specified arity
+    fn random_bases(arity: usize) -> Vec<RistrettoPoint> {
+        (0..arity).map(|_| &Scalar::random(&mut thread_rng()) * &RISTRETTO_BASEPOINT_TABLE).collect()
+    }
+
+    // Calls 'test' (e.g., test_deletions()) on two fresh CRHF trees of the specified shape, then on two
+    // Merkle++ trees and then on two Verkle trees (with the same bases), each time followed by 'args'.
+    macro_rules! test_tree_pairs {
+        ($arity:expr, $num_leaves:expr, $test:ident $(, $args:expr)*) => {{
+            let (arity, num_leaves) = ($arity, $num_leaves);
+
+            let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+            let mut other = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+            $test(&mut merkle, &mut other $(, $args)*);
+
+            let mut merklepp = new_merklepp_from_leaves::<CompRistBlakeIncHash, RistBlakeIncHash>(arity, num_leaves);
+            let mut other = new_merklepp_from_leaves::<CompRistBlakeIncHash, RistBlakeIncHash>(arity, num_leaves);
+            $test(&mut merklepp, &mut other $(, $args)*);
+
+            let bases = random_bases(arity);
+            let mut verkle = new_verkle_from_leaves(arity, num_leaves, bases.clone());
+            let mut other = new_verkle_from_leaves(arity, num_leaves, bases);
+            $test(&mut verkle, &mut other $(, $args)*);
+        }};
+    }
+
+    // sets all the leaves over 'num_rounds' batches of updates, then proves random batches of leaves
+    // against the root
+    fn test_batch_proofs<HashType, Hasher>(
+        merkle: &mut AbstractMerkle<String, HashType, Hasher>,
+        num_rounds: usize,
+    )
+    where
+        HashType: CanonicalBytes + Default + Clone + PartialEq,
+        Hasher: TreeHasherFunc<String, HashType>,
+    {
+        let num_leaves = merkle.num_leaves();
+        let positions = (0..num_leaves).collect::<Vec<usize>>();
+        for round in positions.chunks(num_leaves.div_ceil(num_rounds)) {
+            merkle.update_leaves(round.iter().map(|pos| (*pos, leaf_data(*pos))).collect());
+        }
+        let root = merkle.root_hash();
+
+        for batch_size in [1, 2, num_leaves / 2, num_leaves] {
+            if batch_size == 0 || batch_size > num_leaves {
+                continue;
+            }
+
+            let positions = random_leaf_positions(&mut thread_rng(), num_leaves, batch_size).collect::<Vec<usize>>();
+            let leaves = positions.iter().map(|pos| (*pos, leaf_data(*pos))).collect::<Vec<_>>();
+            let proof = merkle.prove_batch(&positions);
+
+            assert!(merkle.verify_batch(&root, &leaves, &proof));
+
+            // proofs survive serialization
+            let proof = BatchProof::from_bytes(&proof.to_bytes()).unwrap();
+            assert!(merkle.verify_batch(&root, &leaves, &proof));
+
+            // the wrong leaf data must not verify
+            let mut wrong_leaves = leaves.clone();
+            wrong_leaves[0].1 = "wrong".to_owned();
+            assert!(!merkle.verify_batch(&root, &wrong_leaves, &proof));
+
+            // neither should a truncated proof
+            if !proof.is_empty() {
+
+                let mut short_proof = proof.clone();
+                short_proof.hashes.pop();
+                assert!(!merkle.verify_batch(&root, &leaves, &short_proof));
+            }
+        }
+    }
+
+    #[test]
+    fn batch_proof_merkle_crhf() {
+        for arity in [2, 3, 4, 16] {
+            for num_leaves in 2..=70 {
+                let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+
+                test_batch_proofs(&mut merkle, 3);
+            }
+        }
+    }
+
+    #[test]
+    fn single_leaf() {
+        use crate::reference::new_reference_merkle_crhf;
+
+        for arity in [2, 3, 16] {
+            let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, 1);
+            let mut streamed = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, 1);
+            let mut reference = new_reference_merkle_crhf::<TinySha3HashFunc>(arity, 1);
+
+            for round in 0..3 {
+                let updates = vec![(0, leaf_data(round))];
+                merkle.update_leaves(updates.clone());
+                streamed.update_leaves_streaming(updates.clone());
+                reference.update_leaves(updates);
+                assert_eq!(merkle.root_hash(), reference.root_hash());
+                assert_eq!(streamed.root_hash(), reference.root_hash());
+            }
+
+            test_batch_proofs(&mut merkle, 1);
+        }
+    }
+
+    #[test]
+    fn batch_proof_deduplicates_siblings() {
+        let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(2, 8);
+        merkle.update_leaves((0..8).map(|pos| (pos, leaf_data(pos))).collect());
+
+        // a single leaf needs one sibling per level
+        assert_eq!(merkle.prove_batch(&[0]).len(), 3);
+        // siblings need no hashes at the bottom level
+        assert_eq!(merkle.prove_batch(&[0, 1]).len(), 2);
+        // all the leaves need no hashes at all
+        assert!(merkle.prove_batch(&(0..8).collect::<Vec<usize>>()).is_empty());
+        // duplicates are ignored
+        assert_eq!(merkle.prove_batch(&[0, 0, 1]).len(), 2);
+    }
+
+    #[test]
+    fn batch_proof_wrong_root() {
+        let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(4, 64);
+        merkle.update_leaves((0..64).map(|pos| (pos, leaf_data(pos))).collect());
+
+        let proof = merkle.prove_batch(&[3, 17]);
+        let leaves = vec![(3, leaf_data(3)), (17, leaf_data(17))];
+        assert!(!merkle.verify_batch(&MerkleHashValue::default(), &leaves, &proof));
+    }
+
+    #[test]
+    fn batch_proof_merklepp() {
+        for arity in [2, 4, 8] {
+            for num_leaves in [arity, arity * arity, arity * arity + 1, 50] {
+                let mut merklepp = new_merklepp_from_leaves::<CompRistBlakeIncHash, RistBlakeIncHash>(
+                    arity, num_leaves,
+                );
+
+                test_batch_proofs(&mut merklepp, 3);
+            }
+        }
+    }
+
+    #[test]
+    fn batch_proof_verkle() {
+        for arity in [2, 4, 8] {
+            for num_leaves in [arity, arity * arity, arity * arity + 1, 50] {
+                let mut verkle = new_verkle_from_leaves(arity, num_leaves, random_bases(arity));
+
+                test_batch_proofs(&mut verkle, 3);
+            }
+        }
+    }
+
+    #[test]
+    fn root_hash_canonical_bytes() {
+        let leaves = (0..20).map(|pos| (pos, leaf_data(pos))).collect::<Vec<_>>();
+
+        let mut merklepp = new_merklepp_from_leaves::<CompRistBlakeIncHash, RistBlakeIncHash>(4, 20);
+        merklepp.update_leaves(leaves.clone());
+        let root = merklepp.root_hash();
+        assert_eq!(CanonicalBytes::from_bytes(&root.to_bytes()), Some(root));
+
+        let bases = random_bases(4);
+        let mut verkle = new_verkle_from_leaves(4, 20, bases);
+        assert_eq!(verkle.root_hash().to_bytes(), vec![0u8]);
+        verkle.update_leaves(leaves);
+        let root = verkle.root_hash();
+        assert_eq!(CanonicalBytes::from_bytes(&root.to_bytes()), Some(root));
+    }
+
+    #[test]
+    fn fat_verkle_matches_thin() {
+        use crate::verkle::new_fat_verkle_from_leaves;
+
+        let bases = random_bases(4);
+        let mut verkle = new_verkle_from_leaves(4, 50, bases.clone());
+        let mut fat = new_fat_verkle_from_leaves(4, 50, bases);
+
+        for _ in 0..3 {
+            let updates = crate::random_updates(&mut thread_rng(), 50, 20);
+
+            verkle.update_leaves(updates.clone());
+            fat.update_leaves(updates);
+
+            assert_eq!(verkle.root_hash().to_bytes(), fat.root_hash().to_bytes());
+        }
+
+        // both do the same group operations, but fat nodes hash each new commitment to a scalar only once
+        let (thin_stats, fat_stats) = (verkle.hasher.get_stats(), fat.hasher.get_stats());
+        assert_eq!(thin_stats.group_exps, fat_stats.group_exps);
+        assert_eq!(thin_stats.compressions, fat_stats.compressions);
+        assert_eq!(thin_stats.decompressions, fat_stats.decompressions);
+        assert_eq!(thin_stats.field_ops, fat_stats.field_ops);
+        assert_eq!(thin_stats.group_hashes, 0);
+        assert_lt!(fat_stats.sym_hashes, thin_stats.sym_hashes);
+
+        fat.delete_leaves(&(0..50).collect::<Vec<_>>());
+        assert_eq!(fat.root_hash().to_bytes(), vec![0u8]);
+    }
+
+    #[test]
+    fn fat_merklepp_matches_thin() {
+        use crate::merkle_pp::new_fat_merklepp_from_leaves;
+
+        let mut merklepp = new_merklepp_from_leaves::<CompRistBlakeIncHash, RistBlakeIncHash>(4, 50);
+        let mut fat = new_fat_merklepp_from_leaves::<CompRistBlakeIncHash, RistBlakeIncHash>(4, 50);
+
+        // both few and many updated children per parent, so both ways of updating parents are used
+        for num_updates in [3, 50, 3, 20] {
+            let updates = crate::random_updates(&mut thread_rng(), 50, num_updates);
+
+            merklepp.update_leaves(updates.clone());
+            fat.update_leaves(updates);
+
+            assert_eq!(merklepp.root_hash().to_bytes(), fat.root_hash().to_bytes());
+        }
+
+        // the old children's hashes were cached, rather than recomputed
+        fn num_hashes<HashType, Hasher: TreeHasherFunc<String, HashType>>(
+            merkle: &AbstractMerkle<String, HashType, Hasher>,
+        ) -> usize {
+            merkle.hasher.get_num_computations()
+        }
+        assert_lt!(num_hashes(&fat), num_hashes(&merklepp));
+    }
+
+    #[test]
+    fn verkle_config_does_not_change_root() {
+        use crate::verkle::{VerkleComm, VerkleConfig, VerkleHasher};
+
+        let bases = random_bases(4);
+        let config = VerkleConfig { multiexp_cutoff: 0, base_tables: false };
+        let mut verkle = new_verkle_from_leaves(4, 50, bases.clone());
+        let hasher = VerkleHasher::<VerkleComm>::with_config(4, bases.clone(), config);
+        let mut other: AbstractMerkle<String, VerkleComm, _> = AbstractMerkle::with_num_leaves(4, 50, hasher);
+
+        let mut calibrated = VerkleHasher::<VerkleComm>::with_config(4, bases, VerkleConfig::default());
+        assert_le!(calibrated.calibrate_multiexp_cutoff(), 4);
+
+        for num_updates in [1, 3, 20] {
+            let updates = crate::random_updates(&mut thread_rng(), 50, num_updates);
+
+            verkle.update_leaves(updates.clone());
+            other.update_leaves(updates);
+
+            assert_eq!(verkle.root_hash().to_bytes(), other.root_hash().to_bytes());
+        }
+    }
+
+    // applies the same random batches of updates sequentially and in parallel
+    #[cfg(feature = "parallel")]
+    fn test_parallel_updates<HashType, Hasher>(
+        merkle: &mut AbstractMerkle<String, HashType, Hasher>,
+        other: &mut AbstractMerkle<String, HashType, Hasher>,
+        num_rounds: usize,
+    )
+    where
+        HashType: Default + Clone + PartialEq + Debug + Send + Sync,
+        Hasher: ParallelTreeHasherFunc<String, HashType>,
+    {
+        let num_leaves = merkle.num_leaves();
+        for _ in 0..num_rounds {
+            let updates = crate::random_updates(&mut thread_rng(), num_leaves, num_leaves / 3 + 1);
+
+            merkle.update_leaves(updates.clone());
+            other.update_leaves_parallel(updates);
+
+            assert_eq!(merkle.root_hash(), other.root_hash());
+            assert_eq!(merkle.hasher.get_stats(), other.hasher.get_stats());
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_updates_match_sequential() {
+        for arity in [2, 3, 4, 16] {
+            for num_leaves in (2..=70).chain([256, 600]) {
+                let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+                let mut other = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+
+                test_parallel_updates(&mut merkle, &mut other, 3);
+            }
+        }
+
+        for arity in [2, 4, 8] {
+            for num_leaves in [arity * arity, arity * arity + 1, 50] {
+                test_tree_pairs!(arity, num_leaves, test_parallel_updates, 3);
+            }
+        }
+    }
+
+    #[test]
+    fn staged_updates() {
+        for (arity, num_leaves) in [(3, 10), (2, 64)] {
+            let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+            let mut other = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+
+            // the staged batches overlap, so the later ones must win once applied in order
+            let batches = (0..3).map(|_| crate::random_updates(&mut thread_rng(), num_leaves, 6)).collect::<Vec<_>>();
+            let mut staged = batches.iter().map(|updates| merkle.stage_updates(updates.clone())).collect::<Vec<_>>();
+            assert_eq!(merkle.root_hash(), other.root_hash());
+
+            staged.remove(1).discard();
+            for (updates, staged) in [&batches[0], &batches[2]].into_iter().zip(staged) {
+                assert!(!staged.is_empty());
+                merkle.apply(staged);
+                other.update_leaves(updates.clone());
+                assert_eq!(merkle.root_hash(), other.root_hash());
+            }
+            assert_eq!(merkle.hasher.get_num_computations(), other.hasher.get_num_computations());
+        }
+    }
+
+    #[test]
+    fn txn_rollback() {
+        let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(3, 10)
+            .with_leaf_storage(MemLeafStorage::new());
+        merkle.update_leaves(vec![(2, leaf_data(2)), (9, leaf_data(9))]);
+        let root = merkle.root_hash();
+        assert_eq!(merkle.rollback(), Err(TxnError::NoTxn));
+        assert_eq!(merkle.update_leaves_txn(vec![(10, leaf_data(10))]), Err(TxnError::LeafOutOfRange(10)));
+
+        // leaf 9 is on the last level and leaf 2 on the second-to-last one
+        let updates = vec![(9, "new".to_owned()), (2, "new".to_owned()), (4, leaf_data(4))];
+        let new_root = merkle.update_leaves_txn(updates.clone()).unwrap();
+        assert_ne!(new_root, root);
+        assert_eq!(merkle.update_leaves_txn(vec![]), Err(TxnError::TxnInProgress));
+        assert_eq!(merkle.rollback(), Ok(root.clone()));
+        assert_eq!(merkle.get_leaf(2), Some(leaf_data(2)));
+        assert_eq!(merkle.get_leaf(4), None);
+        assert_eq!(merkle.recompute_root(), root);
+
+        assert_eq!(merkle.update_leaves_txn(updates), Ok(new_root.clone()));
+        assert_eq!(merkle.commit(), Ok(new_root.clone()));
+        assert_eq!(merkle.rollback(), Err(TxnError::NoTxn));
+        assert_eq!(merkle.get_leaf(2), Some("new".to_owned()));
+    }
+
+    #[test]
+    fn leaf_storage() {
+        let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(3, 10);
+        merkle.update_leaves(vec![(2, leaf_data(2))]);
+        assert_eq!(merkle.get_leaf(2), None);
+        assert!(merkle.prove_stored_leaves(&[2]).is_none());
+
+        let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(3, 10)
+            .with_leaf_storage(MemLeafStorage::new());
+        merkle.update_leaves(vec![(2, leaf_data(2)), (7, leaf_data(7))]);
+        merkle.update_leaves(vec![(7, "new".to_owned())]);
+        assert_eq!(merkle.get_leaf(2), Some(leaf_data(2)));
+        assert_eq!(merkle.get_leaf(7), Some("new".to_owned()));
+        assert_eq!(merkle.get_leaf(9), None);
+        assert!(merkle.prove_stored_leaves(&[2, 9]).is_none());
+
+        let root = merkle.root_hash();
+        let (leaves, proof) = merkle.prove_stored_leaves(&[2, 7]).unwrap();
+        assert!(merkle.verify_batch(&root, &leaves, &proof));
+    }
+
+    // sets all the leaves, then deletes all but a few of them, and checks the root matches that of a
+    // tree where only those few leaves were ever set
+    fn test_deletions<HashType, Hasher>(
+        merkle: &mut AbstractMerkle<String, HashType, Hasher>,
+        other: &mut AbstractMerkle<String, HashType, Hasher>,
+    )
+    where
+        HashType: Default + Clone + PartialEq + std::fmt::Debug,
+        Hasher: TreeHasherFunc<String, HashType>,
+    {
+        let num_leaves = merkle.num_leaves();
+        let empty_root = merkle.root_hash();
+        let kept = random_leaf_positions(&mut thread_rng(), num_leaves, num_leaves / 4 + 1).collect::<Vec<usize>>();
+        let deleted = (0..num_leaves).filter(|pos| !kept.contains(pos)).collect::<Vec<usize>>();
+
+        merkle.update_leaves((0..num_leaves).map(|pos| (pos, leaf_data(pos))).collect());
+        merkle.delete_leaves(&deleted);
+        // deleting never-set (or already deleted) leaves changes nothing
+        merkle.delete_leaves(&deleted[..deleted.len() / 2]);
+
+        other.update_leaves(kept.iter().map(|pos| (*pos, leaf_data(*pos))).collect());
+        assert_eq!(merkle.root_hash(), other.root_hash());
+
+        // the remaining leaves can still be proven
+        let proof = merkle.prove_batch(&kept);
+        let leaves = kept.iter().map(|pos| (*pos, leaf_data(*pos))).collect::<Vec<_>>();
+        assert!(merkle.verify_batch(&merkle.root_hash(), &leaves, &proof));
+
+        // deleting everything brings back the empty tree
+        merkle.delete_leaves(&kept);
+        assert_eq!(merkle.root_hash(), empty_root);
+    }
+
+    #[test]
+    fn delete_leaves() {
+        for arity in [2, 3, 4] {
+            for num_leaves in [arity, arity * arity, arity * arity + 1, 50] {
+                test_tree_pairs!(arity, num_leaves, test_deletions);
+            }
+        }
+    }
+
+    #[test]
+    fn delete_leaves_forgets_stored_data() {
+        let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(2, 8)
+            .with_leaf_storage(MemLeafStorage::new());
+        merkle.update_leaves(vec![(1, leaf_data(1)), (5, leaf_data(5))]);
+        merkle.delete_leaves(&[5]);
+
+        assert_eq!(merkle.get_leaf(1), Some(leaf_data(1)));
+        assert_eq!(merkle.get_leaf(5), None);
+    }
+
+    // keeps only the non-default hashes, to make sure AbstractMerkle does not assume its nodes are
+    // kept in a vector
+    #[derive(Default)]
+    struct MapNodeStore {
+        len: usize,
+        nodes: HashMap<usize, MerkleHashValue>,
+    }
+
+    impl NodeStore<MerkleHashValue> for MapNodeStore {
+        fn allocate(&mut self, num_nodes: usize) {
+            self.len = num_nodes;
+            self.nodes.clear();
+        }
+
+        fn len(&self) -> usize {
+            self.len
+        }
+
+        fn get(&self, idx: usize) -> Option<MerkleHashValue> {
+            if idx < self.len {
+                Some(self.nodes.get(&idx).cloned().unwrap_or_default())
+            } else {
+                None
+            }
+        }
+
+        fn set(&mut self, idx: usize, hash: MerkleHashValue) {
+            assert_lt!(idx, self.len);
+            self.nodes.insert(idx, hash);
+        }
+    }
+
+    #[test]
+    fn custom_node_store() {
+        let num_leaves = 20;
+        let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(3, num_leaves);
+        let mut other: AbstractMerkle<String, MerkleHashValue, _, NoLeafStorage, MapNodeStore> =
+            AbstractMerkle::with_node_store(3, num_leaves, HasherCRHF::<TinySha3HashFunc>::new(3), MapNodeStore::default());
+
+        let updates = (0..num_leaves).step_by(3).map(|pos| (pos, leaf_data(pos))).collect::<Vec<_>>();
+        merkle.update_leaves(updates.clone());
+        other.update_leaves(updates);
+
+        assert_eq!(merkle.root_hash(), other.root_hash());
+        assert!(other.nodes.nodes.len() < other.nodes.len());
+    }
+
+    #[test]
+    fn blocked_node_store() {
+        use crate::merkle_crhf::new_merkle_crhf_with_node_store;
+        use crate::node_store::BlockedNodeStore;
+
+        for (arity, num_leaves) in [(2, 33), (3, 20), (4, 64), (16, 600)] {
+            let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+            let mut other = new_merkle_crhf_with_node_store::<TinySha3HashFunc, _>(
+                arity, num_leaves, BlockedNodeStore::new(arity, 2),
+            );
+
+            for num_updates in [1, num_leaves / 3, num_leaves] {
+                let updates = crate::random_updates(&mut thread_rng(), num_leaves, num_updates);
+
+                merkle.update_leaves(updates.clone());
+                other.update_leaves(updates);
+                assert_eq!(merkle.root_hash(), other.root_hash());
+            }
+            assert_eq!(merkle.prove_batch(&[0, 5]).to_bytes(), other.prove_batch(&[0, 5]).to_bytes());
+        }
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mmap_node_store() {
+        use crate::merkle_crhf::new_merkle_crhf_with_node_store;
+        use crate::merkle_pp::new_merklepp_with_node_store;
+        use crate::node_store::MmapNodeStore;
+        use crate::verkle::new_verkle_with_node_store;
+
+        let num_leaves = 50;
+        let updates = random_leaf_positions(&mut thread_rng(), num_leaves, 20).map(|pos| (pos, leaf_data(pos))).collect::<Vec<_>>();
+        let dir = std::env::temp_dir();
+        let pid = std::process::id();
+
+        let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(4, num_leaves);
+        let path = dir.join(format!("merkle-race-{}-crhf.nodes", pid));
+        let mut other = new_merkle_crhf_with_node_store::<TinySha3HashFunc, _>(
+            4, num_leaves, MmapNodeStore::create(&path, 33).unwrap(),
+        );
+        merkle.update_leaves(updates.clone());
+        other.update_leaves(updates.clone());
+        assert_eq!(merkle.root_hash(), other.root_hash());
+        std::fs::remove_file(path).unwrap();
+
+        let mut merklepp = new_merklepp_from_leaves::<CompRistBlakeIncHash, RistBlakeIncHash>(4, num_leaves);
+        let path = dir.join(format!("merkle-race-{}-merklepp.nodes", pid));
+        let mut other = new_merklepp_with_node_store::<CompRistBlakeIncHash, RistBlakeIncHash, _>(
+            4, num_leaves, MmapNodeStore::create(&path, 64).unwrap(),
+        );
+        merklepp.update_leaves(updates.clone());
+        other.update_leaves(updates.clone());
+        assert_eq!(merklepp.root_hash(), other.root_hash());
+        std::fs::remove_file(path).unwrap();
+
+        let bases = random_bases(4);
+        let mut verkle = new_verkle_from_leaves(4, num_leaves, bases.clone());
+        let path = dir.join(format!("merkle-race-{}-verkle.nodes", pid));
+        let mut other = new_verkle_with_node_store(
+            4, num_leaves, bases, MmapNodeStore::create(&path, 34).unwrap(),
+        );
+        verkle.update_leaves(updates.clone());
+        other.update_leaves(updates);
+        assert_eq!(verkle.root_hash(), other.root_hash());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(feature = "disk")]
+    #[test]
+    fn disk_node_store() {
+        use crate::merkle_crhf::new_merkle_crhf_with_node_store;
+        use crate::node_store::DiskNodeStore;
+        use crate::verkle::new_verkle_with_node_store;
+
+        let num_leaves = 50;
+        let updates = random_leaf_positions(&mut thread_rng(), num_leaves, 20).map(|pos| (pos, leaf_data(pos))).collect::<Vec<_>>();
+        let dir = std::env::temp_dir().join(format!("merkle-race-{}-disk", std::process::id()));
+
+        let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(4, num_leaves);
+        let mut other = new_merkle_crhf_with_node_store::<TinySha3HashFunc, _>(
+            4, num_leaves, DiskNodeStore::open(dir.join("crhf"), 1 << 20).unwrap(),
+        );
+        for batch in updates.chunks(7) {
+            merkle.update_leaves(batch.to_vec());
+            other.update_leaves(batch.to_vec());
+            assert_eq!(merkle.root_hash(), other.root_hash());
+        }
+
+        // deleting every leaf that was set empties the tree again
+        let positions = updates.iter().map(|(pos, _)| *pos).collect::<Vec<_>>();
+        merkle.delete_leaves(&positions);
+        other.delete_leaves(&positions);
+        assert_eq!(other.root_hash(), MerkleHashValue::default());
+
+        let bases = random_bases(4);
+        let mut verkle = new_verkle_from_leaves(4, num_leaves, bases.clone());
+        let mut other = new_verkle_with_node_store(
+            4, num_leaves, bases, DiskNodeStore::open(dir.join("verkle"), 1 << 20).unwrap(),
+        );
+        verkle.update_leaves(updates.clone());
+        other.update_leaves(updates);
+        assert_eq!(verkle.root_hash(), other.root_hash());
+
+        drop(other);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn save_and_load() {
+        let num_leaves = 30;
+        let updates = random_leaf_positions(&mut thread_rng(), num_leaves, 10).map(|pos| (pos, leaf_data(pos))).collect::<Vec<_>>();
+        let path = std::env::temp_dir().join(format!("merkle-race-{}-snapshot", std::process::id()));
+
+        let bases = random_bases(3);
+        let mut verkle = new_verkle_from_leaves(3, num_leaves, bases.clone());
+        verkle.update_leaves(updates);
+        verkle.save(&path).unwrap();
+
+        assert_eq!(saved_shape(&path).unwrap(), (3, num_leaves));
+        let mut loaded = new_verkle_from_leaves(3, num_leaves, bases);
+        loaded.load(&path).unwrap();
+        assert_eq!(loaded.root_hash(), verkle.root_hash());
+
+        // the loaded tree can be updated further, just like the original
+        let more_updates = vec![(0, leaf_data(100)), (num_leaves - 1, leaf_data(101))];
+        verkle.update_leaves(more_updates.clone());
+        loaded.update_leaves(more_updates);
+        assert_eq!(loaded.root_hash(), verkle.root_hash());
+
+        // cannot load into a tree of a different shape
+        let mut other = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(3, num_leaves + 1);
+        assert!(other.load(&path).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn verkle_bases_file() {
+        use crate::verkle::{deterministic_bases, VerkleBases, VerkleComm, VerkleConfig, VerkleHasher};
+
+        let path = std::env::temp_dir().join(format!("merkle-race-{}-bases", std::process::id()));
+        let updates = vec![(1, leaf_data(1)), (7, leaf_data(7)), (8, leaf_data(8))];
+
+        // the bases are the same on every run, and distinct
+        assert_eq!(deterministic_bases(4), deterministic_bases(4));
+        assert_eq!(deterministic_bases(4).iter().map(|base| base.compress()).collect::<HashSet<_>>().len(), 4);
+
+        let (created, loaded) = VerkleBases::load_or_create(&path, 4).unwrap();
+        assert!(!loaded);
+        let (bases, loaded) = VerkleBases::load_or_create(&path, 4).unwrap();
+        assert!(loaded);
+        assert_eq!(bases.bases(), created.bases());
+
+        // a hasher with the loaded tables agrees with one that computes them
+        let config = VerkleConfig { multiexp_cutoff: 4, base_tables: true };
+        let mut verkle = new_verkle_from_leaves(4, 20, deterministic_bases(4));
+        let hasher = VerkleHasher::<VerkleComm>::with_bases(bases, config);
+        let mut other: AbstractMerkle<String, VerkleComm, _> = AbstractMerkle::with_num_leaves(4, 20, hasher);
+        verkle.update_leaves(updates.clone());
+        other.update_leaves(updates);
+        assert_eq!(other.root_hash(), verkle.root_hash());
+
+        // cannot load bases for a different arity
+        assert!(VerkleBases::load_or_create(&path, 3).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    // builds the tree from scratch, and checks it matches a tree where the leaves were set in a couple
+    // of batches of updates
+    fn test_build<HashType, Hasher>(
+        merkle: &mut AbstractMerkle<String, HashType, Hasher>,
+        other: &mut AbstractMerkle<String, HashType, Hasher>,
+    )
+    where
+        HashType: Default + Clone + PartialEq + std::fmt::Debug,
+        Hasher: TreeHasherFunc<String, HashType>,
+    {
+        let num_leaves = merkle.num_leaves();
+
+        merkle.build_from_leaves((0..num_leaves).map(leaf_data).collect());
+
+        let positions = (0..num_leaves).collect::<Vec<usize>>();
+        for round in positions.chunks(num_leaves.div_ceil(2)) {
+            other.update_leaves(round.iter().map(|pos| (*pos, leaf_data(*pos))).collect());
+        }
+
+        assert_eq!(merkle.root_hash(), other.root_hash());
+
+        // a pre-populated tree (e.g., Verkle, whose parents are then non-empty) can keep being updated
+        for _ in 0..3 {
+            let updates = crate::random_updates(&mut thread_rng(), num_leaves, num_leaves / 3 + 1);
+
+            merkle.update_leaves(updates.clone());
+            other.update_leaves(updates);
+
+            assert_eq!(merkle.root_hash(), other.root_hash());
+        }
+    }
+
+    #[test]
+    fn build_from_leaves() {
+        for arity in [2, 3, 4] {
+            for num_leaves in [arity, arity * arity + 1, 50] {
+                test_tree_pairs!(arity, num_leaves, test_build);
+            }
+        }
+    }
+
+    #[test]
+    fn recompute_root_matches_updates() {
+        let mut rng = thread_rng();
+
+        for arity in [2, 3, 5] {
+            for num_leaves in [2, 7, 30] {
+                let updates = random_leaf_positions(&mut rng, num_leaves, num_leaves / 2)
+                    .map(|pos| (pos, leaf_data(pos)))
+                    .collect::<Vec<_>>();
+
+                let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+                merkle.update_leaves(updates.clone());
+                assert_eq!(merkle.recompute_root(), merkle.root_hash());
+
+                let mut merklepp = new_merklepp_from_leaves::<CompRistBlakeIncHash, RistBlakeIncHash>(arity, num_leaves);
+                merklepp.update_leaves(updates.clone());
+                assert_eq!(merklepp.recompute_root(), merklepp.root_hash());
+
+                let bases = random_bases(arity);
+                let mut verkle = new_verkle_from_leaves(arity, num_leaves, bases);
+                verkle.update_leaves(updates);
+                assert_eq!(verkle.recompute_root(), verkle.root_hash());
+
+                // a corrupted node is caught
+                let leaf_idx = verkle.get_leaf_idx(0);
+                let corrupted = verkle.hasher.hash_leaf_data(0, "corrupted".to_owned());
+                verkle.set_node_hash(&leaf_idx, corrupted);
+                assert_ne!(verkle.recompute_root(), verkle.root_hash());
+            }
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn build_from_leaves_parallel_matches_sequential() {
+        for (arity, num_leaves) in [(2, 33), (3, 10), (4, 64)] {
+            let leaves = (0..num_leaves).map(leaf_data).collect::<Vec<_>>();
+            let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+            let mut other = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+
+            merkle.build_from_leaves(leaves.clone());
+            other.build_from_leaves_parallel(leaves.clone());
+            assert_eq!(merkle.root_hash(), other.root_hash());
+            assert_eq!(merkle.hasher.get_stats(), other.hasher.get_stats());
+
+            let bases = random_bases(arity);
+            let mut verkle = new_verkle_from_leaves(arity, num_leaves, bases.clone());
+            let mut other = new_verkle_from_leaves(arity, num_leaves, bases);
+
+            verkle.build_from_leaves(leaves.clone());
+            other.build_from_leaves_parallel(leaves);
+            assert_eq!(verkle.root_hash(), other.root_hash());
+        }
+    }
+
+    #[test]
+    fn unsorted_and_duplicate_updates() {
+        let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(3, 20);
+        let mut other = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(3, 20);
+
+        merkle.update_leaves(vec![
+            (7, leaf_data(0)), (2, leaf_data(1)), (15, leaf_data(2)), (7, leaf_data(3)), (0, leaf_data(4)),
+        ]);
+        other.update_leaves(vec![(0, leaf_data(4)), (2, leaf_data(1)), (7, leaf_data(3)), (15, leaf_data(2))]);
+        assert_eq!(merkle.root_hash(), other.root_hash());
+
+        merkle.update_leaves_with(
+            vec![(5, "a".to_owned()), (1, "x".to_owned()), (5, "b".to_owned()), (5, "c".to_owned())],
+            |earlier, later| earlier + &later,
+        );
+        other.update_leaves(vec![(1, "x".to_owned()), (5, "abc".to_owned())]);
+        assert_eq!(merkle.root_hash(), other.root_hash());
+    }
+
+    fn test_streaming<HashType, Hasher>(
+        merkle: &mut AbstractMerkle<String, HashType, Hasher>,
+        other: &mut AbstractMerkle<String, HashType, Hasher>,
+    )
+    where
+        HashType: Default + Clone + PartialEq + std::fmt::Debug,
+        Hasher: TreeHasherFunc<String, HashType>,
+    {
+        let num_leaves = merkle.num_leaves();
+
+        for _ in 0..2 {
+            let positions = random_leaf_positions(&mut thread_rng(), num_leaves, num_leaves / 2 + 1).collect::<Vec<usize>>();
+
+            // repeat every other update, but with different data, which should win
+            merkle.update_leaves_streaming(positions.iter().flat_map(|pos| {
+                let repeats = if pos % 2 == 0 { vec![leaf_data(0), leaf_data(*pos)] } else { vec![leaf_data(*pos)] };
+
+                repeats.into_iter().map(move |data| (*pos, data))
+            }));
+            other.update_leaves(positions.iter().map(|pos| (*pos, leaf_data(*pos))).collect());
+
+            assert_eq!(merkle.root_hash(), other.root_hash());
+        }
+    }
+
+    #[test]
+    fn streaming_updates() {
+        for arity in [2, 3, 4] {
+            for num_leaves in [arity, arity * arity - 1, arity * arity + 1, 50] {
+                test_tree_pairs!(arity, num_leaves, test_streaming);
             }
         }
     }
+
+    #[test]
+    fn level_roots_locate_differences() {
+        let (arity, num_leaves) = (3, 20);
+        let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+        let other = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+
+        // every node is on exactly one level
+        let nodes = (0..5).flat_map(|level| merkle.level_nodes(level)).collect::<Vec<_>>();
+        assert_eq!(nodes, (0..merkle.num_nodes()).map(NodeIndex).collect::<Vec<_>>());
+        assert!(merkle.level_roots(5).is_empty());
+        assert!(merkle.level_roots(usize::MAX).is_empty());
+
+        merkle.update_leaves(vec![(11, leaf_data(11))]);
+        assert_eq!(merkle.level_roots(0), vec![merkle.root_hash()]);
+        assert_eq!(merkle.subtree_root(&NodeIndex::root_node()), Some(merkle.root_hash()));
+        assert_eq!(merkle.subtree_root(&NodeIndex(merkle.num_nodes())), None);
+
+        // a single changed leaf is in a single shard at every level
+        for level in 0..4 {
+            let (roots, other_roots) = (merkle.level_roots(level), other.level_roots(level));
+            assert_eq!(roots.len(), other_roots.len());
+            assert_eq!(roots.iter().zip(other_roots.iter()).filter(|(a, b)| a != b).count(), 1);
+        }
+    }
+
+    #[test]
+    fn iterators_visit_every_node() {
+        let (arity, num_leaves) = (3, 20);
+        let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+        merkle.update_leaves((0..num_leaves).map(|pos| (pos, leaf_data(pos))).collect());
+
+        let nodes = merkle.iter_nodes().collect::<Vec<_>>();
+        assert_eq!(nodes.len(), merkle.num_nodes());
+        assert_eq!(nodes[0], (NodeIndex::root_node(), &merkle.root_hash()));
+
+        for level in 0..5 {
+            let (level_nodes, hashes): (Vec<_>, Vec<_>) = merkle.iter_level(level).map(|(node, hash)| (node, hash.clone())).unzip();
+            assert_eq!(level_nodes, merkle.level_nodes(level));
+            assert_eq!(hashes, merkle.level_roots(level));
+        }
+
+        // the leaves come by position, even though they are on two levels
+        assert!(merkle.has_leaves_on_two_levels());
+        let leaves = merkle.iter_leaves().collect::<Vec<_>>();
+        assert_eq!(leaves.len(), num_leaves);
+        for (pos, (leaf, hash)) in leaves.into_iter().enumerate() {
+            assert_eq!(leaf, merkle.get_leaf_idx(pos));
+            assert_eq!(Some(hash.clone()), merkle.subtree_root(&leaf));
+        }
+    }
+
+    #[test]
+    fn dot_export() {
+        // arity 3 with 10 leaves: node 4 is internal, with the last two leaves as its children
+        let merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(3, 10);
+        let mut dot = vec![];
+        merkle.to_dot(&mut dot, usize::MAX).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+
+        assert!(dot.starts_with("digraph merkle {") && dot.ends_with("}\n"));
+        assert_eq!(dot.matches(" -> ").count(), merkle.num_nodes() - 1);
+        assert_eq!(dot.matches("shape=box").count(), 10);
+        assert!(dot.contains("n4 -> n13;") && dot.contains("13 (leaf 8)"));
+        assert!(!dot.contains("dashed"));
+
+        // only the root and its children, which are cut off
+        let mut dot = vec![];
+        merkle.to_dot(&mut dot, 1).unwrap();
+        let dot = String::from_utf8(dot).unwrap();
+        assert_eq!(dot.matches(" -> ").count(), 3);
+        assert_eq!(dot.matches("dashed").count(), 3);
+    }
+
+    #[test]
+    fn diff_finds_changed_leaves() {
+        for arity in [2, 3, 4] {
+            let num_leaves = 50;
+            let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+            let mut other = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+            assert!(merkle.diff(&other).is_empty());
+
+            let mut positions = random_leaf_positions(&mut thread_rng(), num_leaves, 10).collect::<Vec<usize>>();
+            merkle.update_leaves(positions.iter().map(|pos| (*pos, leaf_data(*pos))).collect());
+            positions.sort();
+            assert_eq!(merkle.diff_leaves(&other), positions);
+            assert_eq!(other.diff_leaves(&merkle), positions);
+
+            // the differing nodes on each level are the children of the differing nodes above them
+            let levels = merkle.diff(&other);
+            assert_eq!(levels[0], vec![NodeIndex::root_node()]);
+            for (level, nodes) in levels.iter().enumerate() {
+                let expected = merkle
+                    .level_nodes(level)
+                    .into_iter()
+                    .filter(|node| merkle.get_node_hash(node) != other.get_node_hash(node))
+                    .collect::<Vec<_>>();
+                assert_eq!(*nodes, expected);
+            }
+            assert!(merkle.level_nodes(levels.len()).iter().all(|node| merkle.get_node_hash(node) == other.get_node_hash(node)));
+
+            // syncing the differing leaves makes the trees equal
+            other.update_leaves(positions.iter().map(|pos| (*pos, leaf_data(*pos))).collect());
+            assert!(merkle.diff(&other).is_empty());
+            assert_eq!(merkle.root_hash(), other.root_hash());
+        }
+    }
+
+    #[test]
+    fn snapshots_keep_old_versions() {
+        let num_leaves = 5000;
+        let mut merkle: AbstractMerkle<String, MerkleHashValue, _, NoLeafStorage, CowNodeStore<MerkleHashValue>> =
+            AbstractMerkle::with_node_store(2, num_leaves, HasherCRHF::<TinySha3HashFunc>::new(2), CowNodeStore::new());
+        merkle.build_from_leaves((0..num_leaves).map(leaf_data).collect());
+
+        let before = merkle.snapshot();
+        assert_eq!(before.root_hash(), merkle.root_hash());
+
+        merkle.update_leaves(vec![(3, "x".to_owned())]);
+        let after = merkle.snapshot();
+        assert_ne!(before.root_hash(), after.root_hash());
+        assert_eq!(after.root_hash(), merkle.root_hash());
+
+        // only the chunks on the updated leaf's path were copied
+        assert_gt!(before.nodes().num_shared_chunks(after.nodes()), 0);
+        assert_lt!(before.nodes().num_shared_chunks(after.nodes()), before.nodes().num_chunks());
+
+        // both versions can still prove the leaf, against their own roots
+        let proof = before.prove_batch(&[3]);
+        assert!(merkle.verify_batch(&before.root_hash(), &[(3, leaf_data(3))], &proof));
+        assert!(!merkle.verify_batch(&after.root_hash(), &[(3, leaf_data(3))], &proof));
+
+        let proof = after.prove_batch(&[3]);
+        assert!(merkle.verify_batch(&after.root_hash(), &[(3, "x".to_owned())], &proof));
+    }
 }
@@ -1,5 +1,10 @@
 use crate::merkle_abstract::AbstractMerkle;
-use crate::hashing_traits::{HASH_LENGTH, HashFuncTrait, TreeHasherFunc};
+use crate::merkle_sparse::SparseMerkle;
+use crate::leaf_storage::NoLeafStorage;
+use crate::node_index::ConstArity;
+use crate::node_store::{MemNodeStore, NodeStore};
+use crate::hashing_traits::{CanonicalBytes, HasherStats, HASH_LENGTH, HashFuncTrait, ParallelTreeHasherFunc, TreeHasherFunc};
+use crate::verify::crhf::CrhfScheme;
 use blake2::digest::generic_array;
 use blake2::{Blake2b, Blake2s256, Digest};
 use digest::consts::U32;
@@ -8,11 +13,18 @@ use more_asserts::assert_le;
 use std::fmt::{Debug, Formatter};
 use std::marker::PhantomData;
 use tiny_keccak::{Hasher, Sha3};
-use sha3::Sha3_256;
+use sha2::Sha256;
+use sha3::{Keccak256, Sha3_256};
 
-#[derive(Default, Clone)]
+#[derive(Default, Clone, PartialEq, Eq)]
 pub struct MerkleHashValue {
-    hash: [u8; HASH_LENGTH],
+    pub(crate) hash: [u8; HASH_LENGTH],
+}
+
+impl AsRef<[u8]> for MerkleHashValue {
+    fn as_ref(&self) -> &[u8] {
+        &self.hash
+    }
 }
 
 impl Debug for MerkleHashValue {
@@ -21,6 +33,19 @@ impl Debug for MerkleHashValue {
     }
 }
 
+// serialized as the raw HASH_LENGTH bytes
+impl CanonicalBytes for MerkleHashValue {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.hash.to_vec()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(MerkleHashValue {
+            hash: bytes.try_into().ok()?,
+        })
+    }
+}
+
 pub struct TinySha3HashFunc(Sha3);
 
 impl HashFuncTrait for TinySha3HashFunc {
@@ -53,6 +78,63 @@ impl HashFuncTrait for Sha3HashFunc {
     }
 }
 
+// Keccak-256 with its original padding (i.e., not NIST's SHA3-256), as used by Ethereum, so that we
+// can compare against the costs of hashing in Ethereum's Merkle Patricia Trie.
+pub struct Keccak256HashFunc(Keccak256);
+
+impl HashFuncTrait for Keccak256HashFunc {
+    fn new() -> Self {
+        Keccak256HashFunc(Keccak256::new())
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        self.0.update(buf);
+    }
+
+    fn finalize(self, buf: &mut [u8; HASH_LENGTH]) {
+        self.0.finalize_into(GenericArray::from_mut_slice(buf));
+    }
+}
+
+// NOTE: The sha2 crate detects SHA-NI (or ARMv8 SHA) extensions at runtime and uses them if available.
+pub struct Sha256HashFunc(Sha256);
+
+impl HashFuncTrait for Sha256HashFunc {
+    fn new() -> Self {
+        Sha256HashFunc(Sha256::new())
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        self.0.update(buf);
+    }
+
+    fn finalize(self, buf: &mut [u8; HASH_LENGTH]) {
+        self.0.finalize_into(GenericArray::from_mut_slice(buf));
+    }
+}
+
+// SHA-256 with no domain separation, so that a binary tree's parents are SHA-256(left || right), as in
+// Ethereum's SSZ Merkleization (see ssz.rs). Its leaves are SHA-256(data).
+pub struct SszSha256HashFunc(Sha256);
+
+impl HashFuncTrait for SszSha256HashFunc {
+    fn new() -> Self {
+        SszSha256HashFunc(Sha256::new())
+    }
+
+    fn new_with_domain(_domain: &str) -> Self {
+        Self::new()
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        self.0.update(buf);
+    }
+
+    fn finalize(self, buf: &mut [u8; HASH_LENGTH]) {
+        self.0.finalize_into(GenericArray::from_mut_slice(buf));
+    }
+}
+
 // IIRC, faster for 32-bit platforms
 pub struct Blake2sHashFunc(Blake2s256);
 
@@ -87,20 +169,179 @@ impl HashFuncTrait for Blake2bHashFunc {
     }
 }
 
+// Blake3 hashes leaves and internal nodes in its derive-key mode, with a different context string for
+// each, rather than prepending a domain to the input.
+pub struct Blake3HashFunc(blake3::Hasher);
+
+impl HashFuncTrait for Blake3HashFunc {
+    fn new() -> Self {
+        Blake3HashFunc(blake3::Hasher::new())
+    }
+
+    fn new_with_domain(domain: &str) -> Self {
+        let context = format!("merkle-race {}", domain);
+
+        Blake3HashFunc(blake3::Hasher::new_derive_key(&context))
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        self.0.update(buf);
+    }
+
+    fn finalize(self, buf: &mut [u8; HASH_LENGTH]) {
+        *buf = *self.0.finalize().as_bytes();
+    }
+}
+
+// What HasherCRHF hashes, besides the leaf data and the children's hashes, so that its roots can be
+// matched against other implementations' (by configuring the same scheme), and so that different
+// schemes never produce the same root. The inputs are encoded by merkle_race_verify::crhf, so that
+// proofs can be verified without this crate, and the domain is passed to the hash function via
+// HashFuncTrait::new_with_domain().
+//
+// Known-answer tests for every version are pinned in docs/test-vectors/crhf.json.
+//
+// NOTE: Nodes do not bind their height, since hashers are never told where a node is. But the leaf
+// and internal domains already tell leaves and internal nodes apart, and internal nodes bind the
+// positions of their children by hashing them in order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HashingScheme {
+    pub version: u8,
+    pub leaf_domain: String,
+    pub internal_domain: String,
+    pub bind_offsets: bool,    // whether leaves bind their offset relative to their parent
+    pub bind_arity: bool,      // whether internal nodes bind the tree's arity
+    pub length_prefixes: bool, // whether the leaf data is prefixed by its length, and the children by their #
+    pub child_flags: bool,     // whether each child is prefixed by whether it is empty (0) or not (1)
+}
+
+impl HashingScheme {
+    // The scheme CRHF trees have always used, which only separates the leaf and internal domains
+    pub fn v0() -> Self {
+        HashingScheme::from(CrhfScheme::V0)
+    }
+
+    // Same domains as v0, but binds the leaves' offsets, the arity, the lengths of the leaves and the #
+    // of children, and the version
+    pub fn v1() -> Self {
+        HashingScheme::from(CrhfScheme::V1)
+    }
+
+    // The strict (i.e., audit) scheme: v1, with a flag before every child saying whether it is empty.
+    // Every child hash has HASH_LENGTH bytes, so v1's # of children already lets an input be split into
+    // its children. But an empty child is hashed as HASH_LENGTH zero bytes, which v1 cannot tell apart
+    // from a non-empty child whose hash happens to be all zeros, while the flag does.
+    //
+    // NOTE: v0 roots are ambiguous across arities: e.g., a tree of arity 2 and one of arity 4 with the
+    // same 2 leaves have the same root, since both roots hash the same 2 children.
+    pub fn strict() -> Self {
+        HashingScheme::from(CrhfScheme::STRICT)
+    }
+
+    // returns the scheme with the specified version, if any
+    pub fn from_version(version: u8) -> Option<Self> {
+        CrhfScheme::from_version(version).map(HashingScheme::from)
+    }
+
+    // returns the scheme's parameters, as merkle_race_verify::crhf takes them (e.g., to verify proofs)
+    pub fn encoding(&self) -> CrhfScheme<'_> {
+        CrhfScheme {
+            version: self.version,
+            leaf_domain: &self.leaf_domain,
+            internal_domain: &self.internal_domain,
+            bind_offsets: self.bind_offsets,
+            bind_arity: self.bind_arity,
+            length_prefixes: self.length_prefixes,
+            child_flags: self.child_flags,
+        }
+    }
+}
+
+impl From<CrhfScheme<'_>> for HashingScheme {
+    fn from(scheme: CrhfScheme<'_>) -> Self {
+        HashingScheme {
+            version: scheme.version,
+            leaf_domain: scheme.leaf_domain.to_owned(),
+            internal_domain: scheme.internal_domain.to_owned(),
+            bind_offsets: scheme.bind_offsets,
+            bind_arity: scheme.bind_arity,
+            length_prefixes: scheme.length_prefixes,
+            child_flags: scheme.child_flags,
+        }
+    }
+}
+
+// NOTE: The default is v1, so that every root binds the version of the scheme it was hashed under. v0
+// must be asked for explicitly, e.g., to check roots computed before schemes were versioned, or to
+// match SSZ's roots (see ssz.rs).
+impl Default for HashingScheme {
+    fn default() -> Self {
+        HashingScheme::v1()
+    }
+}
+
 pub struct HasherCRHF<HashFunc> {
-    num_hashes: usize,
+    stats: HasherStats,
     arity: usize,
+    scheme: HashingScheme,
     phantom: PhantomData<HashFunc>,
 }
 
 impl<HashFunc> HasherCRHF<HashFunc> {
-    fn new(arity: usize) -> Self {
+    pub fn new(arity: usize) -> Self {
+        HasherCRHF::with_scheme(arity, HashingScheme::default())
+    }
+
+    pub fn with_scheme(arity: usize, scheme: HashingScheme) -> Self {
         HasherCRHF {
-            num_hashes: 0,
+            stats: HasherStats::default(),
             arity,
+            scheme,
             phantom: Default::default(),
         }
     }
+
+    pub fn scheme(&self) -> &HashingScheme {
+        &self.scheme
+    }
+}
+
+impl<HashFunc: HashFuncTrait> HasherCRHF<HashFunc> {
+    fn leaf_hash(&self, offset: usize, data: String) -> MerkleHashValue {
+        let mut hasher = HashFunc::new_with_domain(&self.scheme.leaf_domain);
+        self.scheme.encoding().encode_leaf(offset, data.as_bytes(), |bytes| hasher.update(bytes));
+
+        let mut hv = MerkleHashValue::default();
+        hasher.finalize(&mut hv.hash);
+        hv
+    }
+
+    fn internal_hash(
+        &self,
+        old_children: &mut [MerkleHashValue],
+        new_children: &[(usize, MerkleHashValue)],
+    ) -> MerkleHashValue {
+        assert_le!(old_children.len(), self.arity);
+
+        // replace old hashes with new ones
+        for (pos, hash) in new_children {
+            old_children[*pos] = hash.clone(); // TODO(Perf): avoid clone?
+        }
+
+        // A parent with only empty children (e.g., because they were all deleted) is itself empty, so
+        // that deleting leaves brings the tree back to the state it was in before they were set.
+        let empty = MerkleHashValue::default();
+        if old_children.iter().all(|h| *h == empty) {
+            return empty;
+        }
+
+        let mut hasher = HashFunc::new_with_domain(&self.scheme.internal_domain);
+        self.scheme.encoding().encode_internal(self.arity, old_children, |bytes| hasher.update(bytes));
+
+        let mut hv = MerkleHashValue::default();
+        hasher.finalize(&mut hv.hash);
+        hv
+    }
 }
 
 impl<HashFunc> TreeHasherFunc<String, MerkleHashValue> for HasherCRHF<HashFunc>
@@ -108,52 +349,60 @@ where
     HashFunc: HashFuncTrait,
 {
     fn get_num_computations(&self) -> usize {
-        self.num_hashes
+        self.stats.sym_hashes
+    }
+
+    fn get_stats(&self) -> HasherStats {
+        self.stats
     }
 
     // fn is_incremental(&self) -> bool {
     //     false
     // }
 
-    fn hash_leaf_data(&mut self, _offset: usize, data: String) -> MerkleHashValue {
-        self.num_hashes += 1;
-
-        let mut hasher = HashFunc::new();
-        hasher.update("leaf:".as_bytes());
-        //hasher.update( offset.to_string().as_bytes());
-        //hasher.update( ":".as_bytes());
-        hasher.update(data.as_bytes());
+    fn hash_leaf_data(&mut self, offset: usize, data: String) -> MerkleHashValue {
+        self.stats.sym_hashes += 1;
 
-        let mut hv = MerkleHashValue::default();
-        hasher.finalize(&mut hv.hash);
-        hv
+        self.leaf_hash(offset, data)
     }
 
     fn hash_nodes(
         &mut self,
         _old_parent_hash: MerkleHashValue,
-        old_children: &mut Vec<MerkleHashValue>,
-        new_children: &Vec<(usize, MerkleHashValue)>,
+        old_children: &mut [MerkleHashValue],
+        new_children: &[(usize, MerkleHashValue)],
     ) -> MerkleHashValue {
-        self.num_hashes += 1;
+        self.stats.sym_hashes += 1;
 
-        assert_le!(old_children.len(), self.arity);
+        self.internal_hash(old_children, new_children)
+    }
+}
 
-        let mut hasher = HashFunc::new();
-        hasher.update(("internal:").as_bytes());
+impl<HashFunc> ParallelTreeHasherFunc<String, MerkleHashValue> for HasherCRHF<HashFunc>
+where
+    HashFunc: HashFuncTrait + Sync,
+{
+    fn hash_leaf_data_shared(&self, offset: usize, data: String) -> MerkleHashValue {
+        self.leaf_hash(offset, data)
+    }
 
-        // replace old hashes with new ones
-        for (pos, hash) in new_children {
-            old_children[*pos] = hash.clone(); // TODO(Perf): avoid clone?
-        }
+    fn count_leaf_hashes(&mut self, num_leaves: usize) {
+        self.stats.sym_hashes += num_leaves;
+    }
 
-        for h in old_children {
-            hasher.update(&h.hash[..]);
-        }
+    fn hash_nodes_shared(
+        &self,
+        _old_parent_hash: MerkleHashValue,
+        old_children: &mut [MerkleHashValue],
+        new_children: &[(usize, MerkleHashValue)],
+    ) -> (MerkleHashValue, HasherStats) {
+        let stats = HasherStats { sym_hashes: 1, ..Default::default() };
 
-        let mut hv = MerkleHashValue::default();
-        hasher.finalize(&mut hv.hash);
-        hv
+        (self.internal_hash(old_children, new_children), stats)
+    }
+
+    fn count_node_hashes(&mut self, stats: HasherStats) {
+        self.stats += stats;
     }
 }
 
@@ -181,10 +430,76 @@ where
     AbstractMerkle::new(arity, height, hasher)
 }
 
+// returns a tree which hashes its nodes as specified by 'scheme' (see HashingScheme)
+pub fn new_merkle_crhf_with_scheme<HashFunc>(
+    arity: usize,
+    num_leaves: usize,
+    scheme: HashingScheme,
+) -> AbstractMerkle<String, MerkleHashValue, HasherCRHF<HashFunc>>
+where
+    HashFunc: HashFuncTrait,
+{
+    let hasher = HasherCRHF::with_scheme(arity, scheme);
+
+    AbstractMerkle::with_num_leaves(arity, num_leaves, hasher)
+}
+
+// returns a tree whose nodes are kept in 'nodes' (e.g., on disk)
+pub fn new_merkle_crhf_with_node_store<HashFunc, Nodes>(
+    arity: usize,
+    num_leaves: usize,
+    nodes: Nodes,
+) -> AbstractMerkle<String, MerkleHashValue, HasherCRHF<HashFunc>, NoLeafStorage, Nodes>
+where
+    HashFunc: HashFuncTrait,
+    Nodes: NodeStore<MerkleHashValue>,
+{
+    let hasher = HasherCRHF::new(arity);
+
+    AbstractMerkle::with_node_store(arity, num_leaves, hasher, nodes)
+}
+
+// returns a tree whose arity is fixed at compile time, which speeds up the index arithmetic when updating
+pub fn new_merkle_crhf_const_arity<HashFunc, const ARITY: usize>(
+    num_leaves: usize,
+) -> AbstractMerkle<String, MerkleHashValue, HasherCRHF<HashFunc>, NoLeafStorage, MemNodeStore<MerkleHashValue>, ConstArity<ARITY>>
+where
+    HashFunc: HashFuncTrait,
+{
+    let hasher = HasherCRHF::new(ARITY);
+
+    AbstractMerkle::with_num_leaves(ARITY, num_leaves, hasher)
+}
+
+// returns a sparse tree with 2^256 leaves
+pub fn new_sparse_merkle_crhf<HashFunc>(
+    arity: usize,
+) -> SparseMerkle<String, MerkleHashValue, HasherCRHF<HashFunc>>
+where
+    HashFunc: HashFuncTrait,
+{
+    let hasher = HasherCRHF::new(arity);
+
+    SparseMerkle::new(arity, hasher)
+}
+
+pub fn new_sparse_merkle_crhf_from_height<HashFunc>(
+    arity: usize,
+    height: usize,
+) -> SparseMerkle<String, MerkleHashValue, HasherCRHF<HashFunc>>
+where
+    HashFunc: HashFuncTrait,
+{
+    let hasher = HasherCRHF::new(arity);
+
+    SparseMerkle::with_height(arity, height, hasher)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{max_leaves, random_updates};
+    use rand::thread_rng;
 
     #[test]
     fn bvt_arity_2_examples() {
@@ -218,11 +533,191 @@ mod tests {
             }
 
             println!();
-            let updates = random_updates(num_leaves, num_updates);
+            let updates = random_updates(&mut thread_rng(), num_leaves, num_updates);
             merkle.update_leaves(updates);
         }
     }
 
+    #[test]
+    fn root_hash_is_independent_of_batching() {
+        for (arity, num_leaves) in [(2, 16), (3, 10), (16, 600)] {
+            let updates = random_updates(&mut thread_rng(), num_leaves, num_leaves / 2);
+
+            let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+            merkle.update_leaves(updates.clone());
+
+            let mut other = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+            for batch in updates.chunks(3) {
+                other.update_leaves(batch.to_vec());
+            }
+
+            assert_ne!(merkle.root_hash(), MerkleHashValue::default());
+            assert_eq!(merkle.root_hash(), other.root_hash());
+
+            let bytes = merkle.root_hash().to_bytes();
+            assert_eq!(bytes.len(), HASH_LENGTH);
+            assert_eq!(MerkleHashValue::from_bytes(&bytes), Some(merkle.root_hash()));
+            assert_eq!(MerkleHashValue::from_bytes(&bytes[1..]), None);
+        }
+    }
+
+    #[test]
+    fn blake3_uses_derive_key_mode() {
+        let leaf = HasherCRHF::<Blake3HashFunc>::with_scheme(4, HashingScheme::v0()).leaf_hash(0, "data".to_owned());
+        let expected = blake3::Hasher::new_derive_key("merkle-race leaf:").update(b"data").finalize();
+        assert_eq!(leaf.hash, *expected.as_bytes());
+
+        let mut merkle = new_merkle_crhf_from_leaves::<Blake3HashFunc>(4, 20);
+        test_with_random_updates(20, &mut merkle);
+        assert_ne!(merkle.root_hash(), MerkleHashValue::default());
+    }
+
+    #[test]
+    fn hashing_schemes() {
+        let v1 = HasherCRHF::<Sha3HashFunc>::with_scheme(4, HashingScheme::v1());
+
+        let mut expected = MerkleHashValue::default();
+        let mut hash_func = Sha3HashFunc::new_with_domain("leaf:");
+        hash_func.update(&[1]);
+        hash_func.update(&3u32.to_le_bytes());
+        hash_func.update(&4u64.to_le_bytes());
+        hash_func.update(b"data");
+        hash_func.finalize(&mut expected.hash);
+        assert_eq!(v1.leaf_hash(3, "data".to_owned()), expected);
+        assert_ne!(v1.leaf_hash(2, "data".to_owned()), expected);
+
+        // v0 is what trees hashed before schemes were configurable, while the default scheme is v1
+        assert_eq!(HasherCRHF::<Sha3HashFunc>::new(4).scheme(), &HashingScheme::v1());
+        let v0 = HasherCRHF::<Sha3HashFunc>::with_scheme(4, HashingScheme::v0());
+        let mut expected = MerkleHashValue::default();
+        let mut hash_func = Sha3HashFunc::new_with_domain("leaf:");
+        hash_func.update(b"data");
+        hash_func.finalize(&mut expected.hash);
+        assert_eq!(v0.leaf_hash(3, "data".to_owned()), expected);
+        assert_eq!(v0.leaf_hash(2, "data".to_owned()), expected);
+
+        let updates = random_updates(&mut thread_rng(), 20, 5);
+        let mut merkle = new_merkle_crhf_with_scheme::<Sha3HashFunc>(4, 20, HashingScheme::v1());
+        let mut other = new_merkle_crhf_with_scheme::<Sha3HashFunc>(4, 20, HashingScheme::v0());
+        merkle.update_leaves(updates.clone());
+        other.update_leaves(updates.clone());
+        assert_ne!(merkle.root_hash(), other.root_hash());
+
+        let root = merkle.root_hash();
+        let proof = merkle.prove_batch(&[updates[0].0]);
+        assert!(merkle.verify_batch(&root, &updates[..1], &proof));
+        assert!(!other.verify_batch(&root, &updates[..1], &proof));
+    }
+
+    // returns the root of a tree of the specified shape with the specified leaves set, under 'scheme'
+    fn kat_root<HashFunc: HashFuncTrait>(
+        arity: usize,
+        num_leaves: usize,
+        scheme: HashingScheme,
+        leaves: Vec<(usize, String)>,
+    ) -> String {
+        let mut merkle = new_merkle_crhf_with_scheme::<HashFunc>(arity, num_leaves, scheme);
+        merkle.update_leaves(leaves);
+
+        hex::encode(merkle.root_hash().hash)
+    }
+
+    #[test]
+    fn known_answers() {
+        let vectors: serde_json::Value =
+            serde_json::from_str(include_str!("../docs/test-vectors/crhf.json")).unwrap();
+
+        for vector in vectors.as_array().unwrap() {
+            let arity = vector["arity"].as_u64().unwrap() as usize;
+            let num_leaves = vector["num_leaves"].as_u64().unwrap() as usize;
+            let scheme = HashingScheme::from_version(vector["version"].as_u64().unwrap() as u8).unwrap();
+            let leaves = vector["leaves"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|leaf| (leaf[0].as_u64().unwrap() as usize, leaf[1].as_str().unwrap().to_owned()))
+                .collect::<Vec<_>>();
+
+            let root = match vector["hash"].as_str().unwrap() {
+                "sha3" => kat_root::<Sha3HashFunc>(arity, num_leaves, scheme, leaves),
+                "sha256" => kat_root::<Sha256HashFunc>(arity, num_leaves, scheme, leaves),
+                "blake3" => kat_root::<Blake3HashFunc>(arity, num_leaves, scheme, leaves),
+                hash => panic!("Unknown hash function {}", hash),
+            };
+            assert_eq!(root, vector["root"].as_str().unwrap(), "Wrong root for {}", vector);
+        }
+    }
+
+    #[test]
+    fn strict_scheme() {
+        let leaves = vec![(0, "a".to_owned()), (1, "b".to_owned())];
+
+        // both roots hash the same two leaves, so only binding the arity tells them apart
+        let (binary, quaternary) = (
+            kat_root::<Sha3HashFunc>(2, 2, HashingScheme::v0(), leaves.clone()),
+            kat_root::<Sha3HashFunc>(4, 2, HashingScheme::v0(), leaves.clone()),
+        );
+        assert_eq!(binary, quaternary);
+
+        let (binary, quaternary) = (
+            kat_root::<Sha3HashFunc>(2, 2, HashingScheme::strict(), leaves.clone()),
+            kat_root::<Sha3HashFunc>(4, 2, HashingScheme::strict(), leaves.clone()),
+        );
+        assert_ne!(binary, quaternary);
+        assert_ne!(binary, kat_root::<Sha3HashFunc>(2, 2, HashingScheme::v1(), leaves.clone()));
+        assert_ne!(binary, kat_root::<Sha3HashFunc>(2, 3, HashingScheme::strict(), leaves));
+
+        let strict = HasherCRHF::<Sha3HashFunc>::with_scheme(2, HashingScheme::strict());
+        let (left, right) = (strict.leaf_hash(0, "a".to_owned()), strict.leaf_hash(1, "b".to_owned()));
+        let strict_hash = |children: [(u8, &MerkleHashValue); 2]| {
+            let mut hash = MerkleHashValue::default();
+            let mut hash_func = Sha3HashFunc::new_with_domain("internal:");
+            hash_func.update(&[2]);
+            hash_func.update(&2u32.to_le_bytes());
+            hash_func.update(&2u32.to_le_bytes());
+            for (flag, child) in children {
+                hash_func.update(&[flag]);
+                hash_func.update(&child.hash);
+            }
+            hash_func.finalize(&mut hash.hash);
+            hash
+        };
+        assert_eq!(binary, hex::encode(strict_hash([(1, &left), (1, &right)]).hash));
+
+        // an empty child is flagged as such, unlike a non-empty child whose hash is all zeros
+        let empty = MerkleHashValue::default();
+        let root = strict.internal_hash(&mut [left.clone(), empty.clone()], &[]);
+        assert_eq!(root, strict_hash([(1, &left), (0, &empty)]));
+        assert_ne!(root, strict_hash([(1, &left), (1, &empty)]));
+    }
+
+    #[test]
+    fn keccak256_is_not_sha3() {
+        let mut hash = [0u8; HASH_LENGTH];
+        Keccak256HashFunc::new().finalize(&mut hash);
+
+        // Ethereum's hash of the empty string
+        assert_eq!(hex::encode(hash), "c5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470");
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_preprocessing_matches_sequential() {
+        for (arity, num_leaves) in [(2, 16), (3, 10), (16, 600)] {
+            let updates = random_updates(&mut thread_rng(), num_leaves, num_leaves / 2);
+
+            let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+            merkle.update_leaves(updates.clone());
+
+            let mut other = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+            let (queue, _) = other.preprocess_leaves_parallel(updates);
+            other.update_preprocessed_leaves(queue);
+
+            assert_eq!(merkle.root_hash(), other.root_hash());
+            assert_eq!(merkle.hasher.get_num_computations(), other.hasher.get_num_computations());
+        }
+    }
+
     #[test]
     fn bvt_perfect() {
         for arity in [2, 4, 8, 16] {
@@ -317,4 +812,64 @@ mod tests {
         ];
         merkle.update_leaves(updates);
     }
+
+    // checks proofs with nothing but merkle_race_verify (and the Digest of the hash function), as a
+    // no_std verifier would
+    #[test]
+    fn verify_crate_checks_proofs() {
+        use merkle_race_verify::{crhf::CrhfScheme, TreeShape};
+
+        fn check<HashFunc: HashFuncTrait, D: Digest>(arity: usize, num_leaves: usize, version: u8) {
+            let scheme = HashingScheme::from_version(version).unwrap();
+            let mut merkle = new_merkle_crhf_with_scheme::<HashFunc>(arity, num_leaves, scheme);
+            merkle.update_leaves(random_updates(&mut thread_rng(), num_leaves, num_leaves / 2));
+
+            let leaves = [(0, "first".to_owned()), (num_leaves / 3, "third".to_owned()), (num_leaves - 1, "last".to_owned())];
+            merkle.update_leaves(leaves.to_vec());
+            let positions = leaves.iter().map(|(pos, _)| *pos).collect::<Vec<_>>();
+            let proof = merkle.prove_batch(&positions);
+
+            // from here on, only the bytes of the root, the leaves and the proof are used
+            let to_output = |hash: &MerkleHashValue| GenericArray::clone_from_slice(&hash.hash);
+            let root = to_output(&merkle.root_hash());
+            let proof = proof.hashes.iter().map(to_output).collect::<Vec<_>>();
+            let leaves = leaves.iter().map(|(pos, data)| (*pos, data.as_bytes())).collect::<Vec<_>>();
+
+            let scheme = CrhfScheme::from_version(version).unwrap();
+            let shape = TreeShape::new(arity, num_leaves);
+            assert!(scheme.verify_batch::<D>(&shape, &root, &leaves, &proof));
+            assert!(!scheme.verify_batch::<D>(&shape, &root, &leaves[1..], &proof));
+            assert!(!scheme.verify_batch::<D>(&shape, &root, &[(0, b"wrong".as_slice())], &proof));
+        }
+
+        for version in 0..=2 {
+            check::<Sha256HashFunc, Sha256>(2, 100, version);
+            check::<Sha3HashFunc, Sha3_256>(16, 1000, version);
+        }
+    }
+
+    #[test]
+    fn const_arity_matches_dyn_arity() {
+        for num_leaves in [16, 100, 600] {
+            let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(16, num_leaves);
+            let mut other = new_merkle_crhf_const_arity::<TinySha3HashFunc, 16>(num_leaves);
+
+            for num_updates in [1, num_leaves / 2, num_leaves] {
+                let updates = random_updates(&mut thread_rng(), num_leaves, num_updates);
+
+                merkle.update_leaves(updates.clone());
+                other.update_leaves(updates);
+                assert_eq!(merkle.root_hash(), other.root_hash());
+            }
+            let positions = [0, num_leaves - 1];
+            assert_eq!(merkle.prove_batch(&positions).to_bytes(), other.prove_batch(&positions).to_bytes());
+
+            // these hash the children in stack buffers (see TreeArity::Buffer)
+            let leaves = positions.iter().map(|pos| (*pos, format!("leaf {}", pos))).collect::<Vec<_>>();
+            other.update_leaves(leaves.clone());
+            let root = other.root_hash();
+            assert_eq!(other.recompute_root(), root);
+            assert!(other.verify_batch(&root, &leaves, &other.prove_batch(&positions)));
+        }
+    }
 }
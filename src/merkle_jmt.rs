@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use crate::hashing_traits::HashFuncTrait;
+use crate::merkle_abstract::normalize_updates;
+use crate::merkle_crhf::MerkleHashValue;
+use crate::merkle_sparse::{SparseKey, SPARSE_KEY_BITS};
+
+// The # of children of a JMT internal node, one per nibble
+const JMT_ARITY: usize = 16;
+
+// A node's key in storage: the version that created it and the nibbles on the path from the root to it
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct NodeKey {
+    pub version: u64,
+    pub nibble_path: Vec<u8>,
+}
+
+// What an internal node knows about each of its children
+#[derive(Clone, Debug)]
+struct Child {
+    version: u64,
+    hash: MerkleHashValue,
+    is_leaf: bool,
+}
+
+#[derive(Clone, Debug)]
+enum JmtNode {
+    Internal { children: Box<[Option<Child>; JMT_ARITY]> },
+    Leaf { key: SparseKey, value: String },
+}
+
+// A proof that a key maps to some value in a JMT (or that it does not): the leaf found on the key's
+// path (if any) and the sibling hashes on that path, from the root downwards.
+#[derive(Clone, Debug)]
+pub struct JmtProof {
+    pub leaf: Option<(SparseKey, MerkleHashValue)>, // the leaf's key and the hash of its value
+    pub siblings: Vec<MerkleHashValue>,
+}
+
+// A Jellyfish Merkle Tree (as in Diem/Aptos): a sparse Merkle tree over 256-bit keys, stored as a
+// 16-ary tree whose leaves sit at the shortest nibble path that tells them apart from all other keys.
+//
+// Nodes are never modified in place: every batch of updates creates a new version of the tree, made of
+// new nodes along the updated paths which point to the unmodified nodes from older versions. So the
+// tree can be read (and proven against) at any version. The nodes replaced in each version are
+// recorded as stale, so they could be pruned once old versions are no longer needed.
+//
+// Each internal node is hashed as a binary Merkle tree over its 16 children, where empty subtrees hash
+// to the default hash, and a subtree with a single leaf in it has that leaf's hash. This way, proofs
+// only contain the siblings on the binary path to a key.
+pub struct JellyfishMerkle<HashFunc> {
+    nodes: HashMap<NodeKey, JmtNode>,
+
+    // the root of each version of the tree, or None if that version is empty
+    roots: Vec<Option<Child>>,
+
+    // the nodes that were replaced by newer versions, with the version that replaced them
+    stale_nodes: Vec<(u64, NodeKey)>,
+
+    num_hashes: usize,
+
+    phantom: PhantomData<HashFunc>,
+}
+
+fn nibble(key: &SparseKey, idx: usize) -> usize {
+    let byte = key[idx / 2];
+
+    (if idx % 2 == 1 { byte & 0x0f } else { byte >> 4 }) as usize
+}
+
+fn bit(key: &SparseKey, idx: usize) -> bool {
+    (key[idx / 8] >> (7 - idx % 8)) & 1 == 1
+}
+
+fn nibble_path(key: &SparseKey, len: usize) -> Vec<u8> {
+    (0..len).map(|i| nibble(key, i) as u8).collect()
+}
+
+impl<HashFunc: HashFuncTrait> Default for JellyfishMerkle<HashFunc> {
+    fn default() -> Self {
+        JellyfishMerkle::new()
+    }
+}
+
+impl<HashFunc: HashFuncTrait> JellyfishMerkle<HashFunc> {
+    pub fn new() -> Self {
+        JellyfishMerkle {
+            nodes: HashMap::new(),
+            roots: Vec::new(),
+            stale_nodes: Vec::new(),
+            num_hashes: 0,
+            phantom: Default::default(),
+        }
+    }
+
+    pub fn get_num_computations(&self) -> usize {
+        self.num_hashes
+    }
+
+    // returns the latest version, or None if no updates were applied yet
+    pub fn latest_version(&self) -> Option<u64> {
+        self.roots.len().checked_sub(1).map(|v| v as u64)
+    }
+
+    // returns the # of nodes stored across all versions, including stale ones
+    pub fn num_stored_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn num_stale_nodes(&self) -> usize {
+        self.stale_nodes.len()
+    }
+
+    pub fn root_hash(&self, version: u64) -> MerkleHashValue {
+        match &self.roots[version as usize] {
+            Some(root) => root.hash.clone(),
+            None => MerkleHashValue::default(),
+        }
+    }
+
+    fn hash_value(value: &str) -> MerkleHashValue {
+        let mut hasher = HashFunc::new_with_domain("value:");
+        hasher.update(value.as_bytes());
+
+        let mut hv = MerkleHashValue::default();
+        hasher.finalize(&mut hv.hash);
+        hv
+    }
+
+    fn hash_leaf(key: &SparseKey, value_hash: &MerkleHashValue) -> MerkleHashValue {
+        let mut hasher = HashFunc::new_with_domain("leaf:");
+        hasher.update(key);
+        hasher.update(&value_hash.hash);
+
+        let mut hv = MerkleHashValue::default();
+        hasher.finalize(&mut hv.hash);
+        hv
+    }
+
+    fn hash_internal(left: &MerkleHashValue, right: &MerkleHashValue) -> MerkleHashValue {
+        let mut hasher = HashFunc::new_with_domain("internal:");
+        hasher.update(&left.hash);
+        hasher.update(&right.hash);
+
+        let mut hv = MerkleHashValue::default();
+        hasher.finalize(&mut hv.hash);
+        hv
+    }
+
+    // Returns the hash of the binary subtree over children [start, start + width) of an internal node.
+    // If 'num_hashes' is None, the hashes computed are not counted (e.g., when proving).
+    fn subtree_hash(
+        children: &[Option<Child>],
+        start: usize,
+        width: usize,
+        num_hashes: Option<&mut usize>,
+    ) -> MerkleHashValue {
+        let mut occupied = children[start..start + width].iter().flatten();
+
+        match (occupied.next(), occupied.next()) {
+            (None, _) => MerkleHashValue::default(),
+            (Some(child), None) if child.is_leaf || width == 1 => child.hash.clone(),
+            _ => {
+                let half = width / 2;
+                let mut num_hashes = num_hashes;
+                let left = Self::subtree_hash(children, start, half, num_hashes.as_deref_mut());
+                let right = Self::subtree_hash(children, start + half, half, num_hashes.as_deref_mut());
+
+                if let Some(num_hashes) = num_hashes {
+                    *num_hashes += 1;
+                }
+                Self::hash_internal(&left, &right)
+            }
+        }
+    }
+
+    // Applies the updates as a new version of the tree and returns that version.
+    //
+    // TODO: Support deleting keys, which requires collapsing internal nodes left with a single leaf.
+    pub fn update_leaves(&mut self, updates: Vec<(SparseKey, String)>) -> u64 {
+        let version = self.roots.len() as u64;
+        let updates = normalize_updates(updates, |_, later| later);
+        let old_root = self.roots.last().cloned().flatten();
+
+        let new_root = if updates.is_empty() {
+            old_root
+        } else {
+            Some(self._update(version, 0, old_root, updates))
+        };
+
+        self.roots.push(new_root);
+        version
+    }
+
+    // Writes the new version of the node at the specified depth on the updates' (common) path, given
+    // its old version 'old' (if any), and returns it.
+    fn _update(
+        &mut self,
+        version: u64,
+        depth: usize,
+        old: Option<Child>,
+        mut updates: Vec<(SparseKey, String)>,
+    ) -> Child {
+        let path = nibble_path(&updates[0].0, depth);
+        let mut old_children: Box<[Option<Child>; JMT_ARITY]> = Default::default();
+
+        if let Some(old) = old {
+            let old_key = NodeKey { version: old.version, nibble_path: path.clone() };
+            self.stale_nodes.push((version, old_key.clone()));
+
+            match self.nodes[&old_key].clone() {
+                JmtNode::Internal { children } => old_children = children,
+                JmtNode::Leaf { key, value } => {
+                    // the old leaf is pushed down alongside the updates, unless one of them overwrites it
+                    if let Err(pos) = updates.binary_search_by(|(k, _)| k.cmp(&key)) {
+                        updates.insert(pos, (key, value));
+                    }
+                }
+            }
+        }
+
+        let node_key = NodeKey { version, nibble_path: path };
+
+        if updates.len() == 1 && old_children.iter().all(Option::is_none) {
+            let (key, value) = updates.pop().unwrap();
+
+            self.num_hashes += 2;
+            let hash = Self::hash_leaf(&key, &Self::hash_value(&value));
+            self.nodes.insert(node_key, JmtNode::Leaf { key, value });
+
+            return Child { version, hash, is_leaf: true };
+        }
+
+        // split the updates by their next nibble and update each child
+        assert!(depth < SPARSE_KEY_BITS / 4, "Duplicate keys in JMT updates");
+
+        let mut children = old_children;
+        let mut updates = updates.into_iter().peekable();
+        while let Some((key, value)) = updates.next() {
+            let idx = nibble(&key, depth);
+            let mut group = vec![(key, value)];
+            while let Some((next_key, _)) = updates.peek() {
+                if nibble(next_key, depth) != idx {
+                    break;
+                }
+                group.push(updates.next().unwrap());
+            }
+
+            let old_child = children[idx].take();
+            children[idx] = Some(self._update(version, depth + 1, old_child, group));
+        }
+
+        let hash = Self::subtree_hash(&children[..], 0, JMT_ARITY, Some(&mut self.num_hashes));
+        self.nodes.insert(node_key, JmtNode::Internal { children });
+
+        Child { version, hash, is_leaf: false }
+    }
+
+    // returns the value of 'key' at the specified version, if any
+    pub fn get(&self, key: &SparseKey, version: u64) -> Option<&String> {
+        let (leaf, _) = self._find_leaf(key, version, false);
+
+        match leaf {
+            Some(JmtNode::Leaf { key: leaf_key, value }) if leaf_key == key => Some(value),
+            _ => None,
+        }
+    }
+
+    // Returns a proof for the value of 'key' (or its absence) at the specified version, together with
+    // that value.
+    pub fn prove(&self, key: &SparseKey, version: u64) -> (Option<&String>, JmtProof) {
+        let (leaf, siblings) = self._find_leaf(key, version, true);
+
+        match leaf {
+            Some(JmtNode::Leaf { key: leaf_key, value }) => {
+                let leaf = Some((*leaf_key, Self::hash_value(value)));
+                let value = if leaf_key == key { Some(value) } else { None };
+
+                (value, JmtProof { leaf, siblings })
+            }
+            _ => (None, JmtProof { leaf: None, siblings }),
+        }
+    }
+
+    // Walks down the path of 'key' at the specified version and returns the leaf it ends at (if any),
+    // together with the sibling hashes on the way if 'with_siblings' is set.
+    fn _find_leaf(
+        &self,
+        key: &SparseKey,
+        version: u64,
+        with_siblings: bool,
+    ) -> (Option<&JmtNode>, Vec<MerkleHashValue>) {
+        let mut siblings = Vec::new();
+        let mut current = self.roots[version as usize].clone();
+        let mut depth = 0;
+
+        while let Some(child) = current {
+            let path = nibble_path(key, depth);
+            let node = &self.nodes[&NodeKey { version: child.version, nibble_path: path.clone() }];
+
+            let children = match node {
+                JmtNode::Leaf { .. } => return (Some(node), siblings),
+                JmtNode::Internal { children } => children,
+            };
+
+            // descend the binary tree inside the node, stopping early at subtrees with at most one leaf
+            let (mut start, mut width) = (0, JMT_ARITY);
+            while width > 1 {
+                let mut occupied = children[start..start + width].iter().flatten();
+                match (occupied.next(), occupied.next()) {
+                    (None, _) => return (None, siblings),
+                    (Some(child), None) if child.is_leaf => {
+                        let offset = children[start..start + width].iter().position(Option::is_some).unwrap();
+                        let leaf_nibble = start + offset;
+                        let leaf_key = NodeKey {
+                            version: child.version,
+                            nibble_path: [path, vec![leaf_nibble as u8]].concat(),
+                        };
+
+                        return (Some(&self.nodes[&leaf_key]), siblings);
+                    }
+                    _ => {}
+                }
+
+                width /= 2;
+                let go_right = nibble(key, depth) >= start + width;
+                if with_siblings {
+                    let sibling_start = if go_right { start } else { start + width };
+                    siblings.push(Self::subtree_hash(&children[..], sibling_start, width, None));
+                }
+                if go_right {
+                    start += width;
+                }
+            }
+
+            current = children[start].clone();
+            depth += 1;
+        }
+
+        (None, siblings)
+    }
+
+    // Verifies a proof returned by prove() that 'key' maps to 'value' (or is absent, if 'value' is None)
+    // in the tree with the specified root.
+    pub fn verify(root: &MerkleHashValue, key: &SparseKey, value: Option<&str>, proof: &JmtProof) -> bool {
+        if proof.siblings.len() > SPARSE_KEY_BITS {
+            return false;
+        }
+
+        let leaf_hash = match (&proof.leaf, value) {
+            (Some((leaf_key, value_hash)), Some(value)) => {
+                if leaf_key != key || *value_hash != Self::hash_value(value) {
+                    return false;
+                }
+                Self::hash_leaf(leaf_key, value_hash)
+            }
+            (Some((leaf_key, value_hash)), None) => {
+                // another key's leaf is on this key's path, so this key must be absent
+                let depth = proof.siblings.len();
+                if leaf_key == key || (0..depth).any(|i| bit(leaf_key, i) != bit(key, i)) {
+                    return false;
+                }
+                Self::hash_leaf(leaf_key, value_hash)
+            }
+            (None, Some(_)) => return false,
+            (None, None) => MerkleHashValue::default(),
+        };
+
+        let computed = proof.siblings.iter().enumerate().rev().fold(leaf_hash, |hash, (i, sibling)| {
+            if bit(key, i) {
+                Self::hash_internal(sibling, &hash)
+            } else {
+                Self::hash_internal(&hash, sibling)
+            }
+        });
+
+        computed == *root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle_crhf::Sha3HashFunc;
+    use crate::random_sparse_updates;
+    use rand::thread_rng;
+
+    #[test]
+    fn versions_and_proofs() {
+        let mut jmt = JellyfishMerkle::<Sha3HashFunc>::new();
+        let updates = random_sparse_updates(&mut thread_rng(), 300);
+        let (first, second) = updates.split_at(200);
+
+        let v0 = jmt.update_leaves(first.to_vec());
+        // the second version overwrites some keys from the first one and adds new ones
+        let mut overwrites = first[..50].iter().map(|(key, _)| (*key, "new".to_owned())).collect::<Vec<_>>();
+        overwrites.extend_from_slice(second);
+        let v1 = jmt.update_leaves(overwrites);
+
+        assert_eq!((v0, v1), (0, 1));
+        assert_ne!(jmt.root_hash(v0), jmt.root_hash(v1));
+        assert!(jmt.num_stale_nodes() > 0);
+
+        // the root does not depend on how the updates were batched
+        let mut other = JellyfishMerkle::<Sha3HashFunc>::new();
+        for (key, value) in first[50..].iter().chain(second) {
+            other.update_leaves(vec![(*key, value.clone())]);
+        }
+        other.update_leaves(first[..50].iter().map(|(key, _)| (*key, "new".to_owned())).collect());
+        assert_eq!(jmt.root_hash(v1), other.root_hash(other.latest_version().unwrap()));
+
+        for (version, key, expected) in [
+            (v0, &first[0].0, Some(first[0].1.as_str())),
+            (v1, &first[0].0, Some("new")),
+            (v0, &second[0].0, None),
+            (v1, &second[0].0, Some(second[0].1.as_str())),
+            (v1, &[0xab; 32], None),
+        ] {
+            let root = jmt.root_hash(version);
+            let (value, proof) = jmt.prove(key, version);
+
+            assert_eq!(value.map(String::as_str), expected);
+            assert_eq!(jmt.get(key, version).map(String::as_str), expected);
+            assert!(JellyfishMerkle::<Sha3HashFunc>::verify(&root, key, expected, &proof));
+            assert!(!JellyfishMerkle::<Sha3HashFunc>::verify(&root, key, Some("wrong"), &proof));
+        }
+    }
+}
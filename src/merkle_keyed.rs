@@ -0,0 +1,254 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::Serialize;
+use sha3::{Digest, Sha3_256};
+
+use crate::hashing_traits::TreeHasherFunc;
+use crate::merkle_abstract::{AbstractMerkle, BatchProof};
+
+// A proof that a key maps to some value in a KeyedMerkle: since several keys can map to the same leaf,
+// the proof consists of all the entries in that leaf, together with a proof for the leaf.
+#[derive(Clone, Debug)]
+pub struct KeyedProof<K, V, HashType> {
+    pub entries: Vec<(K, V)>,
+    pub proof: BatchProof<HashType>,
+}
+
+// Returns a leaf position in [0, num_leaves) for 'key' by hashing it. This is the default way
+// KeyedMerkle maps keys to leaves.
+pub fn hash_to_index<K: Serialize>(key: &K, num_leaves: usize) -> usize {
+    let mut hasher = Sha3_256::new();
+    hasher.update(bincode::serialize(key).unwrap());
+
+    let hash = hasher.finalize();
+    let index = u64::from_le_bytes(hash[..8].try_into().unwrap());
+
+    (index % num_leaves as u64) as usize
+}
+
+// An authenticated map from keys to values, layered over a positional tree: each key is mapped to a
+// leaf position via 'index_fn', and each leaf commits to all the entries whose keys map to it (i.e.,
+// to a "bucket").
+//
+// NOTE: The hashers in this crate hash String leaves, so a bucket is stored in its leaf as the hex
+// encoding of its bincode-serialized entries, sorted by key.
+pub struct KeyedMerkle<K, V, HashType, Hasher, IndexFn = fn(&K, usize) -> usize> {
+    pub tree: AbstractMerkle<String, HashType, Hasher>,
+
+    // maps a key and the # of leaves to a leaf position
+    index_fn: IndexFn,
+
+    // the non-empty buckets, by leaf position
+    buckets: HashMap<usize, BTreeMap<K, V>>,
+}
+
+impl<K, V, HashType, Hasher> KeyedMerkle<K, V, HashType, Hasher>
+where
+    K: Serialize + Ord + Clone,
+    V: Serialize + Clone,
+    HashType: Default + Clone + PartialEq,
+    Hasher: TreeHasherFunc<String, HashType>,
+{
+    // Returns an empty map over 'tree', whose keys are mapped to leaves by hashing them.
+    pub fn new(tree: AbstractMerkle<String, HashType, Hasher>) -> Self {
+        KeyedMerkle::with_index_fn(tree, hash_to_index::<K>)
+    }
+}
+
+impl<K, V, HashType, Hasher, IndexFn> KeyedMerkle<K, V, HashType, Hasher, IndexFn>
+where
+    K: Serialize + Ord + Clone,
+    V: Serialize + Clone,
+    HashType: Default + Clone + PartialEq,
+    Hasher: TreeHasherFunc<String, HashType>,
+    IndexFn: Fn(&K, usize) -> usize,
+{
+    // Returns an empty map over 'tree', whose keys are mapped to leaves via 'index_fn(key, num_leaves)'.
+    // The tree should be empty too.
+    pub fn with_index_fn(tree: AbstractMerkle<String, HashType, Hasher>, index_fn: IndexFn) -> Self {
+        KeyedMerkle {
+            tree,
+            index_fn,
+            buckets: HashMap::new(),
+        }
+    }
+
+    // returns the position of the leaf which 'key' maps to
+    pub fn leaf_position(&self, key: &K) -> usize {
+        let leaf_pos = (self.index_fn)(key, self.tree.num_leaves());
+        assert!(leaf_pos < self.tree.num_leaves(), "index_fn returned an out-of-bounds leaf position");
+
+        leaf_pos
+    }
+
+    pub fn root_hash(&self) -> HashType {
+        self.tree.root_hash()
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.buckets.get(&self.leaf_position(key))?.get(key)
+    }
+
+    // Inserts (or overwrites) the entry for 'key' and returns the old value, if any.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let mut old_values = self.insert_batch(vec![(key, value)]);
+
+        old_values.pop().unwrap()
+    }
+
+    // Inserts all the entries, updating the tree only once, and returns their old values.
+    pub fn insert_batch(&mut self, entries: Vec<(K, V)>) -> Vec<Option<V>> {
+        let mut touched = Vec::with_capacity(entries.len());
+        let mut old_values = Vec::with_capacity(entries.len());
+
+        for (key, value) in entries {
+            let leaf_pos = self.leaf_position(&key);
+
+            old_values.push(self.buckets.entry(leaf_pos).or_default().insert(key, value));
+            touched.push(leaf_pos);
+        }
+
+        self._update_buckets(touched);
+
+        old_values
+    }
+
+    // Removes the entry for 'key' and returns its value, if any.
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let leaf_pos = self.leaf_position(key);
+        let old_value = self.buckets.get_mut(&leaf_pos)?.remove(key)?;
+
+        self._update_buckets(vec![leaf_pos]);
+
+        Some(old_value)
+    }
+
+    // Returns a proof that 'key' maps to its current value, or None if 'key' is not in the map.
+    pub fn prove(&self, key: &K) -> Option<KeyedProof<K, V, HashType>> {
+        let leaf_pos = self.leaf_position(key);
+        let bucket = self.buckets.get(&leaf_pos)?;
+
+        if !bucket.contains_key(key) {
+            return None;
+        }
+
+        Some(KeyedProof {
+            entries: bucket.iter().map(|(k, v)| (k.clone(), v.clone())).collect(),
+            proof: self.tree.prove_batch(&[leaf_pos]),
+        })
+    }
+
+    // Verifies a proof returned by prove() that 'key' maps to 'value' in the map with the specified
+    // root.
+    pub fn verify(
+        &mut self,
+        root: &HashType,
+        key: &K,
+        value: &V,
+        proof: &KeyedProof<K, V, HashType>,
+    ) -> bool
+    where
+        V: PartialEq,
+    {
+        let leaf_pos = self.leaf_position(key);
+
+        // the bucket must actually contain this entry, and all of its keys must map to this leaf
+        let contains_entry = proof.entries.iter().any(|(k, v)| k == key && v == value);
+        let same_leaf = proof.entries.iter().all(|(k, _)| self.leaf_position(k) == leaf_pos);
+        if !contains_entry || !same_leaf {
+            return false;
+        }
+
+        let leaf = (leaf_pos, Self::encode_bucket(proof.entries.iter().map(|(k, v)| (k, v))));
+
+        self.tree.verify_batch(root, &[leaf], &proof.proof)
+    }
+
+    // returns the data of a leaf containing the specified entries
+    fn encode_bucket<'a, I>(entries: I) -> String
+    where
+        I: Iterator<Item = (&'a K, &'a V)>,
+        K: 'a,
+        V: 'a,
+    {
+        let entries = entries.collect::<Vec<_>>();
+
+        hex::encode(bincode::serialize(&entries).unwrap())
+    }
+
+    // rehashes the leaves at the specified positions, whose buckets have changed
+    fn _update_buckets(&mut self, mut positions: Vec<usize>) {
+        positions.sort_unstable();
+        positions.dedup();
+
+        let mut updates = Vec::with_capacity(positions.len());
+        let mut deletions = Vec::new();
+
+        for leaf_pos in positions {
+            match self.buckets.get(&leaf_pos) {
+                Some(bucket) if !bucket.is_empty() => {
+                    updates.push((leaf_pos, Self::encode_bucket(bucket.iter())));
+                }
+                _ => {
+                    self.buckets.remove(&leaf_pos);
+                    deletions.push(leaf_pos);
+                }
+            }
+        }
+
+        if !updates.is_empty() {
+            self.tree.update_leaves(updates);
+        }
+        if !deletions.is_empty() {
+            self.tree.delete_leaves(&deletions);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle_crhf::{new_merkle_crhf_from_leaves, TinySha3HashFunc};
+
+    #[test]
+    fn insert_get_prove() {
+        let mut map = KeyedMerkle::new(new_merkle_crhf_from_leaves::<TinySha3HashFunc>(4, 64));
+        let empty_root = map.root_hash();
+
+        assert_eq!(map.insert("alice".to_owned(), 10u64), None);
+        assert_eq!(map.insert("bob".to_owned(), 20u64), None);
+        assert_eq!(map.insert("alice".to_owned(), 11u64), Some(10));
+
+        assert_eq!(map.get(&"alice".to_owned()), Some(&11));
+        assert_eq!(map.get(&"carol".to_owned()), None);
+
+        let root = map.root_hash();
+        let proof = map.prove(&"alice".to_owned()).unwrap();
+        assert!(map.verify(&root, &"alice".to_owned(), &11, &proof));
+        assert!(!map.verify(&root, &"alice".to_owned(), &10, &proof));
+        assert!(!map.verify(&root, &"bob".to_owned(), &20, &proof));
+        assert!(map.prove(&"carol".to_owned()).is_none());
+
+        assert_eq!(map.remove(&"alice".to_owned()), Some(11));
+        assert_eq!(map.remove(&"bob".to_owned()), Some(20));
+        assert_eq!(map.root_hash(), empty_root);
+    }
+
+    #[test]
+    fn colliding_keys() {
+        // all keys map to the same leaf
+        let mut map = KeyedMerkle::with_index_fn(new_merkle_crhf_from_leaves::<TinySha3HashFunc>(2, 8), |_: &u32, _| 5);
+
+        map.insert_batch((0..10u32).map(|i| (i, i * i)).collect());
+        map.remove(&3);
+
+        let root = map.root_hash();
+        for i in (0..10u32).filter(|i| *i != 3) {
+            let proof = map.prove(&i).unwrap();
+
+            assert_eq!(proof.entries.len(), 9);
+            assert!(map.verify(&root, &i, &(i * i), &proof));
+        }
+        assert!(map.prove(&3).is_none());
+    }
+}
@@ -0,0 +1,242 @@
+use crate::hashing_traits::{HashFuncTrait, TreeHasherFunc};
+use crate::merkle_crhf::{HasherCRHF, HashingScheme, MerkleHashValue};
+
+// An append-only log of leaves, hashed into a binary CRHF Merkle tree as in Certificate Transparency
+// (RFC 6962): the tree over leaves D[0:n] is split into a perfect tree over D[0:k], where k is the
+// largest power of two less than n, and a tree over D[k:n]. Unlike an AbstractMerkle, whose shape
+// depends on its # of leaves, the tree over D[0:n] is a prefix of the tree over D[0:m] for any m > n,
+// so the log can prove that an older root R1 over n leaves is consistent with a newer root R2 over m
+// leaves (i.e., that the log was only appended to in between), via prove_consistency().
+//
+// Leaves and parents are hashed by a HasherCRHF of arity 2, so a log whose # of leaves is a power of
+// two has the same root as a binary CRHF tree with the same leaves and hashing scheme.
+//
+// NOTE(Perf): Roots and proofs are recomputed from the leaf hashes, which takes O(n) hashes each.
+pub struct MerkleLog<HashFunc> {
+    pub hasher: HasherCRHF<HashFunc>,
+    leaves: Vec<MerkleHashValue>, // the leaf hashes
+}
+
+// returns the largest power of two less than n, where n > 1
+fn split(n: usize) -> usize {
+    1 << (usize::BITS - (n - 1).leading_zeros() - 1)
+}
+
+impl<HashFunc: HashFuncTrait> MerkleLog<HashFunc> {
+    pub fn new() -> Self {
+        Self::with_scheme(HashingScheme::default())
+    }
+
+    pub fn with_scheme(scheme: HashingScheme) -> Self {
+        MerkleLog { hasher: HasherCRHF::with_scheme(2, scheme), leaves: vec![] }
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    // appends a leaf to the log, returning its position
+    pub fn append(&mut self, data: String) -> usize {
+        // NOTE: Leaves are hashed with their offset in a binary tree, as AbstractMerkle would (see
+        // HashingScheme::bind_offsets)
+        let leaf_pos = self.leaves.len();
+        let hash = self.hasher.hash_leaf_data(leaf_pos % 2, data);
+        self.leaves.push(hash);
+
+        leaf_pos
+    }
+
+    // returns the root of the log
+    pub fn root(&mut self) -> MerkleHashValue {
+        self.root_at(self.len())
+    }
+
+    // returns the root the log had when it had its first 'n' leaves, where 0 < n <= len()
+    pub fn root_at(&mut self, n: usize) -> MerkleHashValue {
+        assert!(0 < n && n <= self.len(), "The log never had {} leaves", n);
+
+        self.subtree_root(0, n)
+    }
+
+    // returns the root of the subtree over leaves [start, end)
+    fn subtree_root(&mut self, start: usize, end: usize) -> MerkleHashValue {
+        if end - start == 1 {
+            return self.leaves[start].clone();
+        }
+
+        let mid = start + split(end - start);
+        let (left, right) = (self.subtree_root(start, mid), self.subtree_root(mid, end));
+
+        self.hasher.hash_all_children(&[left, right])
+    }
+
+    // Returns a proof that the log's root over its first 'n' leaves is consistent with its root over its
+    // first 'm' leaves, where 0 < n <= m <= len(), as the hashes of the subtrees needed to recompute both
+    // roots (see PROOF(m, D[n]) in RFC 6962, Section 2.1.2).
+    pub fn prove_consistency(&mut self, n: usize, m: usize) -> Vec<MerkleHashValue> {
+        assert!(0 < n && n <= m && m <= self.len(), "Cannot prove {} leaves consistent with {}", n, m);
+
+        let mut proof = vec![];
+        self.subproof(n, 0, m, true, &mut proof);
+
+        proof
+    }
+
+    // appends SUBPROOF(n, D[start:end], complete) to 'proof', where 'complete' is true if the old tree
+    // over D[start:start + n] is the subtree at the root of this proof, whose hash the verifier has
+    fn subproof(&mut self, n: usize, start: usize, end: usize, complete: bool, proof: &mut Vec<MerkleHashValue>) {
+        if n == end - start {
+            if !complete {
+                let root = self.subtree_root(start, end);
+                proof.push(root);
+            }
+            return;
+        }
+
+        let k = split(end - start);
+        if n <= k {
+            self.subproof(n, start, start + k, complete, proof);
+            let right = self.subtree_root(start + k, end);
+            proof.push(right);
+        } else {
+            self.subproof(n - k, start + k, end, false, proof);
+            let left = self.subtree_root(start, start + k);
+            proof.push(left);
+        }
+    }
+
+    // Verifies a proof returned by prove_consistency() that 'old_root' over 'n' leaves is consistent with
+    // 'new_root' over 'm' leaves (see RFC 9162, Section 2.1.4.2).
+    //
+    // NOTE: Only the log's hasher is used here, not its leaves.
+    pub fn verify_consistency(
+        &mut self,
+        n: usize,
+        m: usize,
+        old_root: &MerkleHashValue,
+        new_root: &MerkleHashValue,
+        proof: &[MerkleHashValue],
+    ) -> bool {
+        if n == 0 || n > m {
+            return false;
+        }
+        if n == m {
+            return proof.is_empty() && old_root == new_root;
+        }
+
+        // the old tree is a subtree of the new one if it is perfect, in which case its root is not sent
+        let mut path = proof.iter();
+        let first = match n.is_power_of_two() {
+            true => old_root.clone(),
+            false => match path.next() {
+                Some(hash) => hash.clone(),
+                None => return false,
+            },
+        };
+
+        let (mut old_node, mut new_node) = (n - 1, m - 1);
+        while old_node & 1 == 1 {
+            old_node >>= 1;
+            new_node >>= 1;
+        }
+
+        let (mut old_hash, mut new_hash) = (first.clone(), first);
+        for hash in path {
+            if new_node == 0 {
+                return false;
+            }
+
+            if old_node & 1 == 1 || old_node == new_node {
+                old_hash = self.hasher.hash_all_children(&[hash.clone(), old_hash]);
+                new_hash = self.hasher.hash_all_children(&[hash.clone(), new_hash]);
+
+                while old_node & 1 == 0 && old_node != 0 {
+                    old_node >>= 1;
+                    new_node >>= 1;
+                }
+            } else {
+                new_hash = self.hasher.hash_all_children(&[new_hash, hash.clone()]);
+            }
+
+            old_node >>= 1;
+            new_node >>= 1;
+        }
+
+        new_node == 0 && old_hash == *old_root && new_hash == *new_root
+    }
+}
+
+impl<HashFunc: HashFuncTrait> Default for MerkleLog<HashFunc> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle_crhf::{new_merkle_crhf_from_leaves, new_merkle_crhf_with_scheme, Sha3HashFunc, TinySha3HashFunc};
+
+    #[test]
+    fn consistency_proofs() {
+        let mut log = MerkleLog::<TinySha3HashFunc>::new();
+        for i in 0..20 {
+            log.append(format!("leaf {}", i));
+        }
+
+        for m in 1..=20 {
+            let new_root = log.root_at(m);
+            for n in 1..=m {
+                let old_root = log.root_at(n);
+                let proof = log.prove_consistency(n, m);
+                assert!(log.verify_consistency(n, m, &old_root, &new_root, &proof), "{} vs. {} leaves", n, m);
+
+                if n < m {
+                    assert_ne!(old_root, new_root);
+                    assert!(!log.verify_consistency(n, m, &new_root, &new_root, &proof));
+                    assert!(!log.verify_consistency(n, m, &old_root, &old_root, &proof));
+                    let mut tampered = proof.clone();
+                    *tampered.last_mut().unwrap() = old_root.clone();
+                    assert!(!log.verify_consistency(n, m, &old_root, &new_root, &tampered));
+                    assert!(!log.verify_consistency(n, m, &old_root, &new_root, &proof[1..]));
+                }
+            }
+        }
+
+        // a log which rewrote one of its old leaves is not consistent with its old root
+        let mut forked = MerkleLog::<TinySha3HashFunc>::new();
+        for i in 0..20 {
+            forked.append(format!("leaf {}", if i == 3 { 333 } else { i }));
+        }
+        let (old_root, new_root) = (log.root_at(5), forked.root_at(12));
+        let proof = forked.prove_consistency(5, 12);
+        assert!(!forked.verify_consistency(5, 12, &old_root, &new_root, &proof));
+    }
+
+    #[test]
+    fn perfect_logs_match_trees() {
+        for scheme in [HashingScheme::v0(), HashingScheme::v1()] {
+            let mut log = MerkleLog::<Sha3HashFunc>::with_scheme(scheme.clone());
+            let mut merkle = new_merkle_crhf_with_scheme::<Sha3HashFunc>(2, 16, scheme);
+            for i in 0..16 {
+                log.append(format!("leaf {}", i));
+            }
+            merkle.update_leaves((0..16).map(|i| (i, format!("leaf {}", i))).collect());
+
+            assert_eq!(log.root(), merkle.root_hash());
+        }
+
+        // otherwise, the log's shape differs from a tree's, which puts its leaves on at most two levels
+        let mut log = MerkleLog::<Sha3HashFunc>::new();
+        let mut merkle = new_merkle_crhf_from_leaves::<Sha3HashFunc>(2, 6);
+        for i in 0..6 {
+            log.append(format!("leaf {}", i));
+        }
+        merkle.update_leaves((0..6).map(|i| (i, format!("leaf {}", i))).collect());
+        assert_ne!(log.root(), merkle.root_hash());
+    }
+}
@@ -0,0 +1,446 @@
+use std::fmt::{Debug, Formatter};
+
+use sha3::{Digest, Keccak256};
+
+use crate::hashing_traits::HASH_LENGTH;
+
+// An Ethereum-style hexary Merkle Patricia Trie: keys are split into nibbles, runs of nibbles without
+// any branching are compressed into extension and leaf nodes, and nodes are RLP-encoded and hashed
+// with Keccak-256 (see Appendix D of the Ethereum yellow paper).
+//
+// Unlike AbstractMerkle, updates do not hash anything: they only mark the nodes on the updated paths
+// as dirty, and all the dirty nodes are hashed once, bottom-up, by root_hash(). This is how clients
+// (e.g., geth) amortize hashing across all the updates in a block.
+//
+// NOTE: Nodes whose encoding is shorter than 32 bytes are embedded in their parent rather than
+// hashed, as in Ethereum. With 32-byte (hashed) keys, this only happens for very short leaf values.
+pub struct MerklePatriciaTrie {
+    root: Node,
+    num_hashes: usize,
+}
+
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct MptHashValue {
+    hash: [u8; HASH_LENGTH],
+}
+
+impl Debug for MptHashValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.hash))
+    }
+}
+
+#[derive(Default)]
+struct Node {
+    kind: NodeKind,
+
+    // the node's reference in its parent's encoding (i.e., the RLP of its hash, or its encoding if that
+    // is shorter than 32 bytes), or None if the node changed since it was last hashed
+    cached_ref: Option<Vec<u8>>,
+}
+
+#[derive(Default)]
+enum NodeKind {
+    #[default]
+    Empty,
+    Leaf {
+        path: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Extension {
+        path: Vec<u8>,
+        child: Box<Node>,
+    },
+    Branch {
+        children: Box<[Node; 16]>,
+        value: Option<Vec<u8>>,
+    },
+}
+
+impl Node {
+    fn new(kind: NodeKind) -> Self {
+        Node { kind, cached_ref: None }
+    }
+
+    fn leaf(path: &[u8], value: Vec<u8>) -> Self {
+        Node::new(NodeKind::Leaf { path: path.to_vec(), value })
+    }
+
+    fn branch() -> Self {
+        Node::new(NodeKind::Branch { children: Default::default(), value: None })
+    }
+
+    // Returns a node for 'child' reached via the nibbles in 'path': extension and leaf children
+    // absorb the path, while branch children need an extension node in front of them.
+    fn with_prefix(path: &[u8], child: Node) -> Self {
+        if path.is_empty() {
+            return child;
+        }
+
+        match child.kind {
+            NodeKind::Empty => Node::default(),
+            NodeKind::Leaf { path: rest, value } => Node::leaf(&[path, &rest].concat(), value),
+            NodeKind::Extension { path: rest, child } => Node::new(NodeKind::Extension {
+                path: [path, &rest].concat(),
+                child,
+            }),
+            NodeKind::Branch { .. } => Node::new(NodeKind::Extension {
+                path: path.to_vec(),
+                child: Box::new(child),
+            }),
+        }
+    }
+
+    fn num_nodes(&self) -> usize {
+        match &self.kind {
+            NodeKind::Empty => 0,
+            NodeKind::Leaf { .. } => 1,
+            NodeKind::Extension { child, .. } => 1 + child.num_nodes(),
+            NodeKind::Branch { children, .. } => 1 + children.iter().map(Node::num_nodes).sum::<usize>(),
+        }
+    }
+}
+
+fn to_nibbles(key: &[u8]) -> Vec<u8> {
+    key.iter().flat_map(|b| [b >> 4, b & 0x0f]).collect()
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(x, y)| x == y).count()
+}
+
+// the "hex-prefix" encoding of a nibble path, which also encodes whether the path ends in a leaf
+fn hex_prefix(path: &[u8], is_leaf: bool) -> Vec<u8> {
+    let flag = if is_leaf { 2u8 } else { 0u8 };
+    let mut bytes = Vec::with_capacity(path.len() / 2 + 1);
+
+    let rest = if path.len() % 2 == 1 {
+        bytes.push(((flag + 1) << 4) | path[0]);
+        &path[1..]
+    } else {
+        bytes.push(flag << 4);
+        path
+    };
+
+    bytes.extend(rest.chunks(2).map(|pair| (pair[0] << 4) | pair[1]));
+    bytes
+}
+
+fn rlp_length_prefix(len: usize, short_offset: u8, long_offset: u8) -> Vec<u8> {
+    if len <= 55 {
+        vec![short_offset + len as u8]
+    } else {
+        let len_bytes = len.to_be_bytes();
+        let len_bytes = &len_bytes[len_bytes.iter().take_while(|b| **b == 0).count()..];
+
+        [&[long_offset + len_bytes.len() as u8], len_bytes].concat()
+    }
+}
+
+fn rlp_bytes(bytes: &[u8]) -> Vec<u8> {
+    if bytes.len() == 1 && bytes[0] < 0x80 {
+        bytes.to_vec()
+    } else {
+        [rlp_length_prefix(bytes.len(), 0x80, 0xb7), bytes.to_vec()].concat()
+    }
+}
+
+// 'items' must already be RLP-encoded
+fn rlp_list(items: &[Vec<u8>]) -> Vec<u8> {
+    let payload = items.concat();
+
+    [rlp_length_prefix(payload.len(), 0xc0, 0xf7), payload].concat()
+}
+
+impl Default for MerklePatriciaTrie {
+    fn default() -> Self {
+        MerklePatriciaTrie::new()
+    }
+}
+
+impl MerklePatriciaTrie {
+    pub fn new() -> Self {
+        MerklePatriciaTrie {
+            root: Node::default(),
+            num_hashes: 0,
+        }
+    }
+
+    pub fn get_num_computations(&self) -> usize {
+        self.num_hashes
+    }
+
+    pub fn num_stored_nodes(&self) -> usize {
+        self.root.num_nodes()
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        let nibbles = to_nibbles(key);
+        let mut path = &nibbles[..];
+        let mut node = &self.root;
+
+        loop {
+            match &node.kind {
+                NodeKind::Empty => return None,
+                NodeKind::Leaf { path: leaf_path, value } => {
+                    return (leaf_path[..] == *path).then_some(&value[..]);
+                }
+                NodeKind::Extension { path: ext_path, child } => {
+                    path = path.strip_prefix(&ext_path[..])?;
+                    node = child;
+                }
+                NodeKind::Branch { children, value } => match path.split_first() {
+                    None => return value.as_deref(),
+                    Some((nibble, rest)) => {
+                        node = &children[*nibble as usize];
+                        path = rest;
+                    }
+                },
+            }
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8], value: Vec<u8>) {
+        // NOTE: As in Ethereum, empty values are not stored, since they cannot be told apart from
+        // missing keys.
+        if value.is_empty() {
+            self.remove(key);
+        } else {
+            Self::_insert(&mut self.root, &to_nibbles(key), value);
+        }
+    }
+
+    pub fn remove(&mut self, key: &[u8]) {
+        Self::_remove(&mut self.root, &to_nibbles(key));
+    }
+
+    // Sets the leaves with the specified keys, as in SparseMerkle::update_leaves(), but does not hash
+    // anything until the next root_hash() call.
+    pub fn update_leaves<Key: AsRef<[u8]>>(&mut self, updates: Vec<(Key, String)>) {
+        for (key, value) in updates {
+            self.insert(key.as_ref(), value.into_bytes());
+        }
+    }
+
+    pub fn delete_leaves<Key: AsRef<[u8]>>(&mut self, keys: &[Key]) {
+        for key in keys {
+            self.remove(key.as_ref());
+        }
+    }
+
+    // Hashes all the nodes that changed since the last call and returns the root hash.
+    pub fn root_hash(&mut self) -> MptHashValue {
+        let root_ref = Self::_node_ref(&mut self.root, &mut self.num_hashes);
+
+        // the root is always hashed, even if its encoding is short (as is the empty trie's)
+        let mut hv = MptHashValue::default();
+        if root_ref.len() == HASH_LENGTH + 1 {
+            hv.hash.copy_from_slice(&root_ref[1..]);
+        } else {
+            self.num_hashes += 1;
+            hv.hash = Keccak256::digest(&root_ref).into();
+        }
+
+        hv
+    }
+
+    fn _insert(node: &mut Node, path: &[u8], value: Vec<u8>) {
+        node.cached_ref = None;
+
+        match &mut node.kind {
+            NodeKind::Empty => *node = Node::leaf(path, value),
+            NodeKind::Leaf { path: leaf_path, value: leaf_value } => {
+                if leaf_path[..] == *path {
+                    *leaf_value = value;
+                    return;
+                }
+
+                // split the leaf into a branch (preceded by an extension for the common prefix)
+                let common = common_prefix_len(leaf_path, path);
+                let old_leaf = std::mem::take(node);
+                let (old_path, old_value) = match old_leaf.kind {
+                    NodeKind::Leaf { path, value } => (path, value),
+                    _ => unreachable!(),
+                };
+
+                let mut branch = Node::branch();
+                Self::_insert(&mut branch, &old_path[common..], old_value);
+                Self::_insert(&mut branch, &path[common..], value);
+
+                *node = Node::with_prefix(&path[..common], branch);
+            }
+            NodeKind::Extension { path: ext_path, child } => {
+                let common = common_prefix_len(ext_path, path);
+                if common == ext_path.len() {
+                    Self::_insert(child, &path[common..], value);
+                    return;
+                }
+
+                // split the extension at the first nibble where the paths differ
+                let (ext_path, child) = match std::mem::take(node).kind {
+                    NodeKind::Extension { path, child } => (path, child),
+                    _ => unreachable!(),
+                };
+
+                let mut branch = Node::branch();
+                if let NodeKind::Branch { children, .. } = &mut branch.kind {
+                    children[ext_path[common] as usize] = Node::with_prefix(&ext_path[common + 1..], *child);
+                }
+                Self::_insert(&mut branch, &path[common..], value);
+
+                *node = Node::with_prefix(&path[..common], branch);
+            }
+            NodeKind::Branch { children, value: branch_value } => match path.split_first() {
+                None => *branch_value = Some(value),
+                Some((nibble, rest)) => Self::_insert(&mut children[*nibble as usize], rest, value),
+            },
+        }
+    }
+
+    // Removes the key with the specified path, if any, collapsing the nodes above it so that the
+    // trie stays in its canonical form (e.g., branches always have at least two entries).
+    fn _remove(node: &mut Node, path: &[u8]) {
+        match &mut node.kind {
+            NodeKind::Empty => {}
+            NodeKind::Leaf { path: leaf_path, .. } => {
+                if leaf_path[..] == *path {
+                    *node = Node::default();
+                }
+            }
+            NodeKind::Extension { path: ext_path, child } => {
+                if let Some(rest) = path.strip_prefix(&ext_path[..]) {
+                    node.cached_ref = None;
+                    Self::_remove(child, rest);
+
+                    if !matches!(child.kind, NodeKind::Branch { .. }) {
+                        let (ext_path, child) = match std::mem::take(node).kind {
+                            NodeKind::Extension { path, child } => (path, child),
+                            _ => unreachable!(),
+                        };
+
+                        *node = Node::with_prefix(&ext_path, *child);
+                    }
+                }
+            }
+            NodeKind::Branch { children, value } => {
+                node.cached_ref = None;
+                match path.split_first() {
+                    None => *value = None,
+                    Some((nibble, rest)) => Self::_remove(&mut children[*nibble as usize], rest),
+                }
+
+                let mut non_empty = children
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, child)| !matches!(child.kind, NodeKind::Empty));
+                let only_child = match (non_empty.next(), non_empty.next()) {
+                    (None, _) => None,
+                    (Some((nibble, _)), None) => Some(nibble),
+                    (Some(_), Some(_)) => return,
+                };
+
+                // a branch with a single entry left is replaced by a leaf or an extension
+                match (only_child, value.take()) {
+                    (None, None) => *node = Node::default(),
+                    (None, Some(value)) => *node = Node::leaf(&[], value),
+                    (Some(nibble), None) => {
+                        let child = std::mem::take(&mut children[nibble]);
+                        *node = Node::with_prefix(&[nibble as u8], child);
+                    }
+                    (Some(_), Some(v)) => *value = Some(v),
+                }
+            }
+        }
+    }
+
+    // Returns the node's reference in its parent's encoding, hashing it (and its dirty descendants)
+    // if it changed since it was last hashed.
+    fn _node_ref(node: &mut Node, num_hashes: &mut usize) -> Vec<u8> {
+        if let Some(cached_ref) = &node.cached_ref {
+            return cached_ref.clone();
+        }
+
+        let encoding = match &mut node.kind {
+            NodeKind::Empty => rlp_bytes(&[]),
+            NodeKind::Leaf { path, value } => rlp_list(&[rlp_bytes(&hex_prefix(path, true)), rlp_bytes(value)]),
+            NodeKind::Extension { path, child } => {
+                let child_ref = Self::_node_ref(child, num_hashes);
+
+                rlp_list(&[rlp_bytes(&hex_prefix(path, false)), child_ref])
+            }
+            NodeKind::Branch { children, value } => {
+                let mut items = children
+                    .iter_mut()
+                    .map(|child| Self::_node_ref(child, num_hashes))
+                    .collect::<Vec<Vec<u8>>>();
+                items.push(rlp_bytes(value.as_deref().unwrap_or(&[])));
+
+                rlp_list(&items)
+            }
+        };
+
+        let node_ref = if encoding.len() < HASH_LENGTH {
+            encoding
+        } else {
+            *num_hashes += 1;
+            rlp_bytes(&Keccak256::digest(&encoding))
+        };
+
+        node.cached_ref = Some(node_ref.clone());
+        node_ref
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random_sparse_updates;
+    use rand::thread_rng;
+
+    fn root_hex(mpt: &mut MerklePatriciaTrie) -> String {
+        format!("{:?}", mpt.root_hash())
+    }
+
+    #[test]
+    fn ethereum_test_vectors() {
+        let mut mpt = MerklePatriciaTrie::new();
+        assert_eq!(root_hex(&mut mpt), "56e81f171bcc55a6ff8345e692c0f86e5b48e01b996cadc001622fb5e363b421");
+
+        mpt.insert(b"doe", b"reindeer".to_vec());
+        mpt.insert(b"dog", b"puppy".to_vec());
+        mpt.insert(b"dogglesworth", b"cat".to_vec());
+        assert_eq!(root_hex(&mut mpt), "8aad789dff2f538bca5d8ea56e8abe10f4c7ba3a5dea95fea4cd6e7c3a1168d3");
+        assert_eq!(mpt.get(b"dog"), Some(&b"puppy"[..]));
+        assert_eq!(mpt.get(b"do"), None);
+
+        let mut mpt = MerklePatriciaTrie::new();
+        mpt.insert(b"A", b"aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa".to_vec());
+        assert_eq!(root_hex(&mut mpt), "d23786fb4a010da3ce639d66d5e904a11dbc02746d1ce25029e53290cabf28ab");
+    }
+
+    #[test]
+    fn root_is_independent_of_history() {
+        let updates = random_sparse_updates(&mut thread_rng(), 200);
+        let (kept, deleted) = updates.split_at(120);
+
+        // insert everything, hashing along the way, and then delete some of the keys...
+        let mut mpt = MerklePatriciaTrie::new();
+        for batch in updates.chunks(50) {
+            mpt.update_leaves(batch.to_vec());
+            mpt.root_hash();
+        }
+        mpt.delete_leaves(&deleted.iter().map(|(key, _)| *key).collect::<Vec<_>>());
+
+        // ...which should be the same as only ever inserting the kept keys
+        let mut other = MerklePatriciaTrie::new();
+        other.update_leaves(kept.iter().rev().cloned().collect());
+
+        assert_eq!(mpt.root_hash(), other.root_hash());
+        assert_eq!(mpt.num_stored_nodes(), other.num_stored_nodes());
+        assert_eq!(mpt.get(&kept[7].0), Some(kept[7].1.as_bytes()));
+        assert_eq!(mpt.get(&deleted[7].0), None);
+
+        mpt.delete_leaves(&kept.iter().map(|(key, _)| *key).collect::<Vec<_>>());
+        assert_eq!(mpt.root_hash(), MerklePatriciaTrie::new().root_hash());
+    }
+}
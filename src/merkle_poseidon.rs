@@ -0,0 +1,212 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+
+use ark_bn254::Fr;
+use ark_ff::{PrimeField, Zero};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use blake2::{Blake2b, Digest};
+use digest::consts::U64;
+use light_poseidon::{Poseidon, PoseidonHasher as _};
+use more_asserts::assert_le;
+
+use crate::hashing_traits::{CanonicalBytes, HasherStats, ParallelTreeHasherFunc, TreeHasherFunc};
+use crate::merkle_abstract::AbstractMerkle;
+
+// The largest # of field elements the circom-compatible Poseidon parameters can hash at once, which
+// limits the arity of Poseidon trees.
+pub const MAX_POSEIDON_ARITY: usize = 12;
+
+// A Poseidon hash, i.e., an element of the BN254 scalar field, so that trees can be cheaply updated
+// inside a SNARK.
+#[derive(Default, Clone, PartialEq, Eq)]
+pub struct PoseidonHashValue {
+    hash: Fr,
+}
+
+impl Debug for PoseidonHashValue {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.to_bytes()))
+    }
+}
+
+// serialized as the 32-byte little-endian field element
+impl CanonicalBytes for PoseidonHashValue {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.hash.compressed_size());
+        self.hash.serialize_compressed(&mut bytes).unwrap();
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(PoseidonHashValue {
+            hash: Fr::deserialize_compressed(bytes).ok()?,
+        })
+    }
+}
+
+thread_local! {
+    // Poseidon instances by # of inputs. light_poseidon's hashers need a &mut self and are expensive to
+    // create (they parse their round constants), so each thread keeps its own around.
+    static POSEIDON: RefCell<HashMap<usize, Poseidon<Fr>>> = RefCell::new(HashMap::new());
+}
+
+fn poseidon(inputs: &[Fr]) -> Fr {
+    POSEIDON.with(|instances| {
+        instances
+            .borrow_mut()
+            .entry(inputs.len())
+            .or_insert_with(|| Poseidon::<Fr>::new_circom(inputs.len()).unwrap())
+            .hash(inputs)
+            .unwrap()
+    })
+}
+
+// A (non-incremental) Merkle tree hasher, whose internal nodes are the Poseidon hash of their children.
+pub struct PoseidonHasher {
+    stats: HasherStats,
+    arity: usize,
+}
+
+impl PoseidonHasher {
+    pub(crate) fn new(arity: usize) -> Self {
+        assert_le!(arity, MAX_POSEIDON_ARITY, "Poseidon trees support an arity of at most {}", MAX_POSEIDON_ARITY);
+
+        PoseidonHasher { stats: HasherStats::default(), arity }
+    }
+
+    // NOTE: Leaf data is arbitrary-length, so it is first compressed to a field element via Blake2b (as
+    // in Verkle), and then hashed with a single-input Poseidon, which domain-separates leaves from
+    // internal nodes (for arities > 1).
+    fn leaf_hash(data: String) -> PoseidonHashValue {
+        let mut hasher = Blake2b::<U64>::new();
+        hasher.update("leaf:".as_bytes());
+        hasher.update(data.as_bytes());
+
+        let elem = Fr::from_le_bytes_mod_order(&hasher.finalize());
+
+        PoseidonHashValue { hash: poseidon(&[elem]) }
+    }
+
+    fn internal_hash(
+        &self,
+        old_children: &mut [PoseidonHashValue],
+        new_children: &[(usize, PoseidonHashValue)],
+    ) -> PoseidonHashValue {
+        assert_le!(old_children.len(), self.arity);
+
+        // replace old hashes with new ones
+        for (pos, hash) in new_children {
+            old_children[*pos] = hash.clone();
+        }
+
+        // A parent with only empty children is itself empty (see HasherCRHF::internal_hash)
+        if old_children.iter().all(|h| h.hash.is_zero()) {
+            return PoseidonHashValue::default();
+        }
+
+        let inputs = old_children.iter().map(|h| h.hash).collect::<Vec<Fr>>();
+
+        PoseidonHashValue { hash: poseidon(&inputs) }
+    }
+}
+
+impl TreeHasherFunc<String, PoseidonHashValue> for PoseidonHasher {
+    fn get_num_computations(&self) -> usize {
+        self.stats.sym_hashes
+    }
+
+    fn get_stats(&self) -> HasherStats {
+        self.stats
+    }
+
+    fn hash_leaf_data(&mut self, _offset: usize, data: String) -> PoseidonHashValue {
+        self.stats.sym_hashes += 1;
+
+        Self::leaf_hash(data)
+    }
+
+    fn hash_nodes(
+        &mut self,
+        _old_parent_hash: PoseidonHashValue,
+        old_children: &mut [PoseidonHashValue],
+        new_children: &[(usize, PoseidonHashValue)],
+    ) -> PoseidonHashValue {
+        self.stats.sym_hashes += 1;
+
+        self.internal_hash(old_children, new_children)
+    }
+}
+
+impl ParallelTreeHasherFunc<String, PoseidonHashValue> for PoseidonHasher {
+    fn hash_leaf_data_shared(&self, _offset: usize, data: String) -> PoseidonHashValue {
+        Self::leaf_hash(data)
+    }
+
+    fn count_leaf_hashes(&mut self, num_leaves: usize) {
+        self.stats.sym_hashes += num_leaves;
+    }
+
+    fn hash_nodes_shared(
+        &self,
+        _old_parent_hash: PoseidonHashValue,
+        old_children: &mut [PoseidonHashValue],
+        new_children: &[(usize, PoseidonHashValue)],
+    ) -> (PoseidonHashValue, HasherStats) {
+        let stats = HasherStats { sym_hashes: 1, ..Default::default() };
+
+        (self.internal_hash(old_children, new_children), stats)
+    }
+
+    fn count_node_hashes(&mut self, stats: HasherStats) {
+        self.stats += stats;
+    }
+}
+
+pub fn new_merkle_poseidon_from_leaves(
+    arity: usize,
+    num_leaves: usize,
+) -> AbstractMerkle<String, PoseidonHashValue, PoseidonHasher> {
+    let hasher = PoseidonHasher::new(arity);
+
+    AbstractMerkle::with_num_leaves(arity, num_leaves, hasher)
+}
+
+pub fn new_merkle_poseidon_from_height(
+    arity: usize,
+    height: usize,
+) -> AbstractMerkle<String, PoseidonHashValue, PoseidonHasher> {
+    let hasher = PoseidonHasher::new(arity);
+
+    AbstractMerkle::new(arity, height, hasher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random_updates;
+    use rand::thread_rng;
+
+    #[test]
+    fn updates_match_build_and_proofs_verify() {
+        for arity in [2, 4, MAX_POSEIDON_ARITY] {
+            let num_leaves = 3 * arity + 1;
+            let mut merkle = new_merkle_poseidon_from_leaves(arity, num_leaves);
+            let mut other = new_merkle_poseidon_from_leaves(arity, num_leaves);
+
+            let leaves = (0..num_leaves).map(|pos| format!("leaf {}", pos)).collect::<Vec<_>>();
+            merkle.update_leaves(random_updates(&mut thread_rng(), num_leaves, num_leaves / 2));
+            merkle.update_leaves(leaves.iter().cloned().enumerate().collect());
+            other.build_from_leaves(leaves.clone());
+
+            let root = merkle.root_hash();
+            assert_eq!(root, other.root_hash());
+            assert_eq!(PoseidonHashValue::from_bytes(&root.to_bytes()), Some(root.clone()));
+
+            let proof = merkle.prove_batch(&[1, num_leaves - 1]);
+            let proven = [(1, leaves[1].clone()), (num_leaves - 1, leaves[num_leaves - 1].clone())];
+            assert!(merkle.verify_batch(&root, &proven, &proof));
+        }
+    }
+}
@@ -1,17 +1,22 @@
 use crate::merkle_abstract::AbstractMerkle;
-use crate::hashing_traits::{HASH_LENGTH, TreeHasherFunc};
+use crate::merkle_sparse::SparseMerkle;
+use crate::leaf_storage::NoLeafStorage;
+use crate::node_store::NodeStore;
+use crate::hashing_traits::{CanonicalBytes, HasherStats, HASH_LENGTH, ParallelTreeHasherFunc, TreeHasherFunc};
 use more_asserts::assert_le;
+use serde::de::DeserializeOwned;
 use serde::Serialize;
 use std::fmt::{Debug, Display, Formatter};
 use std::marker::PhantomData;
 use std::ops::{AddAssign, SubAssign};
+use std::sync::OnceLock;
 use std::time::Instant;
 use blake2::{Digest, Blake2b};
 use digest::consts::U32;
 use digest::generic_array::GenericArray;
 use crate::{HistogramAverages, RunningAverage};
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum MerkleppHashValue<SmallIncHash> {
     Internal(SmallIncHash),
     Leaf([u8; HASH_LENGTH]),
@@ -32,8 +37,174 @@ impl<SmallIncHash: Display> Debug for MerkleppHashValue<SmallIncHash> {
     }
 }
 
+// serialized as a 1-byte tag (0 for internal nodes, 1 for leaves), followed by the bincode-serialized
+// incremental hash or the leaf's normal hash, respectively
+impl<SmallIncHash> CanonicalBytes for MerkleppHashValue<SmallIncHash>
+where
+    SmallIncHash: Serialize + DeserializeOwned,
+{
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            MerkleppHashValue::Internal(incr_hash) => {
+                let mut bytes = vec![0u8];
+                bytes.append(bincode::serialize(incr_hash).unwrap().as_mut());
+                bytes
+            }
+            MerkleppHashValue::Leaf(normal_hash) => {
+                let mut bytes = vec![1u8];
+                bytes.extend_from_slice(normal_hash);
+                bytes
+            }
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes.split_first()? {
+            (0, rest) => {
+                let incr_hash: SmallIncHash = bincode::deserialize(rest).ok()?;
+
+                // reject trailing bytes, since they would make the serialization non-canonical
+                if bincode::serialized_size(&incr_hash).ok()? != rest.len() as u64 {
+                    return None;
+                }
+
+                Some(MerkleppHashValue::Internal(incr_hash))
+            }
+            (1, rest) => Some(MerkleppHashValue::Leaf(rest.try_into().ok()?)),
+            _ => None,
+        }
+    }
+}
+
+// An incremental (i.e., homomorphic) hash of byte strings, in which Merkle++ sums up the hashes of a
+// parent's children (see hash_child()). Any type with these operations is one, e.g., rust_incrhash's
+// Ristretto-based hashes or the lattice-based LtHash16.
+pub trait IncrementalHash: Default + AddAssign + SubAssign + for<'a> From<&'a [u8]> {}
+
+impl<T> IncrementalHash for T where T: Default + AddAssign + SubAssign + for<'a> From<&'a [u8]> {}
+
+fn leaf_hash<SmallIncHash>(data: String) -> MerkleppHashValue<SmallIncHash> {
+    // TODO: allow choice here via template parameter
+    let mut hasher = Blake2b::<U32>::new();
+
+    let mut hash = [0u8; HASH_LENGTH];
+    hasher.update("leaf:".as_bytes());
+    hasher.update(data.as_bytes());
+    hasher.finalize_into(GenericArray::from_mut_slice(&mut hash));
+
+    MerkleppHashValue::<SmallIncHash>::Leaf(hash)
+}
+
+// What the nodes of a Merkle++ tree store, which lets us trade off storage for speed (see
+// FatMerkleppNode).
+pub trait MerkleppNode<FastIncHash>: Clone + Default + PartialEq {
+    type SmallIncHash: Default + Clone + PartialEq + Serialize;
+
+    fn from_value(value: MerkleppHashValue<Self::SmallIncHash>) -> Self;
+
+    fn value(&self) -> &MerkleppHashValue<Self::SmallIncHash>;
+
+    // Returns the node's hash as the ith child of its parent (see hash_child()), and whether it was
+    // cached rather than computed.
+    fn child_hash(&self, i: usize) -> (FastIncHash, bool);
+}
+
+impl<SmallIncHash, FastIncHash> MerkleppNode<FastIncHash> for MerkleppHashValue<SmallIncHash>
+where
+    SmallIncHash: Default + Clone + PartialEq + Serialize,
+    FastIncHash: IncrementalHash,
+{
+    type SmallIncHash = SmallIncHash;
+
+    fn from_value(value: MerkleppHashValue<SmallIncHash>) -> Self {
+        value
+    }
+
+    fn value(&self) -> &MerkleppHashValue<SmallIncHash> {
+        self
+    }
+
+    fn child_hash(&self, i: usize) -> (FastIncHash, bool) {
+        (hash_child(i, self), false)
+    }
+}
+
+// A "fat" Merkle++ node, which caches its hash as a child of its parent (see hash_child()) next to it.
+// This makes a node much larger (e.g., by a RistrettoPoint), but saves re-serializing and re-hashing
+// the node when its parent is updated: the old child's hash is subtracted from the parent's without
+// being recomputed, and parents recomputed from scratch only hash their updated children.
+//
+// NOTE: The cache is filled when the node is first hashed by its parent, which is always at the same
+// offset, since nodes never move. It is not part of the node's canonical serialization, so fat and
+// thin trees have the same root bytes (and nodes loaded from disk start with an empty cache).
+#[derive(Clone, Default)]
+pub struct FatMerkleppNode<SmallIncHash, FastIncHash> {
+    value: MerkleppHashValue<SmallIncHash>,
+    child_hash: OnceLock<FastIncHash>,
+}
+
+impl<SmallIncHash: PartialEq, FastIncHash> PartialEq for FatMerkleppNode<SmallIncHash, FastIncHash> {
+    fn eq(&self, other: &Self) -> bool {
+        self.value == other.value
+    }
+}
+
+impl<SmallIncHash, FastIncHash> MerkleppNode<FastIncHash> for FatMerkleppNode<SmallIncHash, FastIncHash>
+where
+    SmallIncHash: Default + Clone + PartialEq + Serialize,
+    FastIncHash: IncrementalHash + Clone,
+{
+    type SmallIncHash = SmallIncHash;
+
+    fn from_value(value: MerkleppHashValue<SmallIncHash>) -> Self {
+        FatMerkleppNode {
+            value,
+            child_hash: OnceLock::new(),
+        }
+    }
+
+    fn value(&self) -> &MerkleppHashValue<SmallIncHash> {
+        &self.value
+    }
+
+    fn child_hash(&self, i: usize) -> (FastIncHash, bool) {
+        if let Some(hash) = self.child_hash.get() {
+            return (hash.clone(), true);
+        }
+
+        let hash: FastIncHash = hash_child(i, &self.value);
+        let _ = self.child_hash.set(hash.clone());
+
+        (hash, false)
+    }
+}
+
+impl<SmallIncHash: Display, FastIncHash> Debug for FatMerkleppNode<SmallIncHash, FastIncHash> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+impl<SmallIncHash, FastIncHash> CanonicalBytes for FatMerkleppNode<SmallIncHash, FastIncHash>
+where
+    SmallIncHash: Serialize + DeserializeOwned,
+{
+    fn to_bytes(&self) -> Vec<u8> {
+        self.value.to_bytes()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(FatMerkleppNode {
+            value: MerkleppHashValue::from_bytes(bytes)?,
+            child_hash: OnceLock::new(),
+        })
+    }
+}
+
+// NOTE: Only the symmetric (leaf) hashes and the incremental hashes are counted in the hasher's stats:
+// whether adding to a parent's hash (de)compresses it depends on the incremental hash types.
 pub struct IncrementalHasher<FastIncHash> {
-    num_hashes: usize,
+    stats: HasherStats,
     arity: usize,
     h: PhantomData<FastIncHash>,
     pub hash_nodes_histogram: HistogramAverages,
@@ -42,9 +213,9 @@ pub struct IncrementalHasher<FastIncHash> {
 }
 
 impl<FastIncHash> IncrementalHasher<FastIncHash> {
-    fn new(arity: usize) -> Self {
+    pub fn new(arity: usize) -> Self {
         IncrementalHasher {
-            num_hashes: 0,
+            stats: HasherStats::default(),
             arity,
             h: Default::default(),
             hash_nodes_histogram: HistogramAverages::new(arity),
@@ -52,22 +223,106 @@ impl<FastIncHash> IncrementalHasher<FastIncHash> {
             avg_accum_time: RunningAverage::new(),
         }
     }
+
+    // Returns the parent's old incremental hash (or the empty hash, if recomputing it from scratch),
+    // the sum of children hashes to add to it, and the number of incremental hashes computed.
+    //
+    // NOTE: Children hashes cached by fat nodes are not counted, since they are not recomputed.
+    fn hash_children<Node>(
+        &self,
+        old_parent_hash: Node,
+        old_children: &mut [Node],
+        new_children: &[(usize, Node)],
+    ) -> (Node::SmallIncHash, FastIncHash, usize)
+    where
+        Node: MerkleppNode<FastIncHash>,
+        FastIncHash: IncrementalHash,
+    {
+        // count the number of children whose hashes have changed
+        let num_changes = new_children.len();
+
+        let incr_hash;
+        let mut num_hashes = 0;
+        let mut num_cached = 0;
+        let mut acc = FastIncHash::default();
+
+        if num_changes > self.arity / 2 {
+            incr_hash = Node::SmallIncHash::default();
+            // if more than half the siblings changed, just recompute the parent from scratch
+            // since otherwise, we'd be computing more than self.arity incremental hashes
+            //
+            // NOTE(Alin): I guess we would only use this optimization when the Merkle++ tree is in-memory, since
+            // we wouldn't want to read unmodified children from disk.
+            num_hashes = self.arity;
+
+            // NOTE: The new children are hashed in place (rather than after being cloned into
+            // 'old_children'), so that fat nodes cache their hashes before they are stored.
+            let mut children = old_children.iter().collect::<Vec<&Node>>();
+            for (pos, hash) in new_children {
+                children[*pos] = hash;
+            }
+
+            // recompute parent's incremental hash from scratch
+            // NOTE: We use an intermediate FastIncHash representation for the incremental hashes
+            // to speed up their addition.
+            for (i, child) in children.into_iter().enumerate() {
+                let (hash, cached) = child.child_hash(i);
+                acc += hash;
+                num_cached += cached as usize;
+            }
+
+            // replace old hashes with new ones
+            for (pos, hash) in new_children {
+                old_children[*pos] = hash.clone(); // TODO(Perf): avoid clone?
+            }
+
+            // NOTE: A parent on the second-to-last level might have less than 'arity' children
+            assert_le!(old_children.len(), self.arity);
+        } else {
+            // if less than half the siblings changed, incrementally update the parent
+            incr_hash = match old_parent_hash.value() {
+                MerkleppHashValue::Internal(hash) => hash.clone(),
+                _ => unreachable!(),
+            };
+
+            for (pos, hash) in new_children {
+                num_hashes += 2;
+
+                let (old_hash, old_cached) = old_children[*pos].child_hash(*pos);
+                let (new_hash, new_cached) = hash.child_hash(*pos);
+                acc -= old_hash;
+                acc += new_hash;
+                num_cached += old_cached as usize + new_cached as usize;
+            }
+
+            assert_le!(num_hashes, self.arity);
+        }
+
+        (incr_hash, acc, num_hashes - num_cached)
+    }
 }
 
 // NOTE: We store children hashes in memory as IncrHash<CompressedRistretto, _>'s, but we hash them to
 // IncrHash<RistrettoPoints, _> since they are faster to add. This is why there are two parameters here
+//
+// NOTE: Empty (i.e., default) children contribute nothing to their parent's hash. This way, the
+// default tree we start from is consistent: a default parent is the (empty) sum of its default
+// children's hashes, so incremental updates and from-scratch recomputations of a parent agree.
 pub fn hash_child<SmallIncHash, FastIncHash>(
     i: usize,
     child_hash: &MerkleppHashValue<SmallIncHash>,
 ) -> FastIncHash
 where
-    SmallIncHash: Serialize,
-    for<'a> FastIncHash: From<&'a [u8]>,
+    SmallIncHash: Default + PartialEq + Serialize,
+    FastIncHash: IncrementalHash,
 {
     match child_hash {
+        MerkleppHashValue::Internal(incr_hash) if *incr_hash == SmallIncHash::default() => {
+            FastIncHash::default()
+        }
         MerkleppHashValue::Internal(incr_hash) => {
+            // NOTE: 32 bytes for (compressed) Ristretto points, but larger for lattice-based hashes
             let mut bytes = bincode::serialize(incr_hash).unwrap();
-            assert_eq!(bytes.len(), 32);
 
             bytes.append(bincode::serialize(&i).unwrap().as_mut());
 
@@ -83,94 +338,85 @@ where
     }
 }
 
-impl<SmallIncHash, FastIncHash> TreeHasherFunc<String, MerkleppHashValue<SmallIncHash>>
-    for IncrementalHasher<FastIncHash>
+impl<Node, FastIncHash> TreeHasherFunc<String, Node> for IncrementalHasher<FastIncHash>
 where
-    SmallIncHash: Default + Clone + Serialize + AddAssign<FastIncHash>,
-    for<'a> FastIncHash: Default + AddAssign + SubAssign + From<&'a [u8]>,
+    Node: MerkleppNode<FastIncHash>,
+    Node::SmallIncHash: AddAssign<FastIncHash>,
+    FastIncHash: IncrementalHash,
 {
     fn get_num_computations(&self) -> usize {
-        self.num_hashes
+        self.stats.group_hashes
+    }
+
+    fn get_stats(&self) -> HasherStats {
+        self.stats
     }
 
     // fn is_incremental(&self) -> bool {
     //     true
     // }
 
-    fn hash_leaf_data(&mut self, _offset: usize, data: String) -> MerkleppHashValue<SmallIncHash> {
-        // TODO: allow choice here via template parameter
-        let mut hasher = Blake2b::<U32>::new();
+    fn hash_leaf_data(&mut self, _offset: usize, data: String) -> Node {
+        self.stats.sym_hashes += 1;
 
-        let mut hash = [0u8; HASH_LENGTH];
-        hasher.update("leaf:".as_bytes());
-        hasher.update(data.as_bytes());
-        hasher.finalize_into(GenericArray::from_mut_slice(&mut hash));
-
-        MerkleppHashValue::<SmallIncHash>::Leaf(hash)
+        Node::from_value(leaf_hash(data))
     }
 
     fn hash_nodes(
         &mut self,
-        old_parent_hash: MerkleppHashValue<SmallIncHash>,
-        old_children: &mut Vec<MerkleppHashValue<SmallIncHash>>,
-        new_children: &Vec<(usize, MerkleppHashValue<SmallIncHash>)>,
-    ) -> MerkleppHashValue<SmallIncHash> {
-        // count the number of children whose hashes have changed
-        let num_changes = new_children.len();
-
-        let mut incr_hash;
-        let mut num_hashes = 0;
-        let mut acc = FastIncHash::default();
-
+        old_parent_hash: Node,
+        old_children: &mut [Node],
+        new_children: &[(usize, Node)],
+    ) -> Node {
         let start = Instant::now();
-        if num_changes > self.arity / 2 {
-            incr_hash = SmallIncHash::default();
-            // if more than half the siblings changed, just recompute the parent from scratch
-            // since otherwise, we'd be computing more than self.arity incremental hashes
-            //
-            // NOTE(Alin): I guess we would only use this optimization when the Merkle++ tree is in-memory, since
-            // we wouldn't want to read unmodified children from disk.
-            num_hashes = self.arity;
-
-            // replace old hashes with new ones
-            for (pos, hash) in new_children {
-                old_children[*pos] = hash.clone(); // TODO(Perf): avoid clone?
-            }
-
-            // recompute parent's incremental hash from scratch
-            // NOTE: We use an intermediate FastIncHash representation for the incremental hashes
-            // to speed up their addition.
-            for i in 0..old_children.len() {
-                acc += hash_child::<SmallIncHash, FastIncHash>(i, &old_children[i]);
-            }
+        let (mut incr_hash, acc, num_hashes) =
+            self.hash_children(old_parent_hash, old_children, new_children);
+        self.stats.group_hashes += num_hashes;
+        self.avg_hash_time.add(start.elapsed().as_micros(), num_hashes);
 
-            assert_eq!(old_children.len(), self.arity);
-        } else {
-            // if less than half the siblings changed, incrementally update the parent
-            incr_hash = match old_parent_hash {
-                MerkleppHashValue::<SmallIncHash>::Internal(hash) => hash,
-                _ => unreachable!(),
-            };
 
-            for (pos, hash) in new_children {
-                num_hashes += 2;
-                acc -= hash_child::<SmallIncHash, FastIncHash>(*pos, &old_children[*pos]);
-                acc += hash_child::<SmallIncHash, FastIncHash>(*pos, hash);
-            }
+        let start_acc = Instant::now();
+        incr_hash += acc;
+        self.avg_accum_time.add(start_acc.elapsed().as_micros(), 1);
 
-            assert_le!(num_hashes, self.arity);
+        // NOTE: e.g., hashing a parent whose children are all empty involves no updated children
+        if !new_children.is_empty() {
+            self.hash_nodes_histogram.add(new_children.len(), start.elapsed().as_micros());
         }
-        self.num_hashes += num_hashes;
-        self.avg_hash_time.add(start.elapsed().as_micros(), num_hashes);
+        Node::from_value(MerkleppHashValue::Internal(incr_hash))
+    }
+}
 
+impl<Node, FastIncHash> ParallelTreeHasherFunc<String, Node> for IncrementalHasher<FastIncHash>
+where
+    Node: MerkleppNode<FastIncHash>,
+    Node::SmallIncHash: AddAssign<FastIncHash>,
+    FastIncHash: IncrementalHash + Sync,
+{
+    fn hash_leaf_data_shared(&self, _offset: usize, data: String) -> Node {
+        Node::from_value(leaf_hash(data))
+    }
 
-        let start_acc = Instant::now();
+    fn count_leaf_hashes(&mut self, num_leaves: usize) {
+        self.stats.sym_hashes += num_leaves;
+    }
+
+    fn hash_nodes_shared(
+        &self,
+        old_parent_hash: Node,
+        old_children: &mut [Node],
+        new_children: &[(usize, Node)],
+    ) -> (Node, HasherStats) {
+        let (mut incr_hash, acc, num_hashes) =
+            self.hash_children(old_parent_hash, old_children, new_children);
         incr_hash += acc;
-        self.avg_accum_time.add(start_acc.elapsed().as_micros(), 1);
+        let stats = HasherStats { group_hashes: num_hashes, ..Default::default() };
 
+        (Node::from_value(MerkleppHashValue::Internal(incr_hash)), stats)
+    }
 
-        self.hash_nodes_histogram.add(new_children.len(), start.elapsed().as_micros());
-        MerkleppHashValue::<SmallIncHash>::Internal(incr_hash)
+    fn count_node_hashes(&mut self, stats: HasherStats) {
+        self.stats += stats;
     }
 }
 
@@ -179,8 +425,8 @@ pub fn new_merklepp_from_height<SmallIncHash, FastIncHash>(
     height: usize,
 ) -> AbstractMerkle<String, MerkleppHashValue<SmallIncHash>, IncrementalHasher<FastIncHash>>
 where
-    SmallIncHash: Clone + Default + Serialize + AddAssign<FastIncHash>,
-    for<'a> FastIncHash: Default + AddAssign + SubAssign + From<&'a [u8]>,
+    SmallIncHash: Clone + Default + PartialEq + Serialize + AddAssign<FastIncHash>,
+    FastIncHash: IncrementalHash,
 {
     let hasher = IncrementalHasher::new(arity);
 
@@ -193,10 +439,66 @@ pub fn new_merklepp_from_leaves<SmallIncHash, FastIncHash>(
     num_leaves: usize,
 ) -> AbstractMerkle<String, MerkleppHashValue<SmallIncHash>, IncrementalHasher<FastIncHash>>
 where
-    SmallIncHash: Clone + Default + Serialize + AddAssign<FastIncHash>,
-    for<'a> FastIncHash: Default + AddAssign + SubAssign + From<&'a [u8]>,
+    SmallIncHash: Clone + Default + PartialEq + Serialize + AddAssign<FastIncHash>,
+    FastIncHash: IncrementalHash,
 {
     let hasher = IncrementalHasher::new(arity);
 
     AbstractMerkle::with_num_leaves(arity, num_leaves, hasher)
-}
\ No newline at end of file
+}
+
+// returns a tree whose nodes are kept in 'nodes' (e.g., on disk)
+pub fn new_merklepp_with_node_store<SmallIncHash, FastIncHash, Nodes>(
+    arity: usize,
+    num_leaves: usize,
+    nodes: Nodes,
+) -> AbstractMerkle<String, MerkleppHashValue<SmallIncHash>, IncrementalHasher<FastIncHash>, NoLeafStorage, Nodes>
+where
+    SmallIncHash: Clone + Default + PartialEq + Serialize + AddAssign<FastIncHash>,
+    FastIncHash: IncrementalHash,
+    Nodes: NodeStore<MerkleppHashValue<SmallIncHash>>,
+{
+    let hasher = IncrementalHasher::new(arity);
+
+    AbstractMerkle::with_node_store(arity, num_leaves, hasher, nodes)
+}
+
+// returns a sparse tree with 2^256 leaves
+pub fn new_sparse_merklepp<SmallIncHash, FastIncHash>(
+    arity: usize,
+) -> SparseMerkle<String, MerkleppHashValue<SmallIncHash>, IncrementalHasher<FastIncHash>>
+where
+    SmallIncHash: Clone + Default + PartialEq + Serialize + AddAssign<FastIncHash>,
+    FastIncHash: IncrementalHash,
+{
+    let hasher = IncrementalHasher::new(arity);
+
+    SparseMerkle::new(arity, hasher)
+}
+
+pub fn new_sparse_merklepp_from_height<SmallIncHash, FastIncHash>(
+    arity: usize,
+    height: usize,
+) -> SparseMerkle<String, MerkleppHashValue<SmallIncHash>, IncrementalHasher<FastIncHash>>
+where
+    SmallIncHash: Clone + Default + PartialEq + Serialize + AddAssign<FastIncHash>,
+    FastIncHash: IncrementalHash,
+{
+    let hasher = IncrementalHasher::new(arity);
+
+    SparseMerkle::with_height(arity, height, hasher)
+}
+
+// returns a tree whose nodes cache their hashes as children (see FatMerkleppNode)
+pub fn new_fat_merklepp_from_leaves<SmallIncHash, FastIncHash>(
+    arity: usize,
+    num_leaves: usize,
+) -> AbstractMerkle<String, FatMerkleppNode<SmallIncHash, FastIncHash>, IncrementalHasher<FastIncHash>>
+where
+    SmallIncHash: Clone + Default + PartialEq + Serialize + AddAssign<FastIncHash>,
+    FastIncHash: IncrementalHash + Clone,
+{
+    let hasher = IncrementalHasher::new(arity);
+
+    AbstractMerkle::with_num_leaves(arity, num_leaves, hasher)
+}
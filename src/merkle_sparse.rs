@@ -0,0 +1,380 @@
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use more_asserts::{assert_ge, assert_le, debug_assert_le};
+use sha3::{Digest, Sha3_256};
+
+use crate::hashing_traits::TreeHasherFunc;
+use crate::merkle_abstract::normalize_updates;
+
+// The number of bits in a key, which determines the number of leaves (i.e., 2^256) in a sparse tree
+pub const SPARSE_KEY_BITS: usize = 256;
+
+// A leaf's key in a sparse tree. The key's bits, starting from the most significant bit of key[0],
+// give the path from the root to the leaf, log2(arity) bits per level.
+pub type SparseKey = [u8; SPARSE_KEY_BITS / 8];
+
+// Returns the key of some piece of data (e.g., an account address) by hashing it, so that keys are
+// uniformly spread across the tree.
+pub fn hash_to_key(data: &[u8]) -> SparseKey {
+    let mut hasher = Sha3_256::new();
+    hasher.update(data);
+
+    hasher.finalize().into()
+}
+
+// A sparse Merkle tree of arity k and height h, whose k^h leaves are *not* all allocated. Instead, only
+// nodes whose hashes differ from that of an empty subtree are stored. This allows for trees with 2^256
+// leaves, keyed by a SparseKey.
+//
+// The hash of an empty subtree rooted at each level is computed once and cached. For the hashers in
+// this crate, it is always the default hash, since an empty child contributes nothing to its parent.
+pub struct SparseMerkle<LeafDataType, HashType, Hasher> {
+    arity: usize, // the tree's arity, which must be a power of two
+
+    bits_per_level: usize, // log2(arity)
+
+    height: usize, // the level of the leaves (the root is at level 0)
+
+    // The hashes of the non-empty nodes, indexed by their level and by the key prefix of the path to
+    // them (with all bits below that level set to zero).
+    nodes: HashMap<(usize, SparseKey), HashType>,
+
+    // default_hashes[i] is the hash of an empty subtree whose root is at level i
+    default_hashes: Vec<HashType>,
+
+    pub hasher: Hasher,
+
+    phantom: PhantomData<LeafDataType>,
+}
+
+impl<LeafDataType, HashType, Hasher> SparseMerkle<LeafDataType, HashType, Hasher>
+    where
+        LeafDataType: Clone,
+        HashType: Default + Clone + PartialEq,
+        Hasher: TreeHasherFunc<LeafDataType, HashType>,
+{
+    // Returns a tree with 2^256 leaves.
+    pub fn new(arity: usize, hasher: Hasher) -> Self {
+        assert!(arity.is_power_of_two());
+
+        let bits_per_level = arity.trailing_zeros() as usize;
+
+        SparseMerkle::with_height(arity, SPARSE_KEY_BITS / bits_per_level, hasher)
+    }
+
+    // Returns a tree with arity^height leaves, whose leaves are keyed by the first log2(arity) * height
+    // bits of a SparseKey (the remaining bits are ignored).
+    pub fn with_height(arity: usize, height: usize, mut hasher: Hasher) -> Self {
+        assert!(arity.is_power_of_two());
+        assert_ge!(arity, 2);
+
+        let bits_per_level = arity.trailing_zeros() as usize;
+        assert_ge!(height, 1);
+        assert_le!(bits_per_level * height, SPARSE_KEY_BITS);
+
+        // compute the empty subtree hashes bottom-up, starting with an empty leaf
+        let mut default_hashes = vec![HashType::default(); height + 1];
+        for level in (0..height).rev() {
+            let children = vec![default_hashes[level + 1].clone(); arity];
+
+            default_hashes[level] = hasher.hash_all_children(&children);
+        }
+
+        SparseMerkle {
+            arity,
+            bits_per_level,
+            height,
+            nodes: HashMap::new(),
+            default_hashes,
+            hasher,
+            phantom: Default::default(),
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // returns the # of nodes whose hashes are actually stored (i.e., the non-empty ones)
+    pub fn num_stored_nodes(&self) -> usize {
+        self.nodes.len()
+    }
+
+    // returns the current root hash of the tree
+    pub fn root_hash(&self) -> HashType {
+        self.get_node_hash(0, &[0u8; SPARSE_KEY_BITS / 8])
+    }
+
+    // returns the offset, relative to its parent, of the node at level 'level' on the path to 'key'
+    fn child_offset(&self, key: &SparseKey, level: usize) -> usize {
+        debug_assert!(level > 0);
+
+        let first_bit = (level - 1) * self.bits_per_level;
+
+        (first_bit..first_bit + self.bits_per_level)
+            .fold(0, |offset, bit| (offset << 1) | get_bit(key, bit))
+    }
+
+    // returns 'key' with all the bits below level 'level' set to zero; i.e., the index of the node at
+    // that level on the path to 'key'
+    fn prefix(&self, key: &SparseKey, level: usize) -> SparseKey {
+        let mut prefix = *key;
+        let num_bits = level * self.bits_per_level;
+
+        if num_bits < SPARSE_KEY_BITS {
+            // keep the first (num_bits % 8) bits of this byte and zero out all subsequent bytes
+            prefix[num_bits / 8] &= !(0xFFu8 >> (num_bits % 8));
+            prefix[num_bits / 8 + 1..].fill(0);
+        }
+
+        prefix
+    }
+
+    // returns the index of the ith child of the node at level 'level' with index 'prefix'
+    fn child_prefix(&self, prefix: &SparseKey, level: usize, i: usize) -> SparseKey {
+        let mut child = *prefix;
+        let first_bit = level * self.bits_per_level;
+
+        for j in 0..self.bits_per_level {
+            set_bit(&mut child, first_bit + j, (i >> (self.bits_per_level - 1 - j)) & 1);
+        }
+
+        child
+    }
+
+    fn get_node_hash(&self, level: usize, prefix: &SparseKey) -> HashType {
+        self.nodes
+            .get(&(level, *prefix))
+            .cloned()
+            .unwrap_or_else(|| self.default_hashes[level].clone())
+    }
+
+    // NOTE: Nodes that become empty are removed, so that deleted leaves no longer take up space.
+    fn set_node_hash(&mut self, level: usize, prefix: SparseKey, hash: HashType) {
+        if hash == self.default_hashes[level] {
+            self.nodes.remove(&(level, prefix));
+        } else {
+            self.nodes.insert((level, prefix), hash);
+        }
+    }
+
+    // Sets the leaves with the specified keys. If a leaf is set more than once, the last update wins.
+    pub fn update_leaves(&mut self, new_leaves: Vec<(SparseKey, LeafDataType)>) {
+        // NOTE: Keys that only differ in their ignored bits refer to the same leaf.
+        let new_leaves = new_leaves
+            .into_iter()
+            .map(|(key, leaf_data)| (self.prefix(&key, self.height), leaf_data))
+            .collect();
+
+        let updates = normalize_updates(new_leaves, |_, later| later)
+            .into_iter()
+            .map(|(prefix, leaf_data)| {
+                let offset = self.child_offset(&prefix, self.height);
+
+                (prefix, self.hasher.hash_leaf_data(offset, leaf_data))
+            })
+            .collect::<Vec<_>>();
+
+        self._process_updates(updates);
+    }
+
+    // Resets the leaves with the specified keys to the empty leaf, as if they were never set.
+    pub fn delete_leaves(&mut self, keys: &[SparseKey]) {
+        let mut keys = keys
+            .iter()
+            .map(|key| self.prefix(key, self.height))
+            .collect::<Vec<_>>();
+        keys.sort_unstable();
+        keys.dedup();
+
+        let empty_leaf = self.default_hashes[self.height].clone();
+        let updates = keys
+            .into_iter()
+            .map(|prefix| (prefix, empty_leaf.clone()))
+            .collect();
+
+        self._process_updates(updates);
+    }
+
+    // Updates the tree level by level, starting with the new leaf hashes in 'updates'.
+    //
+    // Since the updates are sorted by key, the updated children of a parent are always next to each
+    // other, and so are the updated parents on the level above.
+    fn _process_updates(&mut self, mut updates: Vec<(SparseKey, HashType)>) {
+        // NOTE: debug_assert_* calls are disabled for benchmarks!
+        debug_assert!(updates.windows(2).all(|w| w[0].0 < w[1].0));
+
+        let mut old_children: Vec<HashType> = Vec::with_capacity(self.arity);
+        let mut new_children: Vec<(usize, HashType)> = Vec::with_capacity(self.arity);
+
+        for level in (1..=self.height).rev() {
+            let mut parent_updates = Vec::new();
+            let mut updates_iter = updates.into_iter().peekable();
+
+            while let Some((first_child, hash)) = updates_iter.next() {
+                let parent = self.prefix(&first_child, level - 1);
+
+                new_children.clear();
+                new_children.push((self.child_offset(&first_child, level), hash));
+
+                // gather the other updated children of this parent
+                while let Some((sib, _)) = updates_iter.peek() {
+                    if self.prefix(sib, level - 1) != parent {
+                        break;
+                    }
+
+                    let (sib, hash) = updates_iter.next().unwrap();
+                    new_children.push((self.child_offset(&sib, level), hash));
+                }
+                debug_assert_le!(new_children.len(), self.arity);
+
+                old_children.clear();
+                for i in 0..self.arity {
+                    let child = self.child_prefix(&parent, level - 1, i);
+
+                    old_children.push(self.get_node_hash(level, &child));
+                }
+
+                let hash = self.hasher.hash_nodes(
+                    self.get_node_hash(level - 1, &parent),
+                    &mut old_children,
+                    &new_children,
+                );
+
+                for (offset, child_hash) in new_children.drain(..) {
+                    let child = self.child_prefix(&parent, level - 1, offset);
+
+                    self.set_node_hash(level, child, child_hash);
+                }
+
+                parent_updates.push((parent, hash));
+            }
+
+            updates = parent_updates;
+        }
+
+        // only the root is left
+        debug_assert_le!(updates.len(), 1);
+        for (root, hash) in updates {
+            self.set_node_hash(0, root, hash);
+        }
+    }
+}
+
+// returns the ith bit of 'key', where the 0th bit is the most significant bit of key[0]
+fn get_bit(key: &SparseKey, i: usize) -> usize {
+    ((key[i / 8] >> (7 - i % 8)) & 1) as usize
+}
+
+fn set_bit(key: &mut SparseKey, i: usize, bit: usize) {
+    let mask = 1u8 << (7 - i % 8);
+
+    if bit == 1 {
+        key[i / 8] |= mask;
+    } else {
+        key[i / 8] &= !mask;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle_abstract::AbstractMerkle;
+    use crate::merkle_crhf::{
+        new_merkle_crhf_from_height, new_sparse_merkle_crhf, new_sparse_merkle_crhf_from_height,
+        TinySha3HashFunc,
+    };
+    use crate::merkle_pp::{new_merklepp_from_height, new_sparse_merklepp_from_height};
+    use crate::verkle::{new_sparse_verkle_from_height, new_verkle_from_height};
+    use crate::random_leaf_positions;
+    use curve25519_dalek::constants::RISTRETTO_BASEPOINT_TABLE;
+    use curve25519_dalek::scalar::Scalar;
+    use rand::thread_rng;
+    use rust_incrhash::compressed_ristretto::CompRistBlakeIncHash;
+    use rust_incrhash::ristretto::RistBlakeIncHash;
+    use std::fmt::Debug;
+
+    // returns the key of the leaf at position 'pos' in a tree with 'key_bits'-bit keys
+    fn pos_to_key(pos: usize, key_bits: usize) -> SparseKey {
+        let mut key = [0u8; SPARSE_KEY_BITS / 8];
+
+        for i in 0..key_bits {
+            set_bit(&mut key, i, (pos >> (key_bits - 1 - i)) & 1);
+        }
+
+        key
+    }
+
+    // updates the same leaves in a sparse tree and in a dense one of the same shape, and checks the
+    // roots match, both after setting and after deleting leaves
+    fn test_sparse_matches_dense<HashType, Hasher>(
+        sparse: &mut SparseMerkle<String, HashType, Hasher>,
+        dense: &mut AbstractMerkle<String, HashType, Hasher>,
+    )
+    where
+        HashType: Default + Clone + PartialEq + Debug,
+        Hasher: TreeHasherFunc<String, HashType>,
+    {
+        let key_bits = sparse.height * sparse.bits_per_level;
+        let num_leaves = dense.num_leaves();
+        let positions = random_leaf_positions(&mut thread_rng(), num_leaves, num_leaves / 3 + 1).collect::<Vec<usize>>();
+
+        sparse.update_leaves(
+            positions.iter().map(|pos| (pos_to_key(*pos, key_bits), format!("leaf {}", pos))).collect(),
+        );
+        dense.update_leaves(positions.iter().map(|pos| (*pos, format!("leaf {}", pos))).collect());
+        assert_eq!(sparse.root_hash(), dense.root_hash());
+
+        let deleted = &positions[..positions.len() / 2];
+        sparse.delete_leaves(
+            &deleted.iter().map(|pos| pos_to_key(*pos, key_bits)).collect::<Vec<_>>(),
+        );
+        dense.delete_leaves(deleted);
+        assert_eq!(sparse.root_hash(), dense.root_hash());
+
+        // deleting all leaves frees all nodes
+        sparse.delete_leaves(
+            &positions.iter().map(|pos| pos_to_key(*pos, key_bits)).collect::<Vec<_>>(),
+        );
+        assert_eq!(sparse.num_stored_nodes(), 0);
+    }
+
+    #[test]
+    fn sparse_matches_dense() {
+        for (arity, height) in [(2, 6), (4, 3), (16, 2)] {
+            let mut sparse = new_sparse_merkle_crhf_from_height::<TinySha3HashFunc>(arity, height);
+            let mut dense = new_merkle_crhf_from_height::<TinySha3HashFunc>(arity, height);
+            test_sparse_matches_dense(&mut sparse, &mut dense);
+
+            let mut sparse = new_sparse_merklepp_from_height::<CompRistBlakeIncHash, RistBlakeIncHash>(arity, height);
+            let mut dense = new_merklepp_from_height::<CompRistBlakeIncHash, RistBlakeIncHash>(arity, height);
+            test_sparse_matches_dense(&mut sparse, &mut dense);
+
+            let bases: Vec<_> = (0..arity)
+                .map(|_| &Scalar::random(&mut thread_rng()) * &RISTRETTO_BASEPOINT_TABLE)
+                .collect();
+            let mut sparse = new_sparse_verkle_from_height(arity, height, bases.clone());
+            let mut dense = new_verkle_from_height(arity, height, bases);
+            test_sparse_matches_dense(&mut sparse, &mut dense);
+        }
+    }
+
+    #[test]
+    fn full_key_space() {
+        let mut merkle = new_sparse_merkle_crhf::<TinySha3HashFunc>(2);
+        assert_eq!(merkle.height(), SPARSE_KEY_BITS);
+
+        let mut keys = (0..10u32).map(|i| hash_to_key(&i.to_be_bytes())).collect::<Vec<_>>();
+        keys.sort_unstable();
+
+        merkle.update_leaves(keys.iter().map(|key| (*key, hex::encode(key))).collect());
+        assert_ne!(merkle.root_hash(), Default::default());
+        // at most one node per key per level, plus the root
+        assert_le!(merkle.num_stored_nodes(), keys.len() * SPARSE_KEY_BITS + 1);
+
+        merkle.delete_leaves(&keys);
+        assert_eq!(merkle.root_hash(), Default::default());
+        assert_eq!(merkle.num_stored_nodes(), 0);
+    }
+}
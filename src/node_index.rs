@@ -1,5 +1,8 @@
+use arrayvec::ArrayVec;
 use more_asserts::{assert_ge, assert_lt};
 use std::fmt::{Debug, Formatter};
+use std::iter::successors;
+use std::ops::DerefMut;
 
 // There are (n-1) / (k-1) internal nodes and n leaves.
 // We use a node index, i.e., a number from 0 to [(n - 1) / (k - 1) + n] - 1 to refer to any node (internal or leaf.)
@@ -7,16 +10,25 @@ use std::fmt::{Debug, Formatter};
 pub struct NodeIndex(pub(crate) usize);
 
 impl NodeIndex {
-    pub(crate) fn root_node() -> NodeIndex {
+    pub fn new(idx: usize) -> Self {
+        NodeIndex(idx)
+    }
+
+    // returns this node's position in the tree's nodes (i.e., in level order)
+    pub fn index(&self) -> usize {
+        self.0
+    }
+
+    pub fn root_node() -> NodeIndex {
         NodeIndex(0)
     }
     // returns true if this node is the root
-    pub(crate) fn is_root(&self) -> bool {
+    pub fn is_root(&self) -> bool {
         self.0 == 0
     }
 
     // returns this node's child offset; i.e., its position i relative to its parent, where i \in [0, arity)
-    pub(crate) fn child_offset(&self, arity: usize) -> usize {
+    pub fn child_offset(&self, arity: usize) -> usize {
         if self.is_root() {
             0
         } else {
@@ -25,20 +37,31 @@ impl NodeIndex {
     }
 
     // returns the parent's NodeIndex
-    pub(crate) fn parent(&self, arity: usize) -> Self {
+    pub fn parent(&self, arity: usize) -> Self {
         assert_ne!(self.0, 0); // the root has no parent
 
         NodeIndex((self.0 - 1) / arity)
     }
 
     // returns the NodeIndex of the ith child, where i \in [0, arity)
-    pub(crate) fn child(&self, arity: usize, i: usize) -> Self {
+    pub fn child(&self, arity: usize, i: usize) -> Self {
         assert_lt!(i, arity);
         assert_ge!(i, 0);
 
         NodeIndex(self.0 * arity + (i + 1))
     }
 
+    // returns the NodeIndex of the leftmost node on the given level (the root is at level 0), i.e.,
+    // (k^level - 1) / (k - 1), or None if that index overflows
+    pub fn first_on_level(arity: usize, level: usize) -> Option<Self> {
+        let mut idx = 0usize;
+        for _ in 0..level {
+            idx = idx.checked_mul(arity)?.checked_add(1)?;
+        }
+
+        Some(NodeIndex(idx))
+    }
+
     // pub(crate) fn next_sibling(&self) -> Self {
     //     NodeIndex(self.0 + 1)
     // }
@@ -48,12 +71,140 @@ impl NodeIndex {
     //     self.parent(arity) == other.parent(arity)
     // }
 
-    // returns the level i of the node: root is at level 0
-    // i.e., level i has k^i nodes
-    //fn level(&self, arity: usize) -> usize {
-    //  TODO: for k = 2, compute floor(log_2(node + 1))
-    //  ...but for k > 2 need to account for k (see figure above)
-    //}
+    // Returns the level i of the node: root is at level 0, i.e., level i has k^i nodes.
+    //
+    // Since level i starts at node (k^i - 1) / (k - 1), this is floor(log_k(node * (k - 1) + 1)), which
+    // takes a couple of instructions for power-of-two arities, rather than walking up to the root.
+    pub fn level(&self, arity: usize) -> usize {
+        assert_ge!(arity, 2);
+
+        // NOTE: in 128 bits, since node * (k - 1) can overflow a usize
+        let first_on_level_times = self.0 as u128 * (arity as u128 - 1) + 1;
+
+        if arity.is_power_of_two() {
+            (first_on_level_times.ilog2() / arity.ilog2()) as usize
+        } else {
+            first_on_level_times.ilog(arity as u128) as usize
+        }
+    }
+
+    // Returns the nodes in this node's subtree on the given level (which must be at or below this
+    // node's), from left to right, e.g., the leaves below it, if 'level' is that of the leaves.
+    //
+    // NOTE: Unless the tree is perfect, some of these nodes might not exist (e.g., past the last leaf).
+    pub fn leaf_range(&self, arity: usize, level: usize) -> impl Iterator<Item = NodeIndex> {
+        let own_level = self.level(arity);
+        assert_ge!(level, own_level);
+
+        let (mut first, mut last) = (self.0, self.0);
+        for _ in own_level..level {
+            first = first.checked_mul(arity).and_then(|idx| idx.checked_add(1)).expect("Level is too deep");
+            last = last.checked_mul(arity).and_then(|idx| idx.checked_add(arity)).expect("Level is too deep");
+        }
+
+        (first..=last).map(NodeIndex)
+    }
+
+    // returns this node's parent, grandparent and so on, up to (and including) the root
+    pub fn ancestors(&self, arity: usize) -> impl Iterator<Item = NodeIndex> {
+        let mut path = self.path_to_root(arity);
+        path.next();
+
+        path
+    }
+
+    // returns the nodes on the path from this node up to the root, including both
+    pub fn path_to_root(&self, arity: usize) -> impl Iterator<Item = NodeIndex> {
+        successors(Some(*self), move |node| (!node.is_root()).then(|| node.parent(arity)))
+    }
+}
+
+// The arity of a tree, which is either only known at run time (DynArity, the default), or at compile
+// time (ConstArity). In the latter case, the index arithmetic on the update path (e.g., parent() and
+// child_offset()) divides by a constant, which compiles down to a multiplication and shifts rather than
+// a division (or to just shifts, for powers of two). Also, the buffers holding a node's children (or
+// siblings) are ArrayVecs on the stack, rather than Vecs allocated for every parent we hash.
+pub trait TreeArity: Copy {
+    type Buffer<T>: ChildBuffer<T>;
+
+    // panics if 'arity' is not the compile-time arity (if any)
+    fn from_usize(arity: usize) -> Self;
+
+    fn get(self) -> usize;
+
+    fn buffer<T>(self) -> Self::Buffer<T> {
+        Self::Buffer::<T>::with_arity(self.get())
+    }
+}
+
+// A buffer with room for (at most) 'arity' children of a node.
+pub trait ChildBuffer<T>: DerefMut<Target = [T]> + FromIterator<T> {
+    fn with_arity(arity: usize) -> Self;
+
+    fn push(&mut self, elem: T);
+
+    fn clear(&mut self);
+}
+
+impl<T> ChildBuffer<T> for Vec<T> {
+    fn with_arity(arity: usize) -> Self {
+        Vec::with_capacity(arity)
+    }
+
+    fn push(&mut self, elem: T) {
+        Vec::push(self, elem)
+    }
+
+    fn clear(&mut self) {
+        Vec::clear(self)
+    }
+}
+
+impl<T, const ARITY: usize> ChildBuffer<T> for ArrayVec<T, ARITY> {
+    fn with_arity(arity: usize) -> Self {
+        debug_assert_eq!(arity, ARITY);
+        ArrayVec::new()
+    }
+
+    fn push(&mut self, elem: T) {
+        ArrayVec::push(self, elem)
+    }
+
+    fn clear(&mut self) {
+        ArrayVec::clear(self)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DynArity(usize);
+
+impl TreeArity for DynArity {
+    type Buffer<T> = Vec<T>;
+
+    fn from_usize(arity: usize) -> Self {
+        DynArity(arity)
+    }
+
+    fn get(self) -> usize {
+        self.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConstArity<const ARITY: usize>;
+
+impl<const ARITY: usize> TreeArity for ConstArity<ARITY> {
+    type Buffer<T> = ArrayVec<T, ARITY>;
+
+    fn from_usize(arity: usize) -> Self {
+        assert_eq!(arity, ARITY, "Expected a tree of arity {}", ARITY);
+
+        ConstArity
+    }
+
+    fn get(self) -> usize {
+        ARITY
+    }
 }
 
 impl Debug for NodeIndex {
@@ -73,4 +224,57 @@ mod tests {
         let n = NodeIndex(1);
         assert_eq!(n.parent(2), NodeIndex(0));
     }
+
+    #[test]
+    fn const_arity_matches_dyn_arity() {
+        let arity = ConstArity::<16>::from_usize(16).get();
+        assert_eq!(arity, DynArity::from_usize(16).get());
+
+        for idx in 1..1000 {
+            let node = NodeIndex(idx);
+            assert_eq!(node.parent(arity).child(arity, node.child_offset(arity)), node);
+        }
+    }
+
+    #[test]
+    fn level_matches_first_on_level() {
+        for arity in [2, 3, 4, 5, 16, 256] {
+            let mut level = 0;
+            for idx in 0..5000 {
+                if NodeIndex::first_on_level(arity, level + 1).unwrap().0 == idx {
+                    level += 1;
+                }
+
+                let node = NodeIndex(idx);
+                assert_eq!(node.level(arity), level);
+                assert_eq!(node.path_to_root(arity).count(), level + 1);
+            }
+
+            // the first node on the deepest level that fits in a usize, whose index is close to overflowing
+            let deep = (1..).take_while(|&level| NodeIndex::first_on_level(arity, level).is_some()).last().unwrap();
+            let first = NodeIndex::first_on_level(arity, deep).unwrap();
+            assert_eq!(first.level(arity), deep);
+            assert_eq!(NodeIndex(first.0 - 1).level(arity), deep - 1);
+        }
+    }
+
+    #[test]
+    fn navigation() {
+        // a ternary tree: 0 -> [1, 2, 3], 1 -> [4, 5, 6], 2 -> [7, 8, 9], ..., 4 -> [13, 14, 15]
+        let node = NodeIndex::new(14);
+        assert_eq!(node.index(), 14);
+        assert_eq!(node.ancestors(3).collect::<Vec<_>>(), vec![NodeIndex(4), NodeIndex(1), NodeIndex(0)]);
+        assert_eq!(node.path_to_root(3).next(), Some(node));
+        assert_eq!(NodeIndex::root_node().ancestors(3).count(), 0);
+
+        assert_eq!(NodeIndex(1).leaf_range(3, 1).collect::<Vec<_>>(), vec![NodeIndex(1)]);
+        assert_eq!(NodeIndex(1).leaf_range(3, 3).map(|node| node.0).collect::<Vec<_>>(), (13..=21).collect::<Vec<_>>());
+        assert_eq!(NodeIndex::root_node().leaf_range(3, 2).count(), 9);
+    }
+
+    #[test]
+    #[should_panic]
+    fn const_arity_rejects_other_arities() {
+        ConstArity::<16>::from_usize(4);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,546 @@
+#[cfg(feature = "mmap")]
+use std::fs::{File, OpenOptions};
+#[cfg(any(feature = "mmap", feature = "disk"))]
+use std::marker::PhantomData;
+#[cfg(any(feature = "mmap", feature = "disk"))]
+use std::path::Path;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+
+#[cfg(feature = "mmap")]
+use memmap2::MmapMut;
+use more_asserts::assert_gt;
+#[cfg(feature = "mmap")]
+use more_asserts::assert_le;
+#[cfg(any(feature = "mmap", feature = "disk"))]
+use more_asserts::assert_lt;
+
+#[cfg(any(feature = "mmap", feature = "disk"))]
+use crate::hashing_traits::CanonicalBytes;
+
+// Where AbstractMerkle keeps the hashes of its nodes, indexed by their NodeIndex. By default, they are
+// kept in memory (see MemNodeStore), but other implementations could keep them on disk, which lets us
+// benchmark trees that do not fit in RAM.
+pub trait NodeStore<HashType> {
+    // (Re)initializes the store to hold 'num_nodes' empty (i.e., default) hashes.
+    fn allocate(&mut self, num_nodes: usize);
+
+    // returns the number of nodes in the store
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // returns the hash of the node with index 'idx', or None if there is no such node
+    fn get(&self, idx: usize) -> Option<HashType>;
+
+    fn set(&mut self, idx: usize, hash: HashType);
+}
+
+// A NodeStore that can lend out the hashes it stores, rather than cloning them (e.g., LtHash16 hashes
+// take up 2 KiB each). This is not part of NodeStore, since some stores (e.g., MmapNodeStore) only keep
+// the hashes' serializations around.
+pub trait NodeStoreRef<HashType>: NodeStore<HashType> {
+    fn get_ref(&self, idx: usize) -> Option<&HashType>;
+}
+
+// Stores all the hashes in a vector, in memory.
+pub struct MemNodeStore<HashType> {
+    nodes: Vec<HashType>,
+}
+
+impl<HashType> MemNodeStore<HashType> {
+    pub fn new() -> Self {
+        MemNodeStore { nodes: Vec::new() }
+    }
+}
+
+impl<HashType> Default for MemNodeStore<HashType> {
+    fn default() -> Self {
+        MemNodeStore::new()
+    }
+}
+
+impl<HashType: Default + Clone> NodeStore<HashType> for MemNodeStore<HashType> {
+    fn allocate(&mut self, num_nodes: usize) {
+        self.nodes = vec![HashType::default(); num_nodes];
+    }
+
+    fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn get(&self, idx: usize) -> Option<HashType> {
+        self.nodes.get(idx).cloned()
+    }
+
+    fn set(&mut self, idx: usize, hash: HashType) {
+        self.nodes[idx] = hash;
+    }
+}
+
+impl<HashType: Default + Clone> NodeStoreRef<HashType> for MemNodeStore<HashType> {
+    fn get_ref(&self, idx: usize) -> Option<&HashType> {
+        self.nodes.get(idx)
+    }
+}
+
+// The # of hashes in each chunk of a CowNodeStore
+const COW_CHUNK_SIZE: usize = 4096;
+
+// Stores the hashes in memory, in fixed-size chunks that are shared (copy-on-write) between clones of
+// the store. Cloning the store is therefore cheap: a clone only copies a chunk once one of its hashes
+// is set. This is what lets AbstractMerkle::snapshot() keep many historical versions of a tree in
+// memory, since each version only pays for the chunks that were updated since the previous one.
+//
+// NOTE: Small updates should touch few chunks, since the nodes on a path from a leaf to the root are
+// spread across at most one chunk per level.
+pub struct CowNodeStore<HashType> {
+    chunks: Vec<Arc<Vec<HashType>>>,
+    len: usize,
+}
+
+impl<HashType> CowNodeStore<HashType> {
+    pub fn new() -> Self {
+        CowNodeStore { chunks: Vec::new(), len: 0 }
+    }
+
+    // returns the # of chunks that this store shares with 'other' (e.g., a snapshot of it)
+    pub fn num_shared_chunks(&self, other: &Self) -> usize {
+        self.chunks
+            .iter()
+            .zip(other.chunks.iter())
+            .filter(|(chunk, other_chunk)| Arc::ptr_eq(chunk, other_chunk))
+            .count()
+    }
+
+    pub fn num_chunks(&self) -> usize {
+        self.chunks.len()
+    }
+}
+
+impl<HashType> Default for CowNodeStore<HashType> {
+    fn default() -> Self {
+        CowNodeStore::new()
+    }
+}
+
+// NOTE: Implemented by hand, since #[derive(Clone)] would require HashType: Clone
+impl<HashType> Clone for CowNodeStore<HashType> {
+    fn clone(&self) -> Self {
+        CowNodeStore {
+            chunks: self.chunks.clone(),
+            len: self.len,
+        }
+    }
+}
+
+impl<HashType: Default + Clone> NodeStore<HashType> for CowNodeStore<HashType> {
+    fn allocate(&mut self, num_nodes: usize) {
+        self.chunks = (0..num_nodes)
+            .step_by(COW_CHUNK_SIZE)
+            .map(|start| Arc::new(vec![HashType::default(); COW_CHUNK_SIZE.min(num_nodes - start)]))
+            .collect();
+        self.len = num_nodes;
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn get(&self, idx: usize) -> Option<HashType> {
+        self.chunks
+            .get(idx / COW_CHUNK_SIZE)
+            .and_then(|chunk| chunk.get(idx % COW_CHUNK_SIZE))
+            .cloned()
+    }
+
+    fn set(&mut self, idx: usize, hash: HashType) {
+        // copies the chunk first, if it is shared with another version
+        Arc::make_mut(&mut self.chunks[idx / COW_CHUNK_SIZE])[idx % COW_CHUNK_SIZE] = hash;
+    }
+}
+
+impl<HashType: Default + Clone> NodeStoreRef<HashType> for CowNodeStore<HashType> {
+    fn get_ref(&self, idx: usize) -> Option<&HashType> {
+        self.chunks
+            .get(idx / COW_CHUNK_SIZE)
+            .and_then(|chunk| chunk.get(idx % COW_CHUNK_SIZE))
+    }
+}
+
+// The default # of levels in each block of a BlockedNodeStore: i.e., a parent and its children
+pub const BLOCK_LEVELS: usize = 2;
+
+// How the nodes of a tree are laid out in memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NodeLayout {
+    // level by level, in the order of their NodeIndex (e.g., MemNodeStore)
+    Heap,
+    // in blocks of subtrees with this many levels (see BlockedNodeStore)
+    Blocked { levels: usize },
+}
+
+// Parsed from "heap" or "blocked[:LEVELS]"
+impl FromStr for NodeLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split(':').collect::<Vec<&str>>().as_slice() {
+            ["heap"] => Ok(NodeLayout::Heap),
+            ["blocked"] => Ok(NodeLayout::Blocked { levels: BLOCK_LEVELS }),
+            ["blocked", levels] => match levels.parse::<usize>() {
+                Ok(levels) if levels > 0 => Ok(NodeLayout::Blocked { levels }),
+                _ => Err(format!("Invalid # of levels per block '{}' for node layout '{}'", levels, s)),
+            },
+            _ => Err(format!("Unknown node layout '{}' (expected heap or blocked[:LEVELS])", s)),
+        }
+    }
+}
+
+impl Display for NodeLayout {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NodeLayout::Heap => write!(f, "heap"),
+            NodeLayout::Blocked { levels } => write!(f, "blocked:{}", levels),
+        }
+    }
+}
+
+// Where the nodes of one level of the tree are stored in a BlockedNodeStore
+#[derive(Clone, Copy, Debug)]
+struct BlockedLevel {
+    start: usize,       // the NodeIndex of the level's first node
+    block_start: usize, // where the first block containing the level is stored
+    block_size: usize,  // the # of nodes in each block containing the level
+    offset: usize,      // the # of nodes above the level in each block
+    width: usize,       // the # of nodes of the level in each block
+}
+
+// Stores the hashes in memory, but in blocks of 'levels' levels: each block holds a node, followed by its
+// children, grandchildren, etc. (level by level), so that the nodes on a path from a leaf to the root are
+// close together. In the heap layout of MemNodeStore, every level of the path is in a different part of
+// the array, which costs a cache miss per level when updating.
+//
+// e.g., for arity 2 and blocks of 2 levels, the 15 nodes of a tree of height 3 are stored as:
+//
+//      [0 1 2] [3 7 8] [4 9 10] [5 11 12] [6 13 14]
+//
+// The blocks are aligned so that the last block on any path ends on the tree's last full level. The top
+// block therefore has fewer levels if the # of full levels is not a multiple of 'levels', and the nodes
+// on a partially full last level are stored after all the blocks, in heap order. This way, every slot
+// holds a node: the store is no bigger than MemNodeStore.
+//
+// NOTE(Perf): For Blake3 trees with 2^24 leaves, this was ~20% slower than the heap layout, both for
+// arity 2 (1,000 updates per batch) and arity 16 (100,000 updates per batch): finding a node's position
+// (a binary search over the levels, and a division) costs more than the cache misses it saves, since a
+// batch of sorted updates already walks each level of the heap layout in order.
+pub struct BlockedNodeStore<HashType> {
+    nodes: Vec<HashType>,
+    arity: usize,
+    block_levels: usize,
+    levels: Vec<BlockedLevel>, // the full levels, from the root down
+    full_end: usize,           // the NodeIndex of the first node after the full levels
+}
+
+impl<HashType> BlockedNodeStore<HashType> {
+    pub fn new(arity: usize, block_levels: usize) -> Self {
+        assert_gt!(arity, 1);
+        assert_gt!(block_levels, 0);
+
+        BlockedNodeStore {
+            nodes: Vec::new(),
+            arity,
+            block_levels,
+            levels: Vec::new(),
+            full_end: 0,
+        }
+    }
+
+    // returns where the node with index 'idx' is stored
+    fn position(&self, idx: usize) -> usize {
+        if idx >= self.full_end {
+            return idx;
+        }
+
+        let level = &self.levels[self.levels.partition_point(|level| level.start <= idx) - 1];
+        let pos = idx - level.start;
+
+        level.block_start + (pos / level.width) * level.block_size + level.offset + pos % level.width
+    }
+}
+
+impl<HashType: Default + Clone> NodeStore<HashType> for BlockedNodeStore<HashType> {
+    fn allocate(&mut self, num_nodes: usize) {
+        // the NodeIndex of the first node on each level, up to the first level that is not full
+        let mut starts: Vec<usize> = vec![0];
+        while let Some(next) = starts.last().unwrap().checked_mul(self.arity).and_then(|s| s.checked_add(1)) {
+            if next > num_nodes {
+                break;
+            }
+            starts.push(next);
+        }
+        let num_full_levels = starts.len() - 1;
+
+        // the top block only has the leftover levels, so that the last blocks end on the last full level
+        let top_levels = match num_full_levels % self.block_levels {
+            0 => self.block_levels,
+            leftover => leftover,
+        };
+
+        self.levels = (0..num_full_levels)
+            .map(|level| {
+                let (top, block_levels) = if level < top_levels {
+                    (0, top_levels)
+                } else {
+                    (level - (level - top_levels) % self.block_levels, self.block_levels)
+                };
+                let depth = level - top;
+
+                BlockedLevel {
+                    start: starts[level],
+                    block_start: starts[top],
+                    block_size: starts[block_levels],
+                    offset: starts[depth],
+                    width: starts[depth + 1] - starts[depth],
+                }
+            })
+            .collect();
+        self.full_end = starts[num_full_levels];
+        self.nodes = vec![HashType::default(); num_nodes];
+    }
+
+    fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    fn get(&self, idx: usize) -> Option<HashType> {
+        self.get_ref(idx).cloned()
+    }
+
+    fn set(&mut self, idx: usize, hash: HashType) {
+        let pos = self.position(idx);
+
+        self.nodes[pos] = hash;
+    }
+}
+
+impl<HashType: Default + Clone> NodeStoreRef<HashType> for BlockedNodeStore<HashType> {
+    fn get_ref(&self, idx: usize) -> Option<&HashType> {
+        if idx >= self.nodes.len() {
+            return None;
+        }
+
+        Some(&self.nodes[self.position(idx)])
+    }
+}
+
+// Stores the hashes in a memory-mapped file, so that the OS pages them in and out as needed, rather
+// than allocating all of them in memory up front.
+//
+// Each node takes up a fixed-size slot of 'slot_size' bytes: the first byte is the length of the
+// node's canonical serialization, which follows it. A zero length denotes an empty (i.e., default)
+// node, so a freshly-allocated (all-zeroes) file is an empty tree and can be created sparsely.
+#[cfg(feature = "mmap")]
+pub struct MmapNodeStore<HashType> {
+    file: File,
+    mmap: Option<MmapMut>, // None when there are no nodes, since empty files cannot be mapped
+    slot_size: usize,
+    len: usize,
+    phantom: PhantomData<HashType>,
+}
+
+#[cfg(feature = "mmap")]
+impl<HashType> MmapNodeStore<HashType> {
+    // Creates (or truncates) the file at 'path' to store the nodes in. 'slot_size' must be at least
+    // one more than the size of the largest serialized hash (e.g., 33 for 32-byte hashes).
+    pub fn create<P: AsRef<Path>>(path: P, slot_size: usize) -> std::io::Result<Self> {
+        assert_le!(slot_size, u8::MAX as usize + 1);
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+
+        Ok(MmapNodeStore {
+            file,
+            mmap: None,
+            slot_size,
+            len: 0,
+            phantom: Default::default(),
+        })
+    }
+
+    fn slot(&self, idx: usize) -> &[u8] {
+        let start = idx * self.slot_size;
+
+        &self.mmap.as_ref().unwrap()[start..start + self.slot_size]
+    }
+
+    fn slot_mut(&mut self, idx: usize) -> &mut [u8] {
+        let start = idx * self.slot_size;
+
+        &mut self.mmap.as_mut().unwrap()[start..start + self.slot_size]
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl<HashType> NodeStore<HashType> for MmapNodeStore<HashType>
+where
+    HashType: CanonicalBytes + Default + PartialEq,
+{
+    fn allocate(&mut self, num_nodes: usize) {
+        self.mmap = None;
+        self.len = num_nodes;
+
+        // NOTE: Truncating first zeroes out any previous contents. On most filesystems, extending
+        // the file afterwards does not actually write anything to disk.
+        self.file.set_len(0).expect("Could not truncate node file");
+        self.file
+            .set_len((num_nodes * self.slot_size) as u64)
+            .expect("Could not extend node file");
+
+        if num_nodes > 0 {
+            // SAFETY: The file is exclusively ours, since we created (or truncated) it ourselves.
+            self.mmap = Some(unsafe { MmapMut::map_mut(&self.file) }.expect("Could not mmap node file"));
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn get(&self, idx: usize) -> Option<HashType> {
+        if idx >= self.len {
+            return None;
+        }
+
+        let slot = self.slot(idx);
+        let hash_len = slot[0] as usize;
+
+        if hash_len == 0 {
+            Some(HashType::default())
+        } else {
+            Some(HashType::from_bytes(&slot[1..1 + hash_len]).expect("Corrupted node file"))
+        }
+    }
+
+    fn set(&mut self, idx: usize, hash: HashType) {
+        assert_lt!(idx, self.len);
+
+        let bytes = if hash == HashType::default() {
+            vec![]
+        } else {
+            hash.to_bytes()
+        };
+        assert_lt!(bytes.len(), self.slot_size, "Hash does not fit in a node slot");
+
+        let slot = self.slot_mut(idx);
+        slot[0] = bytes.len() as u8;
+        slot[1..1 + bytes.len()].copy_from_slice(&bytes);
+    }
+}
+
+// Stores the hashes in an on-disk key-value store (sled, an embedded database in the spirit of RocksDB),
+// keyed by their node's index, so that trees larger than RAM can be benchmarked in the I/O-bound regime
+// a blockchain's state tree is in. Unlike MmapNodeStore, which pages in a flat array of slots, the
+// database decides how the nodes are laid out on disk, and caches the ones it read or wrote recently.
+//
+// Only non-empty hashes are stored, as their canonical serializations, so a freshly-allocated store is
+// empty on disk too.
+//
+// NOTE(Perf): Every get() and set() is a database lookup or write. The writes are only flushed to disk
+// by the database in the background (or when the store is dropped), so a crash can lose the latest
+// updates, which is fine for benchmarking.
+#[cfg(feature = "disk")]
+pub struct DiskNodeStore<HashType> {
+    db: sled::Db,
+    len: usize,
+    phantom: PhantomData<HashType>,
+}
+
+#[cfg(feature = "disk")]
+impl<HashType> DiskNodeStore<HashType> {
+    // Opens (or creates) the database in the directory at 'path', whose previous nodes (if any) are
+    // discarded by the first allocate(). The database caches up to 'cache_bytes' bytes in memory.
+    pub fn open<P: AsRef<Path>>(path: P, cache_bytes: u64) -> sled::Result<Self> {
+        let db = sled::Config::new().path(path).cache_capacity(cache_bytes).open()?;
+
+        Ok(DiskNodeStore { db, len: 0, phantom: Default::default() })
+    }
+
+    // NOTE: Big-endian, so that the database keeps nodes with close indices (e.g., siblings) together
+    fn key(idx: usize) -> [u8; 8] {
+        (idx as u64).to_be_bytes()
+    }
+}
+
+#[cfg(feature = "disk")]
+impl<HashType> NodeStore<HashType> for DiskNodeStore<HashType>
+where
+    HashType: CanonicalBytes + Default + PartialEq,
+{
+    fn allocate(&mut self, num_nodes: usize) {
+        self.db.clear().expect("Could not clear the node database");
+        self.len = num_nodes;
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn get(&self, idx: usize) -> Option<HashType> {
+        if idx >= self.len {
+            return None;
+        }
+
+        match self.db.get(Self::key(idx)).expect("Could not read from the node database") {
+            Some(bytes) => Some(HashType::from_bytes(&bytes).expect("Corrupted node database")),
+            None => Some(HashType::default()),
+        }
+    }
+
+    fn set(&mut self, idx: usize, hash: HashType) {
+        assert_lt!(idx, self.len);
+
+        let result = if hash == HashType::default() {
+            self.db.remove(Self::key(idx)).map(|_| ())
+        } else {
+            self.db.insert(Self::key(idx), hash.to_bytes()).map(|_| ())
+        };
+        result.expect("Could not write to the node database");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocked_layout_is_a_permutation() {
+        for arity in [2, 3, 16] {
+            for block_levels in [1, 2, 3] {
+                for num_nodes in [0, 1, 2, 7, 15, 31, 100, 273, 1000] {
+                    let mut store = BlockedNodeStore::<usize>::new(arity, block_levels);
+                    store.allocate(num_nodes);
+
+                    let mut positions = (0..num_nodes).map(|idx| store.position(idx)).collect::<Vec<usize>>();
+                    positions.sort();
+                    assert_eq!(positions, (0..num_nodes).collect::<Vec<usize>>());
+                }
+            }
+        }
+
+        // the example above
+        let mut store = BlockedNodeStore::<usize>::new(2, 2);
+        store.allocate(15);
+        let layout = [0, 1, 2, 3, 6, 9, 12, 4, 5, 7, 8, 10, 11, 13, 14];
+        assert_eq!((0..15).map(|idx| store.position(idx)).collect::<Vec<usize>>(), layout);
+        assert_eq!("blocked:2".parse::<NodeLayout>(), Ok(NodeLayout::Blocked { levels: 2 }));
+        assert!("blocked:0".parse::<NodeLayout>().is_err());
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::BTreeMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use more_asserts::assert_lt;
+
+use crate::hashing_traits::ParallelTreeHasherFunc;
+use crate::merkle_abstract::normalize_updates;
+use crate::verify::TreeShape;
+
+// A batch of leaf updates, sorted by position with the updates to the same leaf merged (the last one
+// wins), along with the new hashes of those leaves, as computed by a LeafPrehasher. Applied to a tree
+// via AbstractMerkle::update_prehashed_leaves().
+pub struct PrehashedLeaves<LeafDataType, HashType> {
+    pub updates: Vec<(usize, LeafDataType)>,
+    pub hashes: Vec<HashType>, // hashes[i] is the new hash of the leaf at position updates[i].0
+}
+
+impl<LeafDataType, HashType> PrehashedLeaves<LeafDataType, HashType> {
+    pub fn len(&self) -> usize {
+        self.updates.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.updates.is_empty()
+    }
+}
+
+// Hashes the leaves updated by 'updates', which need not be sorted (see preprocess_leaves()), without
+// counting those hashes.
+pub(crate) fn prehash_leaves<LeafDataType, HashType, Hasher>(
+    shape: &TreeShape,
+    hasher: &Hasher,
+    updates: Vec<(usize, LeafDataType)>,
+) -> PrehashedLeaves<LeafDataType, HashType>
+where
+    LeafDataType: Clone,
+    Hasher: ParallelTreeHasherFunc<LeafDataType, HashType>,
+{
+    let updates = normalize_updates(updates, |_, later| later);
+    let hashes = updates
+        .iter()
+        .map(|(leaf_pos, leaf_data)| {
+            let child_offset = shape.child_offset(shape.leaf_idx(*leaf_pos));
+
+            hasher.hash_leaf_data_shared(child_offset, leaf_data.clone())
+        })
+        .collect();
+
+    PrehashedLeaves { updates, hashes }
+}
+
+struct Job<LeafDataType> {
+    seq: u64,
+    updates: Vec<(usize, LeafDataType)>,
+}
+
+// Hashes batches of leaf updates on a pool of background threads, so that the caller can prepare (or
+// apply) one batch while the next ones are being hashed, like a block executor would pipeline them:
+//
+//   prehasher.submit(block1_updates);
+//   prehasher.submit(block2_updates);
+//   while let Some(leaves) = prehasher.recv() {
+//       merkle.update_prehashed_leaves(leaves);
+//       ...submit the next block's updates...
+//   }
+//
+// Leaf hashes only depend on the leaf's data and its offset relative to its parent, so the workers
+// only need the tree's shape and a hasher, which must hash leaves like the tree's hasher (e.g., a
+// VerkleHasher with the same bases). The workers share that one hasher via an Arc, since leaves are
+// hashed through &self (see ParallelTreeHasherFunc). Each batch is hashed by one worker, and batches are
+// returned in the order they were submitted.
+//
+// NOTE: The workers do not count the leaf hashes they compute. The tree counts them when they are
+// applied, as if it had hashed them itself.
+pub struct LeafPrehasher<LeafDataType, HashType> {
+    shape: TreeShape,
+    jobs: Option<Sender<Job<LeafDataType>>>, // dropped to stop the workers
+    results: Receiver<(u64, PrehashedLeaves<LeafDataType, HashType>)>,
+    ready: BTreeMap<u64, PrehashedLeaves<LeafDataType, HashType>>, // hashed out of order, by job #
+    num_submitted: u64,
+    num_received: u64,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<LeafDataType, HashType> LeafPrehasher<LeafDataType, HashType>
+where
+    LeafDataType: Clone + Send + 'static,
+    HashType: Send + 'static,
+{
+    pub fn new<Hasher>(shape: TreeShape, hasher: Hasher, num_threads: usize) -> Self
+    where
+        Hasher: ParallelTreeHasherFunc<LeafDataType, HashType> + Send + 'static,
+    {
+        assert_lt!(0, num_threads, "Need at least one prehashing thread");
+
+        let hasher = Arc::new(hasher);
+        let (jobs, job_queue) = channel::<Job<LeafDataType>>();
+        let job_queue = Arc::new(Mutex::new(job_queue));
+        let (result_queue, results) = channel();
+
+        let workers = (0..num_threads)
+            .map(|_| {
+                let (hasher, job_queue, result_queue) = (hasher.clone(), job_queue.clone(), result_queue.clone());
+
+                thread::spawn(move || loop {
+                    // NOTE: The lock is only held while waiting for a job, not while hashing it
+                    let job = match job_queue.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break, // the prehasher was dropped
+                    };
+
+                    let leaves = prehash_leaves(&shape, hasher.as_ref(), job.updates);
+
+                    if result_queue.send((job.seq, leaves)).is_err() {
+                        break;
+                    }
+                })
+            })
+            .collect();
+
+        LeafPrehasher {
+            shape,
+            jobs: Some(jobs),
+            results,
+            ready: BTreeMap::new(),
+            num_submitted: 0,
+            num_received: 0,
+            workers,
+        }
+    }
+
+    // queues up a batch of updates to be hashed, which need not be sorted (see preprocess_leaves())
+    pub fn submit(&mut self, updates: Vec<(usize, LeafDataType)>) {
+        assert!(
+            updates.iter().all(|(leaf_pos, _)| *leaf_pos < self.shape.num_leaves),
+            "Leaf position is out of range"
+        );
+
+        let job = Job { seq: self.num_submitted, updates };
+        self.jobs.as_ref().unwrap().send(job).expect("Prehashing threads are gone");
+        self.num_submitted += 1;
+    }
+
+    // returns the # of batches submitted but not yet received
+    pub fn num_pending(&self) -> usize {
+        (self.num_submitted - self.num_received) as usize
+    }
+
+    // returns the next batch, in the order they were submitted, waiting for it to be hashed if needed,
+    // or None if no batches are pending
+    pub fn recv(&mut self) -> Option<PrehashedLeaves<LeafDataType, HashType>> {
+        if self.num_pending() == 0 {
+            return None;
+        }
+
+        while !self.ready.contains_key(&self.num_received) {
+            let (seq, leaves) = self.results.recv().expect("A prehashing thread panicked");
+            self.ready.insert(seq, leaves);
+        }
+
+        let leaves = self.ready.remove(&self.num_received);
+        self.num_received += 1;
+        leaves
+    }
+}
+
+impl<LeafDataType, HashType> Drop for LeafPrehasher<LeafDataType, HashType> {
+    fn drop(&mut self) {
+        // closing the job queue stops the workers once they are done with the jobs already queued up
+        self.jobs = None;
+
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashing_traits::TreeHasherFunc;
+    use crate::merkle_crhf::{new_merkle_crhf_from_leaves, HasherCRHF, TinySha3HashFunc};
+    use crate::random_updates;
+    use rand::thread_rng;
+
+    #[test]
+    fn pipelined_updates() {
+        // arity 3 with 10 leaves has leaves on two levels
+        for (arity, num_leaves) in [(3, 10), (4, 64)] {
+            let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+            let mut other = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+            let mut prehasher = LeafPrehasher::new(merkle.shape(), HasherCRHF::<TinySha3HashFunc>::new(arity), 3);
+
+            let batches = (0..5).map(|_| random_updates(&mut thread_rng(), num_leaves, 8)).collect::<Vec<_>>();
+            for updates in &batches {
+                prehasher.submit(updates.clone());
+            }
+            assert_eq!(prehasher.num_pending(), batches.len());
+
+            for updates in batches {
+                let leaves = prehasher.recv().unwrap();
+                assert_eq!(leaves.len(), leaves.hashes.len());
+
+                merkle.update_prehashed_leaves(leaves);
+                other.update_leaves(updates);
+                assert_eq!(merkle.root_hash(), other.root_hash());
+            }
+            assert!(prehasher.recv().is_none());
+            assert_eq!(merkle.hasher.get_num_computations(), other.hasher.get_num_computations());
+        }
+    }
+}
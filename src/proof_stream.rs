@@ -0,0 +1,191 @@
+use std::io::{self, Read, Write};
+
+use crate::hashing_traits::{CanonicalBytes, TreeHasherFunc};
+use crate::leaf_storage::LeafStorage;
+use crate::merkle_abstract::{AbstractMerkle, BatchProof};
+use crate::node_index::TreeArity;
+use crate::node_store::NodeStore;
+use crate::verify::{BatchVerifier, TreeShape};
+
+// The framed wire format of a batch proof, which lets a verifier (e.g., a light client) check the proof
+// as it arrives, one hash at a time, rather than buffering all of it (see verify_stream()):
+//
+//   header: magic ("MRPF") || version (1 byte) || arity (u32) || # of leaves (u64) || # of hashes (u32)
+//   frames: hash length (1 byte) || hash, once per hash, in the order of BatchProof::hashes
+//
+// where integers are little-endian and hashes are in their canonical serialization, so the same format
+// works for Merkle, Merkle++ and Verkle proofs. Since the header says how many frames follow, several
+// proofs can be sent back to back over the same stream.
+const MAGIC: &[u8; 4] = b"MRPF";
+const VERSION: u8 = 1;
+
+// The header of a framed proof
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProofHeader {
+    pub arity: usize,
+    pub num_leaves: usize,
+    pub num_hashes: usize,
+}
+
+impl ProofHeader {
+    pub fn read<R: Read>(mut reader: R) -> io::Result<Self> {
+        let mut bytes = [0u8; 21];
+        reader.read_exact(&mut bytes)?;
+
+        if bytes[..4] != MAGIC[..] || bytes[4] != VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a framed proof (or an unknown version)"));
+        }
+
+        Ok(ProofHeader {
+            arity: u32::from_le_bytes(bytes[5..9].try_into().unwrap()) as usize,
+            num_leaves: u64::from_le_bytes(bytes[9..17].try_into().unwrap()) as usize,
+            num_hashes: u32::from_le_bytes(bytes[17..21].try_into().unwrap()) as usize,
+        })
+    }
+
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&[VERSION])?;
+        writer.write_all(&(self.arity as u32).to_le_bytes())?;
+        writer.write_all(&(self.num_leaves as u64).to_le_bytes())?;
+        writer.write_all(&(self.num_hashes as u32).to_le_bytes())
+    }
+}
+
+// writes a proof for a tree of the specified shape in the framed format
+pub fn write_framed_proof<W, HashType>(mut writer: W, shape: &TreeShape, proof: &BatchProof<HashType>) -> io::Result<()>
+where
+    W: Write,
+    HashType: CanonicalBytes,
+{
+    let header = ProofHeader { arity: shape.arity, num_leaves: shape.num_leaves, num_hashes: proof.len() };
+    header.write(&mut writer)?;
+
+    for hash in &proof.hashes {
+        let bytes = hash.to_bytes();
+        assert!(bytes.len() <= u8::MAX as usize, "Hash is too big to be framed");
+
+        writer.write_all(&[bytes.len() as u8])?;
+        writer.write_all(&bytes)?;
+    }
+
+    Ok(())
+}
+
+impl<LeafDataType, HashType, Hasher, Storage, Nodes, Arity> AbstractMerkle<LeafDataType, HashType, Hasher, Storage, Nodes, Arity>
+where
+    LeafDataType: Clone,
+    HashType: CanonicalBytes + Default + Clone + PartialEq,
+    Hasher: TreeHasherFunc<LeafDataType, HashType>,
+    Storage: LeafStorage<LeafDataType> + Default,
+    Nodes: NodeStore<HashType>,
+    Arity: TreeArity,
+{
+    // Same as verify_batch(), but reads the proof from 'reader' in the framed format, and verifies it
+    // as it is read, so only a few hashes per level are held in memory at any time (see BatchVerifier).
+    // Returns an error if the stream ends early or is not a proof for a tree of this shape.
+    //
+    // NOTE: The whole proof is read even if it fails to verify early, so that the next proof on the same
+    // stream (if any) can be read.
+    pub fn verify_stream<R: Read>(
+        &mut self,
+        root: &HashType,
+        leaves: &[(usize, LeafDataType)],
+        mut reader: R,
+    ) -> io::Result<bool> {
+        let header = ProofHeader::read(&mut reader)?;
+        if (header.arity, header.num_leaves) != (self.arity(), self.num_leaves()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Proof is for a tree of a different shape"));
+        }
+
+        let mut verifier = self
+            .hash_proven_leaves(leaves)
+            .and_then(|leaf_hashes| BatchVerifier::new(self.shape(), root.clone(), &leaf_hashes));
+
+        let (arity, hasher) = (Arity::from_usize(self.arity()), &mut self.hasher);
+        let mut hash_children = |children: &[HashType]| hasher.hash_all_children_in(arity, children);
+        let mut bytes = [0u8; u8::MAX as usize];
+        for _ in 0..header.num_hashes {
+            let mut len = [0u8];
+            reader.read_exact(&mut len)?;
+            let bytes = &mut bytes[..len[0] as usize];
+            reader.read_exact(bytes)?;
+
+            // once the proof fails, the rest of it is only read past
+            let valid = match (&mut verifier, HashType::from_bytes(bytes)) {
+                (Some(verifier), Some(hash)) => verifier.push(hash, &mut hash_children),
+                _ => false,
+            };
+            if !valid {
+                verifier = None;
+            }
+        }
+
+        Ok(verifier.is_some_and(|verifier| verifier.finish(&mut hash_children)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle_crhf::{new_merkle_crhf_from_leaves, Sha3HashFunc};
+    use crate::random_updates;
+    use crate::verkle::{deterministic_bases, new_verkle_from_leaves};
+    use rand::thread_rng;
+
+    // a reader which returns at most 'chunk' bytes at a time, like a socket might
+    struct Chunked<'a> {
+        bytes: &'a [u8],
+        chunk: usize,
+    }
+
+    impl Read for Chunked<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.chunk).min(self.bytes.len());
+            buf[..n].copy_from_slice(&self.bytes[..n]);
+            self.bytes = &self.bytes[n..];
+
+            Ok(n)
+        }
+    }
+
+    fn check_streamed_proofs<HashType, Hasher>(mut merkle: AbstractMerkle<String, HashType, Hasher>)
+    where
+        HashType: CanonicalBytes + Default + Clone + PartialEq,
+        Hasher: TreeHasherFunc<String, HashType>,
+    {
+        let num_leaves = merkle.num_leaves();
+        let updates = random_updates(&mut thread_rng(), num_leaves, 4);
+        merkle.update_leaves(updates.clone());
+        let root = merkle.root_hash();
+
+        // two proofs back to back, the first of which has a wrong leaf
+        let positions = updates.iter().map(|(pos, _)| *pos).collect::<Vec<_>>();
+        let mut bytes = vec![];
+        write_framed_proof(&mut bytes, &merkle.shape(), &merkle.prove_batch(&positions[..1])).unwrap();
+        let second = bytes.len();
+        write_framed_proof(&mut bytes, &merkle.shape(), &merkle.prove_batch(&positions)).unwrap();
+
+        let mut reader = Chunked { bytes: &bytes, chunk: 3 };
+        assert!(!merkle.verify_stream(&root, &[(positions[0], "wrong".to_owned())], &mut reader).unwrap());
+        assert!(merkle.verify_stream(&root, &updates, &mut reader).unwrap());
+        assert!(reader.bytes.is_empty());
+
+        // a truncated proof is an error, rather than an invalid one
+        let err = merkle.verify_stream(&root, &updates, &bytes[second..bytes.len() - 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut other = new_merkle_crhf_from_leaves::<Sha3HashFunc>(merkle.arity(), num_leaves + 1);
+        let err = other.verify_stream(&Default::default(), &updates, &bytes[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn streamed_proofs() {
+        // arity 3 with 10 leaves has leaves on two levels
+        for (arity, num_leaves) in [(3, 10), (4, 64)] {
+            check_streamed_proofs(new_merkle_crhf_from_leaves::<Sha3HashFunc>(arity, num_leaves));
+            check_streamed_proofs(new_verkle_from_leaves(arity, num_leaves, deterministic_bases(arity)));
+        }
+    }
+}
@@ -0,0 +1,193 @@
+// NOTE: #[pymethods] (as of pyo3 0.22) converts the errors of methods returning PyResult into PyErr
+#![allow(clippy::useless_conversion)]
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use rand::rngs::StdRng;
+
+use crate::racer::{MerkleRacer, RacerRegistry};
+use crate::workload::{self, LeafDistribution};
+use crate::{random_updates, seeded_rng};
+
+// A Python module (built via maturin, see pyproject.toml) for scripting parameter sweeps, e.g., from a
+// notebook:
+//
+//   import merkle_race
+//   workload = merkle_race.Workload(2**20, "zipfian", seed=1)
+//   tree = merkle_race.Tree("verkle", 256, 2**20)
+//   tree.update_leaves(workload.sample_updates(1000))
+//
+// Trees are picked by the names the benchmark uses (see RacerRegistry::with_default_racers()), and
+// roots and proofs are bytes, in their canonical serialization (see MerkleRacer).
+
+// NOTE: Not Send, since contestants need not be, so a tree must stay on the Python thread that created it
+#[pyclass(unsendable, module = "merkle_race")]
+pub struct Tree {
+    tree: Box<dyn MerkleRacer>,
+}
+
+#[pymethods]
+impl Tree {
+    // returns an empty tree of the specified type and shape, where 'seed' seeds any public parameters
+    // (e.g., KZG Verkle bases)
+    #[new]
+    #[pyo3(signature = (tree_type, arity, num_leaves, seed = None))]
+    fn new(tree_type: &str, arity: usize, num_leaves: usize, seed: Option<u64>) -> PyResult<Self> {
+        if arity < 2 || num_leaves < 2 {
+            return Err(PyValueError::new_err("Trees must have arity >= 2 and at least 2 leaves"));
+        }
+
+        RacerRegistry::with_default_racers()
+            .create(tree_type, arity, num_leaves, &mut seeded_rng(seed))
+            .map(|tree| Tree { tree })
+            .ok_or_else(|| PyValueError::new_err(format!("Unknown type of Merkle tree: {}", tree_type)))
+    }
+
+    #[getter]
+    fn num_leaves(&self) -> usize {
+        self.tree.num_leaves()
+    }
+
+    // sets the leaves, given as (position, data) pairs, where a leaf updated more than once ends up
+    // with its last data
+    fn update_leaves(&mut self, updates: Vec<(usize, String)>) -> PyResult<()> {
+        check_positions(updates.iter().map(|(pos, _)| *pos), self.tree.num_leaves())?;
+
+        self.tree.update_batch(updates);
+        Ok(())
+    }
+
+    fn root_hash<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new_bound(py, &self.tree.root())
+    }
+
+    // returns a proof for the leaves at the specified positions
+    fn prove<'py>(&self, py: Python<'py>, positions: Vec<usize>) -> PyResult<Bound<'py, PyBytes>> {
+        check_positions(positions.iter().copied(), self.tree.num_leaves())?;
+
+        Ok(PyBytes::new_bound(py, &self.tree.prove(&positions)))
+    }
+
+    // verifies a proof returned by prove() for the specified (position, data) leaves against a root
+    // returned by root_hash()
+    fn verify(&mut self, root: &[u8], leaves: Vec<(usize, String)>, proof: &[u8]) -> bool {
+        self.tree.verify(root, &leaves, proof)
+    }
+
+    // returns the counters of the work done so far, e.g., to plot the # of hashes per update
+    fn stats(&self) -> Stats {
+        let stats = self.tree.stats();
+
+        Stats { num_hashes: stats.num_hashes, num_nodes: stats.num_nodes, node_bytes: stats.node_bytes }
+    }
+}
+
+// A snapshot of a tree's RacerStats
+#[pyclass(get_all, module = "merkle_race")]
+pub struct Stats {
+    num_hashes: usize,
+    num_nodes: usize,
+    node_bytes: usize,
+}
+
+// Generates the leaves updated by successive batches (see workload::Workload), from its own RNG, so
+// that a sweep can be reproduced by fixing the seed.
+#[pyclass(module = "merkle_race")]
+pub struct Workload {
+    workload: workload::Workload,
+    rng: StdRng,
+}
+
+#[pymethods]
+impl Workload {
+    // 'distribution' is "uniform", "zipfian[:EXPONENT]" or "hotset[:HOT_FRACTION:HOT_PROBABILITY]"
+    #[new]
+    #[pyo3(signature = (num_leaves, distribution = "uniform", seed = None))]
+    fn new(num_leaves: usize, distribution: &str, seed: Option<u64>) -> PyResult<Self> {
+        let distribution = distribution.parse::<LeafDistribution>().map_err(PyValueError::new_err)?;
+
+        Ok(Workload { workload: workload::Workload::new(num_leaves, distribution), rng: seeded_rng(seed) })
+    }
+
+    // returns 'num_pos' distinct, sorted leaf positions
+    fn sample_positions(&mut self, num_pos: usize) -> PyResult<Vec<usize>> {
+        self.workload.check_batch_size(num_pos).map_err(PyValueError::new_err)?;
+
+        Ok(self.workload.sample_positions(&mut self.rng, num_pos))
+    }
+
+    // returns 'num_pos' (position, data) updates to distinct leaves, with random data of 'leaf_size' bytes
+    #[pyo3(signature = (num_pos, leaf_size = 32))]
+    fn sample_updates(&mut self, num_pos: usize, leaf_size: usize) -> PyResult<Vec<(usize, String)>> {
+        let positions = self.sample_positions(num_pos)?;
+        let leaves = workload::random_leaf_data(&mut self.rng, positions.len(), leaf_size);
+
+        Ok(positions.into_iter().zip(leaves).collect())
+    }
+}
+
+// returns 'num_updates' random (position, data) updates to a tree with 'num_leaves' leaves (see
+// crate::random_updates())
+#[pyfunction(name = "random_updates")]
+#[pyo3(signature = (num_leaves, num_updates, seed = None))]
+fn py_random_updates(num_leaves: usize, num_updates: usize, seed: Option<u64>) -> Vec<(usize, String)> {
+    random_updates(&mut seeded_rng(seed), num_leaves, num_updates)
+}
+
+// returns the names of the types of trees that can be created
+#[pyfunction]
+fn tree_types() -> Vec<String> {
+    RacerRegistry::with_default_racers().names().map(str::to_owned).collect()
+}
+
+fn check_positions(mut positions: impl Iterator<Item = usize>, num_leaves: usize) -> PyResult<()> {
+    if positions.any(|pos| pos >= num_leaves) {
+        return Err(PyValueError::new_err("Leaf position is out of range"));
+    }
+
+    Ok(())
+}
+
+#[pymodule]
+fn merkle_race(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Tree>()?;
+    m.add_class::<Stats>()?;
+    m.add_class::<Workload>()?;
+    m.add_function(wrap_pyfunction!(py_random_updates, m)?)?;
+    m.add_function(wrap_pyfunction!(tree_types, m)?)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // NOTE: Only calls what does not need the Python interpreter (e.g., no bytes are returned)
+    #[test]
+    fn sweep() {
+        assert!(Tree::new("no_such_tree", 2, 16, None).is_err());
+        assert!(Tree::new("merkle_sha3", 1, 16, None).is_err());
+        assert!(tree_types().contains(&"verkle".to_owned()));
+
+        let mut workload = Workload::new(100, "zipfian:1.5", Some(7)).unwrap();
+        assert!(Workload::new(100, "gaussian", None).is_err());
+        assert!(workload.sample_positions(101).is_err());
+
+        let mut tree = Tree::new("merkle_sha3", 4, 100, Some(7)).unwrap();
+        let mut other = Tree::new("merkle_sha3", 4, 100, Some(7)).unwrap();
+        for _ in 0..3 {
+            let updates = workload.sample_updates(10, 8).unwrap();
+            assert_eq!(updates.len(), 10);
+            tree.update_leaves(updates.clone()).unwrap();
+            other.tree.update_batch(updates);
+        }
+        assert!(tree.update_leaves(vec![(100, "out of range".to_owned())]).is_err());
+
+        assert_eq!(tree.num_leaves(), 100);
+        assert_eq!(tree.tree.root(), other.tree.root());
+        assert!(tree.stats().num_hashes > 0);
+        assert_eq!(py_random_updates(100, 5, Some(1)), py_random_updates(100, 5, Some(1)));
+    }
+}
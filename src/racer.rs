@@ -0,0 +1,234 @@
+use rand::rngs::StdRng;
+use std::mem::size_of;
+use rust_incrhash::compressed_ristretto::CompRistBlakeIncHash;
+use rust_incrhash::ristretto::RistBlakeIncHash;
+
+use crate::hashing_traits::{CanonicalBytes, HasherStats, ParallelTreeHasherFunc};
+use crate::leaf_storage::LeafStorage;
+use crate::lthash::LtHash16;
+use crate::merkle_abstract::{AbstractMerkle, BatchProof};
+use crate::merkle_crhf::{
+    new_merkle_crhf_from_leaves, Blake2bHashFunc, Blake2sHashFunc, Blake3HashFunc, Keccak256HashFunc,
+    Sha256HashFunc, Sha3HashFunc, TinySha3HashFunc,
+};
+use crate::merkle_poseidon::new_merkle_poseidon_from_leaves;
+use crate::merkle_pp::{new_fat_merklepp_from_leaves, new_merklepp_from_leaves};
+use crate::node_index::TreeArity;
+use crate::node_store::NodeStore;
+use crate::verkle::{deterministic_bases, new_verkle_from_leaves};
+use crate::verkle_kzg::{lagrange_bases, new_kzg_verkle_from_leaves};
+
+// The operations every contestant in the race supports, so that contestants can be benchmarked
+// side by side without knowing their types. Roots and proofs are passed around in their canonical
+// serialization, since their types differ between contestants.
+pub trait MerkleRacer {
+    fn num_leaves(&self) -> usize;
+
+    // sets the leaves at the specified positions (in [0, n), where n is the # of leaves)
+    fn update_batch(&mut self, updates: Vec<(usize, String)>);
+
+    // returns the serialized root hash
+    fn root(&self) -> Vec<u8>;
+
+    // returns the serialized root hash, as recomputed from scratch (see AbstractMerkle::recompute_root())
+    fn recompute_root(&mut self) -> Vec<u8>;
+
+    // returns a serialized proof for the leaves at the specified positions
+    fn prove(&self, positions: &[usize]) -> Vec<u8>;
+
+    // verifies a proof returned by prove() for the specified leaves against a root returned by root();
+    // malformed roots or proofs do not verify
+    fn verify(&mut self, root: &[u8], leaves: &[(usize, String)], proof: &[u8]) -> bool;
+
+    fn stats(&self) -> RacerStats;
+}
+
+// Counters reported by a contestant, which only ever increase
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RacerStats {
+    pub num_hashes: usize, // the # of hashes (or exponentiations) computed so far
+    pub hasher: HasherStats, // the same work, broken down by the kind of operation
+    pub num_nodes: usize,  // the # of nodes (internal and leaves) in the tree
+    pub node_bytes: usize, // the # of bytes taken up by the nodes, not counting any heap allocations they own
+}
+
+impl<HashType, Hasher, Storage, Nodes, Arity> MerkleRacer
+    for AbstractMerkle<String, HashType, Hasher, Storage, Nodes, Arity>
+    where
+        HashType: CanonicalBytes + Default + Clone + PartialEq + Send + Sync,
+        Hasher: ParallelTreeHasherFunc<String, HashType>,
+        Storage: LeafStorage<String> + Default,
+        Nodes: NodeStore<HashType> + Sync,
+        Arity: TreeArity + Sync,
+{
+    fn num_leaves(&self) -> usize {
+        AbstractMerkle::num_leaves(self)
+    }
+
+    fn update_batch(&mut self, updates: Vec<(usize, String)>) {
+        #[cfg(feature = "parallel")]
+        self.update_leaves_parallel(updates);
+        #[cfg(not(feature = "parallel"))]
+        self.update_leaves(updates);
+    }
+
+    fn root(&self) -> Vec<u8> {
+        self.root_hash().to_bytes()
+    }
+
+    fn recompute_root(&mut self) -> Vec<u8> {
+        AbstractMerkle::recompute_root(self).to_bytes()
+    }
+
+    fn prove(&self, positions: &[usize]) -> Vec<u8> {
+        self.prove_batch(positions).to_bytes()
+    }
+
+    fn verify(&mut self, root: &[u8], leaves: &[(usize, String)], proof: &[u8]) -> bool {
+        match (HashType::from_bytes(root), BatchProof::from_bytes(proof)) {
+            (Some(root), Some(proof)) => self.verify_batch(&root, leaves, &proof),
+            _ => false,
+        }
+    }
+
+    fn stats(&self) -> RacerStats {
+        RacerStats {
+            num_hashes: self.hasher.get_num_computations(),
+            hasher: self.hasher.get_stats(),
+            num_nodes: self.num_nodes(),
+            node_bytes: size_of::<HashType>() * self.num_nodes(),
+        }
+    }
+}
+
+// Creates a contestant with the specified arity and # of leaves, using the RNG for any public
+// parameters (e.g., KZG Verkle bases)
+pub type RacerConstructor = Box<dyn Fn(usize, usize, &mut StdRng) -> Box<dyn MerkleRacer>>;
+
+// The contestants that can be raced, by name. Applications can register their own contestants next to
+// the built-in ones (see with_default_racers()).
+#[derive(Default)]
+pub struct RacerRegistry {
+    racers: Vec<(String, RacerConstructor)>,
+}
+
+impl RacerRegistry {
+    pub fn new() -> Self {
+        RacerRegistry::default()
+    }
+
+    // returns a registry with all the dense trees in this crate, under the names the benchmark uses
+    pub fn with_default_racers() -> Self {
+        let mut registry = RacerRegistry::new();
+
+        registry.register("merkle_tiny_sha3", |arity, num_leaves, _| {
+            Box::new(new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves))
+        });
+        registry.register("merkle_sha3", |arity, num_leaves, _| {
+            Box::new(new_merkle_crhf_from_leaves::<Sha3HashFunc>(arity, num_leaves))
+        });
+        registry.register("merkle_sha256", |arity, num_leaves, _| {
+            Box::new(new_merkle_crhf_from_leaves::<Sha256HashFunc>(arity, num_leaves))
+        });
+        registry.register("merkle_keccak256", |arity, num_leaves, _| {
+            Box::new(new_merkle_crhf_from_leaves::<Keccak256HashFunc>(arity, num_leaves))
+        });
+        registry.register("merkle_blake2s", |arity, num_leaves, _| {
+            Box::new(new_merkle_crhf_from_leaves::<Blake2sHashFunc>(arity, num_leaves))
+        });
+        registry.register("merkle_blake2b", |arity, num_leaves, _| {
+            Box::new(new_merkle_crhf_from_leaves::<Blake2bHashFunc>(arity, num_leaves))
+        });
+        registry.register("merkle_blake3", |arity, num_leaves, _| {
+            Box::new(new_merkle_crhf_from_leaves::<Blake3HashFunc>(arity, num_leaves))
+        });
+        registry.register("merkle_poseidon", |arity, num_leaves, _| {
+            Box::new(new_merkle_poseidon_from_leaves(arity, num_leaves))
+        });
+        registry.register("merkle++", |arity, num_leaves, _| {
+            Box::new(new_merklepp_from_leaves::<CompRistBlakeIncHash, RistBlakeIncHash>(arity, num_leaves))
+        });
+        registry.register("merkle++fat", |arity, num_leaves, _| {
+            Box::new(new_fat_merklepp_from_leaves::<CompRistBlakeIncHash, RistBlakeIncHash>(arity, num_leaves))
+        });
+        registry.register("merkle++naive", |arity, num_leaves, _| {
+            Box::new(new_merklepp_from_leaves::<RistBlakeIncHash, RistBlakeIncHash>(arity, num_leaves))
+        });
+        // NOTE: Its hashes are too big to be serialized in proofs, so it can only race with no proofs
+        registry.register("merkle++lthash", |arity, num_leaves, _| {
+            Box::new(new_merklepp_from_leaves::<LtHash16, LtHash16>(arity, num_leaves))
+        });
+        // NOTE: The Verkle trees here use the default multiexp cutoff, rather than a calibrated one
+        registry.register("verkle", |arity, num_leaves, _| {
+            Box::new(new_verkle_from_leaves(arity, num_leaves, deterministic_bases(arity)))
+        });
+        registry.register("verkle_kzg", |arity, num_leaves, rng| {
+            Box::new(new_kzg_verkle_from_leaves(arity, num_leaves, lagrange_bases(rng, arity)))
+        });
+
+        registry
+    }
+
+    // registers a contestant under 'name', replacing any contestant previously registered under it
+    pub fn register<F>(&mut self, name: &str, constructor: F)
+    where
+        F: Fn(usize, usize, &mut StdRng) -> Box<dyn MerkleRacer> + 'static,
+    {
+        let constructor: RacerConstructor = Box::new(constructor);
+
+        match self.racers.iter_mut().find(|(racer_name, _)| racer_name == name) {
+            Some((_, existing)) => *existing = constructor,
+            None => self.racers.push((name.to_owned(), constructor)),
+        }
+    }
+
+    // returns the names of all registered contestants, in the order they were registered
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.racers.iter().map(|(name, _)| name.as_str())
+    }
+
+    // creates the contestant registered under 'name', if any
+    pub fn create(&self, name: &str, arity: usize, num_leaves: usize, rng: &mut StdRng) -> Option<Box<dyn MerkleRacer>> {
+        self.racers
+            .iter()
+            .find(|(racer_name, _)| racer_name == name)
+            .map(|(_, constructor)| constructor(arity, num_leaves, rng))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::seeded_rng;
+
+    #[test]
+    fn all_racers_agree_on_proofs() {
+        let registry = RacerRegistry::with_default_racers();
+        let mut rng = seeded_rng(None);
+        let (arity, num_leaves) = (3, 20);
+
+        for name in registry.names() {
+            let mut racer = registry.create(name, arity, num_leaves, &mut rng).unwrap();
+            assert_eq!(racer.num_leaves(), num_leaves);
+
+            let before = racer.stats();
+            racer.update_batch(vec![(4, "four".to_owned()), (17, "seventeen".to_owned())]);
+            assert!(racer.stats().num_hashes > before.num_hashes, "{} did not count its hashes", name);
+
+            // NOTE: LtHash16 hashes are too big for the proof serialization (see BatchProof::to_bytes())
+            if name == "merkle++lthash" {
+                continue;
+            }
+
+            let root = racer.root();
+            assert_eq!(racer.recompute_root(), root, "{} root does not match the recomputed one", name);
+            let leaves = [(4, "four".to_owned()), (17, "seventeen".to_owned())];
+            let proof = racer.prove(&[4, 17]);
+            assert!(racer.verify(&root, &leaves, &proof), "{} proof did not verify", name);
+            let proof = racer.prove(&[4]);
+            assert!(!racer.verify(&root, &[(4, "five".to_owned())], &proof), "{} accepted a wrong leaf", name);
+        }
+
+        assert!(registry.create("verkle2", arity, num_leaves, &mut rng).is_none());
+    }
+}
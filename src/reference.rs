@@ -0,0 +1,252 @@
+use std::marker::PhantomData;
+
+use more_asserts::{assert_ge, assert_lt};
+
+use crate::hashing_traits::{HashFuncTrait, TreeHasherFunc};
+use crate::max_leaves;
+use crate::merkle_crhf::{HasherCRHF, MerkleHashValue};
+
+// A naive Merkle tree, which the optimized trees (e.g., AbstractMerkle) can be tested against. Its
+// hasher decides whether it is a Merkle, Merkle++ or Verkle tree.
+//
+// Rather than being one array of nodes in which a node's children are found via index arithmetic, the
+// tree is a vector of levels, and each leaf is updated on its own by rehashing every node on its path
+// to the root from *all* of that node's children. So a batch of n updates takes O(n * h) parent hashes
+// (and O(n * h * arity) work), with no queues, no sibling grouping and no incremental hashing of parents.
+//
+// NOTE: The tree must have the same shape as AbstractMerkle::with_num_leaves() for their roots to
+// match: when the leaves do not fit on a single level, the first 'num_internal' nodes on the level
+// below the full ones are internal, the rest of that level's nodes are leaves, and the remaining
+// leaves fill the last level from the left. (That is, leaves are on two levels.)
+pub struct ReferenceMerkle<LeafDataType, HashType, Hasher> {
+    arity: usize,
+    num_leaves: usize,
+    levels: Vec<Vec<HashType>>, // levels[0] only has the root
+    pub hasher: Hasher,
+    phantom: PhantomData<LeafDataType>,
+}
+
+impl<LeafDataType, HashType, Hasher> ReferenceMerkle<LeafDataType, HashType, Hasher>
+where
+    HashType: Clone + Default + PartialEq,
+    Hasher: TreeHasherFunc<LeafDataType, HashType>,
+{
+    pub fn new(arity: usize, num_leaves: usize, hasher: Hasher) -> Self {
+        assert_ge!(arity, 2);
+        assert_ge!(num_leaves, 1);
+
+        // the height of the tallest perfect tree with at most 'num_leaves' leaves
+        let mut height = 0;
+        while max_leaves(arity, height + 1) <= num_leaves {
+            height += 1;
+        }
+
+        let full = max_leaves(arity, height);
+        let mut level_sizes = (0..=height).map(|level| max_leaves(arity, level)).collect::<Vec<usize>>();
+
+        if num_leaves > full {
+            // the # of internal nodes on level 'height', each of which has 'arity' children, except for
+            // the last one, which has as many as are left
+            let num_internal = if full * arity - num_leaves < arity {
+                full
+            } else {
+                (num_leaves - full).div_ceil(arity - 1)
+            };
+            let num_second_to_last = full - num_internal;
+
+            level_sizes.push(num_leaves - num_second_to_last);
+        }
+
+        ReferenceMerkle {
+            arity,
+            num_leaves,
+            levels: level_sizes.into_iter().map(|size| vec![HashType::default(); size]).collect(),
+            hasher,
+            phantom: Default::default(),
+        }
+    }
+
+    pub fn num_leaves(&self) -> usize {
+        self.num_leaves
+    }
+
+    pub fn root_hash(&self) -> HashType {
+        self.levels[0][0].clone()
+    }
+
+    // Returns the level of the leaf at position 'leaf_pos' and its index on that level. The leaves on
+    // the second-to-last level (if any) come first, since they are to the right of the internal nodes
+    // on that level, which come before the whole last level.
+    fn leaf_location(&self, leaf_pos: usize) -> (usize, usize) {
+        assert_lt!(leaf_pos, self.num_leaves);
+
+        let last = self.levels.len() - 1;
+        let num_last = self.levels[last].len();
+        if leaf_pos + num_last < self.num_leaves {
+            let second_to_last = &self.levels[last - 1];
+            let num_second_to_last = self.num_leaves - num_last;
+
+            (last - 1, second_to_last.len() - num_second_to_last + leaf_pos)
+        } else {
+            (last, leaf_pos + num_last - self.num_leaves)
+        }
+    }
+
+    // Sets the leaves one at a time, in order, so a leaf updated more than once ends up with its last
+    // data.
+    pub fn update_leaves(&mut self, updates: Vec<(usize, LeafDataType)>) {
+        for (leaf_pos, leaf_data) in updates {
+            self.update_leaf(leaf_pos, leaf_data);
+        }
+    }
+
+    pub fn update_leaf(&mut self, leaf_pos: usize, leaf_data: LeafDataType) {
+        let (mut level, mut idx) = self.leaf_location(leaf_pos);
+        self.levels[level][idx] = self.hasher.hash_leaf_data(idx % self.arity, leaf_data);
+
+        while level > 0 {
+            let parent = idx / self.arity;
+            let children = &self.levels[level];
+            let last_child = children.len().min((parent + 1) * self.arity);
+            let hash = self.hasher.hash_all_children(&children[parent * self.arity..last_child]);
+
+            level -= 1;
+            idx = parent;
+            self.levels[level][idx] = hash;
+        }
+    }
+}
+
+// returns a reference for the trees returned by new_merkle_crhf_from_leaves()
+pub fn new_reference_merkle_crhf<HashFunc>(
+    arity: usize,
+    num_leaves: usize,
+) -> ReferenceMerkle<String, MerkleHashValue, HasherCRHF<HashFunc>>
+where
+    HashFunc: HashFuncTrait,
+{
+    ReferenceMerkle::new(arity, num_leaves, HasherCRHF::new(arity))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hashing_traits::ParallelTreeHasherFunc;
+    use crate::merkle_abstract::{normalize_updates, AbstractMerkle};
+    use crate::merkle_crhf::{new_merkle_crhf_from_leaves, TinySha3HashFunc};
+    use crate::merkle_pp::{new_merklepp_from_leaves, IncrementalHasher};
+    use crate::verkle::{deterministic_bases, new_verkle_from_leaves, VerkleComm, VerkleConfig, VerkleHasher};
+    use proptest::prelude::*;
+    use rust_incrhash::compressed_ristretto::CompRistBlakeIncHash;
+    use rust_incrhash::ristretto::RistBlakeIncHash;
+    use std::fmt::Debug;
+
+    // Returns batches of updates to random leaves (possibly the same leaf more than once in a batch),
+    // as (leaf index, leaf data) pairs, where the leaf index is later mapped onto the tree's leaves
+    fn batches() -> impl Strategy<Value = Vec<Vec<(prop::sample::Index, u8)>>> {
+        prop::collection::vec(prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..24), 1..4)
+    }
+
+    fn leaf_updates(batch: &[(prop::sample::Index, u8)], num_leaves: usize) -> Vec<(usize, String)> {
+        batch
+            .iter()
+            .map(|(index, data)| (index.index(num_leaves), format!("leaf data {}", data)))
+            .collect()
+    }
+
+    // Applies the batches to the optimized tree via each of its update paths, and one at a time to the
+    // reference tree, and checks that their roots match after every batch.
+    fn check_against_reference<HashType, Hasher, NewMerkle>(
+        new_merkle: NewMerkle,
+        mut reference: ReferenceMerkle<String, HashType, Hasher>,
+        batches: &[Vec<(prop::sample::Index, u8)>],
+    ) where
+        HashType: Clone + Debug + Default + PartialEq + Send + Sync,
+        Hasher: ParallelTreeHasherFunc<String, HashType>,
+        NewMerkle: Fn() -> AbstractMerkle<String, HashType, Hasher>,
+    {
+        let num_leaves = reference.num_leaves();
+        let mut merkle = new_merkle();
+        let mut streamed = new_merkle();
+        #[cfg(feature = "parallel")]
+        let mut parallel = new_merkle();
+
+        for batch in batches {
+            let updates = leaf_updates(batch, num_leaves);
+
+            reference.update_leaves(updates.clone());
+            merkle.update_leaves(updates.clone());
+            assert_eq!(merkle.root_hash(), reference.root_hash());
+
+            // NOTE: The streaming updates must be sorted, so only a leaf's last update is kept
+            streamed.update_leaves_streaming(normalize_updates(updates.clone(), |_, later| later));
+            assert_eq!(streamed.root_hash(), reference.root_hash());
+
+            #[cfg(feature = "parallel")]
+            {
+                parallel.update_leaves_parallel(updates);
+                assert_eq!(parallel.root_hash(), reference.root_hash());
+            }
+        }
+    }
+
+    #[test]
+    fn leaves_on_two_levels() {
+        // arity 3 with 10 leaves: the first node on level 2 is internal, with the last 2 leaves as its
+        // children, and the other 8 nodes on level 2 are the first 8 leaves
+        let reference = new_reference_merkle_crhf::<TinySha3HashFunc>(3, 10);
+        assert_eq!(reference.levels.iter().map(Vec::len).collect::<Vec<_>>(), vec![1, 3, 9, 2]);
+        assert_eq!(reference.leaf_location(0), (2, 1));
+        assert_eq!(reference.leaf_location(7), (2, 8));
+        assert_eq!(reference.leaf_location(8), (3, 0));
+        assert_eq!(reference.leaf_location(9), (3, 1));
+
+        // arity 2 with 7 leaves: all of them are on level 3, since only the last node on level 2 would
+        // otherwise have been a leaf
+        let reference = new_reference_merkle_crhf::<TinySha3HashFunc>(2, 7);
+        assert_eq!(reference.levels.iter().map(Vec::len).collect::<Vec<_>>(), vec![1, 2, 4, 7]);
+        assert_eq!(reference.leaf_location(0), (3, 0));
+
+        // a perfect tree only has leaves on its last level
+        let reference = new_reference_merkle_crhf::<TinySha3HashFunc>(2, 8);
+        assert_eq!(reference.levels.iter().map(Vec::len).collect::<Vec<_>>(), vec![1, 2, 4, 8]);
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn merkle_matches_reference(arity in 2usize..6, num_leaves in 2usize..200, batches in batches()) {
+            check_against_reference(
+                || new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves),
+                new_reference_merkle_crhf::<TinySha3HashFunc>(arity, num_leaves),
+                &batches,
+            );
+        }
+
+        #[test]
+        fn merklepp_matches_reference(arity in 2usize..6, num_leaves in 2usize..100, batches in batches()) {
+            check_against_reference(
+                || new_merklepp_from_leaves::<CompRistBlakeIncHash, RistBlakeIncHash>(arity, num_leaves),
+                ReferenceMerkle::new(arity, num_leaves, IncrementalHasher::<RistBlakeIncHash>::new(arity)),
+                &batches,
+            );
+        }
+    }
+
+    proptest! {
+        // NOTE: Fewer cases, since the Verkle hashers take a while to create (e.g., their base tables)
+        #![proptest_config(ProptestConfig::with_cases(16))]
+
+        #[test]
+        fn verkle_matches_reference(arity in 2usize..6, num_leaves in 2usize..100, batches in batches()) {
+            let hasher = VerkleHasher::<VerkleComm>::with_config(arity, deterministic_bases(arity), VerkleConfig::default());
+
+            check_against_reference(
+                || new_verkle_from_leaves(arity, num_leaves, deterministic_bases(arity)),
+                ReferenceMerkle::new(arity, num_leaves, hasher),
+                &batches,
+            );
+        }
+    }
+}
@@ -0,0 +1,124 @@
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::hashing_traits::CanonicalBytes;
+
+// The root of a tree after one batch of updates, as recorded in a RootLog
+#[derive(Clone, Debug, PartialEq)]
+pub struct RootLogEntry<HashType> {
+    pub batch: usize,       // the # of batches applied before this one
+    pub root: HashType,
+    pub time: SystemTime,   // when the batch was done being applied
+    pub num_updates: usize, // the # of updates in the batch, as given
+}
+
+// A log of a tree's root after every batch of updates, which AbstractMerkle keeps once enabled via
+// enable_root_log(), e.g., to tell when two runs over the same updates started to diverge, or to report
+// how the root evolved over a benchmark.
+//
+// NOTE: Every batch of updates (including deletions) is logged, except for transactions that were rolled
+// back (see update_leaves_txn()), whose entries are dropped.
+#[derive(Clone, Debug, Default)]
+pub struct RootLog<HashType> {
+    entries: Vec<RootLogEntry<HashType>>,
+}
+
+// How an entry is written out, with the root hash in hex (see CanonicalBytes)
+#[derive(Serialize)]
+struct JsonEntry {
+    batch: usize,
+    root: String,
+    unix_time_us: u128,
+    num_updates: usize,
+}
+
+impl<HashType> RootLog<HashType> {
+    pub fn new() -> Self {
+        RootLog { entries: vec![] }
+    }
+
+    pub fn record(&mut self, root: HashType, num_updates: usize) {
+        let batch = self.entries.len();
+
+        self.entries.push(RootLogEntry { batch, root, time: SystemTime::now(), num_updates });
+    }
+
+    // drops every entry but the first 'len' ones
+    pub fn truncate(&mut self, len: usize) {
+        self.entries.truncate(len);
+    }
+
+    pub fn entries(&self) -> &[RootLogEntry<HashType>] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // writes the log as a JSON array of {batch, root, unix_time_us, num_updates} objects
+    pub fn write_json<W: Write>(&self, writer: W) -> io::Result<()>
+    where
+        HashType: CanonicalBytes,
+    {
+        let entries = self
+            .entries
+            .iter()
+            .map(|entry| JsonEntry {
+                batch: entry.batch,
+                root: hex::encode(entry.root.to_bytes()),
+                unix_time_us: entry.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_micros(),
+                num_updates: entry.num_updates,
+            })
+            .collect::<Vec<_>>();
+
+        serde_json::to_writer_pretty(writer, &entries).map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hashing_traits::CanonicalBytes;
+    use crate::merkle_crhf::{new_merkle_crhf_from_leaves, TinySha3HashFunc};
+    use crate::random_updates;
+    use rand::thread_rng;
+
+    #[test]
+    fn root_log() {
+        let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(4, 64);
+        merkle.update_leaves(random_updates(&mut thread_rng(), 64, 5));
+        assert!(merkle.root_log().is_none());
+
+        merkle.enable_root_log();
+        let mut roots = vec![];
+        for num_updates in [5, 1, 10] {
+            merkle.update_leaves(random_updates(&mut thread_rng(), 64, num_updates));
+            roots.push(merkle.root_hash());
+        }
+        merkle.update_leaves_txn(random_updates(&mut thread_rng(), 64, 2)).unwrap();
+        merkle.commit().unwrap();
+        merkle.update_leaves_txn(random_updates(&mut thread_rng(), 64, 4)).unwrap();
+        merkle.rollback().unwrap();
+        merkle.delete_leaves(&[3, 7, 3]);
+
+        let log = merkle.root_log().unwrap();
+        assert_eq!(log.len(), 5);
+        assert_eq!(log.entries().iter().map(|entry| entry.num_updates).collect::<Vec<_>>(), vec![5, 1, 10, 2, 3]);
+        assert_eq!(log.entries()[4].root, merkle.root_hash());
+        assert_eq!(log.entries()[..3].iter().map(|entry| entry.root.clone()).collect::<Vec<_>>(), roots);
+        assert!(log.entries().windows(2).all(|w| w[0].batch + 1 == w[1].batch && w[0].time <= w[1].time));
+
+        let mut json = vec![];
+        log.write_json(&mut json).unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), 5);
+        assert_eq!(json[3]["batch"], 3);
+        assert_eq!(json[0]["root"], hex::encode(roots[0].to_bytes()));
+    }
+}
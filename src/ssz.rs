@@ -0,0 +1,290 @@
+use std::collections::{BTreeSet, HashMap};
+
+use more_asserts::assert_lt;
+use sha2::{Digest, Sha256};
+
+use crate::hashing_traits::{CanonicalBytes, HASH_LENGTH};
+use crate::leaf_storage::LeafStorage;
+use crate::merkle_abstract::AbstractMerkle;
+use crate::merkle_crhf::{new_merkle_crhf_with_scheme, HasherCRHF, HashingScheme, MerkleHashValue, SszSha256HashFunc};
+use crate::node_index::{NodeIndex, TreeArity};
+use crate::node_store::NodeStore;
+
+// Proofs for binary SHA-256 Merkle trees in the format of Ethereum's consensus specs (see
+// ssz/merkle-proofs.md there), so that they can be checked by existing SSZ tooling.
+//
+// A node is identified by its *generalized index*: the root is 1 and the children of node i are 2i
+// and 2i + 1. In a binary AbstractMerkle, whose root is node 0 and whose children of node i are 2i + 1
+// and 2i + 2, that is simply the NodeIndex plus one, wherever the node is (e.g., even for leaves on
+// the second-to-last level).
+pub type GeneralizedIndex = u64;
+
+pub type Chunk = [u8; HASH_LENGTH];
+
+// returns the generalized index of a node in a binary tree
+pub fn generalized_index(node: &NodeIndex) -> GeneralizedIndex {
+    node.index() as GeneralizedIndex + 1
+}
+
+// returns the SHA-256 hash of the two chunks, which is how SSZ hashes a parent from its children
+fn hash_pair(left: &Chunk, right: &Chunk) -> Chunk {
+    let mut hasher = Sha256::new();
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+// Returns a binary tree whose roots are SSZ's, and which prove_ssz() can prove. It hashes nodes under
+// HashingScheme::v0, since SSZ binds nothing but the children's hashes (e.g., no version byte).
+pub fn new_ssz_merkle_from_leaves(
+    num_leaves: usize,
+) -> AbstractMerkle<String, MerkleHashValue, HasherCRHF<SszSha256HashFunc>> {
+    new_merkle_crhf_with_scheme(2, num_leaves, HashingScheme::v0())
+}
+
+// returns the sibling of every node on the path from 'index' up to (but excluding) the root
+fn branch_indices(index: GeneralizedIndex) -> impl Iterator<Item = GeneralizedIndex> {
+    path_indices(index).map(|node| node ^ 1)
+}
+
+// returns every node on the path from 'index' up to (but excluding) the root
+fn path_indices(index: GeneralizedIndex) -> impl Iterator<Item = GeneralizedIndex> {
+    std::iter::successors(Some(index), |node| Some(node / 2)).take_while(|node| *node > 1)
+}
+
+// Returns the generalized indices of the nodes a multiproof for 'indices' consists of, in decreasing
+// order: the siblings on their paths to the root, except for the ones that can be computed from the
+// nodes being proven (i.e., which are on one of those paths themselves).
+pub fn helper_indices(indices: &[GeneralizedIndex]) -> Vec<GeneralizedIndex> {
+    let paths = indices.iter().flat_map(|index| path_indices(*index)).collect::<BTreeSet<_>>();
+    let branches = indices
+        .iter()
+        .flat_map(|index| branch_indices(*index))
+        .filter(|node| !paths.contains(node))
+        .collect::<BTreeSet<_>>();
+
+    branches.into_iter().rev().collect()
+}
+
+// Recomputes the root from the 'leaves' at the specified generalized indices and the 'proof' nodes at
+// their helper_indices(). Returns None if the proof is malformed (e.g., it has the wrong # of nodes, or
+// not enough of them to get to the root).
+pub fn calculate_multi_merkle_root(leaves: &[Chunk], proof: &[Chunk], indices: &[GeneralizedIndex]) -> Option<Chunk> {
+    let helpers = helper_indices(indices);
+    if leaves.len() != indices.len() || proof.len() != helpers.len() || indices.contains(&0) {
+        return None;
+    }
+
+    let mut objects = indices.iter().copied().zip(leaves.iter().copied())
+        .chain(helpers.iter().copied().zip(proof.iter().copied()))
+        .collect::<HashMap<GeneralizedIndex, Chunk>>();
+
+    // NOTE: Follows the spec: parents are appended to the keys as they are computed, and a parent is
+    // only computed if both its children are known (and it is not itself known already).
+    let mut keys = objects.keys().copied().collect::<Vec<_>>();
+    keys.sort_unstable_by(|a, b| b.cmp(a));
+
+    let mut pos = 0;
+    while pos < keys.len() {
+        let k = keys[pos];
+        if k > 1 && !objects.contains_key(&(k / 2)) {
+            if let (Some(left), Some(right)) = (objects.get(&(k & !1)), objects.get(&(k | 1))) {
+                objects.insert(k / 2, hash_pair(left, right));
+                keys.push(k / 2);
+            }
+        }
+        pos += 1;
+    }
+
+    objects.get(&1).copied()
+}
+
+// A multiproof for several nodes (typically leaves) of a binary tree
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SszMultiProof {
+    pub indices: Vec<GeneralizedIndex>, // the generalized indices of the proven nodes
+    pub leaves: Vec<Chunk>,             // the proven nodes, in the same order
+    pub proof: Vec<Chunk>,              // the nodes at helper_indices(indices), in that order
+}
+
+impl SszMultiProof {
+    // returns true if the proven nodes are in the tree with the specified root
+    pub fn verify(&self, root: &Chunk) -> bool {
+        calculate_multi_merkle_root(&self.leaves, &self.proof, &self.indices).as_ref() == Some(root)
+    }
+}
+
+// Serialized as the SSZ container:
+//
+//   class SszMultiProof(Container):
+//       indices: List[uint64, ...]
+//       leaves: List[Bytes32, ...]
+//       proof: List[Bytes32, ...]
+//
+// i.e., since all three fields have a variable size, the little-endian 4-byte offsets of the three
+// lists, followed by the lists themselves.
+impl CanonicalBytes for SszMultiProof {
+    fn to_bytes(&self) -> Vec<u8> {
+        const FIXED_SIZE: usize = 3 * 4;
+        let indices_size = self.indices.len() * 8;
+        let leaves_size = self.leaves.len() * HASH_LENGTH;
+
+        let mut bytes = Vec::with_capacity(FIXED_SIZE + indices_size + leaves_size + self.proof.len() * HASH_LENGTH);
+        for offset in [FIXED_SIZE, FIXED_SIZE + indices_size, FIXED_SIZE + indices_size + leaves_size] {
+            bytes.extend_from_slice(&u32::try_from(offset).expect("Proof is too big for SSZ").to_le_bytes());
+        }
+        for index in &self.indices {
+            bytes.extend_from_slice(&index.to_le_bytes());
+        }
+        for chunk in self.leaves.iter().chain(&self.proof) {
+            bytes.extend_from_slice(chunk);
+        }
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let offset = |i: usize| -> Option<usize> {
+            Some(u32::from_le_bytes(bytes.get(4 * i..4 * (i + 1))?.try_into().ok()?) as usize)
+        };
+        let (indices_start, leaves_start, proof_start) = (offset(0)?, offset(1)?, offset(2)?);
+
+        // NOTE: SSZ requires the first offset to point right after the fixed-size part
+        if indices_start != 12 || leaves_start < indices_start || proof_start < leaves_start || proof_start > bytes.len() {
+            return None;
+        }
+
+        let chunks = |bytes: &[u8]| -> Option<Vec<Chunk>> {
+            if !bytes.len().is_multiple_of(HASH_LENGTH) {
+                return None;
+            }
+
+            Some(bytes.chunks_exact(HASH_LENGTH).map(|chunk| chunk.try_into().unwrap()).collect())
+        };
+
+        let indices = &bytes[indices_start..leaves_start];
+        if !indices.len().is_multiple_of(8) {
+            return None;
+        }
+
+        Some(SszMultiProof {
+            indices: indices.chunks_exact(8).map(|index| u64::from_le_bytes(index.try_into().unwrap())).collect(),
+            leaves: chunks(&bytes[leaves_start..proof_start])?,
+            proof: chunks(&bytes[proof_start..])?,
+        })
+    }
+}
+
+impl<Storage, Nodes, Arity> AbstractMerkle<String, MerkleHashValue, HasherCRHF<SszSha256HashFunc>, Storage, Nodes, Arity>
+where
+    Storage: LeafStorage<String> + Default,
+    Nodes: NodeStore<MerkleHashValue>,
+    Arity: TreeArity,
+{
+    // Returns an SSZ multiproof for the leaves at the specified positions (in [0, n), where n is the #
+    // of leaves), whose proven "leaves" are the leaves' hashes (i.e., SHA-256 of their data).
+    //
+    // NOTE: Our trees keep a parent whose children are all empty (i.e., all-zero) empty as well, while
+    // SSZ would hash them, so a proof that has to hash two empty siblings (e.g., for a leaf that was
+    // never set next to another such leaf) will not verify. Every other proof will.
+    //
+    // NOTE: With 2^k - 1 leaves, all of them are on the last level, so the last parent only has one
+    // child, which SSZ would pad with an empty sibling before hashing. We do not, so such trees cannot
+    // be proven.
+    pub fn prove_ssz(&self, positions: &[usize]) -> SszMultiProof {
+        assert_eq!(self.arity(), 2, "SSZ proofs are only defined for binary trees");
+        assert_eq!(self.hasher.scheme(), &HashingScheme::v0(), "SSZ proofs need the tree to hash under v0");
+        assert!(!self.num_nodes().is_multiple_of(2), "SSZ proofs need every parent to have two children");
+
+        let mut positions = positions.to_vec();
+        positions.sort_unstable();
+        positions.dedup();
+
+        let chunk = |index: GeneralizedIndex| self.subtree_root(&NodeIndex::new(index as usize - 1)).unwrap().hash;
+
+        let indices = positions
+            .iter()
+            .map(|pos| {
+                assert_lt!(*pos, self.num_leaves());
+                generalized_index(&self.get_leaf_idx(*pos))
+            })
+            .collect::<Vec<_>>();
+
+        SszMultiProof {
+            leaves: indices.iter().map(|index| chunk(*index)).collect(),
+            proof: helper_indices(&indices).into_iter().map(chunk).collect(),
+            indices,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random_updates;
+    use rand::thread_rng;
+
+    fn sha256(data: &[u8]) -> Chunk {
+        Sha256::digest(data).into()
+    }
+
+    #[test]
+    fn helper_indices_match_spec() {
+        // a tree of depth 3, whose leaves are 8..16
+        assert_eq!(helper_indices(&[8]), vec![9, 5, 3]);
+        assert_eq!(helper_indices(&[8, 9]), vec![5, 3]);
+        assert_eq!(helper_indices(&[9, 14]), vec![15, 8, 6, 5]);
+        assert!(helper_indices(&[1]).is_empty());
+
+        // the first of SSZ's zero hashes: the root of two empty chunks
+        let zero = [0u8; HASH_LENGTH];
+        assert_eq!(
+            hex::encode(calculate_multi_merkle_root(&[zero], &[zero], &[2]).unwrap()),
+            "f5a5fd42d16a20302798ef6ed309979b43003d2320d9f0e8ea9831a92759fb4b"
+        );
+    }
+
+    #[test]
+    fn ssz_proofs() {
+        // a perfect tree, whose root we can compute by hand
+        let mut merkle = new_ssz_merkle_from_leaves(4);
+        merkle.update_leaves((0..4).map(|pos| (pos, format!("leaf {}", pos))).collect());
+
+        let leaves = (0..4).map(|pos| sha256(format!("leaf {}", pos).as_bytes())).collect::<Vec<_>>();
+        let root = hash_pair(&hash_pair(&leaves[0], &leaves[1]), &hash_pair(&leaves[2], &leaves[3]));
+        assert_eq!(merkle.root_hash().hash, root);
+
+        let proof = merkle.prove_ssz(&[3, 0]);
+        assert_eq!(proof.indices, vec![4, 7]);
+        assert_eq!(proof.leaves, vec![leaves[0], leaves[3]]);
+        assert_eq!(proof.proof, vec![leaves[2], leaves[1]]);
+        assert!(proof.verify(&root));
+        assert_eq!(SszMultiProof::from_bytes(&proof.to_bytes()), Some(proof.clone()));
+
+        let mut wrong = proof.clone();
+        wrong.leaves[1] = leaves[2];
+        assert!(!wrong.verify(&root));
+        assert!(!proof.verify(&leaves[0]));
+
+        let mut malformed = proof.clone();
+        malformed.proof.pop();
+        assert!(!malformed.verify(&root));
+
+        let bytes = proof.to_bytes();
+        assert_eq!(SszMultiProof::from_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(SszMultiProof::from_bytes(&bytes[..8]), None);
+
+        // imperfect trees have leaves on two levels, which generalized indices handle just fine
+        for num_leaves in [5, 6, 13, 100] {
+            let mut merkle = new_ssz_merkle_from_leaves(num_leaves);
+            merkle.update_leaves(random_updates(&mut thread_rng(), num_leaves, num_leaves));
+            let root = merkle.root_hash().hash;
+
+            for positions in [vec![0], vec![num_leaves - 1], vec![1, num_leaves / 2, num_leaves - 2]] {
+                let proof = merkle.prove_ssz(&positions);
+                assert!(proof.verify(&root), "{} leaves, proving {:?}", num_leaves, positions);
+                assert!(SszMultiProof::from_bytes(&proof.to_bytes()).unwrap().verify(&root));
+            }
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::fmt::{Display, Formatter};
+
+use hdrhistogram::Histogram;
+use more_asserts::{assert_gt, assert_lt, debug_assert_gt, debug_assert_lt};
+
+// The # of significant decimal digits the latency histograms keep (i.e., percentiles are within 0.1%)
+const HISTOGRAM_SIGFIGS: u8 = 3;
+
+// Tracks the average time of some operation (e.g., an exponentiation), as well as its distribution, so
+// that we can also report percentiles. Averages hide the tail latencies, which are what matter when,
+// e.g., updates must be done before a block deadline.
+//
+// NOTE: When several operations are timed together (e.g., the exponentiations in a multiexp), each of
+// them is recorded as taking the average time of the batch.
+pub struct RunningAverage {
+    total_time_usec: f64,
+    pub total_measurements: usize,
+
+    // the time per operation, in nanoseconds, since many operations take less than a microsecond
+    histogram: Histogram<u64>,
+}
+
+impl RunningAverage {
+    pub fn new() -> Self {
+        RunningAverage {
+            total_time_usec: 0.0,
+            total_measurements: 0,
+            histogram: Histogram::new(HISTOGRAM_SIGFIGS).unwrap(),
+        }
+    }
+
+    pub fn add(&mut self, time_usec: u128, num_measurements: usize) {
+        self.total_time_usec += time_usec as f64;
+        self.total_measurements += num_measurements;
+
+        if num_measurements > 0 {
+            let time_nsec = u64::try_from(time_usec * 1000 / num_measurements as u128).unwrap_or(u64::MAX);
+
+            // NOTE: The histogram grows as needed, unless the time is too large to track at all
+            if self.histogram.record_n(time_nsec, num_measurements as u64).is_err() {
+                self.histogram.saturating_record_n(time_nsec, num_measurements as u64);
+            }
+        }
+    }
+
+    pub fn average(&self) -> f64 {
+        self.total_time_usec / self.total_measurements as f64
+    }
+
+    // returns the time (in microseconds) that a fraction 'quantile' of the measurements were at most,
+    // e.g., 0.99 for the 99th percentile
+    pub fn percentile(&self, quantile: f64) -> f64 {
+        self.histogram.value_at_quantile(quantile) as f64 / 1000.0
+    }
+
+    pub fn min(&self) -> f64 {
+        self.histogram.min() as f64 / 1000.0
+    }
+
+    pub fn max(&self) -> f64 {
+        self.histogram.max() as f64 / 1000.0
+    }
+
+    pub fn std_dev(&self) -> f64 {
+        self.histogram.stdev() / 1000.0
+    }
+
+    pub fn reset(&mut self) {
+        self.total_time_usec = 0.0;
+        self.total_measurements = 0;
+        self.histogram.reset();
+    }
+}
+
+impl Default for RunningAverage {
+    fn default() -> Self {
+        RunningAverage::new()
+    }
+}
+
+impl Display for RunningAverage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:.2} us ({} samples", self.average(), self.total_measurements)?;
+
+        if self.total_measurements > 0 {
+            write!(
+                f,
+                "; min {:.2}, p50 {:.2}, p95 {:.2}, p99 {:.2}, max {:.2}, std. dev. {:.2}",
+                self.min(),
+                self.percentile(0.5),
+                self.percentile(0.95),
+                self.percentile(0.99),
+                self.max(),
+                self.std_dev()
+            )?;
+        }
+
+        write!(f, ")")
+    }
+}
+
+pub struct HistogramAverages {
+    average: Vec<RunningAverage>
+}
+
+impl HistogramAverages {
+    pub fn new(num: usize) -> Self {
+        HistogramAverages {
+            average: (0..num).map(|_| RunningAverage::new()).collect()
+        }
+    }
+
+    // idx is from 1 to N
+    pub fn add(&mut self, idx: usize, time_usec: u128) {
+        debug_assert_gt!(idx, 0);
+        debug_assert_lt!(idx - 1, self.average.len());
+        self.average[idx - 1].add(time_usec, 1);
+    }
+}
+
+// NOTE: Only prints the entries that were added to, since for large arities (e.g., 1024) most of them
+// never are
+impl Display for HistogramAverages {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        (0..self.average.len())
+            .filter(|idx| self.average[*idx].total_measurements > 0)
+            .try_for_each(|idx| writeln!(f, "{} -> {}", idx + 1, self.average[idx]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn percentiles_show_the_tail() {
+        let mut avg = RunningAverage::new();
+        for _ in 0..98 {
+            avg.add(10, 1);
+        }
+        // a batch of two slow operations
+        avg.add(2000, 2);
+
+        assert_eq!(avg.total_measurements, 100);
+        // NOTE: The histogram is only accurate to within 0.1%
+        let is_close = |actual: f64, expected: f64| (actual - expected).abs() <= expected / 1000.0;
+        assert!(is_close(avg.average(), 29.8));
+        assert!(is_close(avg.percentile(0.5), 10.0));
+        assert!(is_close(avg.percentile(0.99), 1000.0));
+        assert!(is_close(avg.min(), 10.0));
+        assert!(is_close(avg.max(), 1000.0));
+        assert!(avg.std_dev() > 0.0);
+
+        avg.reset();
+        assert_eq!(avg.total_measurements, 0);
+        assert_eq!(avg.to_string(), "NaN us (0 samples)");
+    }
+
+    #[test]
+    fn histogram_skips_empty_entries() {
+        let mut histogram = HistogramAverages::new(1024);
+        histogram.add(3, 10);
+        histogram.add(1000, 20);
+
+        let lines = histogram.to_string();
+        assert_eq!(lines.lines().count(), 2);
+        assert!(lines.starts_with("3 -> 10.00 us (1 samples"));
+        assert!(lines.lines().nth(1).unwrap().starts_with("1000 -> 20.00 us"));
+    }
+}
@@ -0,0 +1,174 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+
+use crate::racer::RacerRegistry;
+use crate::workload::{sample_leaf_positions, LeafDistribution};
+
+// The shapes (arity, # of leaves) vectors are generated for by default: a perfect binary tree, trees
+// with leaves on two levels, and a wider tree (Poseidon trees only support an arity of up to 12)
+pub const DEFAULT_SHAPES: &[(usize, usize)] = &[(2, 8), (2, 7), (3, 10), (8, 40)];
+
+// A known-answer test for one type of tree, so that other implementations (e.g., of Verkle, in Go or
+// Python) can be cross-checked against this one: a tree of type 'tree_type' with 'num_leaves' leaves of
+// arity 'arity', whose leaves are set to 'leaves' and then updated by 'updates', in that order, must end
+// up with the root 'root' (hex-encoded, serialized as in MerkleRacer::root()).
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TestVector {
+    pub tree_type: String,
+    pub arity: usize,
+    pub num_leaves: usize,
+    pub leaves: Vec<(usize, String)>,
+    pub updates: Vec<(usize, String)>,
+    pub root: String,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TestVectorError {
+    UnknownType(String),
+    WrongRoot { index: usize, tree_type: String, expected: String, actual: String },
+}
+
+impl fmt::Display for TestVectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TestVectorError::UnknownType(tree_type) => write!(f, "Unknown type of tree: {}", tree_type),
+            TestVectorError::WrongRoot { index, tree_type, expected, actual } => write!(
+                f,
+                "Vector #{} ({}) has root {}, but the tree's root is {}",
+                index, tree_type, expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for TestVectorError {}
+
+// returns the types of trees in the registry whose roots only depend on their leaves, i.e., all but
+// verkle_kzg, whose bases are picked at random
+pub fn default_vector_types(registry: &RacerRegistry) -> Vec<String> {
+    registry.names().filter(|name| *name != "verkle_kzg").map(str::to_owned).collect()
+}
+
+// returns one vector per tree type and shape, whose leaves are all set and then some of them updated,
+// picked via an RNG seeded with 'seed' (so the same seed always gives the same vectors)
+pub fn generate_vectors(
+    registry: &RacerRegistry,
+    tree_types: &[String],
+    shapes: &[(usize, usize)],
+    seed: u64,
+) -> Result<Vec<TestVector>, TestVectorError> {
+    let mut vectors = vec![];
+
+    for tree_type in tree_types {
+        for &(arity, num_leaves) in shapes {
+            // NOTE: The leaves do not depend on the tree type, so that vectors of different types agree
+            let mut rng = StdRng::seed_from_u64(seed);
+            let leaves = (0..num_leaves).map(|pos| (pos, format!("leaf-{}", pos))).collect::<Vec<_>>();
+            let updates = sample_leaf_positions(&mut rng, num_leaves, num_leaves / 3 + 1, LeafDistribution::Uniform)
+                .into_iter()
+                .map(|pos| (pos, format!("update-{}", pos)))
+                .collect::<Vec<_>>();
+
+            let root = compute_root(registry, tree_type, arity, num_leaves, &leaves, &updates)?;
+            vectors.push(TestVector {
+                tree_type: tree_type.clone(),
+                arity,
+                num_leaves,
+                leaves,
+                updates,
+                root: hex::encode(root),
+            });
+        }
+    }
+
+    Ok(vectors)
+}
+
+// checks every vector against a tree of its type, returning the # of vectors checked, or the first one
+// this crate disagrees with
+pub fn verify_vectors(registry: &RacerRegistry, vectors: &[TestVector]) -> Result<usize, TestVectorError> {
+    for (index, vector) in vectors.iter().enumerate() {
+        let root = compute_root(
+            registry,
+            &vector.tree_type,
+            vector.arity,
+            vector.num_leaves,
+            &vector.leaves,
+            &vector.updates,
+        )?;
+
+        let actual = hex::encode(root);
+        if !actual.eq_ignore_ascii_case(&vector.root) {
+            return Err(TestVectorError::WrongRoot {
+                index,
+                tree_type: vector.tree_type.clone(),
+                expected: vector.root.clone(),
+                actual,
+            });
+        }
+    }
+
+    Ok(vectors.len())
+}
+
+// writes the vectors as a pretty JSON array
+pub fn write_vectors<W: Write>(writer: W, vectors: &[TestVector]) -> io::Result<()> {
+    serde_json::to_writer_pretty(writer, vectors).map_err(io::Error::other)
+}
+
+pub fn read_vectors<R: Read>(reader: R) -> io::Result<Vec<TestVector>> {
+    serde_json::from_reader(reader).map_err(io::Error::other)
+}
+
+fn compute_root(
+    registry: &RacerRegistry,
+    tree_type: &str,
+    arity: usize,
+    num_leaves: usize,
+    leaves: &[(usize, String)],
+    updates: &[(usize, String)],
+) -> Result<Vec<u8>, TestVectorError> {
+    // NOTE: Only trees with random parameters (i.e., verkle_kzg) use the RNG, so their roots are only
+    // reproducible by this crate
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut racer = registry
+        .create(tree_type, arity, num_leaves, &mut rng)
+        .ok_or_else(|| TestVectorError::UnknownType(tree_type.to_owned()))?;
+
+    racer.update_batch(leaves.to_vec());
+    racer.update_batch(updates.to_vec());
+
+    Ok(racer.root())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vectors_round_trip() {
+        let registry = RacerRegistry::with_default_racers();
+        let tree_types = ["merkle_sha3".to_owned(), "merkle++".to_owned(), "verkle".to_owned()];
+        let vectors = generate_vectors(&registry, &tree_types, &[(2, 7), (3, 10)], 42).unwrap();
+        assert_eq!(vectors.len(), 6);
+        assert_eq!(vectors, generate_vectors(&registry, &tree_types, &[(2, 7), (3, 10)], 42).unwrap());
+
+        let mut json = vec![];
+        write_vectors(&mut json, &vectors).unwrap();
+        let mut vectors = read_vectors(&json[..]).unwrap();
+        assert_eq!(verify_vectors(&registry, &vectors), Ok(6));
+
+        vectors[4].updates[0].1 = "tampered".to_owned();
+        assert!(matches!(verify_vectors(&registry, &vectors), Err(TestVectorError::WrongRoot { index: 4, .. })));
+
+        vectors[1].tree_type = "merkle_sha4".to_owned();
+        assert_eq!(
+            verify_vectors(&registry, &vectors),
+            Err(TestVectorError::UnknownType("merkle_sha4".to_owned()))
+        );
+    }
+}
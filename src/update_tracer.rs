@@ -0,0 +1,171 @@
+use std::fmt::{Display, Formatter};
+
+use tracing::trace;
+
+use crate::node_index::NodeIndex;
+
+// Receives the events of AbstractMerkle's update queue, as updated nodes are dequeued and their
+// parents are hashed and queued up, so that the propagation of a batch of updates can be inspected
+// (see AbstractMerkle::update_leaves_traced()). Every event does nothing by default.
+//
+// NOTE: The tracer is a type parameter of the update functions, so the events of tracers which ignore
+// them compile down to nothing.
+pub trait UpdateTracer<HashType> {
+    // 'node' (a leaf if 'leaf_pos' is set) was dequeued with its new hash; its parent is hashed once
+    // all of its updated siblings are dequeued as well
+    fn dequeue(&mut self, _node: NodeIndex, _leaf_pos: Option<usize>, _hash: &HashType) {}
+
+    // 'parent' was hashed, given 'num_updated' new hashes out of its 'num_children' children
+    fn hash(&mut self, _parent: NodeIndex, _num_updated: usize, _num_children: usize) {}
+
+    // 'parent' was queued up with its new hash, to be dequeued once the level below is done
+    fn enqueue(&mut self, _parent: NodeIndex, _hash: &HashType) {}
+}
+
+// Ignores every event.
+pub struct NoTracer;
+
+impl<HashType> UpdateTracer<HashType> for NoTracer {}
+
+// Logs every event as a trace event (e.g., via RUST_LOG=trace), which is what the update functions
+// that take no tracer do.
+pub struct LogTracer {
+    arity: usize,
+}
+
+impl LogTracer {
+    pub fn new(arity: usize) -> Self {
+        LogTracer { arity }
+    }
+}
+
+impl<HashType> UpdateTracer<HashType> for LogTracer {
+    fn dequeue(&mut self, node: NodeIndex, leaf_pos: Option<usize>, _hash: &HashType) {
+        if node.is_root() {
+            trace!("Dequeing new root {}", node.0);
+            return;
+        }
+
+        let (offset, parent) = (node.child_offset(self.arity), node.parent(self.arity).0);
+        match leaf_pos {
+            Some(pos) => trace!("Dequeing new leaf {} (offset {}, leaf #{}) of parent {}", node.0, offset, pos, parent),
+            None => trace!("Dequeing new children {} (offset {}) of parent {}", node.0, offset, parent),
+        }
+    }
+
+    fn hash(&mut self, parent: NodeIndex, num_updated: usize, num_children: usize) {
+        trace!("Hashing parent {} from {} new out of its {} children", parent.0, num_updated, num_children);
+    }
+
+    fn enqueue(&mut self, parent: NodeIndex, _hash: &HashType) {
+        trace!("Queueing parent {}", parent.0);
+    }
+}
+
+// What happened on one level of the tree while propagating a batch of updates
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LevelPropagation {
+    pub num_dequeued: usize, // the # of updated nodes on this level
+    pub num_leaves: usize,   // ...of which leaves
+    pub num_parents: usize,  // the # of parents (on the level above) hashed from this level's nodes
+    pub num_children: usize, // the # of children those parents have, including the ones not updated
+}
+
+// Tallies the events per level, and renders them as a report, from the deepest level up to the root:
+//
+//   level 3: 5 updated nodes (5 leaves) -> 3 parents, from 5 of 9 children (55.6% updated)
+//   ...
+pub struct PropagationReport {
+    arity: usize,
+    levels: Vec<LevelPropagation>, // indexed by level, where the root is at level 0
+}
+
+impl PropagationReport {
+    pub fn new(arity: usize) -> Self {
+        PropagationReport { arity, levels: vec![] }
+    }
+
+    // returns what happened on the given level, if anything
+    pub fn level(&self, level: usize) -> LevelPropagation {
+        self.levels.get(level).copied().unwrap_or_default()
+    }
+
+    fn level_mut(&mut self, level: usize) -> &mut LevelPropagation {
+        if self.levels.len() <= level {
+            self.levels.resize(level + 1, LevelPropagation::default());
+        }
+
+        &mut self.levels[level]
+    }
+}
+
+impl<HashType> UpdateTracer<HashType> for PropagationReport {
+    fn dequeue(&mut self, node: NodeIndex, leaf_pos: Option<usize>, _hash: &HashType) {
+        let level = self.level_mut(node.level(self.arity));
+        level.num_dequeued += 1;
+        level.num_leaves += leaf_pos.is_some() as usize;
+    }
+
+    fn hash(&mut self, parent: NodeIndex, _num_updated: usize, num_children: usize) {
+        let level = self.level_mut(parent.level(self.arity) + 1);
+        level.num_parents += 1;
+        level.num_children += num_children;
+    }
+}
+
+impl Display for PropagationReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, level) in self.levels.iter().enumerate().rev() {
+            if level.num_dequeued == 0 {
+                continue;
+            }
+
+            write!(f, "level {}: {} updated nodes ({} leaves)", i, level.num_dequeued, level.num_leaves)?;
+            if level.num_parents > 0 {
+                write!(
+                    f,
+                    " -> {} parents, from {} of {} children ({:.1}% updated)",
+                    level.num_parents,
+                    level.num_dequeued,
+                    level.num_children,
+                    100.0 * level.num_dequeued as f64 / level.num_children as f64
+                )?;
+            }
+            writeln!(f)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merkle_crhf::{new_merkle_crhf_from_leaves, TinySha3HashFunc};
+
+    #[test]
+    fn propagation_report() {
+        // arity 3 with 10 leaves: leaves 0..8 are on level 2, and leaves 8 and 9 on level 3, under node 4
+        let (arity, num_leaves) = (3, 10);
+        let mut merkle = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+        let mut other = new_merkle_crhf_from_leaves::<TinySha3HashFunc>(arity, num_leaves);
+        let updates = vec![(0, "zero".to_owned()), (1, "one".to_owned()), (9, "nine".to_owned())];
+
+        let mut report = PropagationReport::new(arity);
+        merkle.update_leaves_traced(updates.clone(), &mut report);
+        other.update_leaves(updates);
+        assert_eq!(merkle.root_hash(), other.root_hash());
+
+        assert_eq!(report.level(3), LevelPropagation { num_dequeued: 1, num_leaves: 1, num_parents: 1, num_children: 2 });
+        // node 4 (i.e., the parent of leaf 9) and leaves 0 and 1 share parent 1
+        assert_eq!(report.level(2), LevelPropagation { num_dequeued: 3, num_leaves: 2, num_parents: 1, num_children: 3 });
+        assert_eq!(report.level(1), LevelPropagation { num_dequeued: 1, num_leaves: 0, num_parents: 1, num_children: 3 });
+        assert_eq!(report.level(0), LevelPropagation { num_dequeued: 1, ..Default::default() });
+        assert_eq!(report.level(4), LevelPropagation::default());
+
+        let rendered = report.to_string();
+        assert_eq!(rendered.lines().count(), 4);
+        assert!(rendered.starts_with("level 3: 1 updated nodes (1 leaves) -> 1 parents, from 1 of 2 children (50.0% updated)\n"));
+        assert!(rendered.ends_with("level 0: 1 updated nodes (0 leaves)\n"));
+    }
+}
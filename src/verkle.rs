@@ -1,19 +1,30 @@
-use std::borrow::Borrow;
 use crate::merkle_abstract::AbstractMerkle;
-use crate::hashing_traits::TreeHasherFunc;
+use crate::node_index::NodeIndex;
+use crate::merkle_sparse::SparseMerkle;
+use crate::leaf_storage::NoLeafStorage;
+use crate::node_store::NodeStore;
+use crate::hashing_traits::{CanonicalBytes, HasherStats, ParallelTreeHasherFunc, TreeHasherFunc};
 use serde::Serialize;
+use std::collections::BTreeSet;
 use std::fmt::{Debug, Formatter};
-use std::time::Instant;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::path::Path;
+use std::time::{Duration, Instant};
 use blake2::{Digest, Blake2b};
 use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoBasepointTable, RistrettoPoint, VartimeRistrettoSubsetPrecomputation};
 use curve25519_dalek::scalar::Scalar;
-use curve25519_dalek::traits::{Identity, VartimePrecomputedSubsetMultiscalarMul};
+use curve25519_dalek::traits::{Identity, IsIdentity, VartimeMultiscalarMul, VartimePrecomputedSubsetMultiscalarMul};
 use digest::consts::U64;
 use more_asserts::assert_le;
+use rand::prelude::IteratorRandom;
+use rand::thread_rng;
 use crate::{HistogramAverages, RunningAverage};
 
 // TODO: use Rust unions instead, to avoid the 1-byte tagging overhead
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum VerkleComm {
     Internal(CompressedRistretto),
     Leaf(Scalar),
@@ -38,11 +49,287 @@ impl Debug for VerkleComm {
     }
 }
 
-pub struct VerkleHasher {
-    num_hashes: usize,
+// serialized as a 1-byte tag (0 for empty nodes, 1 for internal nodes, 2 for leaves), followed by the
+// 32-byte compressed point or scalar, respectively
+impl CanonicalBytes for VerkleComm {
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            VerkleComm::Empty => vec![0u8],
+            VerkleComm::Internal(c) => [&[1u8], c.as_bytes().as_slice()].concat(),
+            VerkleComm::Leaf(a) => [&[2u8], a.as_bytes().as_slice()].concat(),
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes.split_first()? {
+            (0, []) => Some(VerkleComm::Empty),
+            (1, rest) => {
+                let c = CompressedRistretto(rest.try_into().ok()?);
+
+                // make sure this is actually a valid point
+                c.decompress()?;
+
+                Some(VerkleComm::Internal(c))
+            }
+            (2, rest) => Some(VerkleComm::Leaf(Scalar::from_canonical_bytes(rest.try_into().ok()?)?)),
+            _ => None,
+        }
+    }
+}
+
+// What the nodes of a Verkle tree store, which lets us trade off storage for speed (see FatVerkleComm).
+pub trait VerkleNode: Clone + Default + PartialEq + From<VerkleComm> + Send + Sync {
+    // returns the node's commitment (or leaf scalar)
+    fn comm(&self) -> VerkleComm;
+
+    // returns the scalar that the node's parent commits to, which is zero for empty nodes
+    fn scalar(&self) -> Scalar;
+
+    // true if an internal node's scalar is hashed once, when the node is created, rather than every time
+    // scalar() is called
+    const CACHES_SCALAR: bool;
+}
+
+impl VerkleNode for VerkleComm {
+    const CACHES_SCALAR: bool = false;
+
+    fn comm(&self) -> VerkleComm {
+        self.clone()
+    }
+
+    fn scalar(&self) -> Scalar {
+        match self {
+            VerkleComm::Internal(c) => hash_to_scalar(c),
+            VerkleComm::Leaf(a) => *a,
+            VerkleComm::Empty => Scalar::zero(),
+        }
+    }
+}
+
+// A "fat" Verkle node, which caches the hash_to_scalar() of an internal node's commitment next to it.
+// This doubles the size of a node, but saves recomputing that scalar every time the node changes: it
+// is computed once when the node's commitment is, rather than once more when the parent is updated.
+//
+// NOTE: The scalar is not part of the node's canonical serialization, which is the same as that of
+// the corresponding VerkleComm, so fat and thin trees have the same root bytes.
+#[derive(Clone, Default, PartialEq)]
+pub struct FatVerkleComm {
+    comm: VerkleComm,
+    scalar: Scalar,
+}
+
+impl From<VerkleComm> for FatVerkleComm {
+    fn from(comm: VerkleComm) -> Self {
+        let scalar = comm.scalar();
+
+        FatVerkleComm { comm, scalar }
+    }
+}
+
+impl VerkleNode for FatVerkleComm {
+    const CACHES_SCALAR: bool = true;
+
+    fn comm(&self) -> VerkleComm {
+        self.comm.clone()
+    }
+
+    fn scalar(&self) -> Scalar {
+        self.scalar
+    }
+}
+
+impl Debug for FatVerkleComm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.comm.fmt(f)
+    }
+}
+
+impl CanonicalBytes for FatVerkleComm {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.comm.to_bytes()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        VerkleComm::from_bytes(bytes).map(FatVerkleComm::from)
+    }
+}
+
+// How a VerkleHasher exponentiates the bases by the changes in its children
+#[derive(Clone, Copy, Debug)]
+pub struct VerkleConfig {
+    // When at most this many children are updated, we do individual exponentiations rather than a
+    // multiexp (see VerkleHasher::calibrate_multiexp_cutoff()).
+    pub multiexp_cutoff: usize,
+
+    // Whether individual exponentiations use a precomputed table for each base, which is faster but
+    // takes around 30 KiB per base, or a variable-base scalar multiplication.
+    pub base_tables: bool,
+}
+
+// NOTE: Run benches/multiexp.rs (or VerkleHasher::calibrate_multiexp_cutoff()) to figure out what
+// cutoff to use on a given machine
+const MULTIEXP_CUTOFF: usize = 4;
+
+// The largest cutoff VerkleHasher::calibrate_multiexp_cutoff() tries, since it does quadratically many
+// exponentiations in the cutoff (e.g., trying every cutoff up to an arity of 256 takes around a minute
+// when the multiexp never wins).
+const MAX_CALIBRATED_CUTOFF: usize = 64;
+
+impl Default for VerkleConfig {
+    fn default() -> Self {
+        VerkleConfig {
+            multiexp_cutoff: MULTIEXP_CUTOFF,
+            base_tables: true,
+        }
+    }
+}
+
+// Returns 'arity' bases, each of which is hashed to the curve from its index, so that no one knows
+// their discrete logs (i.e., they are nothing-up-my-sleeve), and so that every run (and every
+// machine) uses the same bases.
+pub fn deterministic_bases(arity: usize) -> Vec<RistrettoPoint> {
+    seeded_bases(b"merkle-race verkle base ", arity)
+}
+
+// Same as deterministic_bases(), but hashes the index along with 'seed' (i.e., seed || index as a u64,
+// little-endian), so that applications can derive bases of their own that no one knows the discrete
+// logs of either
+pub fn seeded_bases(seed: &[u8], arity: usize) -> Vec<RistrettoPoint> {
+    (0..arity as u64)
+        .map(|index| {
+            let input = [seed, &index.to_le_bytes()].concat();
+
+            RistrettoPoint::hash_from_bytes::<Blake2b<U64>>(&input)
+        })
+        .collect()
+}
+
+// The bases of a VerkleHasher, along with their precomputed tables (see VerkleConfig::base_tables),
+// which take a while to compute for large arities and so can be saved to a file and loaded back.
+//
+// NOTE: The multiexp's VartimeRistrettoSubsetPrecomputation is not saved, since it does not expose its
+// tables, so it is recomputed from the bases.
+//
+// NOTE(Perf): For an arity of 256, computing the bases and their tables takes ~370 ms, while loading
+// them (7.5 MiB) takes ~9 ms.
+pub struct VerkleBases {
+    bases: Vec<RistrettoPoint>,
+    tables: Vec<RistrettoBasepointTable>,
+}
+
+impl VerkleBases {
+    pub fn new(bases: Vec<RistrettoPoint>) -> Self {
+        let tables = bases.iter().map(RistrettoBasepointTable::create).collect();
+
+        VerkleBases { bases, tables }
+    }
+
+    // returns the deterministic_bases() for this arity, along with their tables
+    pub fn deterministic(arity: usize) -> Self {
+        VerkleBases::new(deterministic_bases(arity))
+    }
+
+    pub fn arity(&self) -> usize {
+        self.bases.len()
+    }
+
+    pub fn bases(&self) -> &[RistrettoPoint] {
+        &self.bases
+    }
+
+    // Saves the bases and their tables to 'path'.
+    //
+    // The file starts with the bincode-serialized (arity, size of a table in bytes), followed by each
+    // compressed base and then by each table's in-memory representation.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        bincode::serialize_into(&mut writer, &(self.arity(), size_of::<RistrettoBasepointTable>()))
+            .map_err(std::io::Error::other)?;
+
+        for base in &self.bases {
+            writer.write_all(base.compress().as_bytes())?;
+        }
+
+        for table in &self.tables {
+            // NOTE: A table is an array of points, each of which is an array of integer limbs, so it has no
+            // padding bytes that would make this read undefined.
+            let bytes = unsafe {
+                std::slice::from_raw_parts(table as *const RistrettoBasepointTable as *const u8, size_of::<RistrettoBasepointTable>())
+            };
+            writer.write_all(bytes)?;
+        }
+
+        writer.flush()
+    }
+
+    // Loads the bases and tables saved at 'path' via save().
+    //
+    // NOTE: The tables are only checked to be for the right bases, rather than recomputed, so a
+    // corrupted table goes undetected.
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let invalid_data = |msg: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, msg.to_owned());
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let (arity, table_size): (usize, usize) = bincode::deserialize_from(&mut reader)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+        // e.g., the file was saved by a build whose field elements have a different representation
+        if table_size != size_of::<RistrettoBasepointTable>() {
+            return Err(invalid_data("saved tables have a different size"));
+        }
+
+        let mut bases = Vec::with_capacity(arity);
+        let mut bytes = [0u8; 32];
+        for _ in 0..arity {
+            reader.read_exact(&mut bytes)?;
+
+            let base = CompressedRistretto(bytes).decompress().ok_or_else(|| invalid_data("invalid base"))?;
+            bases.push(base);
+        }
+
+        let mut tables = Vec::with_capacity(arity);
+        let mut bytes = vec![0u8; table_size];
+        for base in &bases {
+            reader.read_exact(&mut bytes)?;
+
+            // NOTE: Any bytes are a valid table (see save()), albeit maybe not one for this base
+            let table = unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const RistrettoBasepointTable) };
+            if table.basepoint() != *base {
+                return Err(invalid_data("saved table does not match its base"));
+            }
+            tables.push(table);
+        }
+
+        Ok(VerkleBases { bases, tables })
+    }
+
+    // Loads the bases saved at 'path', or, if there is no such file, saves the deterministic_bases() for
+    // this arity there (which is how they are only ever computed once). Returns whether they were loaded.
+    pub fn load_or_create<P: AsRef<Path>>(path: P, arity: usize) -> std::io::Result<(Self, bool)> {
+        if !path.as_ref().exists() {
+            let bases = VerkleBases::deterministic(arity);
+            bases.save(path)?;
+
+            return Ok((bases, false));
+        }
+
+        let bases = VerkleBases::load(path)?;
+        if bases.arity() != arity {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "saved bases have a different arity"));
+        }
+
+        Ok((bases, true))
+    }
+}
+
+pub struct VerkleHasher<Node = VerkleComm> {
+    stats: HasherStats,
     arity: usize,
+    config: VerkleConfig,
+    bases: Vec<RistrettoPoint>,
     precomp: VartimeRistrettoSubsetPrecomputation,
-    base_tables: Vec<RistrettoBasepointTable>,
+    base_tables: Vec<RistrettoBasepointTable>, // empty, unless config.base_tables is set
     pub hash_nodes_histogram: HistogramAverages,
     pub avg_single_exp_time: RunningAverage,
     pub avg_multi_exp_time: RunningAverage,
@@ -50,16 +337,48 @@ pub struct VerkleHasher {
     pub avg_accum_time: RunningAverage,
     // pub avg_clone_time: RunningAverage,
     pub avg_push_updates_time: RunningAverage,
+    phantom: PhantomData<Node>,
 }
 
-impl VerkleHasher
+impl<Node> VerkleHasher<Node>
 {
-    fn new(arity: usize, bases: Vec<RistrettoPoint>) -> Self {
+    pub fn new(arity: usize, bases: Vec<RistrettoPoint>) -> Self {
+        Self::with_config(arity, bases, VerkleConfig::default())
+    }
+
+    pub fn with_config(arity: usize, bases: Vec<RistrettoPoint>, config: VerkleConfig) -> Self {
+        assert_eq!(bases.len(), arity);
+
+        let base_tables = if config.base_tables {
+            bases.iter().map(RistrettoBasepointTable::create).collect()
+        } else {
+            vec![]
+        };
+
+        Self::from_parts(arity, bases, base_tables, config)
+    }
+
+    // same as with_config(), but reuses the bases' tables (e.g., ones loaded via VerkleBases::load())
+    pub fn with_bases(bases: VerkleBases, config: VerkleConfig) -> Self {
+        let VerkleBases { bases, tables } = bases;
+        let base_tables = if config.base_tables { tables } else { vec![] };
+
+        Self::from_parts(bases.len(), bases, base_tables, config)
+    }
+
+    fn from_parts(
+        arity: usize,
+        bases: Vec<RistrettoPoint>,
+        base_tables: Vec<RistrettoBasepointTable>,
+        config: VerkleConfig,
+    ) -> Self {
         VerkleHasher {
-            num_hashes: 0,
+            stats: HasherStats::default(),
             arity,
+            config,
             precomp: VartimeRistrettoSubsetPrecomputation::new(bases.clone()),
-            base_tables: bases.into_iter().map(|point| RistrettoBasepointTable::create(&point)).collect(),
+            bases,
+            base_tables,
             hash_nodes_histogram: HistogramAverages::new(arity),
             avg_single_exp_time: RunningAverage::new(),
             avg_multi_exp_time: RunningAverage::new(),
@@ -67,8 +386,251 @@ impl VerkleHasher
             avg_accum_time: RunningAverage::new(),
             // avg_clone_time: RunningAverage::new(),
             avg_push_updates_time: RunningAverage::new(),
+            phantom: Default::default(),
+        }
+    }
+
+    pub fn config(&self) -> VerkleConfig {
+        self.config
+    }
+
+    // Returns the ith base exponentiated by 'exp', via its table if it has one.
+    fn single_exp(&self, index: usize, exp: &Scalar) -> RistrettoPoint {
+        if self.config.base_tables {
+            &self.base_tables[index] * exp
+        } else {
+            self.bases[index] * exp
         }
     }
+
+    // Sets the multiexp cutoff to the largest # of updated children for which individual exponentiations
+    // are faster than a multiexp on this machine, and returns it.
+    //
+    // For k = 1, 2, ..., we time both ways of exponentiating k random bases by random scalars, and stop
+    // at the first k for which the multiexp wins (or at MAX_CALIBRATED_CUTOFF).
+    pub fn calibrate_multiexp_cutoff(&mut self) -> usize {
+        const NUM_REPS: usize = 20;
+        let mut rng = thread_rng();
+
+        let max_cutoff = self.arity.min(MAX_CALIBRATED_CUTOFF);
+        let mut cutoff = max_cutoff;
+        for k in 1..=max_cutoff {
+            let mut single_time = Duration::ZERO;
+            let mut multi_time = Duration::ZERO;
+
+            for _ in 0..NUM_REPS {
+                let updates = (0..self.arity)
+                    .choose_multiple(&mut rng, k)
+                    .into_iter()
+                    .map(|index| (index, Scalar::random(&mut rng)))
+                    .collect::<Vec<(usize, Scalar)>>();
+
+                let start = Instant::now();
+                let single = updates
+                    .iter()
+                    .map(|(index, exp)| self.single_exp(*index, exp))
+                    .fold(RistrettoPoint::identity(), |acc, p| acc + p);
+                single_time += start.elapsed();
+
+                let start = Instant::now();
+                let multi = self.precomp.vartime_subset_multiscalar_mul(updates);
+                multi_time += start.elapsed();
+
+                debug_assert_eq!(single, multi);
+            }
+
+            if multi_time < single_time {
+                cutoff = k - 1;
+                break;
+            }
+        }
+
+        self.config.multiexp_cutoff = cutoff;
+        cutoff
+    }
+}
+
+impl<Node: VerkleNode> VerkleHasher<Node>
+{
+    // Returns, for each updated child, its offset and the scalar its base must be exponentiated by
+    // in order to update the parent's commitment.
+    fn scalar_updates(
+        old_children: &[Node],
+        new_children: &[(usize, Node)],
+        stats: &mut HasherStats,
+    ) -> Vec<(usize, Scalar)> {
+        let mut updates: Vec<(usize, Scalar)> = Vec::with_capacity(new_children.len());
+        for (offset, new_child) in new_children {
+            let old_child = &old_children[*offset];
+
+            match (old_child.comm(), new_child.comm()) {
+                (VerkleComm::Empty, VerkleComm::Empty) => {
+                    // e.g., deleting a leaf that was never set, which changes nothing
+                },
+                (VerkleComm::Internal(_), VerkleComm::Leaf(_)) => {
+                    panic!("Old child was internal, but new one is leaf.");
+                },
+                (VerkleComm::Leaf(_), VerkleComm::Internal(_)) => {
+                    panic!("Old child was a leaf, but new one is internal.");
+                },
+                // NOTE: For thin nodes, this computes hash_to_scalar() for internal children here
+                // (see FatVerkleComm).
+                (old_comm, new_comm) => {
+                    if !Node::CACHES_SCALAR {
+                        stats.sym_hashes += [old_comm, new_comm]
+                            .iter()
+                            .filter(|comm| matches!(comm, VerkleComm::Internal(_)))
+                            .count();
+                    }
+                    stats.field_ops += 1;
+
+                    updates.push((*offset, new_child.scalar() - old_child.scalar()))
+                },
+            }
+        }
+
+        updates
+    }
+
+    // Returns the product of the bases exponentiated by the 'updates', without measuring anything.
+    fn exp_updates(&self, updates: Vec<(usize, Scalar)>) -> RistrettoPoint {
+        if updates.len() <= self.config.multiexp_cutoff {
+            updates
+                .into_iter()
+                .map(|(index, exp)| self.single_exp(index, &exp))
+                .fold(RistrettoPoint::identity(), |acc, p| acc + p)
+        } else {
+            self.precomp.vartime_subset_multiscalar_mul(updates)
+        }
+    }
+
+    // Returns the parent's new commitment, given its old commitment and the change in it.
+    //
+    // NOTE: A parent whose commitment becomes the identity (e.g., because all its children were
+    // deleted) becomes empty, just like it was before any of its children were set.
+    fn accumulate(old_parent: Node, delta: RistrettoPoint, stats: &mut HasherStats) -> Node {
+        let new_parent = match old_parent.comm() {
+            VerkleComm::Empty => {
+                let comp = delta.compress();
+                stats.compressions += 1;
+
+                // NOTE(Perf): In practice, we would pay this cost when decompressing the parent, but
+                // in this implementation the parents are VerkleComm::Empty by default, so that's why
+                // I'm adding it here, so as to get correct numbers.
+                comp.decompress();
+                stats.decompressions += 1;
+
+                if delta.is_identity() {
+                    VerkleComm::Empty
+                } else {
+                    VerkleComm::Internal(comp)
+                }
+            },
+
+            // NOTE(Perf): This actually loses us around 7 us: we do a decompress, we add the delta
+            // and then a compress. No way around it AFAICT. Batching this across all the parents on a
+            // level does not help much either: decompression needs an inverse square root per point,
+            // which cannot be batched, and dalek's batched compression outputs the compression of the
+            // *doubled* points (see the compression benchmark in benches/verkle.rs).
+            VerkleComm::Internal(small_gelem) => {
+                let result = small_gelem.decompress().expect("Expected a valid commitment in the parent node") + delta;
+                stats.decompressions += 1;
+
+                if result.is_identity() {
+                    VerkleComm::Empty
+                } else {
+                    stats.compressions += 1;
+                    VerkleComm::Internal(result.compress())
+                }
+            },
+
+            VerkleComm::Leaf(_) => unreachable!("Expected non-leaf parent node in VerkleHasher::hash_nodes"),
+        };
+
+        if Node::CACHES_SCALAR && matches!(new_parent, VerkleComm::Internal(_)) {
+            stats.sym_hashes += 1;
+        }
+        Node::from(new_parent)
+    }
+}
+
+// An opening of an internal node's commitment to its children, i.e., the claim that
+// comm = \sum_i scalars[i] bases[i], where scalars[i] is the ith child's scalar (see VerkleNode::scalar()).
+// Unlike a BatchProof, which the verifier hashes its way up to the root with, openings are checked
+// independently of each other, and so can be checked all at once (see VerkleHasher::verify_batch()).
+#[derive(Clone, Debug, PartialEq)]
+pub struct LinearProof {
+    pub comm: CompressedRistretto, // the identity, for empty nodes
+    pub scalars: Vec<Scalar>,      // one per child, so fewer than the arity for a node with fewer children
+}
+
+impl<Node> VerkleHasher<Node> {
+    // checks a single opening, via a multiexp of size (# of children)
+    pub fn verify_opening(&self, proof: &LinearProof) -> bool {
+        if proof.scalars.len() > self.arity {
+            return false;
+        }
+
+        let comm = self.precomp.vartime_subset_multiscalar_mul(proof.scalars.iter().copied().enumerate());
+        comm.compress() == proof.comm
+    }
+
+    // Checks many openings at once, via a random linear combination of them: for random r_j, the
+    // openings hold iff \sum_j r_j comm_j = \sum_i (\sum_j r_j scalars_j[i]) bases[i] (except with
+    // negligible probability), which takes a single multiexp of size (arity + # of openings), rather
+    // than one of size arity per opening.
+    pub fn verify_batch(&self, proofs: &[LinearProof]) -> bool {
+        if proofs.iter().any(|proof| proof.scalars.len() > self.arity) {
+            return false;
+        }
+
+        let comms = match proofs.iter().map(|proof| proof.comm.decompress()).collect::<Option<Vec<_>>>() {
+            Some(comms) => comms,
+            None => return false,
+        };
+
+        let mut rng = thread_rng();
+        let mut base_exps = vec![Scalar::zero(); self.arity];
+        let mut comm_exps = Vec::with_capacity(proofs.len());
+        for proof in proofs {
+            let r = Scalar::random(&mut rng);
+            for (exp, scalar) in base_exps.iter_mut().zip(&proof.scalars) {
+                *exp += r * scalar;
+            }
+            comm_exps.push(-r);
+        }
+
+        RistrettoPoint::vartime_multiscalar_mul(base_exps.iter().chain(&comm_exps), self.bases.iter().chain(&comms))
+            .is_identity()
+    }
+}
+
+impl<Node: VerkleNode> AbstractMerkle<String, Node, VerkleHasher<Node>> {
+    // returns openings of all the internal nodes on the paths from the specified leaves to the root,
+    // ordered by node index (i.e., the root first)
+    pub fn prove_openings(&self, positions: &[usize]) -> Vec<LinearProof> {
+        let (arity, shape) = (self.arity(), self.shape());
+        let nodes = positions
+            .iter()
+            .flat_map(|pos| self.get_leaf_idx(*pos).path_to_root(arity).skip(1).map(|node| node.index()))
+            .collect::<BTreeSet<usize>>();
+
+        nodes
+            .into_iter()
+            .map(|node| {
+                let comm = match self.subtree_root(&NodeIndex::new(node)).unwrap_or_default().comm() {
+                    VerkleComm::Internal(comm) => comm,
+                    _ => RistrettoPoint::identity().compress(),
+                };
+                let scalars = shape
+                    .children(node)
+                    .map(|child| self.subtree_root(&NodeIndex::new(child)).unwrap_or_default().scalar())
+                    .collect();
+
+                LinearProof { comm, scalars }
+            })
+            .collect()
+    }
 }
 
 pub fn hash_to_scalar<SmallGroupElem>(gelem: &SmallGroupElem) -> Scalar
@@ -79,79 +641,41 @@ where
 }
 
 // because we'll store CompressedRistretto but multiexp on RistrettoPoint's
-impl TreeHasherFunc<String, VerkleComm>
-    for VerkleHasher
+impl<Node: VerkleNode> TreeHasherFunc<String, Node>
+    for VerkleHasher<Node>
 {
     fn get_num_computations(&self) -> usize {
-        self.num_hashes
+        self.stats.group_exps
+    }
+
+    fn get_stats(&self) -> HasherStats {
+        self.stats
     }
 
     // fn is_incremental(&self) -> bool {
     //     true
     // }
 
-    fn hash_leaf_data(&mut self, _offset: usize, data: String) -> VerkleComm {
-        // TODO: allow choice of inner hash function here via template parameter
-        let mut hasher = Blake2b::<U64>::new();
+    fn hash_leaf_data(&mut self, offset: usize, data: String) -> Node {
+        self.stats.sym_hashes += 1;
 
-        hasher.update("leaf:".as_bytes());
-        hasher.update(data.as_bytes());
-
-        VerkleComm::Leaf(Scalar::from_hash(hasher))
+        self.hash_leaf_data_shared(offset, data)
     }
 
     fn hash_nodes(
         &mut self,
-        old_parent_comm: VerkleComm,
-        old_children: &mut Vec<VerkleComm>,
-        new_children: &Vec<(usize, VerkleComm)>,
-    ) -> VerkleComm {
+        old_parent_comm: Node,
+        old_children: &mut [Node],
+        new_children: &[(usize, Node)],
+    ) -> Node {
         assert_le!(new_children.len(), self.arity);
 
-        // TODO(Perf): Can trade-off double the storage for less than double the speed here
-        // We can have each node store its own scalar hash here, which right now we compute manually
-        // and discard.
+        // NOTE(Perf): Thin nodes recompute the scalar hash of each updated internal child here, while
+        // fat nodes store it (see FatVerkleComm).
 
+        let mut stats = HasherStats::default();
         let start = Instant::now();
-        let mut updates: Vec<(usize, Scalar)> = Vec::with_capacity(new_children.len());
-        for (offset, new_child_elem) in new_children {
-            match (old_children[*offset].borrow(), new_child_elem) {
-                (VerkleComm::Empty, VerkleComm::Empty) => {
-                    panic!("Old child and new child are both empty.");
-                },
-                (VerkleComm::Empty, VerkleComm::Internal(new_gelem)) => {
-                    let new_scalar = hash_to_scalar(new_gelem);
-
-                    updates.push((*offset, new_scalar));
-                },
-                (VerkleComm::Empty, VerkleComm::Leaf(new_scalar)) => {
-                    updates.push((*offset, *new_scalar));
-                },
-
-                (VerkleComm::Internal(_), VerkleComm::Empty) => {
-                    panic!("Old child was internal, but new one is empty.");
-                }
-                (VerkleComm::Internal(old_gelem), VerkleComm::Internal(new_gelem)) => {
-                    let old_scalar = hash_to_scalar(old_gelem);
-                    let new_scalar = hash_to_scalar(new_gelem);
-
-                    updates.push((*offset, new_scalar - old_scalar));
-                }
-                (VerkleComm::Internal(_), VerkleComm::Leaf(_)) => {
-                    panic!("Old child was internal, but new one is leaf.");
-                },
-
-                (VerkleComm::Leaf(_), VerkleComm::Empty) => {
-                    panic!("Old child was a leaf, but new one is empty.");
-                },
-                (VerkleComm::Leaf(_), VerkleComm::Internal(_)) => {
-                    panic!("Old child was a leaf, but new one is internal.");
-                },
-                (VerkleComm::Leaf(old_scalar), VerkleComm::Leaf(new_scalar)) => {
-                    updates.push((*offset, new_scalar - old_scalar));
-                }
-            }
-        }
+        let updates = Self::scalar_updates(old_children, new_children, &mut stats);
         self.avg_push_updates_time.add(start.elapsed().as_micros(), 1);
 
         assert_le!(updates.len(), self.arity);
@@ -159,15 +683,14 @@ impl TreeHasherFunc<String, VerkleComm>
         // NOTE(Perf): If the # of updates is small, just do normal exps!
         let num_exps = updates.len();
         let num_measurements = num_exps;
-        self.num_hashes += num_exps;
+        stats.group_exps += num_exps;
         let mut delta = RistrettoPoint::identity();
 
         let start_exp = Instant::now();
-        if num_exps <= 4 {
-            // NOTE: Run benches/multiexp.rs to figure out what cutoff to use for updates.len()
+        if num_exps <= self.config.multiexp_cutoff {
             for (index, exp) in updates {
                 let start = Instant::now();
-                delta += &self.base_tables[index] * &exp;
+                delta += self.single_exp(index, &exp);
                 self.avg_single_exp_time.add(start.elapsed().as_micros(), 1);
             }
         } else {
@@ -177,39 +700,55 @@ impl TreeHasherFunc<String, VerkleComm>
         }
         self.avg_exp_time.add(start_exp.elapsed().as_micros(), num_measurements);
 
+        let start_acc = Instant::now();
+        let new_parent = Self::accumulate(old_parent_comm, delta, &mut stats);
+        self.avg_accum_time.add(start_acc.elapsed().as_micros(), 1);
+        self.stats += stats;
 
-        let new_parent = match old_parent_comm {
-            VerkleComm::Empty => {
-                let start = Instant::now();
-                let comp = delta.compress();
+        // NOTE: e.g., deleting never-set leaves involves no exponentiations
+        if num_exps > 0 {
+            self.hash_nodes_histogram.add(num_exps, start.elapsed().as_micros());
+        }
 
-                // NOTE(Perf): In practice, we would pay this cost when decompressing the parent, but
-                // in this implementation the parents are VerkleComm::Empty by default, so that's why
-                // I'm adding it here, so as to get correct numbers.
-                comp.decompress();
+        new_parent
+    }
+}
+
+impl<Node: VerkleNode> ParallelTreeHasherFunc<String, Node>
+    for VerkleHasher<Node>
+{
+    fn hash_leaf_data_shared(&self, _offset: usize, data: String) -> Node {
+        // TODO: allow choice of inner hash function here via template parameter
+        let mut hasher = Blake2b::<U64>::new();
 
-                self.avg_accum_time.add(start.elapsed().as_micros(), 1);
+        hasher.update("leaf:".as_bytes());
+        hasher.update(data.as_bytes());
 
-                VerkleComm::Internal(comp)
-            },
+        Node::from(VerkleComm::Leaf(Scalar::from_hash(hasher)))
+    }
 
-            // NOTE(Perf): This actually loses us around 7 us: we do a decompress, we add the delta
-            // and then a compress. No way around it AFAICT.
-            VerkleComm::Internal(_small_gelem) => {
-                unreachable!("I believe this will never be reached because we start with an empty tree");
-                // let start = Instant::now();
-                // let result = small_gelem + delta;
-                // self.avg_accum_time.add(start.elapsed().as_micros(), 1);
-                //
-                // VerkleComm::Internal(result)
-            },
+    fn count_leaf_hashes(&mut self, num_leaves: usize) {
+        self.stats.sym_hashes += num_leaves;
+    }
 
-            VerkleComm::Leaf(_) => unreachable!("Expected non-leaf parent node in VerkleHasher::hash_nodes"),
-        };
+    fn hash_nodes_shared(
+        &self,
+        old_parent_comm: Node,
+        old_children: &mut [Node],
+        new_children: &[(usize, Node)],
+    ) -> (Node, HasherStats) {
+        assert_le!(new_children.len(), self.arity);
 
-        self.hash_nodes_histogram.add(num_exps, start.elapsed().as_micros());
+        let mut stats = HasherStats::default();
+        let updates = Self::scalar_updates(old_children, new_children, &mut stats);
+        stats.group_exps += updates.len();
+        let delta = self.exp_updates(updates);
 
-        new_parent
+        (Self::accumulate(old_parent_comm, delta, &mut stats), stats)
+    }
+
+    fn count_node_hashes(&mut self, stats: HasherStats) {
+        self.stats += stats;
     }
 }
 
@@ -233,4 +772,85 @@ pub fn new_verkle_from_leaves(
     let hasher = VerkleHasher::new(arity, bases);
 
     AbstractMerkle::with_num_leaves(arity, num_leaves, hasher)
-}
\ No newline at end of file
+}
+
+// returns a tree whose nodes cache their scalar hashes (see FatVerkleComm)
+pub fn new_fat_verkle_from_leaves(
+    arity: usize,
+    num_leaves: usize,
+    bases: Vec<RistrettoPoint>,
+) -> AbstractMerkle<String, FatVerkleComm, VerkleHasher<FatVerkleComm>>
+{
+    let hasher = VerkleHasher::new(arity, bases);
+
+    AbstractMerkle::with_num_leaves(arity, num_leaves, hasher)
+}
+
+// returns a tree whose nodes are kept in 'nodes' (e.g., on disk)
+pub fn new_verkle_with_node_store<Nodes>(
+    arity: usize,
+    num_leaves: usize,
+    bases: Vec<RistrettoPoint>,
+    nodes: Nodes,
+) -> AbstractMerkle<String, VerkleComm, VerkleHasher, NoLeafStorage, Nodes>
+where
+    Nodes: NodeStore<VerkleComm>,
+{
+    let hasher = VerkleHasher::new(arity, bases);
+
+    AbstractMerkle::with_node_store(arity, num_leaves, hasher, nodes)
+}
+
+// returns a sparse tree with 2^256 leaves
+pub fn new_sparse_verkle(
+    arity: usize,
+    bases: Vec<RistrettoPoint>,
+) -> SparseMerkle<String, VerkleComm, VerkleHasher>
+{
+    let hasher = VerkleHasher::new(arity, bases);
+
+    SparseMerkle::new(arity, hasher)
+}
+
+pub fn new_sparse_verkle_from_height(
+    arity: usize,
+    height: usize,
+    bases: Vec<RistrettoPoint>,
+) -> SparseMerkle<String, VerkleComm, VerkleHasher>
+{
+    let hasher = VerkleHasher::new(arity, bases);
+
+    SparseMerkle::with_height(arity, height, hasher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random_updates;
+
+    #[test]
+    fn linear_proofs() {
+        // arity 3 with 10 leaves has a parent with fewer children than the arity
+        for (arity, num_leaves) in [(3, 10), (4, 64)] {
+            let mut verkle = new_verkle_from_leaves(arity, num_leaves, deterministic_bases(arity));
+            verkle.update_leaves(random_updates(&mut thread_rng(), num_leaves, num_leaves / 2));
+
+            let proofs = verkle.prove_openings(&[0, num_leaves / 2, num_leaves - 1]);
+            assert_eq!(VerkleComm::Internal(proofs[0].comm), verkle.root_hash());
+            assert!(proofs.iter().all(|proof| verkle.hasher.verify_opening(proof)));
+            assert!(verkle.hasher.verify_batch(&proofs));
+            assert!(verkle.hasher.verify_batch(&[]));
+
+            let mut wrong = proofs.clone();
+            wrong[1].scalars[0] += Scalar::one();
+            assert!(!verkle.hasher.verify_opening(&wrong[1]));
+            assert!(!verkle.hasher.verify_batch(&wrong));
+
+            // the root's commitment does not open to another node's children, even next to valid openings
+            let mut swapped = proofs.clone();
+            swapped.swap(0, 1);
+            swapped[0].comm = proofs[0].comm;
+            assert!(!verkle.hasher.verify_batch(&swapped));
+        }
+    }
+}
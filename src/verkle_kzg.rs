@@ -0,0 +1,491 @@
+use std::collections::BTreeSet;
+use std::fmt::{Debug, Formatter};
+use std::time::Instant;
+
+use ark_bls12_381::{Bls12_381, Fr, G1Affine, G1Projective, G2Affine, G2Projective};
+use ark_ec::pairing::Pairing;
+use ark_ec::{AffineRepr, CurveGroup, Group, VariableBaseMSM};
+use ark_ff::{Field, One, PrimeField, UniformRand, Zero};
+use ark_serialize::{CanonicalDeserialize, CanonicalSerialize};
+use blake2::{Blake2b, Digest};
+use digest::consts::U64;
+use more_asserts::assert_le;
+use rand::Rng;
+
+use crate::hashing_traits::{CanonicalBytes, HasherStats, ParallelTreeHasherFunc, TreeHasherFunc};
+use crate::merkle_abstract::AbstractMerkle;
+use crate::node_index::NodeIndex;
+use crate::{HistogramAverages, RunningAverage};
+
+// A node in a Verkle tree built from KZG commitments over BLS12-381: an internal node stores a KZG
+// commitment to the (hashes of the) children, while a leaf stores its hash as a field element.
+#[derive(Clone, Default, PartialEq)]
+pub enum KzgComm {
+    Internal(G1Affine),
+    Leaf(Fr),
+    #[default]
+    Empty,
+}
+
+impl Debug for KzgComm {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KzgComm::Internal(c) => write!(f, "{}", hex::encode(compressed_bytes(c))),
+            KzgComm::Leaf(a) => write!(f, "{}", hex::encode(compressed_bytes(a))),
+            KzgComm::Empty => write!(f, "empty node"),
+        }
+    }
+}
+
+impl KzgComm {
+    // returns the field element that the node's parent commits to it by
+    fn to_field(&self) -> Fr {
+        match self {
+            KzgComm::Empty => Fr::zero(),
+            KzgComm::Internal(c) => hash_to_field(c),
+            KzgComm::Leaf(a) => *a,
+        }
+    }
+}
+
+fn compressed_bytes<T: CanonicalSerialize>(elem: &T) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(elem.compressed_size());
+    elem.serialize_compressed(&mut bytes).unwrap();
+
+    bytes
+}
+
+// serialized as a 1-byte tag (0 for empty nodes, 1 for internal nodes, 2 for leaves), followed by the
+// 48-byte compressed point or the 32-byte field element, respectively
+impl CanonicalBytes for KzgComm {
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            KzgComm::Empty => vec![0u8],
+            KzgComm::Internal(c) => [vec![1u8], compressed_bytes(c)].concat(),
+            KzgComm::Leaf(a) => [vec![2u8], compressed_bytes(a)].concat(),
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        // NOTE: deserialize_compressed() checks that points are in the prime-order subgroup and that
+        // field elements are canonical
+        match bytes.split_first()? {
+            (0, []) => Some(KzgComm::Empty),
+            (1, rest) if rest.len() == G1Affine::default().compressed_size() => {
+                Some(KzgComm::Internal(G1Affine::deserialize_compressed(rest).ok()?))
+            }
+            (2, rest) if rest.len() == Fr::default().compressed_size() => {
+                Some(KzgComm::Leaf(Fr::deserialize_compressed(rest).ok()?))
+            }
+            _ => None,
+        }
+    }
+}
+
+// Returns the bases used to commit to the children of a node: the commitment to children hashes
+// (f_0, ..., f_{k-1}) is to the polynomial f with f(i) = f_i, so it equals \sum_i f_i [L_i(tau)],
+// where L_i is the ith Lagrange polynomial for the points 0, 1, ..., k-1.
+//
+// NOTE: This picks the trapdoor tau itself, which is fine for benchmarking, but a real deployment
+// would use the output of a trusted setup ceremony.
+pub fn lagrange_bases<R: Rng>(rng: &mut R, arity: usize) -> Vec<G1Affine> {
+    lagrange_bases_at(Fr::rand(rng), arity)
+}
+
+// The seed that saved trees (see main.rs's build, update, prove and verify commands) derive their
+// bases from via seeded_lagrange_bases()
+pub const DEFAULT_SRS_SEED: &[u8] = b"merkle-race verkle_kzg srs";
+
+// Same as lagrange_bases(), but derives tau by hashing 'seed' to a field element, so that every run
+// (and every machine) uses the same bases, and proofs made by one process verify in another.
+//
+// NOTE: Anyone who knows the seed knows tau, and so can forge proofs, so these bases are only good for
+// benchmarking and for cross-checking implementations.
+pub fn seeded_lagrange_bases(seed: &[u8], arity: usize) -> Vec<G1Affine> {
+    lagrange_bases_at(seeded_tau(seed), arity)
+}
+
+// Returns [tau]_2 for the tau that seeded_lagrange_bases() derives from 'seed', which is what openings
+// are verified against (see verify_opening()).
+pub fn seeded_verifier_key(seed: &[u8]) -> G2Affine {
+    verifier_key(seeded_tau(seed))
+}
+
+fn seeded_tau(seed: &[u8]) -> Fr {
+    let mut hasher = Blake2b::<U64>::new();
+    hasher.update(seed);
+
+    Fr::from_le_bytes_mod_order(&hasher.finalize())
+}
+
+// Returns the bases for a known trapdoor 'tau', so that openings can be verified (see verifier_key()).
+pub fn lagrange_bases_at(tau: Fr, arity: usize) -> Vec<G1Affine> {
+    let points = (0..arity as u64).map(Fr::from).collect::<Vec<Fr>>();
+
+    let bases = (0..arity)
+        .map(|i| {
+            let mut num = Fr::one();
+            let mut denom = Fr::one();
+            for j in (0..arity).filter(|j| *j != i) {
+                num *= tau - points[j];
+                denom *= points[i] - points[j];
+            }
+
+            G1Projective::generator() * (num * denom.inverse().unwrap())
+        })
+        .collect::<Vec<G1Projective>>();
+
+    G1Projective::normalize_batch(&bases)
+}
+
+// Returns [tau]_2, which is what openings of commitments under lagrange_bases_at(tau, ...) are verified
+// against (see verify_opening()).
+pub fn verifier_key(tau: Fr) -> G2Affine {
+    (G2Projective::generator() * tau).into_affine()
+}
+
+// Hashes a child commitment to a field element, so it can be committed to by its parent.
+fn hash_to_field(comm: &G1Affine) -> Fr {
+    let mut hasher = Blake2b::<U64>::new();
+    hasher.update(compressed_bytes(comm));
+
+    Fr::from_le_bytes_mod_order(&hasher.finalize())
+}
+
+// An opening of an internal node's commitment at one of its children, i.e., a KZG proof that the
+// committed polynomial f has f(child) = value, where value is the child's field element. Unlike a Merkle
+// proof, it has the same size whatever the arity.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KzgOpening {
+    pub comm: G1Affine, // the identity, for empty nodes
+    pub child: usize,
+    pub value: Fr,
+    pub proof: G1Affine,
+}
+
+// Checks an opening against [tau]_2 (see verifier_key()), i.e., that f(X) - value is divisible by
+// X - child, via e(comm - [value]_1, [1]_2) = e(proof, [tau - child]_2).
+pub fn verify_opening(tau_g2: &G2Affine, opening: &KzgOpening) -> bool {
+    let lhs = opening.comm.into_group() - G1Projective::generator() * opening.value;
+    let rhs = tau_g2.into_group() - G2Projective::generator() * Fr::from(opening.child as u64);
+
+    Bls12_381::pairing(lhs, G2Projective::generator()) == Bls12_381::pairing(opening.proof, rhs)
+}
+
+pub struct KzgVerkleHasher {
+    stats: HasherStats,
+    arity: usize,
+    bases: Vec<G1Affine>,
+    derivs: Vec<Fr>, // A'(j) for A(X) = \prod_i (X - i), where i and j are in [0, arity)
+    pub hash_nodes_histogram: HistogramAverages,
+    pub avg_exp_time: RunningAverage,
+    pub avg_accum_time: RunningAverage,
+}
+
+impl KzgVerkleHasher {
+    fn new(arity: usize, bases: Vec<G1Affine>) -> Self {
+        assert_eq!(bases.len(), arity);
+
+        let derivs = (0..arity)
+            .map(|j| (0..arity).filter(|i| *i != j).map(|i| Fr::from(j as u64) - Fr::from(i as u64)).product())
+            .collect();
+
+        KzgVerkleHasher {
+            stats: HasherStats::default(),
+            arity,
+            bases,
+            derivs,
+            hash_nodes_histogram: HistogramAverages::new(arity),
+            avg_exp_time: RunningAverage::new(),
+            avg_accum_time: RunningAverage::new(),
+        }
+    }
+
+    // Returns, for each updated child, its offset and the field element its base must be multiplied by
+    // in order to update the parent's commitment.
+    fn field_updates(
+        old_children: &[KzgComm],
+        new_children: &[(usize, KzgComm)],
+        stats: &mut HasherStats,
+    ) -> Vec<(usize, Fr)> {
+        let mut to_field = |comm: &KzgComm| {
+            if let KzgComm::Internal(_) = comm {
+                stats.sym_hashes += 1;
+            }
+            comm.to_field()
+        };
+
+        let updates = new_children
+            .iter()
+            .map(|(offset, new_child)| (*offset, to_field(new_child) - to_field(&old_children[*offset])))
+            .filter(|(_, delta)| !delta.is_zero())
+            .collect::<Vec<(usize, Fr)>>();
+        stats.field_ops += new_children.len();
+
+        updates
+    }
+
+    // Returns the change in the parent's commitment: a multi-exponentiation of the updated children's
+    // bases by the changes in their field elements.
+    fn exp_updates(&self, updates: Vec<(usize, Fr)>) -> G1Projective {
+        let (bases, exps): (Vec<G1Affine>, Vec<Fr>) = updates
+            .into_iter()
+            .map(|(offset, exp)| (self.bases[offset], exp))
+            .unzip();
+
+        G1Projective::msm(&bases, &exps).unwrap()
+    }
+
+    // Returns a proof that the polynomial f committed to by a node with the specified children values has
+    // f(child) = values[child], which is a commitment to the quotient q(X) = (f(X) - f(child)) / (X - child).
+    // Like f, q is committed to via its values on 0, 1, ..., k-1, which are (f_j - f_child) / (j - child)
+    // for j != child, and q(child) = f'(child) = -A'(child) \sum_{j != child} q(j) / A'(j).
+    //
+    // NOTE: A node with fewer than 'arity' children commits to zero for the missing ones.
+    fn open(&self, values: &[Fr], child: usize) -> G1Affine {
+        assert_le!(values.len(), self.arity);
+        let value = |j: usize| values.get(j).copied().unwrap_or_else(Fr::zero);
+
+        let mut quotient = (0..self.arity)
+            .map(|j| match j == child {
+                true => Fr::zero(),
+                false => (value(j) - value(child)) * (Fr::from(j as u64) - Fr::from(child as u64)).inverse().unwrap(),
+            })
+            .collect::<Vec<Fr>>();
+        quotient[child] = -self.derivs[child]
+            * quotient
+                .iter()
+                .zip(&self.derivs)
+                .map(|(q, deriv)| *q * deriv.inverse().unwrap())
+                .sum::<Fr>();
+
+        G1Projective::msm(&self.bases, &quotient).unwrap().into_affine()
+    }
+
+    // Returns the parent's new commitment, given its old commitment and the change in it.
+    //
+    // NOTE: As in VerkleHasher, a parent whose commitment becomes the identity becomes empty.
+    fn accumulate(old_parent_comm: KzgComm, delta: G1Projective, stats: &mut HasherStats) -> KzgComm {
+        let new_parent = match old_parent_comm {
+            KzgComm::Empty => delta,
+            KzgComm::Internal(c) => delta + c,
+            KzgComm::Leaf(_) => unreachable!("Expected non-leaf parent node in KzgVerkleHasher::hash_nodes"),
+        };
+
+        if new_parent.is_zero() {
+            KzgComm::Empty
+        } else {
+            stats.compressions += 1;
+            KzgComm::Internal(new_parent.into_affine())
+        }
+    }
+}
+
+impl TreeHasherFunc<String, KzgComm> for KzgVerkleHasher {
+    fn get_num_computations(&self) -> usize {
+        self.stats.group_exps
+    }
+
+    fn get_stats(&self) -> HasherStats {
+        self.stats
+    }
+
+    fn hash_leaf_data(&mut self, offset: usize, data: String) -> KzgComm {
+        self.stats.sym_hashes += 1;
+
+        self.hash_leaf_data_shared(offset, data)
+    }
+
+    fn hash_nodes(
+        &mut self,
+        old_parent_comm: KzgComm,
+        old_children: &mut [KzgComm],
+        new_children: &[(usize, KzgComm)],
+    ) -> KzgComm {
+        assert_le!(new_children.len(), self.arity);
+
+        let mut stats = HasherStats::default();
+        let start = Instant::now();
+        let updates = Self::field_updates(old_children, new_children, &mut stats);
+        let num_exps = updates.len();
+        stats.group_exps += num_exps;
+
+        let start_exp = Instant::now();
+        let delta = self.exp_updates(updates);
+        self.avg_exp_time.add(start_exp.elapsed().as_micros(), num_exps);
+
+        let start_acc = Instant::now();
+        let new_parent = Self::accumulate(old_parent_comm, delta, &mut stats);
+        self.avg_accum_time.add(start_acc.elapsed().as_micros(), 1);
+        self.stats += stats;
+
+        if num_exps > 0 {
+            self.hash_nodes_histogram.add(num_exps, start.elapsed().as_micros());
+        }
+
+        new_parent
+    }
+}
+
+impl ParallelTreeHasherFunc<String, KzgComm> for KzgVerkleHasher {
+    fn hash_leaf_data_shared(&self, _offset: usize, data: String) -> KzgComm {
+        let mut hasher = Blake2b::<U64>::new();
+
+        hasher.update("leaf:".as_bytes());
+        hasher.update(data.as_bytes());
+
+        KzgComm::Leaf(Fr::from_le_bytes_mod_order(&hasher.finalize()))
+    }
+
+    fn count_leaf_hashes(&mut self, num_leaves: usize) {
+        self.stats.sym_hashes += num_leaves;
+    }
+
+    fn hash_nodes_shared(
+        &self,
+        old_parent_comm: KzgComm,
+        old_children: &mut [KzgComm],
+        new_children: &[(usize, KzgComm)],
+    ) -> (KzgComm, HasherStats) {
+        assert_le!(new_children.len(), self.arity);
+
+        let mut stats = HasherStats::default();
+        let updates = Self::field_updates(old_children, new_children, &mut stats);
+        stats.group_exps += updates.len();
+        let delta = self.exp_updates(updates);
+
+        (Self::accumulate(old_parent_comm, delta, &mut stats), stats)
+    }
+
+    fn count_node_hashes(&mut self, stats: HasherStats) {
+        self.stats += stats;
+    }
+}
+
+impl AbstractMerkle<String, KzgComm, KzgVerkleHasher> {
+    // returns openings of all the internal nodes on the paths from the specified leaves to the root, each
+    // at its child on the path, ordered by node index (i.e., the root first)
+    pub fn prove_openings(&self, positions: &[usize]) -> Vec<KzgOpening> {
+        let mut paths = BTreeSet::new();
+        for pos in positions {
+            let mut node = self.get_leaf_idx(*pos);
+            while !node.is_root() {
+                paths.insert((self.parent_node(&node).0, self.child_offset(&node)));
+                node = self.parent_node(&node);
+            }
+        }
+
+        paths
+            .into_iter()
+            .map(|(node, child)| {
+                let node = NodeIndex(node);
+                let comm = match self.get_node_hash(&node).unwrap() {
+                    KzgComm::Internal(comm) => comm,
+                    _ => G1Affine::identity(),
+                };
+                let values = self
+                    .children(&node)
+                    .map(|child| self.get_node_hash(&child).unwrap().to_field())
+                    .collect::<Vec<Fr>>();
+
+                KzgOpening { comm, child, value: values[child], proof: self.hasher.open(&values, child) }
+            })
+            .collect()
+    }
+}
+
+pub fn new_kzg_verkle_from_height(
+    arity: usize,
+    height: usize,
+    bases: Vec<G1Affine>,
+) -> AbstractMerkle<String, KzgComm, KzgVerkleHasher> {
+    let hasher = KzgVerkleHasher::new(arity, bases);
+
+    AbstractMerkle::new(arity, height, hasher)
+}
+
+pub fn new_kzg_verkle_from_leaves(
+    arity: usize,
+    num_leaves: usize,
+    bases: Vec<G1Affine>,
+) -> AbstractMerkle<String, KzgComm, KzgVerkleHasher> {
+    let hasher = KzgVerkleHasher::new(arity, bases);
+
+    AbstractMerkle::with_num_leaves(arity, num_leaves, hasher)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::random_leaf_positions;
+    use rand::thread_rng;
+
+    #[test]
+    fn incremental_matches_from_scratch() {
+        for (arity, num_leaves) in [(2, 16), (3, 10), (16, 40)] {
+            let bases = lagrange_bases(&mut thread_rng(), arity);
+            let mut merkle = new_kzg_verkle_from_leaves(arity, num_leaves, bases.clone());
+            let mut other = new_kzg_verkle_from_leaves(arity, num_leaves, bases);
+
+            let leaves = (0..num_leaves).map(|pos| format!("leaf {}", pos)).collect::<Vec<_>>();
+            for round in random_leaf_positions(&mut thread_rng(), num_leaves, num_leaves).collect::<Vec<_>>().chunks(3) {
+                merkle.update_leaves(round.iter().map(|pos| (*pos, leaves[*pos].clone())).collect());
+            }
+            other.build_from_leaves(leaves);
+
+            assert_eq!(merkle.root_hash(), other.root_hash());
+
+            let root = merkle.root_hash();
+            assert_eq!(KzgComm::from_bytes(&root.to_bytes()), Some(root));
+        }
+    }
+
+    #[test]
+    fn openings() {
+        let tau = Fr::rand(&mut thread_rng());
+        let tau_g2 = verifier_key(tau);
+
+        // NOTE: (3, 10) has a parent with fewer than 'arity' children
+        for (arity, num_leaves) in [(2, 16), (3, 10), (16, 40)] {
+            let mut merkle = new_kzg_verkle_from_leaves(arity, num_leaves, lagrange_bases_at(tau, arity));
+            merkle.update_leaves((0..num_leaves).step_by(3).map(|pos| (pos, format!("leaf {}", pos))).collect());
+
+            let openings = merkle.prove_openings(&[0, 1, num_leaves - 1]);
+            assert_eq!(KzgComm::Internal(openings[0].comm), merkle.root_hash());
+            assert!(openings.iter().all(|opening| verify_opening(&tau_g2, opening)));
+
+            // a wrong value, a wrong child or a key for another tau is rejected
+            let mut wrong = openings[0].clone();
+            wrong.value += Fr::one();
+            assert!(!verify_opening(&tau_g2, &wrong));
+            let mut wrong = openings[0].clone();
+            wrong.child = (wrong.child + 1) % arity;
+            assert!(!verify_opening(&tau_g2, &wrong));
+            assert!(!verify_opening(&verifier_key(tau + Fr::one()), &openings[0]));
+        }
+    }
+
+    #[test]
+    fn seeded_bases() {
+        let bases = seeded_lagrange_bases(DEFAULT_SRS_SEED, 4);
+        assert_eq!(bases, seeded_lagrange_bases(DEFAULT_SRS_SEED, 4));
+        assert_ne!(bases, seeded_lagrange_bases(b"another seed", 4));
+
+        // a proof made by one tree verifies against another one with bases from the same seed, as if
+        // they were in different processes
+        let updates = vec![(3, "three".to_owned()), (17, "seventeen".to_owned())];
+        let mut merkle = new_kzg_verkle_from_leaves(4, 20, bases);
+        merkle.update_leaves(updates.clone());
+        let (root, proof) = (merkle.root_hash(), merkle.prove_batch(&[3, 17]));
+
+        let mut other = new_kzg_verkle_from_leaves(4, 20, seeded_lagrange_bases(DEFAULT_SRS_SEED, 4));
+        assert!(other.verify_batch(&root, &updates, &proof));
+        let mut other = new_kzg_verkle_from_leaves(4, 20, seeded_lagrange_bases(b"another seed", 4));
+        assert!(!other.verify_batch(&root, &updates, &proof));
+
+        // and so does an opening, against the verifier key from the same seed
+        let opening = &merkle.prove_openings(&[3])[0];
+        assert!(verify_opening(&seeded_verifier_key(DEFAULT_SRS_SEED), opening));
+        assert!(!verify_opening(&seeded_verifier_key(b"another seed"), opening));
+    }
+}
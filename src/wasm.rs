@@ -0,0 +1,132 @@
+use wasm_bindgen::prelude::*;
+
+use crate::racer::{MerkleRacer, RacerRegistry};
+use crate::seeded_rng;
+use crate::verkle_kzg::{new_kzg_verkle_from_leaves, seeded_lagrange_bases, DEFAULT_SRS_SEED};
+
+// Bindings for building small trees and verifying their proofs from JavaScript (e.g., to demo how long
+// verifying a Verkle proof takes in a browser). Trees are picked by the names the benchmark uses (see
+// RacerRegistry::with_default_racers()), and roots and proofs are passed around as byte arrays, in
+// their canonical serialization (see MerkleRacer).
+
+// NOTE: Trees live in the browser's memory, and every node is allocated up front
+const MAX_LEAVES: usize = 1 << 20;
+
+#[wasm_bindgen]
+pub struct WasmTree {
+    tree: Box<dyn MerkleRacer>,
+}
+
+#[wasm_bindgen]
+impl WasmTree {
+    // returns an empty tree of the specified type and shape
+    #[wasm_bindgen(constructor)]
+    pub fn new(tree_type: &str, arity: usize, num_leaves: usize) -> Result<WasmTree, JsError> {
+        if arity < 2 || !(2..=MAX_LEAVES).contains(&num_leaves) {
+            return Err(JsError::new(&format!("Trees must have arity >= 2 and between 2 and {} leaves", MAX_LEAVES)));
+        }
+
+        // NOTE: KZG Verkle trees derive their bases from the default seed (as `merkle-race tool` does), so
+        // that any tree of the same shape can verify their proofs
+        let mut registry = RacerRegistry::with_default_racers();
+        registry.register("verkle_kzg", |arity, num_leaves, _| {
+            Box::new(new_kzg_verkle_from_leaves(arity, num_leaves, seeded_lagrange_bases(DEFAULT_SRS_SEED, arity)))
+        });
+
+        registry
+            .create(tree_type, arity, num_leaves, &mut seeded_rng(Some(0)))
+            .map(|tree| WasmTree { tree })
+            .ok_or_else(|| JsError::new(&format!("Unknown type of Merkle tree: {}", tree_type)))
+    }
+
+    #[wasm_bindgen(getter, js_name = numLeaves)]
+    pub fn num_leaves(&self) -> usize {
+        self.tree.num_leaves()
+    }
+
+    // sets the leaves at the specified positions to the specified data, where 'positions[i]' is the
+    // position of 'leaves[i]'
+    #[wasm_bindgen(js_name = setLeaves)]
+    pub fn set_leaves(&mut self, positions: &[u32], leaves: Vec<String>) -> Result<(), JsError> {
+        let updates = leaf_updates(positions, leaves, self.tree.num_leaves())?;
+
+        self.tree.update_batch(updates);
+        Ok(())
+    }
+
+    // returns the serialized root hash
+    #[wasm_bindgen(js_name = rootHash)]
+    pub fn root_hash(&self) -> Vec<u8> {
+        self.tree.root()
+    }
+
+    // returns a serialized proof for the leaves at the specified positions
+    pub fn prove(&self, positions: &[u32]) -> Result<Vec<u8>, JsError> {
+        let positions = positions.iter().map(|pos| *pos as usize).collect::<Vec<_>>();
+        if positions.iter().any(|pos| *pos >= self.tree.num_leaves()) {
+            return Err(JsError::new("Leaf position is out of range"));
+        }
+
+        Ok(self.tree.prove(&positions))
+    }
+
+    // verifies a proof returned by prove() for the specified leaves (as in set_leaves()) against a root
+    // returned by root_hash(), which need not be this tree's
+    pub fn verify(&mut self, root: &[u8], positions: &[u32], leaves: Vec<String>, proof: &[u8]) -> Result<bool, JsError> {
+        let leaves = leaf_updates(positions, leaves, self.tree.num_leaves())?;
+
+        Ok(self.tree.verify(root, &leaves, proof))
+    }
+}
+
+// Verifies a proof (as returned by WasmTree::prove()) for the specified leaves against a root, given only
+// the type and shape of the tree it came from.
+//
+// NOTE: This allocates an empty tree of that shape, so verifying many proofs via WasmTree::verify() is faster.
+#[wasm_bindgen(js_name = verifyProof)]
+pub fn verify_proof(
+    tree_type: &str,
+    arity: usize,
+    num_leaves: usize,
+    root: &[u8],
+    positions: &[u32],
+    leaves: Vec<String>,
+    proof: &[u8],
+) -> Result<bool, JsError> {
+    WasmTree::new(tree_type, arity, num_leaves)?.verify(root, positions, leaves, proof)
+}
+
+fn leaf_updates(positions: &[u32], leaves: Vec<String>, num_leaves: usize) -> Result<Vec<(usize, String)>, JsError> {
+    if positions.len() != leaves.len() {
+        return Err(JsError::new("Expected as many leaf positions as leaves"));
+    }
+    if positions.iter().any(|pos| *pos as usize >= num_leaves) {
+        return Err(JsError::new("Leaf position is out of range"));
+    }
+
+    Ok(positions.iter().map(|pos| *pos as usize).zip(leaves).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // NOTE: Errors cannot be tested natively, since creating a JsError calls into JavaScript
+    #[test]
+    fn prove_and_verify() {
+        for tree_type in ["merkle_sha3", "merkle++", "verkle", "verkle_kzg"] {
+            let mut tree = WasmTree::new(tree_type, 3, 20).unwrap_or_else(|_| panic!("Could not create {}", tree_type));
+            let leaves = vec!["four".to_owned(), "seventeen".to_owned()];
+            assert!(tree.set_leaves(&[4, 17], leaves.clone()).is_ok());
+
+            let root = tree.root_hash();
+            let proof = tree.prove(&[4, 17]).unwrap_or_else(|_| panic!("Could not prove"));
+            let valid = |positions: &[u32], leaves: Vec<String>| {
+                verify_proof(tree_type, 3, 20, &root, positions, leaves, &proof).unwrap_or_else(|_| panic!("Could not verify"))
+            };
+
+            assert!(valid(&[4, 17], leaves), "{} proof did not verify", tree_type);
+            assert!(!valid(&[4, 17], vec!["four".to_owned(), "eighteen".to_owned()]), "{} accepted a wrong leaf", tree_type);
+        }
+    }
+}
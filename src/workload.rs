@@ -0,0 +1,343 @@
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::ops::Range;
+use std::str::FromStr;
+
+use itertools::Itertools;
+use more_asserts::assert_le;
+use rand::distributions::Alphanumeric;
+use rand::prelude::IteratorRandom;
+use rand::Rng;
+use rand_distr::{Distribution, Zipf};
+
+// The default parameters of the skewed distributions (see LeafDistribution)
+pub const ZIPF_EXPONENT: f64 = 1.0;
+pub const HOTSET_FRACTION: f64 = 0.1;
+pub const HOTSET_PROBABILITY: f64 = 0.9;
+
+// How the leaves to update are picked
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LeafDistribution {
+    Uniform,
+    // the ith most popular leaf is updated with probability proportional to 1/i^exponent
+    Zipfian { exponent: f64 },
+    // 'hot_probability' of the updates go to a hot set of 'hot_fraction' of the leaves, and the rest go
+    // to the other (cold) leaves
+    Hotset { hot_fraction: f64, hot_probability: f64 },
+}
+
+// Parsed from "uniform", "zipfian[:EXPONENT]" or "hotset[:HOT_FRACTION:HOT_PROBABILITY]"
+impl FromStr for LeafDistribution {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_param = |param: &str| {
+            param
+                .parse::<f64>()
+                .map_err(|e| format!("Invalid parameter '{}' for leaf distribution '{}': {}", param, s, e))
+        };
+
+        let distribution = match s.split(':').collect::<Vec<&str>>().as_slice() {
+            ["uniform"] => LeafDistribution::Uniform,
+            ["zipfian"] => LeafDistribution::Zipfian { exponent: ZIPF_EXPONENT },
+            ["zipfian", exponent] => LeafDistribution::Zipfian { exponent: parse_param(exponent)? },
+            ["hotset"] => LeafDistribution::Hotset {
+                hot_fraction: HOTSET_FRACTION,
+                hot_probability: HOTSET_PROBABILITY,
+            },
+            ["hotset", fraction, probability] => LeafDistribution::Hotset {
+                hot_fraction: parse_param(fraction)?,
+                hot_probability: parse_param(probability)?,
+            },
+            _ => {
+                return Err(format!(
+                    "Unknown leaf distribution '{}' (expected uniform, zipfian[:EXPONENT] or \
+                     hotset[:HOT_FRACTION:HOT_PROBABILITY])",
+                    s
+                ))
+            }
+        };
+
+        match distribution {
+            LeafDistribution::Zipfian { exponent } if exponent <= 0.0 => {
+                Err(format!("The Zipfian exponent must be positive, got {}", exponent))
+            }
+            LeafDistribution::Hotset { hot_fraction, hot_probability }
+                if !(0.0..=1.0).contains(&hot_fraction) || !(0.0..=1.0).contains(&hot_probability) =>
+            {
+                Err(format!("The hot set's fraction and probability must be in [0, 1], got {}", s))
+            }
+            _ => Ok(distribution),
+        }
+    }
+}
+
+impl Display for LeafDistribution {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LeafDistribution::Uniform => write!(f, "uniform"),
+            LeafDistribution::Zipfian { exponent } => write!(f, "zipfian:{}", exponent),
+            LeafDistribution::Hotset { hot_fraction, hot_probability } => {
+                write!(f, "hotset:{}:{}", hot_fraction, hot_probability)
+            }
+        }
+    }
+}
+
+// Returns the position of the leaf with the specified popularity rank (in [0, num_leaves)).
+//
+// NOTE: Ranks are scattered across the tree (as hashed account addresses would be) rather than mapped
+// to consecutive leaves, which would make hot leaves artificially share their subtrees. Multiplying by
+// a prime larger than the # of leaves is a bijection on [0, num_leaves).
+//
+// NOTE: The prime (near 2^64 over the golden ratio, as in Fibonacci hashing) must not be close to a
+// multiple of a power of two (e.g., a Mersenne prime is -1 mod 2^k), or else the hot leaves of a tree
+// with 2^k leaves would end up next to each other anyway.
+fn rank_to_position(rank: usize, num_leaves: usize) -> usize {
+    const PRIME: u128 = 0x9E37_79B9_7F4A_7C55;
+
+    ((rank as u128 * PRIME) % num_leaves as u128) as usize
+}
+
+// The # of ranks sample_distinct() draws per position it returns, before it gives up on rejection
+// sampling
+const MAX_SAMPLES_PER_POSITION: usize = 16;
+
+// Samples popularity ranks (with replacement) until 'num_pos' of the corresponding positions are
+// distinct, since a batch updates each leaf at most once, and returns these positions sorted. Every
+// sampled rank must be in 'reachable', which must have at least 'num_pos' ranks.
+//
+// NOTE: Rejection sampling gets slow as 'num_pos' approaches the # of reachable leaves, since the
+// coldest leaves are rarely sampled. So after MAX_SAMPLES_PER_POSITION * 'num_pos' samples, the missing
+// positions are picked uniformly (without replacement) from the reachable leaves not picked yet, which
+// only skews the coldest few of them.
+fn sample_distinct<R: Rng>(
+    rng: &mut R,
+    max_num_leaves: usize,
+    num_pos: usize,
+    reachable: Range<usize>,
+    mut sample_rank: impl FnMut(&mut R) -> usize,
+) -> Vec<usize> {
+    assert_le!(num_pos, reachable.len());
+
+    let mut positions = HashSet::with_capacity(num_pos);
+    for _ in 0..MAX_SAMPLES_PER_POSITION * num_pos {
+        if positions.len() == num_pos {
+            break;
+        }
+        positions.insert(rank_to_position(sample_rank(rng), max_num_leaves));
+    }
+
+    let num_missing = num_pos - positions.len();
+    if num_missing > 0 {
+        let missing = reachable
+            .map(|rank| rank_to_position(rank, max_num_leaves))
+            .filter(|pos| !positions.contains(pos))
+            .choose_multiple(rng, num_missing);
+        positions.extend(missing);
+    }
+
+    positions.into_iter().sorted().collect()
+}
+
+// Generates the positions of the leaves updated by successive batches, for a tree with a fixed # of
+// leaves.
+pub struct Workload {
+    num_leaves: usize,
+    distribution: LeafDistribution,
+    zipf: Option<Zipf<f64>>, // only for Zipfian distributions, created once since it precomputes some constants
+}
+
+impl Workload {
+    pub fn new(num_leaves: usize, distribution: LeafDistribution) -> Self {
+        let zipf = match distribution {
+            LeafDistribution::Zipfian { exponent } => Some(Zipf::new(num_leaves as u64, exponent).unwrap()),
+            _ => None,
+        };
+
+        Workload { num_leaves, distribution, zipf }
+    }
+
+    pub fn num_leaves(&self) -> usize {
+        self.num_leaves
+    }
+
+    pub fn distribution(&self) -> LeafDistribution {
+        self.distribution
+    }
+
+    // Returns the popularity ranks the workload's distribution can sample (e.g., only the hot ones, if all
+    // updates go to the hot set), whose # bounds the size of a batch.
+    fn reachable_ranks(&self) -> Range<usize> {
+        let num_leaves = self.num_leaves;
+        match self.distribution {
+            LeafDistribution::Uniform | LeafDistribution::Zipfian { .. } => 0..num_leaves,
+            LeafDistribution::Hotset { hot_probability, .. } => {
+                let num_hot = self.num_hot();
+                if hot_probability == 1.0 || num_hot == num_leaves {
+                    0..num_hot
+                } else if hot_probability == 0.0 {
+                    num_hot..num_leaves
+                } else {
+                    0..num_leaves
+                }
+            }
+        }
+    }
+
+    // the # of hot leaves of a Hotset distribution
+    fn num_hot(&self) -> usize {
+        match self.distribution {
+            LeafDistribution::Hotset { hot_fraction, .. } => {
+                ((self.num_leaves as f64 * hot_fraction).ceil() as usize).clamp(1, self.num_leaves)
+            }
+            _ => 0,
+        }
+    }
+
+    // Returns an error if batches of 'num_pos' distinct leaves cannot be sampled from the workload, so a
+    // benchmark can reject its configuration before running.
+    pub fn check_batch_size(&self, num_pos: usize) -> Result<(), String> {
+        let num_reachable = self.reachable_ranks().len();
+        if num_pos > num_reachable {
+            return Err(format!(
+                "Cannot sample {} distinct leaves from {} over {} leaves, which only reaches {} of them",
+                num_pos, self.distribution, self.num_leaves, num_reachable
+            ));
+        }
+
+        Ok(())
+    }
+
+    // Returns 'num_pos' distinct leaf positions in [0, n), where n is the # of leaves, sampled from the
+    // workload's distribution and sorted. Panics if that many cannot be sampled (see check_batch_size()).
+    pub fn sample_positions<R: Rng>(&self, rng: &mut R, num_pos: usize) -> Vec<usize> {
+        if let Err(err) = self.check_batch_size(num_pos) {
+            panic!("{}", err);
+        }
+
+        let num_leaves = self.num_leaves;
+        let reachable = self.reachable_ranks();
+        match self.distribution {
+            LeafDistribution::Uniform => {
+                (0..num_leaves).choose_multiple(rng, num_pos).into_iter().sorted().collect()
+            }
+            LeafDistribution::Zipfian { .. } => {
+                let zipf = self.zipf.as_ref().unwrap();
+
+                // Zipf samples ranks in [1, num_leaves]
+                sample_distinct(rng, num_leaves, num_pos, reachable, |rng| zipf.sample(rng) as usize - 1)
+            }
+            LeafDistribution::Hotset { hot_probability, .. } => {
+                let num_hot = self.num_hot();
+                sample_distinct(rng, num_leaves, num_pos, reachable, |rng| {
+                    if num_hot == num_leaves || rng.gen_bool(hot_probability) {
+                        rng.gen_range(0..num_hot)
+                    } else {
+                        rng.gen_range(num_hot..num_leaves)
+                    }
+                })
+            }
+        }
+    }
+}
+
+// Returns 'num_pos' distinct leaf positions in [0, max_num_leaves), sampled from 'distribution' and
+// sorted (see Workload, which should be used instead for several batches).
+pub fn sample_leaf_positions<R: Rng>(
+    rng: &mut R,
+    max_num_leaves: usize,
+    num_pos: usize,
+    distribution: LeafDistribution,
+) -> Vec<usize> {
+    Workload::new(max_num_leaves, distribution).sample_positions(rng, num_pos)
+}
+
+// Returns the # of distinct sibling groups (i.e., parents) the leaves at the specified positions fall
+// into, in a tree of the specified arity. The fewer groups, the more updates coalesce into each parent
+// update.
+//
+// NOTE: Assumes the leaves are all on the last level (i.e., the tree is perfect).
+pub fn num_sibling_groups(positions: &[usize], arity: usize) -> usize {
+    positions.iter().map(|pos| pos / arity).unique().count()
+}
+
+// Returns 'num_leaves' random leaves of 'leaf_size' bytes each, which share a random prefix and end in
+// a unique suffix (so leaves are slightly larger if 'leaf_size' is too small to fit the suffix).
+pub fn random_leaf_data<R: Rng>(rng: &mut R, num_leaves: usize, leaf_size: usize) -> Vec<String> {
+    let prefix = (0..leaf_size)
+        .map(|_| char::from(rng.sample(Alphanumeric)))
+        .collect::<String>();
+
+    (0..num_leaves)
+        .map(|i| {
+            let suffix = format!("/{}", i);
+
+            prefix[..leaf_size.saturating_sub(suffix.len())].to_owned() + &suffix
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::thread_rng;
+
+    #[test]
+    fn positions_are_distinct_and_in_range() {
+        for distribution in ["uniform", "zipfian", "zipfian:2", "hotset", "hotset:0.01:0.99"] {
+            let distribution = distribution.parse::<LeafDistribution>().unwrap();
+            assert_eq!(distribution.to_string().parse::<LeafDistribution>(), Ok(distribution));
+
+            for (num_leaves, num_pos) in [(1, 1), (10, 10), (1000, 100)] {
+                let positions = sample_leaf_positions(&mut thread_rng(), num_leaves, num_pos, distribution);
+
+                assert_eq!(positions.len(), num_pos);
+                assert!(positions.windows(2).all(|w| w[0] < w[1]));
+                assert!(positions.iter().all(|pos| *pos < num_leaves));
+            }
+        }
+    }
+
+    #[test]
+    fn infeasible_batches_are_rejected() {
+        let all_hot = LeafDistribution::Hotset { hot_fraction: 0.1, hot_probability: 1.0 };
+        let workload = Workload::new(100, all_hot);
+        assert!(workload.check_batch_size(10).is_ok());
+        assert!(workload.check_batch_size(11).is_err());
+        assert_eq!(workload.sample_positions(&mut thread_rng(), 10).len(), 10);
+
+        let all_cold = LeafDistribution::Hotset { hot_fraction: 0.1, hot_probability: 0.0 };
+        let positions = sample_leaf_positions(&mut thread_rng(), 100, 90, all_cold);
+        assert_eq!(positions.len(), 90);
+        assert!(Workload::new(100, all_cold).check_batch_size(91).is_err());
+
+        // sampling every leaf from a steep Zipfian falls back to picking the coldest ones uniformly
+        let zipfian = LeafDistribution::Zipfian { exponent: 4.0 };
+        assert_eq!(sample_leaf_positions(&mut thread_rng(), 1000, 1000, zipfian), (0..1000).collect::<Vec<_>>());
+        assert!(Workload::new(1000, zipfian).check_batch_size(1001).is_err());
+    }
+
+    #[test]
+    fn hot_leaves_are_scattered() {
+        let num_leaves = 1 << 16;
+        let hotset = LeafDistribution::Hotset { hot_fraction: 0.01, hot_probability: 1.0 };
+        let positions = sample_leaf_positions(&mut thread_rng(), num_leaves, 500, hotset);
+
+        // the hot leaves are not all in the same few subtrees
+        assert!(num_sibling_groups(&positions, 16) > 400);
+        assert!(num_sibling_groups(&positions, num_leaves / 4) == 4);
+
+        assert_eq!(num_sibling_groups(&[0, 1, 2, 3, 4, 15, 16], 4), 4);
+        assert!("zipfian:0".parse::<LeafDistribution>().is_err());
+        assert!("hotset:2:0.5".parse::<LeafDistribution>().is_err());
+    }
+
+    #[test]
+    fn leaf_data_has_requested_size() {
+        let leaves = random_leaf_data(&mut thread_rng(), 20, 16);
+
+        assert!(leaves.iter().all(|leaf| leaf.len() == 16));
+        assert_eq!(leaves.iter().unique().count(), 20);
+    }
+}
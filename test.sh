@@ -1,6 +1,6 @@
-#cargo run --release -- -t=merkle_sha3 -a 2 -h 3 -u 4
-#cargo run --release -- -t=merkle_sha3 -a 2 -h 3 -u 8
-cargo run --release -- -t=merkle_sha3 -a 2 -h 15 -u 5000
-#cargo run --release -- -t=merkle_sha3 -a 2 -h 10 -u 1024
-cargo run --release -- -t=merkle_sha3 -a 2 -h 20 -u 50000
-cargo run --release -- -t=merkle_sha3 -a 2 -h 28 -u 200000
+#cargo run --release -- bench -t=merkle_sha3 -a 2 -h 3 -u 4
+#cargo run --release -- bench -t=merkle_sha3 -a 2 -h 3 -u 8
+cargo run --release -- bench -t=merkle_sha3 -a 2 -h 15 -u 5000
+#cargo run --release -- bench -t=merkle_sha3 -a 2 -h 10 -u 1024
+cargo run --release -- bench -t=merkle_sha3 -a 2 -h 20 -u 50000
+cargo run --release -- bench -t=merkle_sha3 -a 2 -h 28 -u 200000
@@ -0,0 +1,177 @@
+// How merkle-race's CRHF trees (i.e., its HasherCRHF) hash their nodes, so that their proofs can be
+// verified here with any hash function implementing Digest (e.g., sha2::Sha256 or sha3::Sha3_256),
+// without the merkle-race crate. The input of each hash is laid out as:
+//
+//   leaf:     domain || [version] || [offset] || [data length] || data
+//   internal: domain || [version] || [arity] || [# of children] || [flag_0] || child_0 || ... || [flag_{k-1}] || child_{k-1}
+//
+// where the version and the flags are bytes and the others are little-endian u32s (u64 for the data
+// length), each only included if enabled by the scheme (and the version only if it is not 0).
+//
+// NOTE: Here, the domain is always hashed as a prefix of the input, as merkle-race does for every hash
+// function but Blake3 (which derives a key from it instead) and SSZ's SHA-256 (which drops it, so use
+// empty domains for SSZ trees).
+
+use crate::{TreeShape, verify_batch as verify_batch_with};
+use alloc::vec::Vec;
+use digest::{Digest, Output};
+
+// The parameters of a CRHF tree's hashing scheme (see HashingScheme in merkle-race, which pins the
+// known-answer tests for every version in docs/test-vectors/crhf.json)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CrhfScheme<'a> {
+    pub version: u8,
+    pub leaf_domain: &'a str,
+    pub internal_domain: &'a str,
+    pub bind_offsets: bool,    // whether leaves bind their offset relative to their parent
+    pub bind_arity: bool,      // whether internal nodes bind the tree's arity
+    pub length_prefixes: bool, // whether the leaf data is prefixed by its length, and the children by their #
+    pub child_flags: bool,     // whether each child is prefixed by whether it is empty (0) or not (1)
+}
+
+impl CrhfScheme<'static> {
+    // only separates the leaf and internal domains
+    pub const V0: Self = CrhfScheme {
+        version: 0,
+        leaf_domain: "leaf:",
+        internal_domain: "internal:",
+        bind_offsets: false,
+        bind_arity: false,
+        length_prefixes: false,
+        child_flags: false,
+    };
+
+    // binds the leaves' offsets, the arity, the lengths of the leaves and the # of children, and the version
+    pub const V1: Self = CrhfScheme {
+        version: 1,
+        bind_offsets: true,
+        bind_arity: true,
+        length_prefixes: true,
+        ..CrhfScheme::V0
+    };
+
+    // v1, with a flag before every child saying whether it is empty
+    pub const STRICT: Self = CrhfScheme {
+        version: 2,
+        child_flags: true,
+        ..CrhfScheme::V1
+    };
+
+    // returns the scheme with the specified version, if any
+    pub fn from_version(version: u8) -> Option<Self> {
+        match version {
+            0 => Some(CrhfScheme::V0),
+            1 => Some(CrhfScheme::V1),
+            2 => Some(CrhfScheme::STRICT),
+            _ => None,
+        }
+    }
+}
+
+impl CrhfScheme<'_> {
+    // passes the input of the hash of a leaf with the specified data, at position 'offset' relative to its
+    // parent, to 'update', except for the leaf domain
+    pub fn encode_leaf(&self, offset: usize, data: &[u8], mut update: impl FnMut(&[u8])) {
+        if self.version != 0 {
+            update(&[self.version]);
+        }
+        if self.bind_offsets {
+            update(&(offset as u32).to_le_bytes());
+        }
+        if self.length_prefixes {
+            update(&(data.len() as u64).to_le_bytes());
+        }
+        update(data);
+    }
+
+    // passes the input of the hash of a parent with the specified children in a tree of arity 'arity' to
+    // 'update', except for the internal domain. Empty children are all zeros.
+    //
+    // NOTE: A parent whose children are all empty is itself empty, rather than hashed.
+    pub fn encode_internal<H: AsRef<[u8]>>(&self, arity: usize, children: &[H], mut update: impl FnMut(&[u8])) {
+        if self.version != 0 {
+            update(&[self.version]);
+        }
+        if self.bind_arity {
+            update(&(arity as u32).to_le_bytes());
+        }
+        if self.length_prefixes {
+            update(&(children.len() as u32).to_le_bytes());
+        }
+        for child in children {
+            if self.child_flags {
+                update(&[!is_empty(child.as_ref()) as u8]);
+            }
+            update(child.as_ref());
+        }
+    }
+
+    // returns the hash of a leaf with the specified data, at position 'offset' relative to its parent
+    pub fn hash_leaf<D: Digest>(&self, offset: usize, data: &[u8]) -> Output<D> {
+        let mut hasher = D::new_with_prefix(self.leaf_domain);
+        self.encode_leaf(offset, data, |bytes| hasher.update(bytes));
+
+        hasher.finalize()
+    }
+
+    // returns the hash of a parent with the specified children in a tree of arity 'arity'
+    pub fn hash_children<D: Digest>(&self, arity: usize, children: &[Output<D>]) -> Output<D> {
+        if children.iter().all(|child| is_empty(child)) {
+            return Output::<D>::default();
+        }
+
+        let mut hasher = D::new_with_prefix(self.internal_domain);
+        self.encode_internal(arity, children, |bytes| hasher.update(bytes));
+
+        hasher.finalize()
+    }
+
+    // Verifies a batch proof for the leaves with the specified data, given as (position, data) pairs,
+    // against 'root', in a CRHF tree of the specified shape which hashes under this scheme with D (see
+    // crate::verify_batch()).
+    pub fn verify_batch<D: Digest>(
+        &self,
+        shape: &TreeShape,
+        root: &Output<D>,
+        leaves: &[(usize, &[u8])],
+        proof: &[Output<D>],
+    ) -> bool {
+        // NOTE: Leaves out of range are hashed too, but then rejected by crate::verify_batch()
+        let leaf_hashes = leaves
+            .iter()
+            .map(|(pos, data)| (*pos, self.hash_leaf::<D>(shape.child_offset(shape.leaf_idx(*pos)), data)))
+            .collect::<Vec<_>>();
+
+        verify_batch_with(shape, root, &leaf_hashes, proof, |children| self.hash_children::<D>(shape.arity, children))
+    }
+}
+
+fn is_empty(hash: &[u8]) -> bool {
+    hash.iter().all(|byte| *byte == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::Sha256;
+
+    #[test]
+    fn empty_parents() {
+        let empty = Output::<Sha256>::default();
+        let leaf = CrhfScheme::V1.hash_leaf::<Sha256>(0, b"leaf");
+
+        // a parent is only empty if all of its children are
+        assert_eq!(CrhfScheme::V1.hash_children::<Sha256>(2, &[empty, empty]), empty);
+        assert_ne!(CrhfScheme::V1.hash_children::<Sha256>(2, &[leaf, empty]), empty);
+
+        // only the strict scheme tells an empty child apart from one whose hash is all zeros, since it
+        // flags it, so its parents hash different inputs
+        let mut v1_input = Vec::new();
+        CrhfScheme::V1.encode_internal(2, &[leaf, empty], |bytes| v1_input.extend_from_slice(bytes));
+        let mut strict_input = Vec::new();
+        CrhfScheme::STRICT.encode_internal(2, &[leaf, empty], |bytes| strict_input.extend_from_slice(bytes));
+        assert_eq!(strict_input.len(), v1_input.len() + 2);
+        assert_eq!(strict_input[9], 1);
+        assert_eq!(strict_input[10 + leaf.len()], 0);
+    }
+}
@@ -0,0 +1,368 @@
+#![no_std]
+
+// The parts of merkle-race needed to verify its batch proofs (see AbstractMerkle::prove_batch()), which
+// only depend on core and alloc, so that verifiers can run in no_std environments (e.g., embedded,
+// zkVM or wasm). Trees themselves are built by the (std-only) merkle-race crate.
+//
+// Verifiers pass in the leaf hashes and a function which hashes a parent from all of its children, so
+// the same code walks Merkle, Merkle++ and Verkle proofs. However, only CRHF trees can be verified
+// without std: crhf.rs hashes their leaves and parents the way merkle-race does, given any Digest.
+// There are no no_std parent hashes for Merkle++ (the LtHash or Ristretto sum of its children) or for
+// Verkle (a Pedersen or KZG commitment to its children), so their proofs must be verified with
+// TreeHasherFunc::hash_all_children() from the std-only merkle-race crate.
+
+extern crate alloc;
+
+pub mod crhf;
+
+use alloc::collections::{BTreeMap, BinaryHeap};
+use alloc::vec::Vec;
+use core::ops::Range;
+
+// The shape of a tree of arity k with n leaves, all of which are either on its last level h or, if
+// they do not fit there, split amongst levels h and h + 1 (see https://hackmd.io/54A_Zk58SHqxpBQwwfx0Cg).
+//
+// Nodes are numbered level by level, starting with the root at 0, so the children of node i are
+// nodes i*k + 1 .. i*k + k. The leaves are the last n nodes, by position: first the ones on the
+// second-to-last level (if any), then the ones on the last level.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TreeShape {
+    pub arity: usize,
+    pub num_leaves: usize,
+    pub num_internal_nodes: usize,
+    pub num_second_to_last: usize, // the # of leaves on the second-to-last level
+}
+
+impl TreeShape {
+    pub fn new(arity: usize, num_leaves: usize) -> Self {
+        assert!(arity >= 2, "Arity must be at least 2");
+
+        // compute the height of the tallest perfect tree with at most 'num_leaves' leaves
+        let mut height: usize = 0;
+        let mut n = num_leaves;
+        while n / arity > 0 {
+            height += 1;
+
+            n /= arity;
+        }
+
+        let max_leaves = arity.pow(height as u32);
+        let num_perfect_internal = (max_leaves - 1) / (arity - 1);
+        if num_leaves <= max_leaves {
+            return TreeShape { arity, num_leaves, num_internal_nodes: num_perfect_internal, num_second_to_last: 0 };
+        }
+
+        // If all leaves fit on the last level, we are done. Otherwise, the last R nodes on level
+        // 'height' are leaves, and their would-be siblings on level 'height + 1' are leaves as well,
+        // where the last internal node on level 'height' has 'epsilon' in [1, arity] children.
+        let last_level_max_size = max_leaves * arity;
+        let num_second_to_last = if last_level_max_size - num_leaves >= arity {
+            let mut epsilon = arity;
+            let r_num_f = |e: usize| last_level_max_size - num_leaves - (arity - e);
+            let mut r_num = r_num_f(epsilon);
+            let r_denom = arity - 1;
+
+            while r_num % r_denom != 0 {
+                epsilon -= 1;
+                if epsilon == 0 {
+                    panic!("Alin math fail: epsilon was supposed to stay in [1, arity]");
+                }
+                r_num = r_num_f(epsilon);
+            }
+
+            r_num / r_denom
+        } else {
+            0
+        };
+
+        TreeShape {
+            arity,
+            num_leaves,
+            num_internal_nodes: num_perfect_internal + max_leaves - num_second_to_last,
+            num_second_to_last,
+        }
+    }
+
+    // returns the # of nodes (internal and leaves) in the tree
+    pub fn num_nodes(&self) -> usize {
+        self.num_internal_nodes + self.num_leaves
+    }
+
+    // returns the index of the first leaf on the last level
+    pub fn first_last_level_leaf(&self) -> usize {
+        self.num_internal_nodes + self.num_second_to_last
+    }
+
+    // returns the index of the leaf at position 'leaf_pos', in [0, n)
+    pub fn leaf_idx(&self, leaf_pos: usize) -> usize {
+        self.num_internal_nodes + leaf_pos
+    }
+
+    // returns the node's position i relative to its parent, where i \in [0, arity)
+    pub fn child_offset(&self, idx: usize) -> usize {
+        if idx == 0 {
+            0
+        } else {
+            (idx - 1) % self.arity
+        }
+    }
+
+    pub fn parent(&self, idx: usize) -> usize {
+        (idx - 1) / self.arity
+    }
+
+    // returns the indices of the children this node actually has; a parent on the second-to-last level
+    // might have less than 'arity' children, and leaves have none
+    pub fn children(&self, idx: usize) -> Range<usize> {
+        let first = (idx * self.arity + 1).min(self.num_nodes());
+        let end = (idx * self.arity + self.arity + 1).min(self.num_nodes());
+
+        first..end
+    }
+}
+
+// Verifies a batch proof for the leaves with the specified hashes, given as (position, leaf hash) pairs,
+// against 'root', where 'hash_children' hashes a parent from the hashes of all of its children. The
+// proof's hashes must be the ones AbstractMerkle::prove_batch() returns, in the same order.
+//
+// NOTE: The same leaf can be given more than once, but only with the same hash.
+pub fn verify_batch<HashType, HashChildren>(
+    shape: &TreeShape,
+    root: &HashType,
+    leaf_hashes: &[(usize, HashType)],
+    proof: &[HashType],
+    mut hash_children: HashChildren,
+) -> bool
+where
+    HashType: Clone + PartialEq,
+    HashChildren: FnMut(&[HashType]) -> HashType,
+{
+    let mut verifier = match BatchVerifier::new(*shape, root.clone(), leaf_hashes) {
+        Some(verifier) => verifier,
+        None => return false,
+    };
+
+    for hash in proof {
+        if !verifier.push(hash.clone(), &mut hash_children) {
+            return false;
+        }
+    }
+
+    verifier.finish(&mut hash_children)
+}
+
+// Verifies a batch proof one hash at a time, as the hashes arrive (e.g., over the network), so that the
+// proof never needs to be held in memory: only the hashes of the nodes whose parents are not computed
+// yet are kept around, which are at most (arity - 1) per level plus the leaves not reached yet.
+//
+// The parents are visited by decreasing index, so children are always computed before their parents,
+// and each proof hash is the next missing child of the parent being computed.
+pub struct BatchVerifier<HashType> {
+    shape: TreeShape,
+    root: HashType,
+    computed: BTreeMap<usize, HashType>, // the nodes whose parents were not computed yet
+    heap: BinaryHeap<usize>,
+    parent: Option<(usize, Range<usize>)>, // the parent being computed, and its children left to add
+    children: Vec<HashType>,               // the hashes of the parent's children, so far
+    failed: bool,
+}
+
+impl<HashType: Clone + PartialEq> BatchVerifier<HashType> {
+    // returns a verifier for the leaves with the specified hashes, or None if some of them are out of
+    // range or given twice with different hashes
+    pub fn new(shape: TreeShape, root: HashType, leaf_hashes: &[(usize, HashType)]) -> Option<Self> {
+        let mut computed: BTreeMap<usize, HashType> = BTreeMap::new();
+        let mut heap: BinaryHeap<usize> = BinaryHeap::new();
+
+        for (pos, hash) in leaf_hashes {
+            if *pos >= shape.num_leaves {
+                return None;
+            }
+
+            let leaf_idx = shape.leaf_idx(*pos);
+            match computed.insert(leaf_idx, hash.clone()) {
+                // the same leaf cannot be proven to have two different values
+                Some(old_hash) if old_hash != *hash => return None,
+                Some(_) => {}
+                None => heap.push(leaf_idx),
+            }
+        }
+
+        Some(BatchVerifier {
+            shape,
+            root,
+            computed,
+            heap,
+            parent: None,
+            children: Vec::with_capacity(shape.arity),
+            failed: false,
+        })
+    }
+
+    // returns the # of node hashes currently held, i.e., how much memory the verifier is using
+    pub fn num_pending(&self) -> usize {
+        self.computed.len() + self.children.len()
+    }
+
+    // consumes the next hash of the proof, returning false if the proof already failed to verify (e.g.,
+    // because it has too many hashes)
+    pub fn push<HashChildren>(&mut self, hash: HashType, hash_children: &mut HashChildren) -> bool
+    where
+        HashChildren: FnMut(&[HashType]) -> HashType,
+    {
+        self.advance(hash_children);
+        if self.failed || self.parent.is_none() {
+            self.failed = true;
+            return false;
+        }
+
+        // NOTE: advance() only stops at a parent when the next child is not computed, so it is this one
+        if let Some((_, children)) = &mut self.parent {
+            children.next();
+        }
+        self.children.push(hash);
+
+        true
+    }
+
+    // returns true if the proof's hashes pushed so far (and no more) prove the leaves against the root
+    pub fn finish<HashChildren>(mut self, hash_children: &mut HashChildren) -> bool
+    where
+        HashChildren: FnMut(&[HashType]) -> HashType,
+    {
+        self.advance(hash_children);
+
+        // a valid proof is not missing any hashes
+        !self.failed && self.parent.is_none() && self.computed.get(&0) == Some(&self.root)
+    }
+
+    // computes parents until one is missing a child that is not computed, i.e., one that must come
+    // from the proof, or until the root is reached
+    fn advance<HashChildren>(&mut self, hash_children: &mut HashChildren)
+    where
+        HashChildren: FnMut(&[HashType]) -> HashType,
+    {
+        if self.failed {
+            return;
+        }
+
+        loop {
+            if let Some((parent_idx, children)) = &mut self.parent {
+                while let Some(child_idx) = children.clone().next() {
+                    match self.computed.remove(&child_idx) {
+                        Some(hash) => self.children.push(hash),
+                        None => return,
+                    }
+                    children.next();
+                }
+
+                let hash = hash_children(&self.children);
+                self.children.clear();
+                self.computed.insert(*parent_idx, hash);
+                self.heap.push(*parent_idx);
+                self.parent = None;
+            }
+
+            let idx = match self.heap.pop() {
+                Some(0) | None => return,
+                Some(idx) => idx,
+            };
+
+            let parent_idx = self.shape.parent(idx);
+            if !self.computed.contains_key(&parent_idx) {
+                self.parent = Some((parent_idx, self.shape.children(parent_idx)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    // a (non-cryptographic) hash, which is enough to check that the right children are hashed in order
+    fn hash_children(children: &[u64]) -> u64 {
+        children.iter().fold(17, |hash, child| hash.wrapping_mul(31).wrapping_add(*child))
+    }
+
+    #[test]
+    fn tree_shapes() {
+        // arity 3 with 10 leaves: the first node on level 2 is internal, with the last 2 leaves as its
+        // children, and the other 8 nodes on level 2 are the first 8 leaves
+        let shape = TreeShape::new(3, 10);
+        assert_eq!((shape.num_internal_nodes, shape.num_second_to_last, shape.num_nodes()), (5, 8, 15));
+        assert_eq!(shape.first_last_level_leaf(), 13);
+        assert_eq!(shape.children(4), 13..15);
+        assert_eq!(shape.children(5), 15..15);
+
+        // arity 2 with 7 leaves: all of them are on level 3, since only the last node on level 2 would
+        // otherwise have been a leaf
+        let shape = TreeShape::new(2, 7);
+        assert_eq!((shape.num_internal_nodes, shape.num_second_to_last), (7, 0));
+        assert_eq!(shape.children(6), 13..14);
+
+        let shape = TreeShape::new(2, 8);
+        assert_eq!((shape.num_internal_nodes, shape.num_second_to_last), (7, 0));
+        assert_eq!((shape.leaf_idx(0), shape.child_offset(7), shape.child_offset(8)), (7, 0, 1));
+    }
+
+    #[test]
+    fn batch_proofs() {
+        // a binary tree with 4 leaves at nodes 3..7
+        let shape = TreeShape::new(2, 4);
+        let leaves = [10u64, 11, 12, 13];
+        let (left, right) = (hash_children(&leaves[..2]), hash_children(&leaves[2..]));
+        let root = hash_children(&[left, right]);
+
+        // proving leaf 0 takes its sibling (leaf 1), and then its parent's sibling
+        let proof = [leaves[1], right];
+        assert!(verify_batch(&shape, &root, &[(0, leaves[0])], &proof, hash_children));
+        assert!(!verify_batch(&shape, &root, &[(0, leaves[1])], &proof, hash_children));
+        assert!(!verify_batch(&shape, &left, &[(0, leaves[0])], &proof, hash_children));
+        assert!(!verify_batch(&shape, &root, &[(0, leaves[0])], &proof[..1], hash_children));
+        assert!(!verify_batch(&shape, &root, &[(0, leaves[0])], &[leaves[1], right, right], hash_children));
+        assert!(!verify_batch(&shape, &root, &[(4, leaves[0])], &proof, hash_children));
+
+        // proving leaves 0 and 3 takes leaf 2 (the sibling of the higher leaf) first, then leaf 1
+        let leaf_hashes = vec![(3, leaves[3]), (0, leaves[0]), (0, leaves[0])];
+        assert!(verify_batch(&shape, &root, &leaf_hashes, &[leaves[2], leaves[1]], hash_children));
+        assert!(!verify_batch(&shape, &root, &[(0, leaves[0]), (0, leaves[1])], &proof, hash_children));
+    }
+
+    #[test]
+    fn streaming_verification() {
+        // a binary tree with 16 leaves at nodes 15..31
+        let shape = TreeShape::new(2, 16);
+        let mut nodes = vec![0u64; shape.num_nodes()];
+        for idx in (0..shape.num_nodes()).rev() {
+            nodes[idx] = match shape.children(idx) {
+                children if children.is_empty() => idx as u64 * 7,
+                children => hash_children(&nodes[children]),
+            };
+        }
+
+        // proving leaf 0 takes its sibling, and then the sibling of each of its ancestors
+        let proof = [nodes[16], nodes[8], nodes[4], nodes[2]];
+        let mut verifier = BatchVerifier::new(shape, nodes[0], &[(0, nodes[15])]).unwrap();
+        for hash in proof {
+            assert!(verifier.push(hash, &mut hash_children));
+
+            // only the hash of the last computed ancestor is held in between hashes
+            assert_eq!(verifier.num_pending(), 2);
+        }
+        assert!(verifier.finish(&mut hash_children));
+
+        let mut verifier = BatchVerifier::new(shape, nodes[0], &[(0, nodes[15])]).unwrap();
+        for hash in proof.iter().chain(&[nodes[2]]) {
+            verifier.push(*hash, &mut hash_children);
+        }
+        assert!(!verifier.finish(&mut hash_children));
+
+        let mut verifier = BatchVerifier::new(shape, nodes[0], &[(0, nodes[15])]).unwrap();
+        verifier.push(proof[0], &mut hash_children);
+        assert!(!verifier.finish(&mut hash_children));
+
+        assert!(BatchVerifier::new(shape, nodes[0], &[(16, nodes[15])]).is_none());
+    }
+}